@@ -1,4 +1,498 @@
 use mem::Mem;
+use region::Region;
+
+use std::mem;
+
+// Noise timer periods in CPU cycles, picked by the low nibble of $400E
+const NOISE_PERIODS_NTSC: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const NOISE_PERIODS_PAL: [u16; 16] = [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778];
+// DMC output rates in CPU cycles, picked by the low nibble of $4010
+const DMC_RATES_NTSC: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+const DMC_RATES_PAL: [u16; 16] = [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50];
+// CPU cycles after a $4017 write at which the frame counter clocks the
+// envelopes, sweeps and length counters. The 4-step sequence ends on the
+// fourth and the 5-step one on the fifth.
+const FRAME_STEPS_NTSC: [u32; 5] = [7457, 14913, 22371, 29829, 37281];
+const FRAME_STEPS_PAL: [u32; 5] = [8313, 16627, 24939, 33253, 41565];
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Volume envelope shared by the pulse and noise channels
+struct Envelope {
+    // Also the length counter halt flag
+    looping: bool,
+    constant_volume: bool,
+    // Constant volume, or the divider period
+    volume: u8,
+    start: bool,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            looping: false,
+            constant_volume: false,
+            volume: 0,
+            start: false,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // $4000, $4004 and $400C: --LC VVVV
+    fn storeb(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant_volume = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay }
+    }
+}
+
+struct Pulse {
+    // Pulse 1 negates its sweep with ones' complement, taking one more
+    // off the period than pulse 2 does
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+    enabled: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement: ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: 0,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+            enabled: false,
+        }
+    }
+
+    fn storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.envelope.storeb(val);
+            }
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x07;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Period the sweep unit is heading for. It is worked out continuously
+    // and silences the channel when out of range, even with the sweep off.
+    fn sweep_target(&self) -> i32 {
+        let change = (self.period >> self.sweep_shift) as i32;
+        if self.sweep_negate {
+            self.period as i32 - change - self.ones_complement as i32
+        } else {
+            self.period as i32 + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target().max(0) as u16;
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Triangle {
+    // Halts the length counter and keeps reloading the linear counter
+    control: bool,
+    linear_period: u8,
+    linear: u8,
+    linear_reload: bool,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    enabled: bool,
+}
+
+impl Triangle {
+    fn new() -> Triangle {
+        Triangle {
+            control: false,
+            linear_period: 0,
+            linear: 0,
+            linear_reload: false,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: 0,
+            enabled: false,
+        }
+    }
+
+    fn storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.linear_period = val & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // Clocked every CPU cycle. The sequencer holds its place, and so the
+    // output level, while either counter is at zero.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear = self.linear_period;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+struct Noise {
+    // Short mode taps bit 6 instead of bit 1, for a 93-step sequence
+    short_mode: bool,
+    period_index: u8,
+    shift: u16,
+    timer: u16,
+    length: u8,
+    envelope: Envelope,
+    enabled: bool,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            short_mode: false,
+            period_index: 0,
+            shift: 1,
+            timer: 0,
+            length: 0,
+            envelope: Envelope::new(),
+            enabled: false,
+        }
+    }
+
+    fn storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.envelope.storeb(val),
+            1 => {}
+            2 => {
+                self.short_mode = val & 0x80 != 0;
+                self.period_index = val & 0x0F;
+            }
+            _ => {
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // Clocked every CPU cycle with the period from the region's table
+    fn clock_timer(&mut self, period: u16) {
+        if self.timer == 0 {
+            self.timer = period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+// Delta modulation channel: plays 1-bit deltas read from CPU memory
+// at $C000-$FFFF, wrapping round to $8000
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    rate_index: u8,
+    // 7-bit output level, moved up or down by 2 for each bit
+    level: u8,
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    timer: u16,
+    irq: bool,
+}
+
+impl Dmc {
+    fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            rate_index: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_len: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            timer: 0,
+            irq: false,
+        }
+    }
+
+    fn storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = val & 0x40 != 0;
+                self.rate_index = val & 0x0F;
+            }
+            1 => self.level = val & 0x7F,
+            2 => self.sample_addr = 0xC000 | (val as u16) << 6,
+            _ => self.sample_len = (val as u16) << 4 | 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    // Address the memory reader wants to fill the empty sample buffer from
+    fn request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        self.current_addr = if self.current_addr == 0xFFFF { 0x8000 } else { self.current_addr + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle with the rate from the region's table
+    fn clock_timer(&mut self, rate: u16) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = rate - 1;
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
 
 pub struct Apu {
     /*
@@ -11,51 +505,422 @@ pub struct Apu {
     $4015 	        All 	    Channel enable and length counter status
     $4017 	        All     	Frame counter
     */
-    pulse_1: [u8; 4],
-    pulse_2: [u8; 4],
-    triangle: [u8; 4],
-    noise: [u8; 4],
-    dmc: [u8; 4],
-    status: u8,
-    frame_counter: u8
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    // Frame counter: $4017 is MI-- ----, 5-step mode (M), IRQ inhibit (I)
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles into the frame counter's sequence
+    frame_cycle: u32,
+    // Pulse timers tick on every other CPU cycle
+    odd_cycle: bool,
+    region: Region,
+    // Output resampled to the host's rate, each sample the average of
+    // the mix over its CPU cycles. No samples are kept at rate 0.
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    sample_clock: f64,
+    sample_sum: f32,
+    sample_cycles: u32,
+    samples: Vec<f32>,
 }
 
 impl Apu {
-    fn getMemLocation(&mut self, addr: usize) -> &mut u8 {
-        match addr {
-            0...3 => {
-                &mut self.pulse_1[addr % 4]
-            }
-            4...7 => {
-                &mut self.pulse_2[addr % 4]
-            }
-            8...11 => {
-                &mut self.triangle[addr % 4]
-            }
-            12...15 => {
-                &mut self.noise[addr % 4]
-            }
-            16...19 => {
-                &mut self.dmc[addr % 4]
-            }
-            20 => {
-                &mut self.status
-            }
-            21 => {
-                &mut self.frame_counter
+    pub fn new() -> Apu {
+        Apu {
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            region: Region::Ntsc,
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            sample_cycles: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        let rate = self.sample_rate;
+        self.set_sample_rate(rate);
+    }
+
+    // Start producing samples at the given rate, or stop at 0
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.cycles_per_sample = if rate == 0 { 0.0 } else { self.region.cpu_clock() as f64 / rate as f64 };
+        self.sample_clock = 0.0;
+        self.sample_sum = 0.0;
+        self.sample_cycles = 0;
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Take the samples produced since last asked
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::replace(&mut self.samples, Vec::new())
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Period of the noise timer in CPU cycles. The Dendy shares PAL's
+    // tables, which were made for its near identical CPU clock.
+    pub fn noise_period(&self) -> u16 {
+        let index = self.noise.period_index as usize;
+        match self.region {
+            Region::Ntsc => NOISE_PERIODS_NTSC[index],
+            Region::Pal | Region::Dendy => NOISE_PERIODS_PAL[index],
+        }
+    }
+
+    // CPU cycles between DMC output bits
+    pub fn dmc_rate(&self) -> u16 {
+        let index = self.dmc.rate_index as usize;
+        match self.region {
+            Region::Ntsc => DMC_RATES_NTSC[index],
+            Region::Pal | Region::Dendy => DMC_RATES_PAL[index],
+        }
+    }
+
+    // When the frame counter's steps fall. The Dendy's counter runs at
+    // NTSC speed.
+    pub fn frame_steps(&self) -> &'static [u32; 5] {
+        match self.region {
+            Region::Ntsc | Region::Dendy => &FRAME_STEPS_NTSC,
+            Region::Pal => &FRAME_STEPS_PAL,
+        }
+    }
+
+    // Advance by a single CPU cycle, mixing the cartridge's expansion
+    // audio for the cycle into the output
+    pub fn step(&mut self, expansion: f32) {
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        let period = self.noise_period();
+        self.noise.clock_timer(period);
+        let rate = self.dmc_rate();
+        self.dmc.clock_timer(rate);
+
+        self.frame_cycle += 1;
+        let steps = self.frame_steps();
+        let last = if self.five_step { steps[4] } else { steps[3] };
+        if self.frame_cycle == steps[0] || self.frame_cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if self.frame_cycle == steps[1] || self.frame_cycle == last {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+        if self.frame_cycle == last {
+            if !self.five_step && !self.irq_inhibit {
+                self.frame_irq = true;
             }
-            _ => {
-                panic!("Invalid address in Apu")
+            self.frame_cycle = 0;
+        }
+
+        if self.sample_rate != 0 {
+            self.sample_sum += self.output() + expansion;
+            self.sample_cycles += 1;
+            self.sample_clock += 1.0;
+            if self.sample_clock >= self.cycles_per_sample {
+                self.sample_clock -= self.cycles_per_sample;
+                self.samples.push(self.sample_sum / self.sample_cycles as f32);
+                self.sample_sum = 0.0;
+                self.sample_cycles = 0;
             }
         }
     }
+
+    // Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    // Length counters and sweeps
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.clock_length();
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_length();
+        self.pulse_2.clock_sweep();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+    }
+
+    // Whether the frame counter or the DMC is asserting /IRQ
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Address of the byte the DMC wants from CPU memory, if its sample
+    // buffer is empty. The bus reads it and hands it to dmc_fill.
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.request()
+    }
+
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.fill(val);
+    }
+
+    // Mix of the five channels from 0.0 to 1.0, following the
+    // nonlinear response of the 2A03's output. The samples add the
+    // expansion audio on top.
+    pub fn output(&self) -> f32 {
+        let pulses = (self.pulse_1.output() + self.pulse_2.output()) as f32;
+        let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        let tnd = self.triangle.output() as f32 / 8227.0 + self.noise.output() as f32 / 12241.0
+            + self.dmc.level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+        pulse_out + tnd_out
+    }
+
+    fn status(&self) -> u8 {
+        (self.pulse_1.length > 0) as u8
+            | ((self.pulse_2.length > 0) as u8) << 1
+            | ((self.triangle.length > 0) as u8) << 2
+            | ((self.noise.length > 0) as u8) << 3
+            | ((self.dmc.bytes_remaining > 0) as u8) << 4
+            | (self.frame_irq as u8) << 6
+            | (self.dmc.irq as u8) << 7
+    }
 }
 
 impl Mem for Apu {
+    // Only $4015 can be read, the other registers are write only
     fn loadb(&mut self, addr: u16) -> u8 {
-        *self.getMemLocation(addr as usize)
+        match addr {
+            0x15 => {
+                let status = self.status();
+                self.frame_irq = false;
+                status
+            }
+            _ => 0,
+        }
     }
+
     fn storeb(&mut self, addr: u16, val: u8) {
-        *self.getMemLocation(addr as usize) = val;
+        match addr {
+            0x00...0x03 => self.pulse_1.storeb(addr % 4, val),
+            0x04...0x07 => self.pulse_2.storeb(addr % 4, val),
+            0x08...0x0B => self.triangle.storeb(addr % 4, val),
+            0x0C...0x0F => self.noise.storeb(addr % 4, val),
+            0x10...0x13 => self.dmc.storeb(addr % 4, val),
+            // $4014 is OAM DMA and $4016 the controller strobe
+            0x15 => {
+                self.pulse_1.set_enabled(val & 0x01 != 0);
+                self.pulse_2.set_enabled(val & 0x02 != 0);
+                self.triangle.set_enabled(val & 0x04 != 0);
+                self.noise.set_enabled(val & 0x08 != 0);
+                self.dmc.set_enabled(val & 0x10 != 0);
+            }
+            0x17 => {
+                self.five_step = val & 0x80 != 0;
+                self.irq_inhibit = val & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // The 5-step sequence clocks everything straight away
+                if self.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => panic!("Invalid address in Apu"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.step(0.0);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn length_counters_show_in_the_status_and_count_down() {
+        let mut apu = Apu::new();
+        // Writes to a disabled channel leave its length counter at zero
+        apu.storeb(0x03, 0x08);
+        assert_eq!(apu.loadb(0x15), 0x00);
+
+        apu.storeb(0x15, 0x0F);
+        apu.storeb(0x03, 0x18);
+        apu.storeb(0x0B, 0x18);
+        assert_eq!(apu.loadb(0x15), 0x05);
+        // A length of 2 runs out after two half frames
+        run(&mut apu, FRAME_STEPS_NTSC[1]);
+        assert_eq!(apu.loadb(0x15), 0x05);
+        run(&mut apu, FRAME_STEPS_NTSC[3] - FRAME_STEPS_NTSC[1]);
+        // The frame IRQ has been raised by now as well
+        assert_eq!(apu.loadb(0x15), 0x40);
+
+        apu.storeb(0x0F, 0x08);
+        assert_eq!(apu.loadb(0x15), 0x08);
+        apu.storeb(0x15, 0x00);
+        assert_eq!(apu.loadb(0x15), 0x00);
+    }
+
+    #[test]
+    fn the_frame_irq_follows_the_region() {
+        let mut apu = Apu::new();
+        apu.set_region(Region::Pal);
+        run(&mut apu, FRAME_STEPS_NTSC[3]);
+        assert!(!apu.irq_pending());
+        run(&mut apu, FRAME_STEPS_PAL[3] - FRAME_STEPS_NTSC[3]);
+        assert!(apu.irq_pending());
+        // Reading the status clears it
+        assert_eq!(apu.loadb(0x15) & 0x40, 0x40);
+        assert!(!apu.irq_pending());
+
+        // Neither the 5-step sequence nor an inhibited one raise it
+        apu.storeb(0x17, 0x80);
+        run(&mut apu, FRAME_STEPS_PAL[4] * 2);
+        assert!(!apu.irq_pending());
+        apu.storeb(0x17, 0x40);
+        run(&mut apu, FRAME_STEPS_PAL[3] * 2);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn noise_and_dmc_rates_follow_the_region() {
+        let mut apu = Apu::new();
+        apu.storeb(0x0E, 0x02);
+        apu.storeb(0x10, 0x0F);
+        assert_eq!((apu.noise_period(), apu.dmc_rate()), (16, 54));
+        apu.set_region(Region::Dendy);
+        assert_eq!((apu.noise_period(), apu.dmc_rate()), (14, 50));
+        assert_eq!(apu.frame_steps()[3], 29829);
+    }
+
+    #[test]
+    fn the_sweep_mutes_pulses_heading_out_of_range() {
+        let mut pulse = Pulse::new(true);
+        pulse.set_enabled(true);
+        pulse.storeb(0, 0xBF);
+        pulse.storeb(2, 0xFF);
+        pulse.storeb(3, 0x07);
+        pulse.step = 2;
+        // An upward sweep with no shift still puts the target past $7FF
+        assert_eq!(pulse.output(), 0);
+        pulse.storeb(1, 0x81);
+        assert_eq!(pulse.output(), 0);
+        pulse.storeb(1, 0x0F);
+        assert_eq!(pulse.output(), 15);
+        // Pulse 1 takes one more off a negated sweep than pulse 2
+        pulse.storeb(1, 0x8F);
+        assert_eq!(pulse.sweep_target(), 0x7FF - 0x0F - 1);
+        let mut pulse_2 = Pulse::new(false);
+        pulse_2.storeb(2, 0xFF);
+        pulse_2.storeb(3, 0x07);
+        pulse_2.storeb(1, 0x8F);
+        assert_eq!(pulse_2.sweep_target(), 0x7FF - 0x0F);
+        // Periods under 8 are muted too
+        pulse.storeb(2, 0x07);
+        pulse.storeb(3, 0x00);
+        pulse.step = 2;
+        assert_eq!(pulse.output(), 0);
+    }
+
+    #[test]
+    fn the_dmc_reads_its_sample_and_raises_an_irq() {
+        let mut apu = Apu::new();
+        // IRQ on, fastest rate, sample at $C040 of 17 bytes
+        apu.storeb(0x10, 0x8F);
+        apu.storeb(0x12, 0x01);
+        apu.storeb(0x13, 0x01);
+        assert_eq!(apu.dmc_request(), None);
+        apu.storeb(0x15, 0x10);
+        assert_eq!(apu.loadb(0x15) & 0x10, 0x10);
+
+        let mut addrs = Vec::new();
+        for _ in 0..17 * 8 * 54 {
+            apu.step(0.0);
+            if let Some(addr) = apu.dmc_request() {
+                addrs.push(addr);
+                apu.dmc_fill(0xFF);
+            }
+        }
+        assert_eq!(addrs, (0xC040..0xC051).collect::<Vec<u16>>());
+        assert!(apu.irq_pending());
+        assert_eq!(apu.loadb(0x15) & 0x90, 0x80);
+        // The output level climbed with each set bit
+        assert!(apu.dmc.level > 100);
+        apu.storeb(0x15, 0x00);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn samples_average_the_mix_and_expansion_audio() {
+        let mut apu = Apu::new();
+        run(&mut apu, 100);
+        assert!(apu.take_samples().is_empty());
+
+        apu.set_sample_rate(44100);
+        let rest = apu.output();
+        for _ in 0..Region::Ntsc.cpu_clock() / 10 {
+            apu.step(0.25);
+        }
+        // A tenth of a second, give or take the fraction of a sample left
+        let samples = apu.take_samples();
+        assert!(samples.len() >= 4409 && samples.len() <= 4410);
+        assert!(samples.iter().all(|&sample| (sample - rest - 0.25).abs() < 1e-6));
+        assert!(apu.take_samples().is_empty());
+
+        // PAL's slower clock makes for fewer cycles a sample
+        apu.set_region(Region::Pal);
+        for _ in 0..Region::Pal.cpu_clock() / 10 {
+            apu.step(0.0);
+        }
+        let samples = apu.take_samples();
+        assert!(samples.len() >= 4409 && samples.len() <= 4410);
+    }
+
+    #[test]
+    fn the_dmc_address_wraps_to_8000() {
+        let mut dmc = Dmc::new();
+        dmc.sample_len = 2;
+        dmc.sample_addr = 0xFFFF;
+        dmc.set_enabled(true);
+        dmc.fill(0);
+        dmc.buffer = None;
+        assert_eq!(dmc.request(), Some(0x8000));
+    }
+
+    #[test]
+    fn the_mix_stays_in_range() {
+        let mut apu = Apu::new();
+        // The triangle rests at the top of its sequence
+        let rest = apu.output();
+        assert!(rest > 0.0);
+        apu.storeb(0x11, 0x7F);
+        let level = apu.output();
+        assert!(level > rest && level < 1.0);
+    }
+}
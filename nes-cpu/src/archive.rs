@@ -0,0 +1,314 @@
+use std::io::Read;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use rom::RomError;
+use util;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZIP_MAGIC: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+
+const ZIP_END_SIGNATURE: u32 = 0x06054B50;
+const ZIP_CENTRAL_SIGNATURE: u32 = 0x02014B50;
+const ZIP_LOCAL_SIGNATURE: u32 = 0x04034B50;
+// End of central directory record, without its trailing comment
+const ZIP_END_SIZE: usize = 22;
+
+// Most space set aside up front for a member, whatever size its header
+// claims. No image comes near it, and the vector grows past it if needed.
+const MAX_PREALLOCATION: usize = 16 << 20;
+
+// Members taken from a zip when none is named
+const IMAGE_EXTENSIONS: [&'static str; 6] = ["nes", "fds", "unf", "unif", "nsf", "nsfe"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Plain,
+    Gzip,
+    Zip,
+}
+
+impl Container {
+    pub fn detect(data: &[u8]) -> Container {
+        if data.starts_with(&GZIP_MAGIC) {
+            Container::Gzip
+        } else if data.starts_with(&ZIP_MAGIC) {
+            Container::Zip
+        } else {
+            Container::Plain
+        }
+    }
+}
+
+// Read a possibly compressed image in full, decompressing it if needed
+pub fn read(r: &mut Read, member: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let mut data = Vec::new();
+    try!(r.read_to_end(&mut data));
+    extract(data, member)
+}
+
+// Unwrap a .gz or .zip image, taking the named zip member or else the
+// first one with a ROM extension. Other data is returned unchanged.
+pub fn extract(data: Vec<u8>, member: Option<&str>) -> Result<Vec<u8>, RomError> {
+    match Container::detect(&data) {
+        Container::Plain => Ok(data),
+        Container::Gzip => {
+            let mut out = Vec::new();
+            try!(GzDecoder::new(&data[..]).read_to_end(&mut out));
+            Ok(out)
+        }
+        Container::Zip => extract_zip(&data, member),
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Result<usize, RomError> {
+    match data.get(pos..pos + 2) {
+        Some(bytes) => Ok(bytes[0] as usize | (bytes[1] as usize) << 8),
+        None => Err(RomError::FormatError),
+    }
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, RomError> {
+    match data.get(pos..pos + 4) {
+        Some(bytes) => Ok(bytes[0] as u32 | (bytes[1] as u32) << 8
+                          | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24),
+        None => Err(RomError::FormatError),
+    }
+}
+
+fn has_image_extension(name: &str) -> bool {
+    match name.rsplit('.').next() {
+        Some(ext) if ext.len() < name.len() => {
+            IMAGE_EXTENSIONS.iter().any(|image| image.eq_ignore_ascii_case(ext))
+        }
+        _ => false,
+    }
+}
+
+// Central directory entry of a zip member
+struct ZipEntry {
+    name: String,
+    method: usize,
+    crc32: u32,
+    compressed_size: usize,
+    size: usize,
+    local_offset: usize,
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, RomError> {
+    // The end record sits at the end of the archive, before a comment of
+    // up to 64KB
+    if data.len() < ZIP_END_SIZE {
+        return Err(RomError::FormatError);
+    }
+    let search_start = data.len().saturating_sub(ZIP_END_SIZE + 0xFFFF);
+    let mut end = None;
+    for pos in (search_start..data.len() - ZIP_END_SIZE + 1).rev() {
+        if try!(read_u32(data, pos)) == ZIP_END_SIGNATURE {
+            end = Some(pos);
+            break;
+        }
+    }
+    let end = match end {
+        Some(end) => end,
+        None => return Err(RomError::FormatError),
+    };
+
+    let count = try!(read_u16(data, end + 10));
+    let mut pos = try!(read_u32(data, end + 16)) as usize;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if try!(read_u32(data, pos)) != ZIP_CENTRAL_SIGNATURE {
+            return Err(RomError::FormatError);
+        }
+        let name_len = try!(read_u16(data, pos + 28));
+        let extra_len = try!(read_u16(data, pos + 30));
+        let comment_len = try!(read_u16(data, pos + 32));
+        let name = match data.get(pos + 46..pos + 46 + name_len) {
+            Some(name) => String::from_utf8_lossy(name).into_owned(),
+            None => return Err(RomError::FormatError),
+        };
+        entries.push(ZipEntry {
+            name: name,
+            method: try!(read_u16(data, pos + 10)),
+            crc32: try!(read_u32(data, pos + 16)),
+            compressed_size: try!(read_u32(data, pos + 20)) as usize,
+            size: try!(read_u32(data, pos + 24)) as usize,
+            local_offset: try!(read_u32(data, pos + 42)) as usize,
+        });
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extract_zip(data: &[u8], member: Option<&str>) -> Result<Vec<u8>, RomError> {
+    let entries = try!(zip_entries(data));
+    let entry = match member {
+        Some(member) => entries.iter().find(|entry| entry.name == member),
+        None => entries.iter().find(|entry| has_image_extension(&entry.name)),
+    };
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Err(RomError::FormatError),
+    };
+
+    // Local header lengths can differ from the central directory's
+    let local = entry.local_offset;
+    if try!(read_u32(data, local)) != ZIP_LOCAL_SIGNATURE {
+        return Err(RomError::FormatError);
+    }
+    let start = local + 30 + try!(read_u16(data, local + 26)) + try!(read_u16(data, local + 28));
+    let compressed = match data.get(start..start + entry.compressed_size) {
+        Some(compressed) => compressed,
+        None => return Err(RomError::FormatError),
+    };
+
+    let out = match entry.method {
+        // Stored
+        0 => compressed.to_vec(),
+        // Deflate
+        8 => {
+            let mut out = Vec::with_capacity(entry.size.min(MAX_PREALLOCATION));
+            try!(DeflateDecoder::new(compressed).read_to_end(&mut out));
+            out
+        }
+        _ => return Err(RomError::FormatError),
+    };
+    if out.len() != entry.size || util::crc32(0, &out) != entry.crc32 {
+        return Err(RomError::FormatError);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::{DeflateEncoder, GzEncoder};
+
+    fn push16(out: &mut Vec<u8>, value: usize) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+    }
+
+    fn push32(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    // A zip holding the given members, deflated or stored
+    fn zip(members: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for &(name, data, deflate) in members {
+            let body = if deflate {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            } else {
+                data.to_vec()
+            };
+            let method = if deflate { 8 } else { 0 };
+            let crc = util::crc32(0, data);
+            let offset = out.len() as u32;
+
+            push32(&mut out, ZIP_LOCAL_SIGNATURE);
+            push16(&mut out, 20);
+            push16(&mut out, 0);
+            push16(&mut out, method);
+            push32(&mut out, 0);
+            push32(&mut out, crc);
+            push32(&mut out, body.len() as u32);
+            push32(&mut out, data.len() as u32);
+            push16(&mut out, name.len());
+            push16(&mut out, 0);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&body);
+
+            push32(&mut central, ZIP_CENTRAL_SIGNATURE);
+            push16(&mut central, 20);
+            push16(&mut central, 20);
+            push16(&mut central, 0);
+            push16(&mut central, method);
+            push32(&mut central, 0);
+            push32(&mut central, crc);
+            push32(&mut central, body.len() as u32);
+            push32(&mut central, data.len() as u32);
+            push16(&mut central, name.len());
+            push16(&mut central, 0);
+            push16(&mut central, 0);
+            push16(&mut central, 0);
+            push16(&mut central, 0);
+            push32(&mut central, 0);
+            push32(&mut central, offset);
+            central.extend_from_slice(name.as_bytes());
+        }
+
+        let central_offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        push32(&mut out, ZIP_END_SIGNATURE);
+        push16(&mut out, 0);
+        push16(&mut out, 0);
+        push16(&mut out, members.len());
+        push16(&mut out, members.len());
+        push32(&mut out, central.len() as u32);
+        push32(&mut out, central_offset);
+        push16(&mut out, 0);
+        out
+    }
+
+    #[test]
+    fn plain_data_is_returned_unchanged() {
+        let data = b"NES\x1A plain".to_vec();
+        assert_eq!(Container::detect(&data), Container::Plain);
+        assert_eq!(extract(data.clone(), None).unwrap(), data);
+    }
+
+    #[test]
+    fn gzip_is_decompressed() {
+        let data: Vec<u8> = (0..1000).map(|i| (i % 7) as u8).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gz = encoder.finish().unwrap();
+        assert_eq!(Container::detect(&gz), Container::Gzip);
+        assert_eq!(read(&mut &gz[..], None).unwrap(), data);
+    }
+
+    #[test]
+    fn zip_takes_the_first_rom_member_by_default() {
+        let data = zip(&[
+            ("readme.txt", b"not a rom", false),
+            ("game", b"no extension", false),
+            ("Game.NES", b"the rom, deflated the rom", true),
+            ("other.fds", b"second image", false),
+        ]);
+        assert_eq!(Container::detect(&data), Container::Zip);
+        assert_eq!(extract(data, None).unwrap(), b"the rom, deflated the rom".to_vec());
+    }
+
+    #[test]
+    fn zip_takes_a_named_member() {
+        let data = zip(&[("a.nes", b"first", false), ("b.nes", b"second", true)]);
+        assert_eq!(extract(data.clone(), Some("b.nes")).unwrap(), b"second".to_vec());
+        assert_eq!(extract(data.clone(), Some("readme.txt")).ok(), None);
+    }
+
+    #[test]
+    fn zip_without_a_rom_or_with_a_bad_crc_is_rejected() {
+        assert!(extract(zip(&[("readme.txt", b"text", false)]), None).is_err());
+
+        let mut data = zip(&[("a.nes", b"first", false)]);
+        // Corrupt the stored body, which follows the 30 byte local header
+        data[30 + 5] ^= 0xFF;
+        assert!(extract(data, None).is_err());
+    }
+
+    #[test]
+    fn a_huge_claimed_size_is_not_allocated_up_front() {
+        let mut data = zip(&[("a.nes", b"first", true)]);
+        // Claim 4GB in the central directory, which follows the 30 byte
+        // local header, the name and the deflated body
+        let central = data.len() - 22 - (46 + 5);
+        for byte in &mut data[central + 24..central + 28] {
+            *byte = 0xFF;
+        }
+        assert!(extract(data, None).is_err());
+    }
+}
@@ -49,9 +49,37 @@ impl<M: Mem> AddressingMode<M> for MemoryAddressingMode {
     }
 }
 
-// Decode the given opcode
-fn decode_op() {
-
+// Base cycle counts by opcode, before page crossing and branch penalties
+const CYCLES: [u8; 256] = [
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+// Reads through absolute,X / absolute,Y / (indirect),Y take an extra
+// cycle when indexing crosses a page. Stores and read-modify-write
+// instructions always spend it, which CYCLES already includes.
+fn page_cross_penalty(op: u8) -> bool {
+    match op {
+        0x11 | 0x19 | 0x1D | 0x31 | 0x39 | 0x3D | 0x51 | 0x59 | 0x5D |
+        0x71 | 0x79 | 0x7D | 0xB1 | 0xB3 | 0xB9 | 0xBB | 0xBC | 0xBD |
+        0xBE | 0xBF | 0xD1 | 0xD9 | 0xDD | 0xF1 | 0xF9 | 0xFD |
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => true,
+        _ => false,
+    }
 }
 
 struct Registers {
@@ -73,6 +101,9 @@ enum Flags {
     Zero = 1 << 1,
     Interrupt = 1 << 2,
     Decimal = 1 << 3,
+    // Only exists in the copy of the status pushed to the stack
+    Break = 1 << 4,
+    Unused = 1 << 5,
     Overflow = 1 << 6,
     Negative = 1 << 7
 }
@@ -102,6 +133,10 @@ impl Registers {
 pub struct NesCpu<M: Mem> {
     clock: u64,
     regs: Registers,
+    // Set by the indexed addressing modes for the page crossing penalty
+    page_crossed: bool,
+    // Level of /IRQ, held by whatever on the bus is asserting it
+    irq_line: bool,
     pub mem: M
 }
 
@@ -122,23 +157,114 @@ impl<M: Mem> Mem for NesCpu<M> {
 }
 
 impl<M: Mem> NesCpu<M> {
+    pub fn new(mem: M) -> NesCpu<M> {
+        NesCpu {
+            clock: 0,
+            regs: Registers {
+                a: 0,
+                x: 0,
+                y: 0,
+                pc: 0,
+                sp: 0xFD,
+                status: Flags::Interrupt as u8 | Flags::Unused as u8
+            },
+            page_crossed: false,
+            irq_line: false,
+            mem: mem
+        }
+    }
+
+    // Jump through the reset vector. The stack pointer moves down three
+    // bytes as for an interrupt, but nothing is written.
+    pub fn reset(&mut self) {
+        self.regs.sp = self.regs.sp.wrapping_sub(3);
+        self.regs.save_flag(Flags::Interrupt, true);
+        self.regs.pc = self.loadw(0xFFFC);
+        self.clock += 7;
+    }
+
+    // Service a non-maskable interrupt, as raised by the PPU at the start
+    // of vblank
+    pub fn nmi(&mut self) {
+        self.interrupt(0xFFFA);
+    }
+
+    // Assert or release /IRQ. It is level triggered: the CPU takes it
+    // before each instruction for as long as it is asserted and the
+    // interrupt flag is clear.
+    pub fn set_irq(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    // Push the return address and the status, with the break flag clear,
+    // and jump through the given vector
+    fn interrupt(&mut self, vector: u16) -> u64 {
+        let pc = self.regs.pc;
+        self.pushw(pc);
+        let p = (self.regs.status & !(Flags::Break as u8)) | Flags::Unused as u8;
+        self.push(p);
+        self.regs.save_flag(Flags::Interrupt, true);
+        self.regs.pc = self.loadw(vector);
+        self.clock += 7;
+        7
+    }
+
     pub fn step_to(&mut self, cycle: u64) {
         while self.clock < cycle {
-            self.execute_instruction();
+            self.step();
+        }
+    }
+
+    // Execute a single instruction, or take a pending IRQ in its place,
+    // returning the cycles it took
+    pub fn step(&mut self) -> u64 {
+        if self.irq_line && !self.regs.flag_set(Flags::Interrupt) {
+            return self.interrupt(0xFFFE);
         }
+        self.execute_instruction()
+    }
+
+    // Spend cycles with the CPU halted, as it is during DMA
+    pub fn stall(&mut self, cycles: u64) {
+        self.clock += cycles;
+    }
+
+    // CPU cycles executed so far
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.regs.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.regs.pc = pc;
+    }
+
+    pub fn set_a(&mut self, a: u8) {
+        self.regs.a = a;
+    }
+
+    pub fn set_x(&mut self, x: u8) {
+        self.regs.x = x;
+    }
+
+    pub fn set_sp(&mut self, sp: u8) {
+        self.regs.sp = sp;
     }
 
     fn load_pc_bump(&mut self) -> u8 {
         let pc = self.regs.pc;
         let val = self.loadb(pc);
-        self.regs.pc += 1;
+        self.regs.pc = pc.wrapping_add(1);
         val
     }
 
     fn loadw_pc_bump(&mut self) -> u16 {
         let pc = self.regs.pc;
         let val = self.loadw(pc);
-        self.regs.pc += 2;
+        self.regs.pc = pc.wrapping_add(2);
         val
     }
 
@@ -149,18 +275,289 @@ impl<M: Mem> NesCpu<M> {
     }
 
     fn execute_instruction(&mut self) -> u64 {
-        // Check if there is CPU Interrupt, handle it if so
-        // Fetch the next instruction
-        // Prepare the operand according to addressing mode
+        use self::MemRegType::{NoType, X, Y};
+
+        // Addressing modes are built before the instruction is called,
+        // since both need to borrow the CPU
+        macro_rules! op {
+            ($cpu:ident, $inst:ident, $mode:ident) => {{
+                let mode = $cpu.$mode();
+                $cpu.$inst(mode)
+            }};
+            ($cpu:ident, $inst:ident, $mode:ident, $reg:expr) => {{
+                let mode = $cpu.$mode($reg);
+                $cpu.$inst(mode)
+            }};
+        }
 
-        // Execute instruction
-        // Update PC and return the CPU cycles
-        0
+        let op = self.load_pc_bump();
+        self.page_crossed = false;
+        let mut cycles = CYCLES[op as usize] as u64;
+
+        match op {
+            // Loads and stores
+            0xA9 => op!(self, lda, immediate),
+            0xA5 => op!(self, lda, zero_page, NoType),
+            0xB5 => op!(self, lda, zero_page, X),
+            0xAD => op!(self, lda, absolute, NoType),
+            0xBD => op!(self, lda, absolute, X),
+            0xB9 => op!(self, lda, absolute, Y),
+            0xA1 => op!(self, lda, indirect, X),
+            0xB1 => op!(self, lda, indirect, Y),
+            0xA2 => op!(self, ldx, immediate),
+            0xA6 => op!(self, ldx, zero_page, NoType),
+            0xB6 => op!(self, ldx, zero_page, Y),
+            0xAE => op!(self, ldx, absolute, NoType),
+            0xBE => op!(self, ldx, absolute, Y),
+            0xA0 => op!(self, ldy, immediate),
+            0xA4 => op!(self, ldy, zero_page, NoType),
+            0xB4 => op!(self, ldy, zero_page, X),
+            0xAC => op!(self, ldy, absolute, NoType),
+            0xBC => op!(self, ldy, absolute, X),
+            0x85 => op!(self, sta, zero_page, NoType),
+            0x95 => op!(self, sta, zero_page, X),
+            0x8D => op!(self, sta, absolute, NoType),
+            0x9D => op!(self, sta, absolute, X),
+            0x99 => op!(self, sta, absolute, Y),
+            0x81 => op!(self, sta, indirect, X),
+            0x91 => op!(self, sta, indirect, Y),
+            0x86 => op!(self, stx, zero_page, NoType),
+            0x96 => op!(self, stx, zero_page, Y),
+            0x8E => op!(self, stx, absolute, NoType),
+            0x84 => op!(self, sty, zero_page, NoType),
+            0x94 => op!(self, sty, zero_page, X),
+            0x8C => op!(self, sty, absolute, NoType),
+
+            // Arithmetic and logic
+            0x69 => op!(self, adc, immediate),
+            0x65 => op!(self, adc, zero_page, NoType),
+            0x75 => op!(self, adc, zero_page, X),
+            0x6D => op!(self, adc, absolute, NoType),
+            0x7D => op!(self, adc, absolute, X),
+            0x79 => op!(self, adc, absolute, Y),
+            0x61 => op!(self, adc, indirect, X),
+            0x71 => op!(self, adc, indirect, Y),
+            0xE9 | 0xEB => op!(self, sbc, immediate),
+            0xE5 => op!(self, sbc, zero_page, NoType),
+            0xF5 => op!(self, sbc, zero_page, X),
+            0xED => op!(self, sbc, absolute, NoType),
+            0xFD => op!(self, sbc, absolute, X),
+            0xF9 => op!(self, sbc, absolute, Y),
+            0xE1 => op!(self, sbc, indirect, X),
+            0xF1 => op!(self, sbc, indirect, Y),
+            0x29 => op!(self, and, immediate),
+            0x25 => op!(self, and, zero_page, NoType),
+            0x35 => op!(self, and, zero_page, X),
+            0x2D => op!(self, and, absolute, NoType),
+            0x3D => op!(self, and, absolute, X),
+            0x39 => op!(self, and, absolute, Y),
+            0x21 => op!(self, and, indirect, X),
+            0x31 => op!(self, and, indirect, Y),
+            0x09 => op!(self, ora, immediate),
+            0x05 => op!(self, ora, zero_page, NoType),
+            0x15 => op!(self, ora, zero_page, X),
+            0x0D => op!(self, ora, absolute, NoType),
+            0x1D => op!(self, ora, absolute, X),
+            0x19 => op!(self, ora, absolute, Y),
+            0x01 => op!(self, ora, indirect, X),
+            0x11 => op!(self, ora, indirect, Y),
+            0x49 => op!(self, eor, immediate),
+            0x45 => op!(self, eor, zero_page, NoType),
+            0x55 => op!(self, eor, zero_page, X),
+            0x4D => op!(self, eor, absolute, NoType),
+            0x5D => op!(self, eor, absolute, X),
+            0x59 => op!(self, eor, absolute, Y),
+            0x41 => op!(self, eor, indirect, X),
+            0x51 => op!(self, eor, indirect, Y),
+            0x24 => op!(self, bit, zero_page, NoType),
+            0x2C => op!(self, bit, absolute, NoType),
+            0xC9 => op!(self, cmp, immediate),
+            0xC5 => op!(self, cmp, zero_page, NoType),
+            0xD5 => op!(self, cmp, zero_page, X),
+            0xCD => op!(self, cmp, absolute, NoType),
+            0xDD => op!(self, cmp, absolute, X),
+            0xD9 => op!(self, cmp, absolute, Y),
+            0xC1 => op!(self, cmp, indirect, X),
+            0xD1 => op!(self, cmp, indirect, Y),
+            0xE0 => op!(self, cpx, immediate),
+            0xE4 => op!(self, cpx, zero_page, NoType),
+            0xEC => op!(self, cpx, absolute, NoType),
+            0xC0 => op!(self, cpy, immediate),
+            0xC4 => op!(self, cpy, zero_page, NoType),
+            0xCC => op!(self, cpy, absolute, NoType),
+
+            // Increments, decrements and shifts
+            0xE6 => op!(self, inc, zero_page, NoType),
+            0xF6 => op!(self, inc, zero_page, X),
+            0xEE => op!(self, inc, absolute, NoType),
+            0xFE => op!(self, inc, absolute, X),
+            0xC6 => op!(self, dec, zero_page, NoType),
+            0xD6 => op!(self, dec, zero_page, X),
+            0xCE => op!(self, dec, absolute, NoType),
+            0xDE => op!(self, dec, absolute, X),
+            0xE8 => self.inx(),
+            0xC8 => self.iny(),
+            0xCA => self.dex(),
+            0x88 => self.dey(),
+            0x0A => op!(self, asl, accumulator),
+            0x06 => op!(self, asl, zero_page, NoType),
+            0x16 => op!(self, asl, zero_page, X),
+            0x0E => op!(self, asl, absolute, NoType),
+            0x1E => op!(self, asl, absolute, X),
+            0x4A => op!(self, lsr, accumulator),
+            0x46 => op!(self, lsr, zero_page, NoType),
+            0x56 => op!(self, lsr, zero_page, X),
+            0x4E => op!(self, lsr, absolute, NoType),
+            0x5E => op!(self, lsr, absolute, X),
+            0x2A => op!(self, rol, accumulator),
+            0x26 => op!(self, rol, zero_page, NoType),
+            0x36 => op!(self, rol, zero_page, X),
+            0x2E => op!(self, rol, absolute, NoType),
+            0x3E => op!(self, rol, absolute, X),
+            0x6A => op!(self, ror, accumulator),
+            0x66 => op!(self, ror, zero_page, NoType),
+            0x76 => op!(self, ror, zero_page, X),
+            0x6E => op!(self, ror, absolute, NoType),
+            0x7E => op!(self, ror, absolute, X),
+
+            // Jumps and branches
+            0x4C => {
+                let addr = self.loadw_pc_bump();
+                self.jmp(addr);
+            }
+            0x6C => {
+                let ptr = self.loadw_pc_bump();
+                // The pointer's high byte is read without carrying into
+                // the next page
+                let lower = self.loadb(ptr) as u16;
+                let higher = self.loadb((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF)) as u16;
+                self.jmp(lower | higher << 8);
+            }
+            0x20 => self.jsr(),
+            0x60 => self.rts(),
+            0x40 => self.rti(),
+            0x00 => self.brk(),
+            0x10 => cycles += self.bpl(),
+            0x30 => cycles += self.bmi(),
+            0x50 => cycles += self.bvc(),
+            0x70 => cycles += self.bvs(),
+            0x90 => cycles += self.bcc(),
+            0xB0 => cycles += self.bcs(),
+            0xD0 => cycles += self.bne(),
+            0xF0 => cycles += self.beq(),
+
+            // Register, status and stack instructions
+            0xAA => self.tax(),
+            0x8A => self.txa(),
+            0xA8 => self.tay(),
+            0x98 => self.tya(),
+            0x18 => self.clc(),
+            0x38 => self.sec(),
+            0x58 => self.cli(),
+            0x78 => self.sei(),
+            0xB8 => self.clv(),
+            0xD8 => self.cld(),
+            0xF8 => self.sed(),
+            0x9A => self.txs(),
+            0xBA => self.tsx(),
+            0x48 => self.pha(),
+            0x68 => self.pla(),
+            0x08 => self.php(),
+            0x28 => self.plp(),
+
+            // Unofficial instructions in common use
+            0xA7 => op!(self, lax, zero_page, NoType),
+            0xB7 => op!(self, lax, zero_page, Y),
+            0xAF => op!(self, lax, absolute, NoType),
+            0xBF => op!(self, lax, absolute, Y),
+            0xA3 => op!(self, lax, indirect, X),
+            0xB3 => op!(self, lax, indirect, Y),
+            0x87 => op!(self, sax, zero_page, NoType),
+            0x97 => op!(self, sax, zero_page, Y),
+            0x8F => op!(self, sax, absolute, NoType),
+            0x83 => op!(self, sax, indirect, X),
+            0xC7 => op!(self, dcp, zero_page, NoType),
+            0xD7 => op!(self, dcp, zero_page, X),
+            0xCF => op!(self, dcp, absolute, NoType),
+            0xDF => op!(self, dcp, absolute, X),
+            0xDB => op!(self, dcp, absolute, Y),
+            0xC3 => op!(self, dcp, indirect, X),
+            0xD3 => op!(self, dcp, indirect, Y),
+            0xE7 => op!(self, isc, zero_page, NoType),
+            0xF7 => op!(self, isc, zero_page, X),
+            0xEF => op!(self, isc, absolute, NoType),
+            0xFF => op!(self, isc, absolute, X),
+            0xFB => op!(self, isc, absolute, Y),
+            0xE3 => op!(self, isc, indirect, X),
+            0xF3 => op!(self, isc, indirect, Y),
+            0x07 => op!(self, slo, zero_page, NoType),
+            0x17 => op!(self, slo, zero_page, X),
+            0x0F => op!(self, slo, absolute, NoType),
+            0x1F => op!(self, slo, absolute, X),
+            0x1B => op!(self, slo, absolute, Y),
+            0x03 => op!(self, slo, indirect, X),
+            0x13 => op!(self, slo, indirect, Y),
+            0x27 => op!(self, rla, zero_page, NoType),
+            0x37 => op!(self, rla, zero_page, X),
+            0x2F => op!(self, rla, absolute, NoType),
+            0x3F => op!(self, rla, absolute, X),
+            0x3B => op!(self, rla, absolute, Y),
+            0x23 => op!(self, rla, indirect, X),
+            0x33 => op!(self, rla, indirect, Y),
+            0x47 => op!(self, sre, zero_page, NoType),
+            0x57 => op!(self, sre, zero_page, X),
+            0x4F => op!(self, sre, absolute, NoType),
+            0x5F => op!(self, sre, absolute, X),
+            0x5B => op!(self, sre, absolute, Y),
+            0x43 => op!(self, sre, indirect, X),
+            0x53 => op!(self, sre, indirect, Y),
+            0x67 => op!(self, rra, zero_page, NoType),
+            0x77 => op!(self, rra, zero_page, X),
+            0x6F => op!(self, rra, absolute, NoType),
+            0x7F => op!(self, rra, absolute, X),
+            0x7B => op!(self, rra, absolute, Y),
+            0x63 => op!(self, rra, indirect, X),
+            0x73 => op!(self, rra, indirect, Y),
+
+            // NOPs, including the unofficial ones that read an operand
+            0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => self.nop(),
+            0x80 | 0x82 | 0x89 | 0xC2 | 0xE2 => {
+                self.load_pc_bump();
+            }
+            0x04 | 0x44 | 0x64 => {
+                self.zero_page(NoType);
+            }
+            0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => {
+                self.zero_page(X);
+            }
+            0x0C => {
+                self.absolute(NoType);
+            }
+            0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {
+                let mode = self.absolute(X);
+                mode.load(self);
+            }
+
+            // The remaining unofficial opcodes are unstable or halt the
+            // CPU; treat them as single byte NOPs
+            _ => self.nop()
+        }
+
+        if self.page_crossed && page_cross_penalty(op) {
+            cycles += 1;
+        }
+        self.clock += cycles;
+        cycles
     }
 
     // Add with carry
     fn adc<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
         let val = mode.load(self);
+        self.add(val);
+    }
+
+    // Shared by ADC and SBC, the latter adding the complement
+    fn add(&mut self, val: u8) {
         let mut result = self.regs.a as u32 + val as u32;
         if self.regs.flag_set(Flags::Carry) {
             result += 1
@@ -169,8 +566,10 @@ impl<M: Mem> NesCpu<M> {
         self.regs.save_flag(Flags::Carry, (result & 0x100) != 0);
 
         let result = result as u8;
-        let twos = result ^ self.regs.a;
-        self.regs.save_flag(Flags::Overflow, (twos & 0x80 == 0) && (twos & 0x80 == 0x80));
+        // Overflow when both operands share a sign the result lacks
+        let overflow = (self.regs.a ^ result) & (val ^ result) & 0x80 != 0;
+        self.regs.save_flag(Flags::Overflow, overflow);
+        self.regs.a = result;
         self.regs.check_negative(result);
         self.regs.check_zero(result);
     }
@@ -191,9 +590,9 @@ impl<M: Mem> NesCpu<M> {
         let new_val = old_val << 1;
         
         self.regs.save_flag(Flags::Carry, old_val & (1 << 7) != 0);
-        self.regs.a = new_val;
         self.regs.check_negative(new_val);
         self.regs.check_zero(new_val);
+        mode.store(self, new_val);
     }
 
     // Test bits
@@ -201,70 +600,87 @@ impl<M: Mem> NesCpu<M> {
         let val = mode.load(self);
         let result = val & self.regs.a;
 
-        self.regs.save_flag(Flags::Overflow, result & (1 << 6) != 0);
-        self.regs.check_negative(result);
+        // N and V are copied from the operand itself
+        self.regs.save_flag(Flags::Overflow, val & (1 << 6) != 0);
+        self.regs.check_negative(val);
         self.regs.check_zero(result);
     }
 
     // Branch Instructions
-    fn try_branch(&mut self, flag: bool) {
-        // Not 100% sure that this is correct
-        if flag {
-            let rel_addr = self.load_pc_bump();
-            self.regs.pc += rel_addr as u16;
+    // The offset is signed and relative to the next instruction. Taking
+    // the branch costs a cycle, and another if it lands in a new page.
+    fn try_branch(&mut self, flag: bool) -> u64 {
+        let rel_addr = self.load_pc_bump() as i8;
+        if !flag {
+            return 0;
+        }
+        let pc = self.regs.pc;
+        let target = pc.wrapping_add(rel_addr as u16);
+        self.regs.pc = target;
+        if pc & 0xFF00 != target & 0xFF00 {
+            2
+        } else {
+            1
         }
     }
 
     // Branch on plus
-    fn bpl<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
+    fn bpl(&mut self) -> u64 {
         let neg_flag = self.regs.flag_set(Flags::Negative);
-        self.try_branch(!neg_flag);
+        self.try_branch(!neg_flag)
     }
 
     // Branch on minus
-    fn bmi(&mut self) {
+    fn bmi(&mut self) -> u64 {
         let neg_flag = self.regs.flag_set(Flags::Negative);
-        self.try_branch(neg_flag);
+        self.try_branch(neg_flag)
     }
 
     // Branch on overflow clear
-    fn bvc(&mut self) {
+    fn bvc(&mut self) -> u64 {
         let ov_flag = self.regs.flag_set(Flags::Overflow);
-        self.try_branch(!ov_flag);
+        self.try_branch(!ov_flag)
     }
 
     // Branch on overflow set
-    fn bvs(&mut self) {
+    fn bvs(&mut self) -> u64 {
         let ov_flag = self.regs.flag_set(Flags::Overflow);
-        self.try_branch(ov_flag);
+        self.try_branch(ov_flag)
     }
 
     // Branch on carry clear
-    fn bcc(&mut self) {
+    fn bcc(&mut self) -> u64 {
         let carry_flag = self.regs.flag_set(Flags::Carry);
-        self.try_branch(!carry_flag);
+        self.try_branch(!carry_flag)
     }
 
     // Branch on carry set
-    fn bcs(&mut self) {
+    fn bcs(&mut self) -> u64 {
         let carry_flag = self.regs.flag_set(Flags::Carry);
-        self.try_branch(carry_flag);
+        self.try_branch(carry_flag)
     }
 
     // Branch on not equal
-    fn bne(&mut self) {
+    fn bne(&mut self) -> u64 {
         let zero_flag = self.regs.flag_set(Flags::Zero);
-        self.try_branch(!zero_flag);
+        self.try_branch(!zero_flag)
     }
 
     // Branch on equal
-    fn beq(&mut self) {
+    fn beq(&mut self) -> u64 {
         let zero_flag = self.regs.flag_set(Flags::Zero);
-        self.try_branch(zero_flag);
+        self.try_branch(zero_flag)
     }
 
+    // Break: an interrupt through the IRQ vector, skipping a padding byte
     fn brk(&mut self) {
-
+        self.load_pc_bump();
+        let pc = self.regs.pc;
+        self.pushw(pc);
+        let p = self.regs.status | Flags::Break as u8 | Flags::Unused as u8;
+        self.push(p);
+        self.regs.save_flag(Flags::Interrupt, true);
+        self.regs.pc = self.loadw(0xFFFE);
     }
 
     // Compare accumulator
@@ -272,8 +688,8 @@ impl<M: Mem> NesCpu<M> {
         let val = mode.load(self);
         let a = self.regs.a;
         self.regs.save_flag(Flags::Carry, a >= val);
-        self.regs.check_zero(a - val);
-        self.regs.check_negative(a - val);
+        self.regs.check_zero(a.wrapping_sub(val));
+        self.regs.check_negative(a.wrapping_sub(val));
     }
 
     // Compare X register
@@ -281,8 +697,8 @@ impl<M: Mem> NesCpu<M> {
         let val = mode.load(self);
         let x = self.regs.x;
         self.regs.save_flag(Flags::Carry, x >= val);
-        self.regs.check_zero(x - val);
-        self.regs.check_negative(x - val);
+        self.regs.check_zero(x.wrapping_sub(val));
+        self.regs.check_negative(x.wrapping_sub(val));
     }
 
     // Compare Y register
@@ -290,13 +706,13 @@ impl<M: Mem> NesCpu<M> {
         let val = mode.load(self);
         let y = self.regs.y;
         self.regs.save_flag(Flags::Carry, y >= val);
-        self.regs.check_zero(y - val);
-        self.regs.check_negative(y - val);
+        self.regs.check_zero(y.wrapping_sub(val));
+        self.regs.check_negative(y.wrapping_sub(val));
     }
 
     // Decrement memory
     fn dec<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
-        let val = mode.load(self) - 1;
+        let val = mode.load(self).wrapping_sub(1);
         self.regs.check_negative(val);
         self.regs.check_zero(val);
         mode.store(self, val);
@@ -304,7 +720,7 @@ impl<M: Mem> NesCpu<M> {
 
     // Decrement X
     fn dex(&mut self) {
-        let val = self.regs.x - 1;
+        let val = self.regs.x.wrapping_sub(1);
         self.regs.x = val;
         self.regs.check_negative(val);
         self.regs.check_zero(val);
@@ -312,7 +728,7 @@ impl<M: Mem> NesCpu<M> {
 
     // Decrement Y
     fn dey(&mut self) {
-        let val = self.regs.y - 1;
+        let val = self.regs.y.wrapping_sub(1);
         self.regs.y = val;
         self.regs.check_negative(val);
         self.regs.check_zero(val);
@@ -329,7 +745,7 @@ impl<M: Mem> NesCpu<M> {
 
     // Increment Memory
     fn inc<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
-        let val = mode.load(self) + 1;
+        let val = mode.load(self).wrapping_add(1);
         self.regs.check_negative(val);
         self.regs.check_zero(val);
         mode.store(self, val);
@@ -337,7 +753,7 @@ impl<M: Mem> NesCpu<M> {
 
     // Increment X
     fn inx(&mut self) {
-        let val = self.regs.x + 1;
+        let val = self.regs.x.wrapping_add(1);
         self.regs.x = val;
         self.regs.check_negative(val);
         self.regs.check_zero(val);
@@ -345,20 +761,24 @@ impl<M: Mem> NesCpu<M> {
 
     // Increment Y
     fn iny(&mut self) {
-        let val = self.regs.y + 1;
+        let val = self.regs.y.wrapping_add(1);
         self.regs.y = val;
         self.regs.check_negative(val);
         self.regs.check_zero(val);
     }
 
     // Jump
-    fn jmp(&mut self) {
-        // TODO
+    fn jmp(&mut self, addr: u16) {
+        self.regs.pc = addr;
     }
 
     // Jump to Subroutine
+    // The return address pushed is that of the operand's last byte
     fn jsr(&mut self) {
-        
+        let addr = self.loadw_pc_bump();
+        let ret = self.regs.pc.wrapping_sub(1);
+        self.pushw(ret);
+        self.regs.pc = addr;
     }
 
     // Load accumulator
@@ -392,6 +812,7 @@ impl<M: Mem> NesCpu<M> {
         let val = val >> 1;
         self.regs.check_zero(val);
         self.regs.check_negative(val);
+        mode.store(self, val);
     }
 
     // No Operation
@@ -421,6 +842,7 @@ impl<M: Mem> NesCpu<M> {
 
         self.regs.check_negative(val);
         self.regs.check_zero(val);
+        mode.store(self, val);
     }
 
     // Rotate right
@@ -436,21 +858,24 @@ impl<M: Mem> NesCpu<M> {
 
         self.regs.check_negative(val);
         self.regs.check_zero(val);
+        mode.store(self, val);
     }
 
     // Return from interrupt
     fn rti(&mut self) {
-
+        self.plp();
+        self.regs.pc = self.popw();
     }
 
     // Return from subroutine
     fn rts(&mut self) {
-
+        self.regs.pc = self.popw().wrapping_add(1);
     }
 
     // Subtract with carry
-    fn sbc(&mut self) {
-
+    fn sbc<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
+        let val = mode.load(self);
+        self.add(!val);
     }
 
     // Store accumulator
@@ -554,12 +979,28 @@ impl<M: Mem> NesCpu<M> {
         self.regs.check_zero(sp);
     }
 
+    // The stack lives in page 1, growing down
     fn push(&mut self, val: u8) {
-
+        let sp = self.regs.sp;
+        self.storeb(0x0100 | sp as u16, val);
+        self.regs.sp = sp.wrapping_sub(1);
     }
 
     fn pop(&mut self) -> u8 {
-        panic!("Not implemented")
+        let sp = self.regs.sp.wrapping_add(1);
+        self.regs.sp = sp;
+        self.loadb(0x0100 | sp as u16)
+    }
+
+    fn pushw(&mut self, val: u16) {
+        self.push((val >> 8) as u8);
+        self.push(val as u8);
+    }
+
+    fn popw(&mut self) -> u16 {
+        let lower = self.pop() as u16;
+        let higher = self.pop() as u16;
+        lower | higher << 8
     }
 
     // Push the accumulator
@@ -578,14 +1019,68 @@ impl<M: Mem> NesCpu<M> {
 
     // Push processor status
     fn php(&mut self) {
-        let p = self.regs.status;
+        let p = self.regs.status | Flags::Break as u8 | Flags::Unused as u8;
         self.push(p);
     }
 
     // Pop processor status
     fn plp(&mut self) {
         let p = self.pop();
-        self.regs.status = p;
+        self.regs.status = (p & !(Flags::Break as u8)) | Flags::Unused as u8;
+    }
+
+    //// Unofficial Instructions
+    // Load A and X
+    fn lax<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
+        let val = mode.load(self);
+        self.regs.a = val;
+        self.regs.x = val;
+        self.regs.check_negative(val);
+        self.regs.check_zero(val);
+    }
+
+    // Store A AND X
+    fn sax<MODE: AddressingMode<M>>(&mut self, mode: MODE) {
+        let val = self.regs.a & self.regs.x;
+        mode.store(self, val);
+    }
+
+    // Decrement memory then compare
+    fn dcp(&mut self, mode: MemoryAddressingMode) {
+        let val = mode.load(self).wrapping_sub(1);
+        mode.store(self, val);
+        self.cmp(MemoryAddressingMode { val: *mode });
+    }
+
+    // Increment memory then subtract
+    fn isc(&mut self, mode: MemoryAddressingMode) {
+        let val = mode.load(self).wrapping_add(1);
+        mode.store(self, val);
+        self.add(!val);
+    }
+
+    // Shift left then OR
+    fn slo(&mut self, mode: MemoryAddressingMode) {
+        self.asl(MemoryAddressingMode { val: *mode });
+        self.ora(mode);
+    }
+
+    // Rotate left then AND
+    fn rla(&mut self, mode: MemoryAddressingMode) {
+        self.rol(MemoryAddressingMode { val: *mode });
+        self.and(mode);
+    }
+
+    // Shift right then XOR
+    fn sre(&mut self, mode: MemoryAddressingMode) {
+        self.lsr(MemoryAddressingMode { val: *mode });
+        self.eor(mode);
+    }
+
+    // Rotate right then add
+    fn rra(&mut self, mode: MemoryAddressingMode) {
+        self.ror(MemoryAddressingMode { val: *mode });
+        self.adc(mode);
     }
 
     // Memory addressing modes
@@ -601,10 +1096,10 @@ impl<M: Mem> NesCpu<M> {
         MemoryAddressingMode {
             val: match zero_type {
                 MemRegType::X => {
-                    (self.load_pc_bump() + self.regs.x) as u16
+                    self.load_pc_bump().wrapping_add(self.regs.x) as u16
                 },
                 MemRegType::Y => {
-                    (self.load_pc_bump() + self.regs.y) as u16
+                    self.load_pc_bump().wrapping_add(self.regs.y) as u16
                 },
                 MemRegType::NoType => { 
                     self.load_pc_bump() as u16
@@ -614,32 +1109,43 @@ impl<M: Mem> NesCpu<M> {
     }
 
     fn absolute(&mut self, abs_type: MemRegType) -> MemoryAddressingMode {
+        let base = self.loadw_pc_bump();
         MemoryAddressingMode {
             val: match abs_type {
                 MemRegType::X => {
-                    self.loadw_pc_bump() + self.regs.x as u16
+                    let x = self.regs.x;
+                    self.index(base, x)
                 },
                 MemRegType::Y => {
-                    self.loadw_pc_bump() + self.regs.y as u16
+                    let y = self.regs.y;
+                    self.index(base, y)
                 },
                 MemRegType::NoType => {
-                    self.loadw_pc_bump()
+                    base
                 }
             }
         }
     }
 
+    // Add an index register to a base address, noting page crossings
+    fn index(&mut self, base: u16, reg: u8) -> u16 {
+        let addr = base.wrapping_add(reg as u16);
+        self.page_crossed = base & 0xFF00 != addr & 0xFF00;
+        addr
+    }
+
     fn indirect(&mut self, ind_type: MemRegType) -> MemoryAddressingMode {
         let ptr = self.load_pc_bump();
         MemoryAddressingMode {
             val: match ind_type {
                 MemRegType::X => {
                     let x = self.regs.x;
-                    self.loadw_from_zp(ptr as u16 + x as u16)
+                    self.loadw_from_zp(ptr.wrapping_add(x) as u16)
                 },
                 MemRegType::Y => {
                     let y = self.regs.y;
-                    self.loadw_from_zp(ptr as u16) + y as u16
+                    let base = self.loadw_from_zp(ptr as u16);
+                    self.index(base, y)
                 },
                 MemRegType::NoType => {
                     panic!("Indirect addresses must always utilize X or Y")
@@ -647,4 +1153,359 @@ impl<M: Mem> NesCpu<M> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flat 64KB of RAM, with programs loaded at $8000
+    struct TestBus {
+        mem: Vec<u8>,
+    }
+
+    impl Mem for TestBus {
+        fn loadb(&mut self, addr: u16) -> u8 {
+            self.mem[addr as usize]
+        }
+
+        fn storeb(&mut self, addr: u16, val: u8) {
+            self.mem[addr as usize] = val;
+        }
+    }
+
+    fn cpu_at(addr: u16, program: &[u8]) -> NesCpu<TestBus> {
+        let mut mem = vec![0u8; 0x10000];
+        mem[addr as usize..addr as usize + program.len()].copy_from_slice(program);
+        let mut cpu = NesCpu::new(TestBus { mem: mem });
+        cpu.set_pc(addr);
+        cpu
+    }
+
+    fn program(bytes: &[u8]) -> NesCpu<TestBus> {
+        cpu_at(0x8000, bytes)
+    }
+
+    // Run instructions, returning the cycles each took
+    fn run(cpu: &mut NesCpu<TestBus>, count: usize) -> Vec<u64> {
+        (0..count).map(|_| cpu.step()).collect()
+    }
+
+    fn flags(cpu: &NesCpu<TestBus>) -> (bool, bool, bool, bool) {
+        (cpu.regs.flag_set(Flags::Negative), cpu.regs.flag_set(Flags::Overflow),
+         cpu.regs.flag_set(Flags::Zero), cpu.regs.flag_set(Flags::Carry))
+    }
+
+    #[test]
+    fn base_cycles_match_the_6502() {
+        let reference: [u8; 256] = [
+            7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+            6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+            6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+            6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+            2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+            2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+            2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+            2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+            2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+            2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+            2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+        ];
+        for op in 0..256 {
+            assert_eq!(CYCLES[op], reference[op], "opcode {:02X}", op);
+        }
+    }
+
+    #[test]
+    fn loads_set_zero_and_negative() {
+        // LDA #$00; LDX #$80; LDY #$01
+        let mut cpu = program(&[0xA9, 0x00, 0xA2, 0x80, 0xA0, 0x01]);
+        assert_eq!(run(&mut cpu, 1), vec![2]);
+        assert_eq!(flags(&cpu), (false, false, true, false));
+        run(&mut cpu, 1);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        run(&mut cpu, 1);
+        assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.y), (0x00, 0x80, 0x01));
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        assert_eq!(cpu.clock(), 6);
+    }
+
+    #[test]
+    fn adc_sets_carry_and_overflow() {
+        // LDA #$50; ADC #$50; CLC; LDA #$FF; ADC #$01
+        let mut cpu = program(&[0xA9, 0x50, 0x69, 0x50, 0x18, 0xA9, 0xFF, 0x69, 0x01]);
+        run(&mut cpu, 2);
+        assert_eq!(cpu.regs.a, 0xA0);
+        assert_eq!(flags(&cpu), (true, true, false, false));
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs.a, 0x00);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+    }
+
+    #[test]
+    fn sbc_borrows_through_carry() {
+        // SEC; LDA #$50; SBC #$F0; SEC; LDA #$50; SBC #$B0; SEC; LDA #$05; SBC #$03 (unofficial $EB)
+        let mut cpu = program(&[0x38, 0xA9, 0x50, 0xE9, 0xF0, 0x38, 0xA9, 0x50, 0xE9, 0xB0,
+                            0x38, 0xA9, 0x05, 0xEB, 0x03]);
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs.a, 0x60);
+        assert_eq!(flags(&cpu), (false, false, false, false));
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs.a, 0xA0);
+        assert_eq!(flags(&cpu), (true, true, false, false));
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs.a, 0x02);
+        assert_eq!(flags(&cpu), (false, false, false, true));
+    }
+
+    #[test]
+    fn decimal_mode_is_ignored() {
+        // SED; CLC; LDA #$09; ADC #$01
+        let mut cpu = program(&[0xF8, 0x18, 0xA9, 0x09, 0x69, 0x01]);
+        run(&mut cpu, 4);
+        assert_eq!(cpu.regs.a, 0x0A);
+        assert!(cpu.regs.flag_set(Flags::Decimal));
+    }
+
+    #[test]
+    fn compares_and_bit_tests() {
+        // LDA #$40; CMP #$40; CMP #$41; BIT $10
+        let mut cpu = program(&[0xA9, 0x40, 0xC9, 0x40, 0xC9, 0x41, 0x24, 0x10]);
+        cpu.mem.mem[0x10] = 0xC0;
+        run(&mut cpu, 2);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+        run(&mut cpu, 1);
+        assert_eq!(flags(&cpu), (true, false, false, false));
+        assert_eq!(run(&mut cpu, 1), vec![3]);
+        assert_eq!(flags(&cpu), (true, true, false, false));
+    }
+
+    #[test]
+    fn shifts_and_rotates() {
+        // ASL $10; SEC; ROR A; ROL $10; LSR A; INC $11; DEC $12
+        let mut cpu = program(&[0x06, 0x10, 0x38, 0x6A, 0x26, 0x10, 0x4A, 0xE6, 0x11, 0xC6, 0x12]);
+        cpu.mem.mem[0x10] = 0x81;
+        cpu.mem.mem[0x11] = 0xFF;
+        cpu.regs.a = 0x02;
+        assert_eq!(run(&mut cpu, 1), vec![5]);
+        assert_eq!(cpu.mem.mem[0x10], 0x02);
+        assert!(cpu.regs.flag_set(Flags::Carry));
+        run(&mut cpu, 2);
+        assert_eq!(cpu.regs.a, 0x81);
+        assert!(!cpu.regs.flag_set(Flags::Carry));
+        run(&mut cpu, 1);
+        assert_eq!(cpu.mem.mem[0x10], 0x04);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs.a, 0x40);
+        assert!(cpu.regs.flag_set(Flags::Carry));
+        run(&mut cpu, 2);
+        assert_eq!((cpu.mem.mem[0x11], cpu.mem.mem[0x12]), (0x00, 0xFF));
+        assert!(cpu.regs.flag_set(Flags::Negative));
+    }
+
+    #[test]
+    fn indexed_reads_pay_for_page_crossings() {
+        // LDX #$01; LDA $12FF,X; LDA $1200,X; STA $12FF,X; STA $1200,X
+        let mut cpu = program(&[0xA2, 0x01, 0xBD, 0xFF, 0x12, 0xBD, 0x00, 0x12,
+                            0x9D, 0xFF, 0x12, 0x9D, 0x00, 0x12]);
+        assert_eq!(run(&mut cpu, 5), vec![2, 5, 4, 5, 5]);
+
+        // LDY #$10; LDA ($20),Y across a page and not; INC $12FF,X
+        let mut cpu = program(&[0xA0, 0x10, 0xB1, 0x20, 0xB1, 0x22, 0xA2, 0x01, 0xFE, 0xFF, 0x12]);
+        cpu.mem.mem[0x20..0x24].copy_from_slice(&[0xF8, 0x12, 0x00, 0x12]);
+        cpu.mem.mem[0x1308] = 0x77;
+        assert_eq!(run(&mut cpu, 3), vec![2, 6, 5]);
+        assert_eq!(run(&mut cpu, 2), vec![2, 7]);
+        assert_eq!(cpu.mem.mem[0x1300], 1);
+    }
+
+    #[test]
+    fn zero_page_indexing_wraps() {
+        // LDX #$10; LDA $F8,X; LDX #$01; LDA ($FE,X)
+        let mut cpu = program(&[0xA2, 0x10, 0xB5, 0xF8, 0xA2, 0x01, 0xA1, 0xFE]);
+        cpu.mem.mem[0x08] = 0x42;
+        cpu.mem.mem[0xFF] = 0x34;
+        cpu.mem.mem[0x00] = 0x12;
+        cpu.mem.mem[0x1234] = 0x99;
+        run(&mut cpu, 2);
+        assert_eq!(cpu.regs.a, 0x42);
+        assert_eq!(run(&mut cpu, 2), vec![2, 6]);
+        assert_eq!(cpu.regs.a, 0x99);
+    }
+
+    #[test]
+    fn branches_cost_more_when_taken_and_across_pages() {
+        // CLC; BCS +2 (not taken); BCC +2 (taken)
+        let mut cpu = program(&[0x18, 0xB0, 0x02, 0x90, 0x02]);
+        assert_eq!(run(&mut cpu, 3), vec![2, 2, 3]);
+        assert_eq!(cpu.pc(), 0x8007);
+
+        // BNE +$20 from the end of a page, and back again
+        let mut cpu = cpu_at(0x80F0, &[0xD0, 0x20]);
+        cpu.mem.mem[0x8112..0x8114].copy_from_slice(&[0xD0, 0xDC]);
+        assert_eq!(run(&mut cpu, 1), vec![4]);
+        assert_eq!(cpu.pc(), 0x8112);
+        assert_eq!(run(&mut cpu, 1), vec![4]);
+        assert_eq!(cpu.pc(), 0x80F0);
+    }
+
+    #[test]
+    fn jsr_pushes_the_last_operand_byte() {
+        // JSR $9000, with RTS there
+        let mut cpu = program(&[0x20, 0x00, 0x90]);
+        cpu.mem.mem[0x9000] = 0x60;
+        assert_eq!(run(&mut cpu, 1), vec![6]);
+        assert_eq!(cpu.pc(), 0x9000);
+        assert_eq!((cpu.mem.mem[0x1FD], cpu.mem.mem[0x1FC]), (0x80, 0x02));
+        assert_eq!(run(&mut cpu, 1), vec![6]);
+        assert_eq!(cpu.pc(), 0x8003);
+        assert_eq!(cpu.regs.sp, 0xFD);
+    }
+
+    #[test]
+    fn jmp_indirect_stays_in_the_pointer_page() {
+        let mut cpu = program(&[0x6C, 0xFF, 0x02]);
+        cpu.mem.mem[0x02FF] = 0x00;
+        cpu.mem.mem[0x0200] = 0x90;
+        cpu.mem.mem[0x0300] = 0x50;
+        assert_eq!(run(&mut cpu, 1), vec![5]);
+        assert_eq!(cpu.pc(), 0x9000);
+    }
+
+    #[test]
+    fn brk_and_rti() {
+        // CLI; BRK and its padding byte; RTI at $9000
+        let mut cpu = program(&[0x58, 0x00, 0xFF]);
+        cpu.mem.mem[0xFFFE] = 0x00;
+        cpu.mem.mem[0xFFFF] = 0x90;
+        cpu.mem.mem[0x9000] = 0x40;
+        assert_eq!(run(&mut cpu, 2), vec![2, 7]);
+        assert_eq!(cpu.pc(), 0x9000);
+        assert!(cpu.regs.flag_set(Flags::Interrupt));
+        assert_eq!(cpu.mem.mem[0x1FB] & 0x34, 0x30);
+        assert_eq!(run(&mut cpu, 1), vec![6]);
+        assert_eq!(cpu.pc(), 0x8003);
+        assert!(!cpu.regs.flag_set(Flags::Interrupt));
+        assert!(!cpu.regs.flag_set(Flags::Break));
+    }
+
+    #[test]
+    fn nmi_pushes_status_without_break() {
+        let mut cpu = program(&[0xEA]);
+        cpu.mem.mem[0xFFFA] = 0x34;
+        cpu.mem.mem[0xFFFB] = 0x12;
+        cpu.nmi();
+        assert_eq!(cpu.pc(), 0x1234);
+        assert_eq!(cpu.clock(), 7);
+        assert_eq!((cpu.mem.mem[0x1FD], cpu.mem.mem[0x1FC]), (0x80, 0x00));
+        assert_eq!(cpu.mem.mem[0x1FB] & 0x30, 0x20);
+    }
+
+    #[test]
+    fn irq_is_level_triggered_and_masked_by_the_interrupt_flag() {
+        // CLI; NOP; NOP
+        let mut cpu = program(&[0x58, 0xEA, 0xEA]);
+        cpu.mem.mem[0xFFFE] = 0x00;
+        cpu.mem.mem[0xFFFF] = 0x90;
+        cpu.mem.mem[0x9000] = 0xEA;
+        cpu.set_irq(true);
+        // The interrupt flag starts set, so CLI runs first
+        assert_eq!(run(&mut cpu, 2), vec![2, 7]);
+        assert_eq!(cpu.pc(), 0x9000);
+        assert_eq!((cpu.mem.mem[0x1FD], cpu.mem.mem[0x1FC]), (0x80, 0x01));
+        assert_eq!(cpu.mem.mem[0x1FB] & 0x34, 0x20);
+        // Now masked, the handler runs while the line stays asserted
+        assert_eq!(run(&mut cpu, 1), vec![2]);
+        assert_eq!(cpu.pc(), 0x9001);
+    }
+
+    #[test]
+    fn php_sets_break_and_plp_drops_it() {
+        // PHP; LDA #$FF; PHA; PLP
+        let mut cpu = program(&[0x08, 0xA9, 0xFF, 0x48, 0x28]);
+        assert_eq!(run(&mut cpu, 4), vec![3, 2, 3, 4]);
+        assert_eq!(cpu.mem.mem[0x1FD], 0x34);
+        assert_eq!(cpu.regs.status, 0xEF);
+    }
+
+    #[test]
+    fn stack_transfers() {
+        // LDX #$80; TXS; TSX; PHA; PLA
+        let mut cpu = program(&[0xA2, 0x80, 0x9A, 0xBA, 0x48, 0x68]);
+        cpu.regs.a = 0x00;
+        run(&mut cpu, 3);
+        assert_eq!(cpu.regs.sp, 0x80);
+        assert!(cpu.regs.flag_set(Flags::Negative));
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs.sp, 0x7F);
+        run(&mut cpu, 1);
+        assert_eq!(cpu.regs.sp, 0x80);
+        assert!(cpu.regs.flag_set(Flags::Zero));
+    }
+
+    #[test]
+    fn unofficial_load_and_store() {
+        // LAX $10; LDA #$F0; LDX #$3C; SAX $11
+        let mut cpu = program(&[0xA7, 0x10, 0xA9, 0xF0, 0xA2, 0x3C, 0x87, 0x11]);
+        cpu.mem.mem[0x10] = 0x85;
+        assert_eq!(run(&mut cpu, 1), vec![3]);
+        assert_eq!((cpu.regs.a, cpu.regs.x), (0x85, 0x85));
+        assert!(cpu.regs.flag_set(Flags::Negative));
+        assert_eq!(run(&mut cpu, 3), vec![2, 2, 3]);
+        assert_eq!(cpu.mem.mem[0x11], 0x30);
+    }
+
+    #[test]
+    fn unofficial_read_modify_write() {
+        // LDA #$40; DCP $10; ISC $11
+        let mut cpu = program(&[0xA9, 0x40, 0xC7, 0x10, 0xE7, 0x11]);
+        cpu.mem.mem[0x10] = 0x41;
+        cpu.mem.mem[0x11] = 0x0F;
+        assert_eq!(run(&mut cpu, 2), vec![2, 5]);
+        assert_eq!(cpu.mem.mem[0x10], 0x40);
+        assert_eq!(flags(&cpu), (false, false, true, true));
+        run(&mut cpu, 1);
+        assert_eq!(cpu.mem.mem[0x11], 0x10);
+        assert_eq!(cpu.regs.a, 0x30);
+
+        // LDA #$01; SLO $10; RLA $11; SRE $12; RRA $13
+        let mut cpu = program(&[0xA9, 0x01, 0x07, 0x10, 0x27, 0x11, 0x47, 0x12, 0x67, 0x13]);
+        cpu.mem.mem[0x10..0x14].copy_from_slice(&[0x81, 0x40, 0x03, 0x02]);
+        run(&mut cpu, 2);
+        assert_eq!((cpu.mem.mem[0x10], cpu.regs.a), (0x02, 0x03));
+        assert!(cpu.regs.flag_set(Flags::Carry));
+        run(&mut cpu, 1);
+        assert_eq!((cpu.mem.mem[0x11], cpu.regs.a), (0x81, 0x01));
+        run(&mut cpu, 1);
+        assert_eq!((cpu.mem.mem[0x12], cpu.regs.a), (0x01, 0x00));
+        assert!(cpu.regs.flag_set(Flags::Carry));
+        run(&mut cpu, 1);
+        assert_eq!((cpu.mem.mem[0x13], cpu.regs.a), (0x81, 0x81));
+        assert!(!cpu.regs.flag_set(Flags::Carry));
+    }
+
+    #[test]
+    fn unofficial_nops_skip_their_operands() {
+        // NOP #; NOP zp; NOP abs; NOP abs,X across a page; $1A
+        let mut cpu = program(&[0x80, 0xFF, 0x04, 0x10, 0x0C, 0x00, 0x20, 0x1C, 0xFF, 0x20, 0x1A]);
+        cpu.regs.x = 1;
+        assert_eq!(run(&mut cpu, 5), vec![2, 3, 4, 5, 2]);
+        assert_eq!(cpu.pc(), 0x800B);
+    }
+
+    #[test]
+    fn reset_reads_the_vector() {
+        let mut cpu = program(&[]);
+        cpu.mem.mem[0xFFFC] = 0x00;
+        cpu.mem.mem[0xFFFD] = 0xC0;
+        cpu.reset();
+        assert_eq!(cpu.pc(), 0xC000);
+        assert_eq!(cpu.regs.sp, 0xFA);
+        assert_eq!(cpu.clock(), 7);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{Read, BufReader};
+use std::path::{Path, PathBuf};
+use archive;
+use rom::RomError;
+
+// Size of one disk side in a .fds image, with gaps and CRCs stripped
+pub const SIDE_SIZE: usize = 65500;
+pub const BIOS_SIZE: usize = 0x2000;
+
+// Gaps are written as zero bytes and end with a single $80 mark.
+// The lead-in is about 28300 bits, and 976 bits separate blocks.
+const LEAD_IN_BYTES: usize = 28300 / 8;
+const BLOCK_GAP_BYTES: usize = 976 / 8;
+// Minimum length of a side as seen by the drive, with gaps and CRCs added
+const SURFACE_SIZE: usize = 80000;
+
+const HEADER_MAGIC: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+// Disk info block: block code 1 followed by "*NINTENDO-HVC*"
+const DISK_INFO_MAGIC: &'static [u8] = b"\x01*NINTENDO-HVC*";
+
+// Famicom Disk System image, one entry per disk side
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    // Load a .fds image, with or without the 16 byte fwNES header. The
+    // image may be compressed with gzip or inside a zip.
+    pub fn load(r: &mut Read) -> Result<FdsImage, RomError> {
+        let data = try!(archive::read(r, None));
+
+        let body = if data.len() >= 16 && data[0..4] == HEADER_MAGIC {
+            &data[16..]
+        } else {
+            &data[..]
+        };
+        if body.len() < SIDE_SIZE {
+            return Err(RomError::FormatError);
+        }
+
+        let sides: Vec<Vec<u8>> = body.chunks(SIDE_SIZE)
+            .filter(|side| side.len() == SIDE_SIZE)
+            .map(|side| side.to_vec())
+            .collect();
+        if sides.iter().any(|side| !side.starts_with(DISK_INFO_MAGIC)) {
+            return Err(RomError::FormatError);
+        }
+
+        Ok(FdsImage { sides: sides })
+    }
+
+    // Lay a side out as the drive reads it off the disk surface: each
+    // block is preceded by a gap and followed by its CRC
+    pub fn surface(&self, side: usize) -> Vec<u8> {
+        let data = &self.sides[side];
+        let mut out = Vec::with_capacity(SURFACE_SIZE);
+        let mut pos = 0;
+        let mut gap = LEAD_IN_BYTES;
+
+        loop {
+            let len = match data.get(pos) {
+                Some(&code) => match block_len(&data[..pos], code) {
+                    Some(len) => len,
+                    None => break,
+                },
+                None => break,
+            };
+            if pos + len > data.len() {
+                break;
+            }
+
+            out.extend(::std::iter::repeat(0).take(gap));
+            out.push(0x80);
+            let block = &data[pos..pos + len];
+            out.extend_from_slice(block);
+            let crc = block.iter().fold(0, |crc, &byte| crc16(crc, byte));
+            out.push(crc as u8);
+            out.push((crc >> 8) as u8);
+
+            pos += len;
+            gap = BLOCK_GAP_BYTES;
+        }
+
+        if out.len() < SURFACE_SIZE {
+            out.resize(SURFACE_SIZE, 0);
+        }
+        out
+    }
+
+    // Rebuild the sides from surfaces the drive may have written to, the
+    // inverse of surface. Blocks found on a surface are copied over the
+    // matching side, keeping whatever followed the last block.
+    pub fn from_surfaces(&self, surfaces: &[Vec<u8>]) -> FdsImage {
+        let sides = self.sides.iter().zip(surfaces.iter()).map(|(side, surface)| {
+            let mut out = Vec::with_capacity(SIDE_SIZE);
+            let mut pos = 0;
+            loop {
+                // Skip the gap up to its end mark
+                match surface[pos..].iter().position(|&byte| byte != 0) {
+                    Some(len) if surface[pos + len] == 0x80 => pos += len + 1,
+                    _ => break,
+                }
+                let len = match surface.get(pos) {
+                    Some(&code) => match block_len(&out, code) {
+                        Some(len) => len,
+                        None => break,
+                    },
+                    None => break,
+                };
+                if pos + len > surface.len() || out.len() + len > SIDE_SIZE {
+                    break;
+                }
+                out.extend_from_slice(&surface[pos..pos + len]);
+                // Step over the CRC
+                pos += len + 2;
+                if pos >= surface.len() {
+                    break;
+                }
+            }
+            let written = out.len();
+            out.extend_from_slice(&side[written..]);
+            out
+        }).collect();
+        FdsImage { sides: sides }
+    }
+
+    // The sides one after another, as held in a .fds file without its header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.sides.concat()
+    }
+}
+
+// Length of the block starting with the given code, from the side data
+// before it. File data is sized by the file header just before it.
+fn block_len(before: &[u8], code: u8) -> Option<usize> {
+    match code {
+        // Disk info
+        1 => Some(56),
+        // File amount
+        2 => Some(2),
+        // File header
+        3 => Some(16),
+        // File data
+        4 if before.len() >= 16 && before[before.len() - 16] == 3 => {
+            let size = before[before.len() - 3] as usize | (before[before.len() - 2] as usize) << 8;
+            Some(1 + size)
+        }
+        _ => None,
+    }
+}
+
+// CRC-16 used on disk blocks, fed least significant bit first
+pub fn crc16(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc >>= 1;
+        if carry {
+            crc ^= 0x8408;
+        }
+        if byte & (1 << bit) != 0 {
+            crc ^= 0x8000;
+        }
+    }
+    crc
+}
+
+// Read the 8KB disksys.rom BIOS from the given path
+pub fn load_bios(path: &Path) -> Result<Vec<u8>, RomError> {
+    let mut reader = BufReader::new(try!(File::open(path)));
+    let mut bios = Vec::new();
+    try!(reader.read_to_end(&mut bios));
+    if bios.len() != BIOS_SIZE {
+        return Err(RomError::FormatError);
+    }
+    Ok(bios)
+}
+
+// Sidecar file next to a disk image that holds writes made to it, as an
+// IPS patch against the image
+pub fn diff_path(image: &Path) -> PathBuf {
+    image.with_extension("ips")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+
+    // A side holding one file of the given data, zero padded
+    pub fn side(file: &[u8]) -> Vec<u8> {
+        let mut side = DISK_INFO_MAGIC.to_vec();
+        side.resize(56, 0);
+        side.extend_from_slice(&[2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.extend_from_slice(b"FILENAME");
+        header.extend_from_slice(&[0x00, 0x60, file.len() as u8, (file.len() >> 8) as u8, 0]);
+        side.extend_from_slice(&header);
+        side.push(4);
+        side.extend_from_slice(file);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn surfaces_hold_gaps_marks_and_crcs() {
+        let image = FdsImage { sides: vec![side(&[1, 2, 3])] };
+        let surface = image.surface(0);
+        assert_eq!(surface.len(), SURFACE_SIZE);
+        assert_eq!(surface[LEAD_IN_BYTES], 0x80);
+        assert_eq!(&surface[LEAD_IN_BYTES + 1..LEAD_IN_BYTES + 16], DISK_INFO_MAGIC);
+
+        let block = &image.sides[0][..56];
+        let crc = block.iter().fold(0, |crc, &byte| crc16(crc, byte));
+        let crc_pos = LEAD_IN_BYTES + 1 + 56;
+        assert_eq!((surface[crc_pos], surface[crc_pos + 1]), (crc as u8, (crc >> 8) as u8));
+        assert_eq!(surface[crc_pos + 2 + BLOCK_GAP_BYTES], 0x80);
+        assert_eq!(surface[crc_pos + 2 + BLOCK_GAP_BYTES + 1], 2);
+    }
+
+    #[test]
+    fn sides_are_recovered_from_surfaces() {
+        let mut original = side(&[1, 2, 3]);
+        // Trailing data past the last block is kept as it was
+        original[SIDE_SIZE - 1] = 0xEE;
+        let image = FdsImage { sides: vec![original.clone(), side(&[4])] };
+        let surfaces: Vec<Vec<u8>> = (0..2).map(|side| image.surface(side)).collect();
+        assert_eq!(image.from_surfaces(&surfaces).sides, image.sides);
+
+        let mut written = original;
+        written[56 + 2 + 16 + 1] = 9;
+        let changed = FdsImage { sides: vec![written.clone(), side(&[4])] };
+        let surfaces: Vec<Vec<u8>> = (0..2).map(|side| changed.surface(side)).collect();
+        assert_eq!(image.from_surfaces(&surfaces).sides[0], written);
+    }
+
+    #[test]
+    fn gzipped_images_load() {
+        let mut data = HEADER_MAGIC.to_vec();
+        data.resize(16, 0);
+        data.extend_from_slice(&side(&[1, 2, 3]));
+        data.extend_from_slice(&side(&[4]));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gz = encoder.finish().unwrap();
+
+        let image = FdsImage::load(&mut &gz[..]).unwrap();
+        assert_eq!(image.sides, vec![side(&[1, 2, 3]), side(&[4])]);
+    }
+
+    #[test]
+    fn writes_go_to_an_ips_file_beside_the_image() {
+        assert_eq!(diff_path(Path::new("games/zelda.fds")), Path::new("games/zelda.ips"));
+    }
+}
@@ -0,0 +1,821 @@
+use video::Image;
+
+// Names accepted by create, which can be joined with commas for a chain
+pub const FILTER_NAMES: [&'static str; 10] = [
+    "scale2x", "scale3x", "hq2x", "hq3x", "hq4x", "xbrz2x", "xbrz3x", "xbrz4x", "scanlines", "crt",
+];
+
+// A transformation of an image, usually scaling it up. Filters run on the
+// CPU alone so they work without a display.
+pub trait Filter {
+    fn apply(&self, image: &Image) -> Image;
+}
+
+// Create a filter by name:
+//
+// scale2x, scale3x      Scale2x and Scale3x (AdvMAME), exact colour matching
+// hq2x, hq3x, hq4x      hqx
+// xbrz2x ... xbrz4x     xBRZ
+// scanlines             2x with every other line darkened
+// crt                   3x with scanlines and an aperture grille mask
+pub fn create(name: &str) -> Option<Box<Filter>> {
+    match name {
+        "scale2x" => Some(Box::new(ScaleNx { scale: 2 })),
+        "scale3x" => Some(Box::new(ScaleNx { scale: 3 })),
+        "hq2x" => Some(Box::new(HqNx { scale: 2 })),
+        "hq3x" => Some(Box::new(HqNx { scale: 3 })),
+        "hq4x" => Some(Box::new(HqNx { scale: 4 })),
+        "xbrz2x" => Some(Box::new(Xbrz { scale: 2 })),
+        "xbrz3x" => Some(Box::new(Xbrz { scale: 3 })),
+        "xbrz4x" => Some(Box::new(Xbrz { scale: 4 })),
+        "scanlines" => Some(Box::new(Crt { scale: 2, scanline: 0.5, mask: 0.0 })),
+        "crt" => Some(Box::new(Crt { scale: 3, scanline: 0.4, mask: 0.3 })),
+        _ => None,
+    }
+}
+
+// Filters applied one after another
+pub struct FilterChain {
+    filters: Vec<Box<Filter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> FilterChain {
+        FilterChain { filters: Vec::new() }
+    }
+
+    // Build a chain from names separated by commas, such as
+    // "scale2x,scanlines". An empty string gives an empty chain.
+    pub fn parse(spec: &str) -> Option<FilterChain> {
+        let mut chain = FilterChain::new();
+        for name in spec.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+            match create(name) {
+                Some(filter) => chain.push(filter),
+                None => return None,
+            }
+        }
+        Some(chain)
+    }
+
+    pub fn push(&mut self, filter: Box<Filter>) {
+        self.filters.push(filter);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn apply(&self, image: Image) -> Image {
+        self.filters.iter().fold(image, |image, filter| filter.apply(&image))
+    }
+}
+
+type Rgb = (u8, u8, u8);
+
+// Pixel at x, y with coordinates off the edge clamped to it
+fn clamped(image: &Image, x: isize, y: isize) -> Rgb {
+    let x = x.max(0).min(image.width as isize - 1) as usize;
+    let y = y.max(0).min(image.height as isize - 1) as usize;
+    image.pixel(x, y)
+}
+
+// The 3x3 neighbourhood of a pixel, row by row
+fn neighbourhood(image: &Image, x: usize, y: usize) -> [Rgb; 9] {
+    let (x, y) = (x as isize, y as isize);
+    let mut n = [(0, 0, 0); 9];
+    for (i, pixel) in n.iter_mut().enumerate() {
+        *pixel = clamped(image, x + (i % 3) as isize - 1, y + (i / 3) as isize - 1);
+    }
+    n
+}
+
+// Weighted mix of a and b, with weight parts of b in total parts
+fn mix(a: Rgb, b: Rgb, weight: f32) -> Rgb {
+    let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * weight).round() as u8;
+    (channel(a.0, b.0), channel(a.1, b.1), channel(a.2, b.2))
+}
+
+// Scale an image by having f fill in the block of scale by scale output
+// pixels, row by row, for each source pixel at x, y
+fn scale_image<F: FnMut(usize, usize, &mut [Rgb])>(image: &Image, scale: usize, mut f: F) -> Image {
+    let mut out = Image::new(image.width * scale, image.height * scale);
+    let mut block = vec![(0, 0, 0); scale * scale];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            f(x, y, &mut block);
+            for sy in 0..scale {
+                let start = (y * scale + sy) * out.width + x * scale;
+                out.pixels[start..start + scale].copy_from_slice(&block[sy * scale..(sy + 1) * scale]);
+            }
+        }
+    }
+    out
+}
+
+// Scale2x and Scale3x, which fill each corner from a neighbour where two
+// neighbours agree on an edge across it
+struct ScaleNx {
+    scale: usize,
+}
+
+impl ScaleNx {
+    // Subpixels of a pixel, row by row, from its neighbourhood
+    //   A B C
+    //   D E F
+    //   G H I
+    fn expand(&self, n: &[Rgb; 9], out: &mut [Rgb]) {
+        let (a, b, c, d, e, f, g, h, i) = (n[0], n[1], n[2], n[3], n[4], n[5], n[6], n[7], n[8]);
+        if self.scale == 2 {
+            if b != h && d != f {
+                out[0] = if d == b { d } else { e };
+                out[1] = if b == f { f } else { e };
+                out[2] = if d == h { d } else { e };
+                out[3] = if h == f { f } else { e };
+            } else {
+                out.copy_from_slice(&[e; 4]);
+            }
+        } else if b != h && d != f {
+            out[0] = if d == b { d } else { e };
+            out[1] = if (d == b && e != c) || (b == f && e != a) { b } else { e };
+            out[2] = if b == f { f } else { e };
+            out[3] = if (d == b && e != g) || (d == h && e != a) { d } else { e };
+            out[4] = e;
+            out[5] = if (b == f && e != i) || (h == f && e != c) { f } else { e };
+            out[6] = if d == h { d } else { e };
+            out[7] = if (d == h && e != i) || (h == f && e != g) { h } else { e };
+            out[8] = if h == f { f } else { e };
+        } else {
+            out.copy_from_slice(&[e; 9]);
+        }
+    }
+}
+
+impl Filter for ScaleNx {
+    fn apply(&self, image: &Image) -> Image {
+        scale_image(image, self.scale, |x, y, block| self.expand(&neighbourhood(image, x, y), block))
+    }
+}
+
+// hqx by Maxim Stepin. Each neighbour is compared with the centre in YUV
+// using hqx's thresholds, and the eight results pick the rule hqx's tables
+// give for blending each corner. The rules are kept for the top left
+// corner of hq2x; the other corners rotate the neighbourhood to use them.
+// hq3x and hq4x pick the same rule for a corner and differ only in how its
+// blend is spread over their subpixels.
+struct HqNx {
+    scale: usize,
+}
+
+fn yuv((r, g, b): Rgb) -> (i32, i32, i32) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    ((299 * r + 587 * g + 114 * b) / 1000,
+     (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+     (500 * r - 419 * g - 81 * b) / 1000 + 128)
+}
+
+// Whether two colours differ enough in YUV for hqx to treat them as an
+// edge
+fn hq_differ(a: Rgb, b: Rgb) -> bool {
+    let ((ya, ua, va), (yb, ub, vb)) = (yuv(a), yuv(b));
+    (ya - yb).abs() > 0x30 || (ua - ub).abs() > 0x07 || (va - vb).abs() > 0x06
+}
+
+// A blend of the neighbourhood as weights in sixteenths, numbering the
+// neighbours as hqx does
+//   1 2 3
+//   4 5 6
+//   7 8 9
+type HqBlend = &'static [(usize, u32)];
+
+const HQ_CENTRE: HqBlend = &[(5, 16)];
+
+// hq2x's blends for the top left subpixel, named after the reference's
+// PIXEL00_xx macros
+#[derive(Clone, Copy, PartialEq)]
+enum HqPixel {
+    P0,
+    P10,
+    P11,
+    P12,
+    P20,
+    P21,
+    P22,
+    P60,
+    P61,
+    P70,
+    P90,
+    P100,
+}
+
+impl HqPixel {
+    fn blend(self) -> HqBlend {
+        match self {
+            HqPixel::P0 => HQ_CENTRE,
+            HqPixel::P10 => &[(5, 12), (1, 4)],
+            HqPixel::P11 => &[(5, 12), (4, 4)],
+            HqPixel::P12 => &[(5, 12), (2, 4)],
+            HqPixel::P20 => &[(5, 8), (4, 4), (2, 4)],
+            HqPixel::P21 => &[(5, 8), (1, 4), (2, 4)],
+            HqPixel::P22 => &[(5, 8), (1, 4), (4, 4)],
+            HqPixel::P60 => &[(5, 10), (2, 4), (4, 2)],
+            HqPixel::P61 => &[(5, 10), (4, 4), (2, 2)],
+            HqPixel::P70 => &[(5, 12), (4, 2), (2, 2)],
+            HqPixel::P90 => &[(5, 4), (4, 6), (2, 6)],
+            HqPixel::P100 => &[(5, 14), (4, 1), (2, 1)],
+        }
+    }
+}
+
+// How hqx blends the top left corner: with then if the two neighbours of
+// edge differ from each other or there's no edge to test, and otherwise
+// with otherwise, the neighbours then joining up across the corner
+struct HqRule {
+    edge: Option<(usize, usize)>,
+    then: HqPixel,
+    otherwise: HqPixel,
+}
+
+impl HqRule {
+    fn pick(&self, then: bool) -> HqPixel {
+        if then { self.then } else { self.otherwise }
+    }
+}
+
+static HQ_RULES: [HqRule; 14] = [
+    HqRule { edge: None, then: HqPixel::P10, otherwise: HqPixel::P10 },
+    HqRule { edge: None, then: HqPixel::P11, otherwise: HqPixel::P11 },
+    HqRule { edge: None, then: HqPixel::P12, otherwise: HqPixel::P12 },
+    HqRule { edge: None, then: HqPixel::P20, otherwise: HqPixel::P20 },
+    HqRule { edge: None, then: HqPixel::P21, otherwise: HqPixel::P21 },
+    HqRule { edge: None, then: HqPixel::P22, otherwise: HqPixel::P22 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P0, otherwise: HqPixel::P100 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P0, otherwise: HqPixel::P20 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P0, otherwise: HqPixel::P90 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P10, otherwise: HqPixel::P20 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P10, otherwise: HqPixel::P70 },
+    HqRule { edge: Some((2, 4)), then: HqPixel::P10, otherwise: HqPixel::P90 },
+    HqRule { edge: Some((2, 6)), then: HqPixel::P11, otherwise: HqPixel::P60 },
+    HqRule { edge: Some((4, 8)), then: HqPixel::P12, otherwise: HqPixel::P61 },
+];
+
+// Index into HQ_RULES for the top left corner of each pattern, the
+// pattern having a bit set for each neighbour differing from the centre:
+// 1, 2, 4 and 8 for neighbours 1 to 4, then 16 to 128 for 6 to 9
+static HQ_CORNERS: [u8; 256] = [
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 9, 7, 4, 2, 11, 8,
+    3, 3, 5, 12, 3, 3, 5, 12, 4, 2, 7, 7, 4, 2, 0, 7,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 11, 8, 4, 2, 10, 6,
+    3, 3, 5, 12, 3, 3, 5, 12, 4, 2, 10, 7, 4, 2, 0, 6,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 13, 7, 7, 4, 13, 10, 7,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 7, 4, 2, 10, 7,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 13, 0, 7, 4, 13, 0, 6,
+    3, 3, 5, 1, 3, 3, 5, 12, 4, 2, 10, 7, 4, 13, 0, 6,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 9, 7, 4, 2, 11, 8,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 7, 4, 2, 10, 7,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 11, 8, 4, 2, 10, 6,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 8, 4, 2, 0, 6,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 7, 4, 2, 10, 8,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 7, 4, 2, 0, 7,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 10, 7, 4, 2, 0, 6,
+    3, 3, 5, 1, 3, 3, 5, 1, 4, 2, 0, 7, 4, 2, 0, 6,
+];
+
+// Neighbour n of a neighbourhood stored row by row
+fn hq_at(w: &[Rgb; 9], n: usize) -> Rgb {
+    w[n - 1]
+}
+
+fn hq_mix(w: &[Rgb; 9], blend: HqBlend) -> Rgb {
+    let (mut r, mut g, mut b) = (0, 0, 0);
+    for &(n, weight) in blend {
+        let (pr, pg, pb) = hq_at(w, n);
+        r += pr as u32 * weight;
+        g += pg as u32 * weight;
+        b += pb as u32 * weight;
+    }
+    ((r / 16) as u8, (g / 16) as u8, (b / 16) as u8)
+}
+
+// The rule for the top left corner of w, and whether it takes its then
+// blend
+fn hq_corner(w: &[Rgb; 9]) -> (&'static HqRule, bool) {
+    let centre = hq_at(w, 5);
+    let mut pattern = 0;
+    for (bit, &n) in [1, 2, 3, 4, 6, 7, 8, 9].iter().enumerate() {
+        if hq_differ(hq_at(w, n), centre) {
+            pattern |= 1 << bit;
+        }
+    }
+    let rule = &HQ_RULES[HQ_CORNERS[pattern] as usize];
+    let then = match rule.edge {
+        Some((a, b)) => hq_differ(hq_at(w, a), hq_at(w, b)),
+        None => true,
+    };
+    (rule, then)
+}
+
+// hq3x's top left subpixel
+fn hq3x_corner((rule, then): (&HqRule, bool)) -> HqBlend {
+    if !then {
+        return match rule.otherwise {
+            HqPixel::P20 => &[(5, 2), (2, 7), (4, 7)],
+            HqPixel::P90 => &[(2, 8), (4, 8)],
+            _ => &[(5, 8), (2, 4), (4, 4)],
+        };
+    }
+    match rule.then {
+        HqPixel::P0 => HQ_CENTRE,
+        HqPixel::P11 => &[(5, 12), (4, 4)],
+        HqPixel::P12 => &[(5, 12), (2, 4)],
+        HqPixel::P20 => &[(5, 8), (2, 4), (4, 4)],
+        _ => &[(5, 12), (1, 4)],
+    }
+}
+
+// hq3x's top middle subpixel, which follows an edge joining up across
+// whichever of the corners beside it has one
+fn hq3x_edge(w: &[Rgb; 9], left: (&HqRule, bool), right: (&HqRule, bool)) -> HqBlend {
+    let centre = hq_at(w, 5);
+    if !hq_differ(hq_at(w, 2), centre) {
+        return &[(5, 12), (2, 4)];
+    }
+    let joined = |(rule, then): (&HqRule, bool), pixel: HqPixel| rule.edge.is_some() && rule.otherwise == pixel && !then;
+    let line = |(rule, _): (&HqRule, bool)| rule.edge.is_some() && rule.otherwise == HqPixel::P20;
+    if line(left) && line(right) {
+        HQ_CENTRE
+    } else if joined(left, HqPixel::P20) || joined(right, HqPixel::P20) {
+        &[(5, 14), (2, 2)]
+    } else if joined(left, HqPixel::P90) {
+        // A slope, which runs on across the top if neighbour 3 differs
+        // and turns down the left otherwise
+        if hq_differ(hq_at(w, 3), centre) { &[(2, 12), (5, 4)] } else { &[(5, 12), (2, 4)] }
+    } else if joined(right, HqPixel::P90) {
+        if hq_differ(hq_at(w, 1), centre) { &[(2, 12), (5, 4)] } else { &[(5, 12), (2, 4)] }
+    } else {
+        HQ_CENTRE
+    }
+}
+
+// hq4x's top left quarter, row by row
+fn hq4x_quarter(w: &[Rgb; 9], (rule, then): (&HqRule, bool)) -> [HqBlend; 4] {
+    if !then {
+        return match rule.otherwise {
+            HqPixel::P20 => [&[(2, 8), (4, 8)], &[(2, 8), (5, 8)], &[(4, 8), (5, 8)], HQ_CENTRE],
+            HqPixel::P70 => [&[(5, 8), (2, 4), (4, 4)], &[(5, 12), (2, 4)], &[(5, 12), (4, 4)], HQ_CENTRE],
+            HqPixel::P100 => [&[(5, 8), (2, 4), (4, 4)], HQ_CENTRE, HQ_CENTRE, HQ_CENTRE],
+            HqPixel::P90 if hq_differ(hq_at(w, 3), hq_at(w, 5)) => {
+                [&[(2, 8), (4, 8)], &[(2, 10), (4, 6)], &[(4, 8), (5, 4), (2, 4)], &[(5, 12), (4, 2), (2, 2)]]
+            }
+            HqPixel::P90 => {
+                [&[(2, 8), (4, 8)], &[(2, 8), (5, 4), (4, 4)], &[(4, 10), (2, 6)], &[(5, 12), (4, 2), (2, 2)]]
+            }
+            HqPixel::P60 => [&[(5, 12), (2, 4)], &[(2, 12), (5, 4)], &[(5, 10), (4, 6)], &[(5, 14), (4, 2)]],
+            _ => [&[(5, 12), (4, 4)], &[(5, 10), (2, 6)], &[(4, 12), (5, 4)], &[(5, 14), (2, 2)]],
+        };
+    }
+    match rule.then {
+        HqPixel::P0 => [HQ_CENTRE; 4],
+        HqPixel::P10 => [&[(5, 10), (1, 6)], &[(5, 12), (1, 4)], &[(5, 12), (1, 4)], &[(5, 14), (1, 2)]],
+        HqPixel::P11 => [&[(5, 10), (4, 6)], &[(5, 14), (4, 2)], &[(5, 10), (4, 6)], &[(5, 14), (4, 2)]],
+        HqPixel::P12 => [&[(5, 10), (2, 6)], &[(5, 10), (2, 6)], &[(5, 14), (2, 2)], &[(5, 14), (2, 2)]],
+        HqPixel::P21 => [&[(5, 10), (1, 6)], &[(5, 10), (2, 4), (1, 2)], &[(5, 12), (1, 4)], &[(5, 14), (1, 2)]],
+        HqPixel::P22 => [&[(5, 10), (1, 6)], &[(5, 12), (1, 4)], &[(5, 10), (4, 4), (1, 2)], &[(5, 14), (1, 2)]],
+        _ => [&[(5, 8), (2, 4), (4, 4)], &[(5, 10), (2, 4), (4, 2)], &[(5, 10), (4, 4), (2, 2)], &[(5, 12), (4, 2), (2, 2)]],
+    }
+}
+
+impl Filter for HqNx {
+    fn apply(&self, image: &Image) -> Image {
+        let scale = self.scale;
+        scale_image(image, scale, |x, y, block| {
+            // The neighbourhood turned so that each corner in turn,
+            // clockwise from the top left, is at the top left
+            let mut frames = [neighbourhood(image, x, y); 4];
+            for k in 1..4 {
+                for i in 0..9 {
+                    let (row, col) = rotate((i / 3, i % 3), 3);
+                    frames[k][i] = frames[k - 1][row * 3 + col];
+                }
+            }
+            let mut corners = [(&HQ_RULES[0], true); 4];
+            for (corner, w) in corners.iter_mut().zip(frames.iter()) {
+                *corner = hq_corner(w);
+            }
+
+            if scale == 3 {
+                block[4] = hq_at(&frames[0], 5);
+            }
+            for (k, w) in frames.iter().enumerate() {
+                let mut set = |row: usize, col: usize, blend: HqBlend| {
+                    let (r, c) = rotate_times((row, col), scale, k);
+                    block[r * scale + c] = hq_mix(w, blend);
+                };
+                match scale {
+                    2 => set(0, 0, corners[k].0.pick(corners[k].1).blend()),
+                    3 => {
+                        set(0, 0, hq3x_corner(corners[k]));
+                        set(0, 1, hq3x_edge(w, corners[k], corners[(k + 1) % 4]));
+                    }
+                    _ => {
+                        for (i, &blend) in hq4x_quarter(w, corners[k]).iter().enumerate() {
+                            set(i / 2, i % 2, blend);
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
+
+// xBRZ by Zenju. Each 2x2 block of pixels is checked for which diagonal
+// runs along an edge, using weighted colour distances over the 4x4 pixels
+// around it. Corners across the edge are then blended, with the shape of
+// the blend following how steep the edge is.
+struct Xbrz {
+    scale: usize,
+}
+
+const BLEND_NONE: u8 = 0;
+const BLEND_NORMAL: u8 = 1;
+const BLEND_DOMINANT: u8 = 2;
+
+const XBRZ_EQUAL_TOLERANCE: f32 = 30.0;
+const XBRZ_CENTER_WEIGHT: f32 = 4.0;
+const XBRZ_DOMINANT_THRESHOLD: f32 = 3.6;
+const XBRZ_STEEP_THRESHOLD: f32 = 2.2;
+
+// Distance between colours in YCbCr (BT.2020)
+fn xbrz_distance(a: Rgb, b: Rgb) -> f32 {
+    let r = a.0 as f32 - b.0 as f32;
+    let g = a.1 as f32 - b.1 as f32;
+    let b = a.2 as f32 - b.2 as f32;
+    let y = 0.2627 * r + 0.6780 * g + 0.0593 * b;
+    let cb = 0.5 / (1.0 - 0.0593) * (b - y);
+    let cr = 0.5 / (1.0 - 0.2627) * (r - y);
+    (y * y + cb * cb + cr * cr).sqrt()
+}
+
+fn xbrz_equal(a: Rgb, b: Rgb) -> bool {
+    xbrz_distance(a, b) < XBRZ_EQUAL_TOLERANCE
+}
+
+// Rotate coordinates in an n by n block a quarter turn, so that the
+// bottom right corner becomes the bottom left
+fn rotate((row, col): (usize, usize), n: usize) -> (usize, usize) {
+    (col, n - 1 - row)
+}
+
+fn rotate_times(mut pos: (usize, usize), n: usize, times: usize) -> (usize, usize) {
+    for _ in 0..times {
+        pos = rotate(pos, n);
+    }
+    pos
+}
+
+impl Xbrz {
+    // Blend types of the corners meeting in the middle of a 2x2 block
+    // with f at x, y, as (f, g, j, k) for its
+    //   a b c d
+    //   e f g h
+    //   i j k l
+    //   m n o p
+    fn preprocess(image: &Image, x: isize, y: isize) -> (u8, u8, u8, u8) {
+        let p = |dx: isize, dy: isize| clamped(image, x + dx, y + dy);
+        let (b, c) = (p(0, -1), p(1, -1));
+        let (e, f, g, h) = (p(-1, 0), p(0, 0), p(1, 0), p(2, 0));
+        let (i, j, k, l) = (p(-1, 1), p(0, 1), p(1, 1), p(2, 1));
+        let (n, o) = (p(0, 2), p(1, 2));
+
+        let mut blend = (BLEND_NONE, BLEND_NONE, BLEND_NONE, BLEND_NONE);
+        if (f == g && j == k) || (f == j && g == k) {
+            return blend;
+        }
+        let dist = xbrz_distance;
+        let jg = dist(i, f) + dist(f, c) + dist(n, k) + dist(k, h) + XBRZ_CENTER_WEIGHT * dist(j, g);
+        let fk = dist(e, j) + dist(j, o) + dist(b, g) + dist(g, l) + XBRZ_CENTER_WEIGHT * dist(f, k);
+        if jg < fk {
+            let kind = if XBRZ_DOMINANT_THRESHOLD * jg < fk { BLEND_DOMINANT } else { BLEND_NORMAL };
+            if f != g && f != j {
+                blend.0 = kind;
+            }
+            if k != j && k != g {
+                blend.3 = kind;
+            }
+        } else if fk < jg {
+            let kind = if XBRZ_DOMINANT_THRESHOLD * fk < jg { BLEND_DOMINANT } else { BLEND_NORMAL };
+            if j != f && j != k {
+                blend.2 = kind;
+            }
+            if g != f && g != k {
+                blend.1 = kind;
+            }
+        }
+        blend
+    }
+
+    // Blend the bottom right corner of a block, the kernel and corner
+    // blend types being rotated so that it is the one to blend
+    fn blend_corner(&self, ker: &[Rgb; 9], corners: &[[u8; 2]; 2], rot: usize, out: &mut [Rgb]) {
+        let corner = |row: usize, col: usize| {
+            let (r, c) = rotate_times((row, col), 2, rot);
+            corners[r][c]
+        };
+        let px = |row: usize, col: usize| {
+            let (r, c) = rotate_times((row, col), 3, rot);
+            ker[r * 3 + c]
+        };
+        let blend = corner(1, 1);
+        if blend == BLEND_NONE {
+            return;
+        }
+        let (b, c, d, e, f, g, h, i) = (px(0, 1), px(0, 2), px(1, 0), px(1, 1), px(1, 2), px(2, 0), px(2, 1), px(2, 2));
+
+        let line_blend = if blend >= BLEND_DOMINANT {
+            true
+        } else if corner(0, 1) != BLEND_NONE && !xbrz_equal(e, g) {
+            // Leave pixels blended from an adjacent corner alone, except
+            // for 90 degree corners
+            false
+        } else if corner(1, 0) != BLEND_NONE && !xbrz_equal(e, c) {
+            false
+        } else {
+            // Only the corner of L shapes
+            !(!xbrz_equal(e, i) && xbrz_equal(g, h) && xbrz_equal(h, i) && xbrz_equal(i, f) && xbrz_equal(f, c))
+        };
+
+        let col = if xbrz_distance(e, f) <= xbrz_distance(e, h) { f } else { h };
+        let scale = self.scale;
+        let mut set = |row: usize, column: usize, num: u32, den: u32| {
+            let (r, c) = rotate_times((row, column), scale, rot);
+            let dst = &mut out[r * scale + c];
+            *dst = mix(*dst, col, num as f32 / den as f32);
+        };
+        let last = scale - 1;
+
+        if !line_blend {
+            match scale {
+                2 => set(1, 1, 21, 100),
+                3 => set(2, 2, 45, 100),
+                _ => {
+                    set(3, 3, 68, 100);
+                    set(3, 2, 9, 100);
+                    set(2, 3, 9, 100);
+                }
+            }
+            return;
+        }
+
+        let fg = xbrz_distance(f, g);
+        let hc = xbrz_distance(h, c);
+        let shallow = XBRZ_STEEP_THRESHOLD * fg <= hc && e != g && d != g;
+        let steep = XBRZ_STEEP_THRESHOLD * hc <= fg && e != c && b != c;
+        match (scale, shallow, steep) {
+            (2, true, true) => {
+                set(1, 0, 1, 4);
+                set(0, 1, 1, 4);
+                set(1, 1, 5, 6);
+            }
+            (2, true, false) => {
+                set(last, 0, 1, 4);
+                set(last, 1, 3, 4);
+            }
+            (2, false, true) => {
+                set(0, last, 1, 4);
+                set(1, last, 3, 4);
+            }
+            (2, false, false) => set(1, 1, 1, 2),
+            (3, true, true) => {
+                set(2, 0, 1, 4);
+                set(0, 2, 1, 4);
+                set(2, 1, 3, 4);
+                set(1, 2, 3, 4);
+                set(2, 2, 1, 1);
+            }
+            (3, true, false) => {
+                set(last, 0, 1, 4);
+                set(last - 1, 2, 1, 4);
+                set(last, 1, 3, 4);
+                set(last, 2, 1, 1);
+            }
+            (3, false, true) => {
+                set(0, last, 1, 4);
+                set(2, last - 1, 1, 4);
+                set(1, last, 3, 4);
+                set(2, last, 1, 1);
+            }
+            (3, false, false) => {
+                set(1, 2, 1, 8);
+                set(2, 1, 1, 8);
+                set(2, 2, 7, 8);
+            }
+            (_, true, true) => {
+                set(3, 1, 3, 4);
+                set(1, 3, 3, 4);
+                set(3, 0, 1, 4);
+                set(0, 3, 1, 4);
+                set(2, 2, 1, 3);
+                set(3, 3, 1, 1);
+                set(3, 2, 1, 1);
+                set(2, 3, 1, 1);
+            }
+            (_, true, false) => {
+                set(last, 0, 1, 4);
+                set(last - 1, 2, 1, 4);
+                set(last, 1, 3, 4);
+                set(last - 1, 3, 3, 4);
+                set(last, 2, 1, 1);
+                set(last, 3, 1, 1);
+            }
+            (_, false, true) => {
+                set(0, last, 1, 4);
+                set(2, last - 1, 1, 4);
+                set(1, last, 3, 4);
+                set(3, last - 1, 3, 4);
+                set(2, last, 1, 1);
+                set(3, last, 1, 1);
+            }
+            (_, false, false) => {
+                set(last, scale / 2, 1, 2);
+                set(last - 1, scale / 2 + 1, 1, 2);
+                set(last, last, 1, 1);
+            }
+        }
+    }
+}
+
+impl Filter for Xbrz {
+    fn apply(&self, image: &Image) -> Image {
+        let (width, height) = (image.width, image.height);
+        // Blend types of each pixel's corners, [row][column]
+        let mut corners = vec![[[BLEND_NONE; 2]; 2]; width * height];
+        for y in -1..height as isize {
+            for x in -1..width as isize {
+                let (f, g, j, k) = Xbrz::preprocess(image, x, y);
+                let mut set = |px: isize, py: isize, row: usize, col: usize, blend: u8| {
+                    if px >= 0 && py >= 0 && (px as usize) < width && (py as usize) < height {
+                        corners[py as usize * width + px as usize][row][col] = blend;
+                    }
+                };
+                set(x, y, 1, 1, f);
+                set(x + 1, y, 1, 0, g);
+                set(x, y + 1, 0, 1, j);
+                set(x + 1, y + 1, 0, 0, k);
+            }
+        }
+
+        scale_image(image, self.scale, |x, y, block| {
+            let ker = neighbourhood(image, x, y);
+            for pixel in block.iter_mut() {
+                *pixel = ker[4];
+            }
+            for rot in 0..4 {
+                self.blend_corner(&ker, &corners[y * width + x], rot, block);
+            }
+        })
+    }
+}
+
+// Scanlines, and optionally the vertical stripes of an aperture grille,
+// over an image scaled up by pixel repetition. The last row of each
+// pixel is darkened by scanline; mask darkens the two channels not
+// matching each column's stripe.
+struct Crt {
+    scale: usize,
+    scanline: f32,
+    mask: f32,
+}
+
+impl Filter for Crt {
+    fn apply(&self, image: &Image) -> Image {
+        let scale = self.scale;
+        scale_image(image, scale, |x, y, block| {
+            let (r, g, b) = image.pixel(x, y);
+            for (k, out) in block.iter_mut().enumerate() {
+                let mut level = [r as f32, g as f32, b as f32];
+                if k / scale == scale - 1 {
+                    for channel in level.iter_mut() {
+                        *channel *= 1.0 - self.scanline;
+                    }
+                }
+                if self.mask > 0.0 {
+                    let stripe = (x * scale + k % scale) % 3;
+                    for (channel, level) in level.iter_mut().enumerate() {
+                        if channel != stripe {
+                            *level *= 1.0 - self.mask;
+                        }
+                    }
+                }
+                *out = (level[0].round() as u8, level[1].round() as u8, level[2].round() as u8);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 3x3 image of colour around a centre pixel of centre
+    fn dot(colour: Rgb, centre: Rgb) -> Image {
+        let mut image = Image::new(3, 3);
+        for pixel in image.pixels.iter_mut() {
+            *pixel = colour;
+        }
+        image.pixels[4] = centre;
+        image
+    }
+
+    // The block an hqx filter scales the centre pixel of a 3x3 image to
+    fn centre_block(name: &str, image: &Image) -> Vec<Rgb> {
+        let scale = name.as_bytes()[2] as usize - b'0' as usize;
+        let out = create(name).unwrap().apply(image);
+        let mut block = Vec::new();
+        for y in scale..scale * 2 {
+            for x in scale..scale * 2 {
+                block.push(out.pixel(x, y));
+            }
+        }
+        block
+    }
+
+    #[test]
+    fn flat_images_stay_flat() {
+        let image = dot((90, 20, 200), (90, 20, 200));
+        for name in &["hq2x", "hq3x", "hq4x"] {
+            let out = create(name).unwrap().apply(&image);
+            assert!(out.pixels.iter().all(|&pixel| pixel == (90, 20, 200)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn a_lone_pixel_keeps_its_colour_but_for_the_corners() {
+        let image = dot((0, 0, 0), (255, 255, 255));
+        // hq2x's PIXEL00_100, (14 * w5 + w4 + w2) / 16
+        assert_eq!(centre_block("hq2x", &image), vec![(223, 223, 223); 4]);
+
+        // hq3x's PIXEL00_2, (2 * w5 + w4 + w2) / 4, in each corner
+        let block = centre_block("hq3x", &image);
+        for (i, &pixel) in block.iter().enumerate() {
+            let level = if i == 0 || i == 2 || i == 6 || i == 8 { 127 } else { 255 };
+            assert_eq!(pixel, (level, level, level), "{}", i);
+        }
+
+        let block = centre_block("hq4x", &image);
+        for (i, &pixel) in block.iter().enumerate() {
+            let level = if i == 0 || i == 3 || i == 12 || i == 15 { 127 } else { 255 };
+            assert_eq!(pixel, (level, level, level), "{}", i);
+        }
+    }
+
+    #[test]
+    fn colours_within_the_thresholds_blend_with_their_edge_neighbours() {
+        // Y differs by 40, under hqx's 0x30, so no neighbour counts as an
+        // edge and every corner takes PIXEL00_20, (2 * w5 + w4 + w2) / 4
+        let image = dot((0, 0, 0), (40, 40, 40));
+        assert_eq!(centre_block("hq2x", &image), vec![(20, 20, 20); 4]);
+    }
+
+    // image flipped over its main diagonal
+    fn transpose(image: &Image) -> Image {
+        let mut out = Image::new(image.height, image.width);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                out.pixels[x * out.width + y] = image.pixel(x, y);
+            }
+        }
+        out
+    }
+
+    // image turned a quarter clockwise
+    fn turn(image: &Image) -> Image {
+        let mut out = Image::new(image.height, image.width);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                out.pixels[x * out.width + image.height - 1 - y] = image.pixel(x, y);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn turning_or_flipping_the_image_does_the_same_to_the_output() {
+        // Every combination of neighbours differing turns up among the
+        // 3x3 neighbourhoods of enough random pixels in two colours
+        let mut image = Image::new(24, 24);
+        let mut seed = 1u32;
+        for pixel in image.pixels.iter_mut() {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            *pixel = match seed >> 29 {
+                0...3 => (0, 0, 0),
+                4...6 => (255, 255, 255),
+                _ => (250, 250, 250),
+            };
+        }
+        for name in &["hq2x", "hq3x", "hq4x"] {
+            let filter = create(name).unwrap();
+            let out = filter.apply(&image);
+            assert!(filter.apply(&turn(&image)).pixels == turn(&out).pixels, "{} turned", name);
+            assert!(filter.apply(&transpose(&image)).pixels == transpose(&out).pixels, "{} flipped", name);
+        }
+    }
+}
@@ -4,6 +4,12 @@ pub struct IoPort {
     dummy: u8
 }
 
+impl IoPort {
+    pub fn new() -> IoPort {
+        IoPort { dummy: 0 }
+    }
+}
+
 impl Mem for IoPort {
     fn loadb(&mut self, addr: u16) -> u8 {
         self.dummy
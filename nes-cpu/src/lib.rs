@@ -1,29 +1,408 @@
+extern crate flate2;
+
 pub mod cpu;
 pub mod mem;
 pub mod rom;
+pub mod fds;
+pub mod nsf;
+pub mod unif;
+pub mod romdb;
+pub mod patch;
+pub mod archive;
 pub mod util;
 pub mod ppu;
 pub mod apu;
 pub mod ioport;
-/*
+pub mod mapper;
+pub mod vs;
+pub mod video;
+pub mod palette;
+pub mod ntsc;
+pub mod filter;
+pub mod region;
+
+use fds::FdsImage;
+use mem::{Mem, MemoryMap};
+use palette::Palette;
+use region::Region;
+use rom::{Rom, RomError};
+use video::{Image, PixelFormat};
+
+// The console: the CPU with the rest of the system on its bus. The PPU, APU
+// and cartridge are run after each instruction for as long as the
+// instruction took.
 pub struct Nes {
-    cycle: u64,
-    cpu: cpu::NesCpu,
-    mem: mem::MemoryMap
+    pub cpu: cpu::NesCpu<MemoryMap>,
+    // Colours frames are shown in, the PPU's own for Vs. System games
+    palette: Palette,
 }
 
 impl Nes {
-    pub fn step(&mut self, count: u64) {
-        self.cycle += count;
-        self.cpu.step_to(self.cycle);
+    // Power on with the given bus, jumping through the reset vector
+    pub fn new(mem: MemoryMap) -> Nes {
+        let mut cpu = cpu::NesCpu::new(mem);
+        cpu.reset();
+        let cycles = cpu.clock();
+        cpu.mem.clock(cycles);
+        let palette = cpu.mem.vs()
+            .and_then(|vs| Palette::vs(vs.ppu))
+            .unwrap_or_else(|| Palette::builtin("ntsc").unwrap());
+        Nes {
+            cpu: cpu,
+            palette: palette,
+        }
+    }
+
+    // Power on with a game, in the region its header asks for unless
+    // overridden
+    pub fn from_rom(rom: Rom, region_override: Option<Region>) -> Result<Nes, RomError> {
+        Ok(Nes::new(try!(MemoryMap::from_rom(rom, region_override))))
+    }
+
+    // Power on a Famicom Disk System with the given disk and BIOS
+    pub fn from_fds(image: FdsImage, bios: Vec<u8>) -> Result<Nes, RomError> {
+        Ok(Nes::new(try!(MemoryMap::from_fds(image, bios))))
+    }
+
+    // Run a single instruction and the rest of the system alongside it,
+    // then service an NMI if the PPU raised one and bring /IRQ up to date
+    // for the next instruction. Returns the CPU cycles taken, including any
+    // the CPU was halted for by DMA.
+    pub fn step(&mut self) -> u64 {
+        let start = self.cpu.clock();
+        let mut cycles = self.cpu.step();
+        // The $4014 write is the last cycle of the instruction
+        let odd_cycle = (self.cpu.clock() - 1) % 2 == 1;
+        let dma = self.cpu.mem.take_dma_cycles(odd_cycle);
+        self.cpu.stall(dma);
+        cycles += dma;
+        self.cpu.mem.clock(cycles);
+        if self.cpu.mem.ppu_mut().take_nmi() {
+            let before = self.cpu.clock();
+            self.cpu.nmi();
+            let cycles = self.cpu.clock() - before;
+            self.cpu.mem.clock(cycles);
+        }
+        let irq = self.cpu.mem.irq_pending();
+        self.cpu.set_irq(irq);
+        self.cpu.clock() - start
+    }
+
+    // Run until the PPU finishes drawing a frame
+    pub fn step_frame(&mut self) {
+        while !self.cpu.mem.ppu_mut().take_frame_complete() {
+            self.step();
+        }
+    }
+
+    // Run until the CPU reaches the given cycle
+    pub fn step_to(&mut self, cycle: u64) {
+        while self.cpu.clock() < cycle {
+            self.step();
+        }
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    // Colour the last frame the PPU completed into the given format
+    pub fn convert_frame(&self, format: PixelFormat, out: &mut [u8]) {
+        video::convert(self.cpu.mem.ppu().frame(), self.palette.colors(), format, out);
+    }
+
+    pub fn frame_image(&self) -> Image {
+        Image::from_frame(self.cpu.mem.ppu().frame(), self.palette.colors())
+    }
+
+    pub fn ram_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu.mem.loadb(addr & 0x07FF)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use super::*;
+    use mapper::tests::rom;
+    use rom::{Console, VsHardware, VsPpu};
+    use fds;
+    use vs;
+
+    // A cartridge with the program at $E000 and the given interrupt handler
+    // at $F000, which both the NMI and IRQ vectors point at. Every board
+    // fixes the last 8KB of PRG ROM there at power on.
+    pub fn nes(mapper: u16, program: &[u8], handler: &[u8]) -> Nes {
+        let mut rom = rom(mapper, 0x4000);
+        rom.prg[0x2000..0x2000 + program.len()].copy_from_slice(program);
+        rom.prg[0x3000..0x3000 + handler.len()].copy_from_slice(handler);
+        rom.prg[0x3FFA..].copy_from_slice(&[0x00, 0xF0, 0x00, 0xE0, 0x00, 0xF0]);
+        Nes::from_rom(rom, None).unwrap()
+    }
+
+    #[test]
+    fn the_ppu_nmi_reaches_the_cpu_once_a_frame() {
+        // LDA #$80; STA $2000; JMP *
+        let program = [0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0xE0];
+        // INC $00; RTI
+        let mut nes = nes(0, &program, &[0xE6, 0x00, 0x40]);
+        for frame in 1..4 {
+            nes.step_frame();
+            // The handler runs once vblank starts, a line after the frame
+            nes.step_to(nes.cpu.clock() + 200);
+            assert_eq!(nes.ram_loadb(0), frame);
+        }
+    }
+
+    #[test]
+    fn the_apu_frame_irq_reaches_the_cpu() {
+        // CLI; JMP *
+        let program = [0x58, 0x4C, 0x01, 0xE0];
+        // INC $00; LDA $4015 to acknowledge; RTI
+        let mut nes = nes(0, &program, &[0xE6, 0x00, 0xAD, 0x15, 0x40, 0x40]);
+        nes.step_to(29000);
+        assert_eq!(nes.ram_loadb(0), 0);
+        nes.step_to(29829 * 3 + 100);
+        assert_eq!(nes.ram_loadb(0), 3);
+    }
+
+    #[test]
+    fn the_vrc7_irq_counts_scanlines_of_cpu_cycles() {
+        // LDA #$F0; STA $E010 for the latch; LDA #$02; STA $F000 to
+        // enable it in scanline mode; CLI; JMP *
+        let program = [0xA9, 0xF0, 0x8D, 0x10, 0xE0, 0xA9, 0x02, 0x8D, 0x00, 0xF0, 0x58, 0x4C, 0x0B, 0xE0];
+        // INC $00; STA $F010 to acknowledge; RTI
+        let mut nes = nes(85, &program, &[0xE6, 0x00, 0x8D, 0x10, 0xF0, 0x40]);
+        while nes.cpu.pc() != 0xE00A {
+            nes.step();
+        }
+        let armed = nes.cpu.clock();
+        while nes.cpu.pc() != 0xF000 {
+            nes.step();
+        }
+        // 16 clocks of the counter, each 341 / 3 CPU cycles
+        let cycles = nes.cpu.clock() - armed;
+        assert!(cycles >= 1819 && cycles <= 1830, "{}", cycles);
+        nes.step_to(armed + 20000);
+        assert_eq!(nes.ram_loadb(0), 1);
+    }
+
+    #[test]
+    fn the_mmc5_scanline_irq_reaches_the_cpu() {
+        let program = [
+            // Inhibit the APU frame IRQ
+            0xA9, 0x40, 0x8D, 0x17, 0x40,
+            // IRQ on line 16
+            0xA9, 0x10, 0x8D, 0x03, 0x52,
+            0xA9, 0x80, 0x8D, 0x04, 0x52,
+            // Render the background and sprites
+            0xA9, 0x18, 0x8D, 0x01, 0x20,
+            // CLI; JMP *
+            0x58, 0x4C, 0x15, 0xE0,
+        ];
+        // INC $00; LDA $5204 to acknowledge; RTI
+        let mut nes = nes(5, &program, &[0xE6, 0x00, 0xAD, 0x04, 0x52, 0x40]);
+        // Rendering starts partway into the first frame, so go by the next
+        nes.step_frame();
+        for _ in 0..2 {
+            let count = nes.ram_loadb(0);
+            while nes.ram_loadb(0) == count {
+                nes.step();
+            }
+            assert_eq!(nes.cpu.mem.ppu_mut().scanline(), 16);
+            // Once a frame
+            nes.step_frame();
+            assert_eq!(nes.ram_loadb(0), count + 1);
+        }
+    }
+
+    #[test]
+    fn the_fme7_cycle_irq_reaches_the_cpu() {
+        let program = [
+            // Counter = 1000, then IRQ and counting on
+            0xA9, 0x0E, 0x8D, 0x00, 0x80, 0xA9, 0xE8, 0x8D, 0x00, 0xA0,
+            0xA9, 0x0F, 0x8D, 0x00, 0x80, 0xA9, 0x03, 0x8D, 0x00, 0xA0,
+            0xA9, 0x0D, 0x8D, 0x00, 0x80, 0xA9, 0x81, 0x8D, 0x00, 0xA0,
+            // CLI; JMP *
+            0x58, 0x4C, 0x1F, 0xE0,
+        ];
+        // INC $00; acknowledge and stop the counter; RTI
+        let handler = [0xE6, 0x00, 0xA9, 0x0D, 0x8D, 0x00, 0x80, 0xA9, 0x00, 0x8D, 0x00, 0xA0, 0x40];
+        let mut nes = nes(69, &program, &handler);
+        // The APU frame IRQ is left on but comes much later
+        while nes.cpu.pc() != 0xE01E {
+            nes.step();
+        }
+        let armed = nes.cpu.clock();
+        while nes.cpu.pc() != 0xF000 {
+            nes.step();
+        }
+        // The counter underflows 1001 cycles on, and the CPU takes the IRQ
+        // after the JMP in progress, 7 cycles before reaching the handler
+        let cycles = nes.cpu.clock() - armed;
+        assert!(cycles >= 1001 && cycles <= 1001 + 3 + 7, "took {} cycles", cycles);
+        nes.step_to(armed + 20000);
+        assert_eq!(nes.ram_loadb(0), 1);
+    }
+
+    #[test]
+    fn the_n163_counter_irq_reaches_the_cpu() {
+        let program = [
+            // Counter $7C17 with IRQ on, 1000 cycles short of $7FFF
+            0xA9, 0x17, 0x8D, 0x00, 0x50, 0xA9, 0xFC, 0x8D, 0x00, 0x58,
+            // CLI; JMP *
+            0x58, 0x4C, 0x0B, 0xE0,
+        ];
+        // INC $00; acknowledge and disable; RTI
+        let handler = [0xE6, 0x00, 0xA9, 0x00, 0x8D, 0x00, 0x58, 0x40];
+        let mut nes = nes(19, &program, &handler);
+        while nes.cpu.pc() != 0xE00A {
+            nes.step();
+        }
+        let armed = nes.cpu.clock();
+        while nes.cpu.pc() != 0xF000 {
+            nes.step();
+        }
+        // The CPU takes the IRQ after the JMP in progress, 7 cycles before
+        // reaching the handler
+        let cycles = nes.cpu.clock() - armed;
+        assert!(cycles >= 1000 && cycles <= 1000 + 3 + 7, "took {} cycles", cycles);
+        nes.step_to(armed + 20000);
+        assert_eq!(nes.ram_loadb(0), 1);
+    }
+
+    #[test]
+    fn oam_dma_halts_the_cpu_for_one_more_cycle_when_odd() {
+        // STA $4014 at an even then an odd cycle, padded with a NOP
+        let program = [0x8D, 0x14, 0x40, 0xEA, 0x8D, 0x14, 0x40];
+        let mut nes = nes(0, &program, &[0x40]);
+        // Reset leaves the clock at 7, so the first store ends on cycle 10
+        assert_eq!(nes.step(), 4 + 513);
+        assert_eq!(nes.step(), 2);
+        assert_eq!(nes.step(), 4 + 514);
+    }
+
+    #[test]
+    fn oam_dma_copies_the_page() {
+        // LDA #$5A; STA $0203; LDA #$02; STA $4014
+        let program = [0xA9, 0x5A, 0x8D, 0x03, 0x02, 0xA9, 0x02, 0x8D, 0x14, 0x40];
+        let mut nes = nes(0, &program, &[0x40]);
+        for _ in 0..4 {
+            nes.step();
+        }
+        let ppu = nes.cpu.mem.ppu_mut();
+        ppu.storeb(3, 3);
+        assert_eq!(ppu.loadb(4), 0x5A);
+    }
+
+    #[test]
+    fn pal_games_run_the_ppu_at_pal_speed() {
+        let mut rom = rom(0, 0x4000);
+        rom.prg[..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+        rom.prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        rom.header.timing = rom::Timing::Pal;
+        let mut nes = Nes::from_rom(rom, None).unwrap();
+        nes.step_to(10000);
+        let clock = nes.cpu.clock();
+        let ppu = nes.cpu.mem.ppu_mut();
+        let dots = ppu.scanline() as u64 * 341 + ppu.dot() as u64;
+        assert_eq!(dots, clock * 16 / 5);
+    }
+
+    #[test]
+    fn the_ppu_keeps_up_with_the_cpu() {
+        let mut nes = nes(0, &[0x4C, 0x00, 0xE0], &[0x40]);
+        nes.step_to(10000);
+        let clock = nes.cpu.clock();
+        let ppu = nes.cpu.mem.ppu_mut();
+        let dots = ppu.scanline() as u64 * 341 + ppu.dot() as u64;
+        assert_eq!(dots, clock * 3);
+    }
+
+    #[test]
+    fn vs_system_games_get_their_ppu() {
+        let mut rom = rom(0, 0x4000);
+        rom.header.console = Console::VsSystem(VsPpu::Rc2c05_02, VsHardware::Unisystem);
+        // JMP *
+        rom.prg[0x2000..0x2003].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+        rom.prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xE0]);
+        let mut nes = Nes::from_rom(rom, None).unwrap();
+        assert_eq!(nes.palette(), &Palette::vs(VsPpu::Rc2c05_02).unwrap());
+
+        // $2000 is PPUMASK on the 2C05, so this doesn't enable NMI
+        nes.cpu.mem.storeb(0x2000, 0x80);
+        nes.cpu.mem.storeb(0x2001, 0x00);
+        nes.step_to(29781 * 2);
+        assert!(!nes.cpu.mem.ppu_mut().take_nmi());
+        assert_eq!(nes.cpu.mem.loadb(0x2002) & 0x3F, 0x3D);
+
+        // Frames are coloured with the RGB palette
+        let pixel = nes.frame_image().pixel(0, 0);
+        assert_eq!(pixel, vs::rgb888(0o333));
+        let mut out = vec![0; video::frame_size(PixelFormat::Rgba8888)];
+        nes.convert_frame(PixelFormat::Rgba8888, &mut out);
+        assert_eq!((out[0], out[1], out[2]), pixel);
+
+        // The coin switch opens again as the cabinet is clocked
+        nes.cpu.mem.vs_mut().unwrap().insert_coin(0);
+        assert_eq!(nes.cpu.mem.loadb(0x4016) & 0x20, 0x20);
+        nes.step_to(29781 * 8);
+        assert_eq!(nes.cpu.mem.loadb(0x4016) & 0x20, 0x00);
+    }
+
+    // A stand-in for disksys.rom: the given program at $E000, which the
+    // reset vector points at
+    fn bios(program: &[u8]) -> Vec<u8> {
+        let mut bios = vec![0; fds::BIOS_SIZE];
+        bios[..program.len()].copy_from_slice(program);
+        bios[0x1FFA..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+        bios
+    }
+
+    #[test]
+    fn a_disk_boots_and_is_read_through_the_drive() {
+        let program = [
+            // Enable disk I/O, then start the motor reading
+            0xA9, 0x01, 0x8D, 0x23, 0x40,
+            0xA9, 0x45, 0x8D, 0x25, 0x40,
+            // Wait for a byte and store it at $00, then the next at $01
+            0xAD, 0x30, 0x40, 0x29, 0x02, 0xF0, 0xF9,
+            0xAD, 0x31, 0x40, 0x85, 0x00,
+            0xAD, 0x30, 0x40, 0x29, 0x02, 0xF0, 0xF9,
+            0xAD, 0x31, 0x40, 0x85, 0x01,
+            // Leave a mark in the adapter's RAM; JMP *
+            0xA9, 0x5A, 0x8D, 0x00, 0x60, 0x4C, 0x27, 0xE0,
+        ];
+        let image = FdsImage { sides: vec![fds::tests::side(&[1, 2, 3])] };
+        let mut nes = Nes::from_fds(image, bios(&program)).unwrap();
+        // The head returns to the start, then crosses the lead-in gap
+        nes.step_to(1000000);
+        assert_eq!(nes.cpu.pc(), 0xE027);
+        // The disk info block starts with its block code and "*NINTENDO-HVC*"
+        assert_eq!((nes.ram_loadb(0), nes.ram_loadb(1)), (0x01, b'*'));
+        assert_eq!(nes.cpu.mem.loadb(0x6000), 0x5A);
+    }
+
+    #[test]
+    fn a_disk_needs_a_whole_bios_and_a_side() {
+        let image = || FdsImage { sides: vec![fds::tests::side(&[1, 2, 3])] };
+        match Nes::from_fds(image(), vec![0; 0x1000]) {
+            Err(RomError::FormatError) => {}
+            _ => panic!("accepted a 4KB BIOS"),
+        }
+        match Nes::from_fds(FdsImage { sides: Vec::new() }, bios(&[])) {
+            Err(RomError::FormatError) => {}
+            _ => panic!("accepted an image without sides"),
+        }
+        assert!(Nes::from_fds(image(), bios(&[0x4C, 0x00, 0xE0])).is_ok());
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn other_games_get_the_ntsc_palette() {
+        let nes = nes(0, &[0x4C, 0x00, 0xE0], &[0x40]);
+        assert_eq!(nes.palette(), &Palette::builtin("ntsc").unwrap());
     }
 }
-*/
\ No newline at end of file
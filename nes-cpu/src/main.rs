@@ -1,10 +1,25 @@
+extern crate flate2;
+
 mod rom;
+mod fds;
+mod nsf;
+mod unif;
+mod romdb;
+mod patch;
+mod archive;
 mod util;
 mod mem;
 mod cpu;
 mod apu;
 mod ppu;
 mod ioport;
+mod mapper;
+mod vs;
+mod video;
+mod palette;
+mod ntsc;
+mod filter;
+mod region;
 
 use std::io::{self, BufReader};
 use std::io::prelude::*;
@@ -0,0 +1,462 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use fds::{self, FdsImage};
+use mapper::{self, Mapper, Mirroring};
+use mapper::fds_audio::FdsAudio;
+use patch;
+use rom::RomError;
+
+// The disk moves under the head at about 96.4kbit/s, one byte
+// every 150 CPU cycles
+const CPU_CYCLES_PER_BYTE: u32 = 150;
+// Time for the head to return to the start of the disk and spin up
+const HEAD_RETURN_CYCLES: u32 = 50000;
+// How long swap_side leaves the drive empty so that the BIOS notices
+const SWAP_CYCLES: u32 = 1789773;
+
+// Famicom Disk System RAM adapter and disk drive
+//
+// Register  Function
+// $4020     Timer IRQ reload low byte
+// $4021     Timer IRQ reload high byte
+// $4022     ---- --ER  Timer IRQ enable (E), repeat (R)
+// $4023     ---- --SD  Sound register enable (S), disk register enable (D)
+// $4024     Write data
+// $4025     IS1C MRTm  Disk IRQ enable (I), start transfer (S), CRC control (C),
+//                      mirroring (M), read mode (R), reset transfer (T), motor (m)
+// $4030     Disk status (read): end of head (bit 6), CRC error (bit 4),
+//           byte transferred (bit 1), timer IRQ (bit 0)
+// $4031     Read data
+// $4032     Drive status: write protected (bit 2), not ready (bit 1),
+//           no disk (bit 0)
+// $4033     External connector, bit 7 set for good battery
+// $4040-    Sound, see FdsAudio
+//
+// $6000-$DFFF is 32KB of RAM and $E000-$FFFF is the BIOS. Pattern
+// tables are 8KB of CHR RAM.
+pub struct Fds {
+    bios: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+
+    // Image as loaded, and the disk surfaces with writes applied
+    image: FdsImage,
+    sides: Vec<Vec<u8>>,
+    inserted: Option<usize>,
+    // Side to insert once a swap delay runs out
+    pending_insert: Option<usize>,
+    insert_delay: u32,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_enabled: bool,
+    timer_repeat: bool,
+    timer_irq: bool,
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+
+    control: u8,
+    read_data: u8,
+    write_data: u8,
+    byte_transferred: bool,
+    disk_irq: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+    crc: u16,
+
+    audio: FdsAudio,
+}
+
+impl Fds {
+    pub fn new(bios: Vec<u8>, image: FdsImage) -> Fds {
+        let sides: Vec<Vec<u8>> = (0..image.sides.len()).map(|side| image.surface(side)).collect();
+        let inserted = if sides.is_empty() { None } else { Some(0) };
+        Fds {
+            bios: bios,
+            prg_ram: vec![0u8; 0x8000],
+            chr_ram: vec![0u8; 0x2000],
+
+            image: image,
+            sides: sides,
+            inserted: inserted,
+            pending_insert: None,
+            insert_delay: 0,
+
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+
+            control: 0,
+            read_data: 0,
+            write_data: 0,
+            byte_transferred: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            crc: 0,
+
+            audio: FdsAudio::new(),
+        }
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.sides.len()
+    }
+
+    // Currently inserted side, if any
+    pub fn inserted_side(&self) -> Option<usize> {
+        self.inserted
+    }
+
+    pub fn eject(&mut self) {
+        self.inserted = None;
+        self.pending_insert = None;
+    }
+
+    pub fn insert(&mut self, side: usize) {
+        if side < self.sides.len() {
+            self.inserted = Some(side);
+            self.pending_insert = None;
+            self.end_of_head = true;
+        }
+    }
+
+    // Flip the inserted disk over, leaving the drive empty for a while
+    // so that games waiting for an eject see one
+    pub fn swap_side(&mut self) {
+        if let Some(side) = self.inserted {
+            let other = side ^ 1;
+            if other < self.sides.len() {
+                self.eject();
+                self.pending_insert = Some(other);
+                self.insert_delay = SWAP_CYCLES;
+            }
+        }
+    }
+
+    // Disk sides with the writes made so far, in .fds layout
+    pub fn written_image(&self) -> FdsImage {
+        self.image.from_surfaces(&self.sides)
+    }
+
+    // Write the changes made to the disks as an IPS patch against the
+    // image they were loaded from
+    pub fn save_writes(&self, w: &mut Write) -> io::Result<()> {
+        patch::write_ips(&self.image.to_bytes(), &self.written_image().to_bytes(), w)
+    }
+
+    // Restore writes saved by save_writes, replacing any made since loading
+    pub fn load_writes(&mut self, r: &mut Read) -> Result<(), RomError> {
+        let mut data = Vec::new();
+        try!(r.read_to_end(&mut data));
+        let original = self.image.to_bytes();
+        let written = try!(patch::apply(&data, &original));
+        if written.len() != original.len() {
+            return Err(RomError::FormatError);
+        }
+        let image = FdsImage { sides: written.chunks(fds::SIDE_SIZE).map(|side| side.to_vec()).collect() };
+        self.sides = (0..image.sides.len()).map(|side| image.surface(side)).collect();
+        Ok(())
+    }
+
+    // Save writes next to the disk image at fds::diff_path, leaving the
+    // image itself untouched
+    pub fn save_writes_to(&self, image_path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(try!(File::create(fds::diff_path(image_path))));
+        try!(self.save_writes(&mut w));
+        w.flush()
+    }
+
+    // Restore writes saved by save_writes_to, if there are any
+    pub fn load_writes_from(&mut self, image_path: &Path) -> Result<(), RomError> {
+        let file = match File::open(fds::diff_path(image_path)) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(RomError::from(err)),
+        };
+        self.load_writes(&mut BufReader::new(file))
+    }
+
+    fn motor_on(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+
+    fn reset_transfer(&self) -> bool {
+        self.control & 0x02 != 0
+    }
+
+    fn read_mode(&self) -> bool {
+        self.control & 0x04 != 0
+    }
+
+    fn crc_control(&self) -> bool {
+        self.control & 0x10 != 0
+    }
+
+    fn transfer_started(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+
+    fn disk_irq_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_io_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let side = match self.inserted {
+            Some(side) if self.motor_on() => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer() && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let irq = self.disk_irq_enabled();
+        if self.read_mode() {
+            let byte = self.sides[side][self.position];
+            if !self.transfer_started() {
+                self.gap_ended = false;
+            } else if byte != 0 && !self.gap_ended {
+                // The gap end mark itself is not transferred
+                self.gap_ended = true;
+                self.crc = 0;
+            } else if self.gap_ended {
+                self.crc = fds::crc16(self.crc, byte);
+                self.read_data = byte;
+                self.byte_transferred = true;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let byte = if self.crc_control() {
+                // Shift out the CRC accumulated over the block
+                let byte = self.crc as u8;
+                self.crc >>= 8;
+                self.gap_ended = false;
+                byte
+            } else {
+                self.byte_transferred = true;
+                self.disk_irq |= irq;
+                let byte = if self.transfer_started() { self.write_data } else { 0 };
+                if self.gap_ended {
+                    self.crc = fds::crc16(self.crc, byte);
+                } else if byte != 0 {
+                    self.gap_ended = true;
+                    self.crc = 0;
+                }
+                byte
+            };
+            self.sides[side][self.position] = byte;
+        }
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            // Stop at the end of the disk until the motor is restarted
+            self.control &= !0x01;
+            self.end_of_head = true;
+        } else {
+            self.delay = CPU_CYCLES_PER_BYTE;
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.disk_io_enabled => {
+                let val = (self.timer_irq as u8)
+                    | (self.byte_transferred as u8) << 1
+                    | (self.end_of_head as u8) << 6;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                val
+            }
+            0x4031 if self.disk_io_enabled => {
+                self.byte_transferred = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            0x4032 if self.disk_io_enabled => {
+                let empty = self.inserted.is_none();
+                (empty as u8) | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2
+            }
+            0x4033 if self.disk_io_enabled => 0x80,
+            0x4040...0x4097 if self.sound_io_enabled => self.audio.loadb(addr),
+            0x6000...0xDFFF => self.prg_ram[addr as usize - 0x6000],
+            0xE000...0xFFFF => self.bios[addr as usize - 0xE000],
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4023 => {
+                self.disk_io_enabled = val & 0x01 != 0;
+                self.sound_io_enabled = val & 0x02 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            }
+            0x4020...0x4026 if !self.disk_io_enabled => {}
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | val as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (val as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = val & 0x01 != 0;
+                self.timer_enabled = val & 0x02 != 0;
+                self.timer_irq = false;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                }
+            }
+            0x4024 => {
+                self.write_data = val;
+                self.byte_transferred = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.control = val;
+                self.disk_irq = false;
+            }
+            0x4040...0x4097 => {
+                if self.sound_io_enabled {
+                    self.audio.storeb(addr, val);
+                }
+            }
+            0x6000...0xDFFF => self.prg_ram[addr as usize - 0x6000] = val,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        self.chr_ram[addr as usize & 0x1FFF]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        self.chr_ram[addr as usize & 0x1FFF] = val;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.control & 0x08 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn step(&mut self) {
+        if let Some(side) = self.pending_insert {
+            self.insert_delay -= 1;
+            if self.insert_delay == 0 {
+                self.insert(side);
+            }
+        }
+
+        self.clock_timer();
+        self.clock_drive();
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * mapper::FDS_GAIN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::env;
+    use fds::tests::side;
+
+    fn image() -> FdsImage {
+        FdsImage { sides: vec![side(&[1, 2, 3]), side(&[4, 5])] }
+    }
+
+    // Write a byte of the first file on the second side, as the drive would
+    fn write_file(disk: &mut Fds) {
+        let mut written = image();
+        written.sides[1][56 + 2 + 16 + 2] = 0x77;
+        disk.sides[1] = written.surface(1);
+    }
+
+    #[test]
+    fn writes_are_saved_as_an_ips_patch_of_the_image() {
+        let mut disk = Fds::new(vec![0; fds::BIOS_SIZE], image());
+        write_file(&mut disk);
+        let mut diff = Vec::new();
+        disk.save_writes(&mut diff).unwrap();
+        let written = disk.written_image().to_bytes();
+        assert_eq!(patch::apply(&diff, &image().to_bytes()).unwrap(), written);
+
+        let mut reloaded = Fds::new(vec![0; fds::BIOS_SIZE], image());
+        reloaded.load_writes(&mut &diff[..]).unwrap();
+        assert_eq!(reloaded.written_image().to_bytes(), written);
+        assert_eq!(reloaded.sides, disk.sides);
+    }
+
+    #[test]
+    fn writes_persist_next_to_the_image() {
+        let dir = env::temp_dir().join(format!("fds-writes-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let image_path = dir.join("game.fds");
+
+        // Nothing saved yet
+        let mut disk = Fds::new(vec![0; fds::BIOS_SIZE], image());
+        disk.load_writes_from(&image_path).unwrap();
+        assert_eq!(disk.written_image().to_bytes(), image().to_bytes());
+
+        write_file(&mut disk);
+        disk.save_writes_to(&image_path).unwrap();
+        assert!(fds::diff_path(&image_path).exists());
+
+        let mut reloaded = Fds::new(vec![0; fds::BIOS_SIZE], image());
+        reloaded.load_writes_from(&image_path).unwrap();
+        assert_eq!(reloaded.written_image().to_bytes(), disk.written_image().to_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
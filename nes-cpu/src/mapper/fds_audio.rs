@@ -0,0 +1,438 @@
+// Change to the modulation counter for each 3-bit modulation table entry.
+// None resets the counter to zero.
+const MOD_ADJUST: [Option<i8>; 8] = [
+    Some(0), Some(1), Some(2), Some(4), None, Some(-4), Some(-2), Some(-1),
+];
+
+// Output multiplier for each master volume setting, 2/2 down to 2/5
+const MASTER_VOLUME: [f32; 4] = [2.0 / 2.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+#[derive(Clone, Copy)]
+struct Envelope {
+    speed: u8,
+    gain: u8,
+    increase: bool,
+    disabled: bool,
+    counter: u32,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            speed: 0,
+            gain: 0,
+            increase: false,
+            disabled: true,
+            counter: 0,
+        }
+    }
+
+    // $4080 / $4084: MDSS SSSS  Disable (M), increase (D), speed or gain (S)
+    fn storeb(&mut self, val: u8) {
+        self.disabled = val & 0x80 != 0;
+        self.increase = val & 0x40 != 0;
+        self.speed = val & 0x3F;
+        self.counter = 0;
+        if self.disabled {
+            self.gain = val & 0x3F;
+        }
+    }
+
+    // Clocked every CPU cycle; ticks every 8 * (speed + 1) * master cycles
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled || master_speed == 0 {
+            return;
+        }
+        self.counter += 1;
+        if self.counter >= 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            self.counter = 0;
+            if self.increase && self.gain < 32 {
+                self.gain += 1;
+            } else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+        }
+    }
+}
+
+// Wavetable channel of the FDS RAM adapter, with frequency modulation.
+//
+// $4040-$407F  64 entry, 6-bit wavetable
+// $4080        Volume envelope
+// $4082-$4083  Wave frequency, 12 bits; $4083 bit 7 halts the wave,
+//              bit 6 halts both envelopes
+// $4084        Modulation envelope
+// $4085        Modulation counter, 7-bit signed
+// $4086-$4087  Modulation frequency, 12 bits; $4087 bit 7 halts modulation
+// $4088        Append an entry to the 32 entry modulation table
+// $4089        W--- --VV  Wavetable write enable (W), master volume (V)
+// $408A        Envelope speed multiplier
+// $4090        Volume gain (read)
+// $4092        Modulation gain (read)
+pub struct FdsAudio {
+    wave: [u8; 64],
+    // Each write fills two consecutive entries
+    mod_table: [u8; 64],
+    mod_write_pos: usize,
+
+    volume: Envelope,
+    modulation: Envelope,
+    master_speed: u8,
+    master_volume: u8,
+    envelopes_halted: bool,
+
+    wave_freq: u16,
+    wave_halted: bool,
+    wave_writable: bool,
+    wave_acc: u32,
+
+    mod_freq: u16,
+    mod_halted: bool,
+    mod_acc: u32,
+    mod_pos: usize,
+    mod_counter: i8,
+
+    // Volume gain is latched when the wave wraps around
+    output_gain: u8,
+}
+
+impl FdsAudio {
+    pub fn new() -> FdsAudio {
+        FdsAudio {
+            wave: [0; 64],
+            mod_table: [0; 64],
+            mod_write_pos: 0,
+
+            volume: Envelope::new(),
+            modulation: Envelope::new(),
+            master_speed: 0xE8,
+            master_volume: 0,
+            envelopes_halted: false,
+
+            wave_freq: 0,
+            wave_halted: true,
+            wave_writable: false,
+            wave_acc: 0,
+
+            mod_freq: 0,
+            mod_halted: true,
+            mod_acc: 0,
+            mod_pos: 0,
+            mod_counter: 0,
+
+            output_gain: 0,
+        }
+    }
+
+    pub fn loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4040...0x407F => {
+                if self.wave_writable {
+                    self.wave[(addr - 0x4040) as usize]
+                } else {
+                    self.wave[self.wave_position()]
+                }
+            }
+            0x4090 => self.volume.gain,
+            0x4092 => self.modulation.gain,
+            _ => 0,
+        }
+    }
+
+    pub fn storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040...0x407F => {
+                if self.wave_writable {
+                    self.wave[(addr - 0x4040) as usize] = val & 0x3F;
+                }
+            }
+            0x4080 => self.volume.storeb(val),
+            0x4082 => self.wave_freq = (self.wave_freq & 0x0F00) | val as u16,
+            0x4083 => {
+                self.wave_freq = (self.wave_freq & 0x00FF) | (val as u16 & 0x0F) << 8;
+                self.wave_halted = val & 0x80 != 0;
+                self.envelopes_halted = val & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_acc = 0;
+                }
+            }
+            0x4084 => self.modulation.storeb(val),
+            0x4085 => {
+                // Sign extend the 7-bit counter
+                self.mod_counter = ((val & 0x7F) << 1) as i8 >> 1;
+            }
+            0x4086 => self.mod_freq = (self.mod_freq & 0x0F00) | val as u16,
+            0x4087 => {
+                self.mod_freq = (self.mod_freq & 0x00FF) | (val as u16 & 0x0F) << 8;
+                self.mod_halted = val & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_acc = 0;
+                }
+            }
+            0x4088 => {
+                // The table can only be written while modulation is halted
+                if self.mod_halted {
+                    self.mod_table[self.mod_write_pos] = val & 0x07;
+                    self.mod_table[self.mod_write_pos + 1] = val & 0x07;
+                    self.mod_write_pos = (self.mod_write_pos + 2) & 0x3F;
+                }
+            }
+            0x4089 => {
+                self.wave_writable = val & 0x80 != 0;
+                self.master_volume = val & 0x03;
+            }
+            0x408A => self.master_speed = val,
+            _ => {}
+        }
+    }
+
+    fn wave_position(&self) -> usize {
+        ((self.wave_acc >> 16) & 0x3F) as usize
+    }
+
+    // Wave frequency after applying the modulator
+    fn modulated_pitch(&self) -> u32 {
+        let pitch = self.wave_freq as i32;
+        let mut temp = self.mod_counter as i32 * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        ::std::cmp::max(0, pitch + temp) as u32
+    }
+
+    fn clock_modulator(&mut self) {
+        if self.mod_halted {
+            return;
+        }
+        self.mod_acc += self.mod_freq as u32;
+        if self.mod_acc >= 0x10000 {
+            self.mod_acc -= 0x10000;
+            let entry = self.mod_table[self.mod_pos];
+            self.mod_pos = (self.mod_pos + 1) & 0x3F;
+            self.mod_counter = match MOD_ADJUST[entry as usize] {
+                // Wrap within the 7-bit range
+                Some(adjust) => (((self.mod_counter as i16 + adjust as i16) << 9) >> 9) as i8,
+                None => 0,
+            };
+        }
+    }
+
+    // Advance by one CPU cycle
+    pub fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted {
+            let master = self.master_speed;
+            self.volume.clock(master);
+            self.modulation.clock(master);
+        }
+
+        self.clock_modulator();
+
+        // The wave holds its position while the table is writable
+        if !self.wave_halted && !self.wave_writable {
+            let before = self.wave_position();
+            self.wave_acc = (self.wave_acc + self.modulated_pitch()) & 0x3F_FFFF;
+            if self.wave_position() < before {
+                self.output_gain = ::std::cmp::min(self.volume.gain, 32);
+            }
+        }
+    }
+
+    // Current sample, 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        let level = self.wave[self.wave_position()] as f32 * self.output_gain as f32;
+        level / (63.0 * 32.0) * MASTER_VOLUME[self.master_volume as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Half the table at full level and half silent, played at full volume
+    fn square(freq: u16) -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.storeb(0x4089, 0x80);
+        for i in 0..64 {
+            audio.storeb(0x4040 + i, if i < 32 { 0x3F } else { 0 });
+        }
+        audio.storeb(0x4089, 0x00);
+        audio.storeb(0x4080, 0x80 | 32);
+        audio.storeb(0x4082, freq as u8);
+        audio.storeb(0x4083, (freq >> 8) as u8);
+        audio
+    }
+
+    // CPU cycles between changes of the output, once the wave has wrapped
+    // around to latch the volume
+    fn toggles(audio: &mut FdsAudio, count: usize) -> Vec<usize> {
+        while audio.output() == 0.0 {
+            audio.clock();
+        }
+        let mut out = Vec::new();
+        let mut last = audio.output();
+        let mut since = 0;
+        while out.len() < count {
+            audio.clock();
+            since += 1;
+            if audio.output() != last {
+                out.push(since);
+                since = 0;
+                last = audio.output();
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn the_wavetable_is_only_written_while_enabled() {
+        let mut audio = FdsAudio::new();
+        audio.storeb(0x4045, 0x12);
+        audio.storeb(0x4089, 0x80);
+        assert_eq!(audio.loadb(0x4045), 0x00);
+        // Entries are 6 bits
+        audio.storeb(0x4045, 0xFF);
+        assert_eq!(audio.loadb(0x4045), 0x3F);
+        audio.storeb(0x4089, 0x00);
+        audio.storeb(0x4046, 0x12);
+        audio.storeb(0x4089, 0x80);
+        assert_eq!(audio.loadb(0x4046), 0x00);
+    }
+
+    #[test]
+    fn the_wave_steps_through_64_entries_at_its_frequency() {
+        // Each cycle adds the frequency to a 16.6 bit position
+        let mut audio = square(0x400);
+        assert_eq!(toggles(&mut audio, 4), vec![2048, 2048, 2048, 2048]);
+        let mut audio = square(0x100);
+        assert_eq!(toggles(&mut audio, 2), vec![8192, 8192]);
+    }
+
+    #[test]
+    fn the_wave_holds_while_halted_or_writable() {
+        let mut audio = square(0x400);
+        toggles(&mut audio, 1);
+        let level = audio.output();
+        audio.storeb(0x4089, 0x80);
+        for _ in 0..10000 {
+            audio.clock();
+        }
+        assert_eq!(audio.output(), level);
+
+        // Halting resets the wave to its first entry
+        audio.storeb(0x4089, 0x00);
+        audio.storeb(0x4083, 0x84);
+        assert_eq!(audio.wave_position(), 0);
+    }
+
+    #[test]
+    fn master_volume_scales_the_output() {
+        let mut audio = square(0x400);
+        toggles(&mut audio, 0);
+        assert_eq!(audio.output(), 1.0);
+        for &(setting, scale) in [(1, 2.0 / 3.0), (2, 2.0 / 4.0), (3, 2.0 / 5.0)].iter() {
+            audio.storeb(0x4089, setting);
+            assert_eq!(audio.output(), scale);
+        }
+    }
+
+    #[test]
+    fn the_volume_envelope_ramps_every_8_speed_master_cycles() {
+        let mut audio = square(0x400);
+        // Increase at speed 0, from gain 0. Only a disabled envelope
+        // sets the gain directly.
+        audio.storeb(0x4080, 0x80);
+        audio.storeb(0x4080, 0x40);
+        assert_eq!(audio.loadb(0x4090), 0);
+        for gain in 1..4 {
+            for _ in 0..8 * 0xE8 {
+                audio.clock();
+            }
+            assert_eq!(audio.loadb(0x4090), gain);
+        }
+    }
+
+    fn modulator(entry: u8, freq: u16) -> FdsAudio {
+        let mut audio = FdsAudio::new();
+        audio.storeb(0x4087, 0x80);
+        for _ in 0..32 {
+            audio.storeb(0x4088, entry);
+        }
+        audio.storeb(0x4086, freq as u8);
+        audio.storeb(0x4087, (freq >> 8) as u8);
+        audio
+    }
+
+    #[test]
+    fn the_modulation_table_steps_the_counter() {
+        // An entry every 32 cycles, each adding 1
+        let mut audio = modulator(1, 0x800);
+        for step in 1..5 {
+            for _ in 0..32 {
+                audio.clock();
+            }
+            assert_eq!(audio.mod_counter, step);
+        }
+
+        // The counter wraps within 7 bits
+        audio.storeb(0x4085, 0x3F);
+        for _ in 0..32 {
+            audio.clock();
+        }
+        assert_eq!(audio.mod_counter, -64);
+
+        // Entry 4 resets it, and the table can't be written while running
+        let mut audio = modulator(4, 0x800);
+        audio.storeb(0x4088, 1);
+        audio.storeb(0x4085, 0x20);
+        for _ in 0..32 {
+            audio.clock();
+        }
+        assert_eq!(audio.mod_counter, 0);
+    }
+
+    #[test]
+    fn each_table_write_fills_two_entries() {
+        let mut audio = modulator(0, 0x800);
+        audio.storeb(0x4087, 0x80);
+        audio.storeb(0x4088, 0x03);
+        audio.storeb(0x4088, 0x07);
+        audio.storeb(0x4087, 0x08);
+        // +4 twice then -1 twice
+        let mut counters = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..32 {
+                audio.clock();
+            }
+            counters.push(audio.mod_counter);
+        }
+        assert_eq!(counters, vec![4, 8, 7, 6]);
+    }
+
+    #[test]
+    fn the_modulator_bends_the_wave_pitch() {
+        let mut audio = square(0x400);
+        audio.storeb(0x4084, 0x80 | 16);
+        // Counter 16 at gain 16 adds a quarter to the frequency
+        audio.storeb(0x4085, 0x10);
+        assert_eq!(audio.modulated_pitch(), 0x500);
+        audio.storeb(0x4085, 0x70);
+        assert_eq!(audio.modulated_pitch(), 0x300);
+        // The counter holds while modulation is halted, so the wave plays
+        // three quarters as fast
+        assert_eq!(toggles(&mut audio, 3).iter().sum::<usize>(), 3 * 2048 * 4 / 3);
+    }
+}
@@ -0,0 +1,174 @@
+use mapper::{self, Mapper, Mirroring};
+use mapper::sunsoft5b::Sunsoft5b;
+use rom::Rom;
+
+// Mapper 69: Sunsoft FME-7, and the 5A/5B which add audio
+//
+// Register     Function
+// $8000-$9FFF  Command select
+// $A000-$BFFF  Command parameter
+// $C000-$DFFF  5B audio register select
+// $E000-$FFFF  5B audio register data
+//
+// Command  Function
+// $0-$7    1KB CHR banks
+// $8       ERBB BBBB  $6000 bank: RAM enable (E), RAM over ROM (R), bank (B)
+// $9-$B    8KB PRG banks at $8000, $A000 and $C000
+// $C       Mirroring: vertical, horizontal, single screen lower/upper
+// $D       C--- ---I  IRQ counter enable (C), IRQ enable (I), acknowledges IRQ
+// $E-$F    IRQ counter low and high byte
+//
+// $E000-$FFFF is fixed to the last 8KB PRG bank.
+pub struct Fme7 {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000, $8000, $A000 and $C000
+    prg_banks: [u8; 4],
+    mirroring: u8,
+    irq_enabled: bool,
+    irq_counter_enabled: bool,
+    irq_counter: u16,
+    irq_pending: bool,
+    audio: Sunsoft5b,
+    audio_select: u8,
+}
+
+impl Fme7 {
+    pub fn new(rom: Rom) -> Fme7 {
+        let chr_is_ram = rom.chr.is_empty();
+        let prg_ram = rom.prg_ram(0x2000);
+        Fme7 {
+            prg: rom.prg,
+            prg_ram: prg_ram,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: 0,
+            irq_enabled: false,
+            irq_counter_enabled: false,
+            irq_counter: 0,
+            irq_pending: false,
+            audio: Sunsoft5b::new(),
+            audio_select: 0,
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7] as usize;
+        mapper::bank_offset(bank, 0x400, self.chr.len()) + (addr as usize & 0x3FF)
+    }
+
+    fn prg_ram_selected(&self) -> bool {
+        self.prg_banks[0] & 0x40 != 0
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_banks[0] & 0xC0 == 0xC0
+    }
+
+    fn write_parameter(&mut self, val: u8) {
+        match self.command {
+            0x0...0x7 => self.chr_banks[self.command as usize] = val,
+            0x8 => self.prg_banks[0] = val,
+            0x9...0xB => self.prg_banks[(self.command - 0x8) as usize] = val & 0x3F,
+            0xC => self.mirroring = val & 0x03,
+            0xD => {
+                self.irq_enabled = val & 0x01 != 0;
+                self.irq_counter_enabled = val & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | val as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (val as u16) << 8,
+        }
+    }
+}
+
+impl Mapper for Fme7 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        let len = self.prg.len();
+        let bank = match addr {
+            0x6000...0x7FFF => {
+                if self.prg_ram_selected() {
+                    return if self.prg_ram_enabled() {
+                        mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()).map_or(0, |offset| self.prg_ram[offset])
+                    } else {
+                        0
+                    };
+                }
+                self.prg_banks[0] as usize & 0x3F
+            }
+            0x8000...0x9FFF => self.prg_banks[1] as usize,
+            0xA000...0xBFFF => self.prg_banks[2] as usize,
+            0xC000...0xDFFF => self.prg_banks[3] as usize,
+            0xE000...0xFFFF => len / 0x2000 - 1,
+            _ => return 0,
+        };
+        self.prg[mapper::bank_offset(bank, 0x2000, len) + (addr as usize & 0x1FFF)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF => {
+                if self.prg_ram_enabled() {
+                    if let Some(offset) = mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()) {
+                        self.prg_ram[offset] = val;
+                    }
+                }
+            }
+            0x8000...0x9FFF => self.command = val & 0x0F,
+            0xA000...0xBFFF => self.write_parameter(val),
+            0xC000...0xDFFF => self.audio_select = val & 0x0F,
+            0xE000...0xFFFF => {
+                let reg = self.audio_select;
+                self.audio.write(reg, val);
+            }
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn step(&mut self) {
+        // 16-bit down counter clocked every CPU cycle, firing on underflow
+        if self.irq_counter_enabled {
+            if self.irq_counter == 0 && self.irq_enabled {
+                self.irq_pending = true;
+            }
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+        }
+
+        self.audio.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * mapper::SUNSOFT5B_GAIN
+    }
+}
@@ -0,0 +1,698 @@
+use mapper::{self, FetchPhase, Mapper, Mirroring};
+use rom::Rom;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Envelopes and length counters run off a fixed 240Hz clock
+// rather than the APU frame counter
+const QUARTER_FRAME_CYCLES: u16 = 7457;
+
+// Tile fetches per scanline: 32 visible plus 2 prefetched for the next line
+const TILES_PER_LINE: u8 = 34;
+
+// 2A03-style pulse channel without the sweep unit
+struct Pulse {
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: u8,
+    // Also the envelope loop flag
+    halt: bool,
+    constant_volume: bool,
+    // Constant volume, or the envelope divider period
+    volume: u8,
+    envelope_start: bool,
+    envelope_divider: u8,
+    envelope_decay: u8,
+    enabled: bool,
+}
+
+impl Pulse {
+    fn new() -> Pulse {
+        Pulse {
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: 0,
+            halt: false,
+            constant_volume: false,
+            volume: 0,
+            envelope_start: false,
+            envelope_divider: 0,
+            envelope_decay: 0,
+            enabled: false,
+        }
+    }
+
+    fn storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.halt = val & 0x20 != 0;
+                self.constant_volume = val & 0x10 != 0;
+                self.volume = val & 0x0F;
+            }
+            2 => self.period = (self.period & 0x700) | val as u16,
+            3 => {
+                self.period = (self.period & 0xFF) | ((val as u16 & 0x07) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(val >> 3) as usize];
+                }
+                self.step = 0;
+                self.envelope_start = true;
+            }
+            // The sweep register at $5001/$5005 is not connected
+            _ => {}
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length = 0;
+        }
+    }
+
+    // Clocked every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_start {
+            self.envelope_start = false;
+            self.envelope_decay = 15;
+            self.envelope_divider = self.volume;
+        } else if self.envelope_divider == 0 {
+            self.envelope_divider = self.volume;
+            if self.envelope_decay > 0 {
+                self.envelope_decay -= 1;
+            } else if self.halt {
+                self.envelope_decay = 15;
+            }
+        } else {
+            self.envelope_divider -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.halt && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || DUTY_TABLE[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else if self.constant_volume {
+            self.volume
+        } else {
+            self.envelope_decay
+        }
+    }
+}
+
+// Mapper 5: Nintendo MMC5 (ExROM)
+//
+// Register     Function
+// $5000-$5007  Pulse channels 1 and 2, as $4000-$4007 without sweep
+// $5010        I--- ---M  PCM IRQ enable (I), PCM read mode (M)
+// $5011        PCM raw output in write mode
+// $5015        Pulse length counter enable / status
+// $5100        PRG mode: 32KB, 16KB, 16KB+8KB, 8KB
+// $5101        CHR mode: 8KB, 4KB, 2KB, 1KB
+// $5102-$5103  PRG RAM write protect, writable when set to 2 and 1
+// $5104        ExRAM mode: nametable, extended attributes, RAM, ROM
+// $5105        DDCC BBAA  Nametable source for each quarter of $2000-$2FFF:
+//                         CIRAM page 0, CIRAM page 1, ExRAM, fill mode
+// $5106-$5107  Fill mode tile and attribute
+// $5113        PRG RAM bank at $6000
+// $5114-$5117  PRG banks, bit 7 selecting ROM over RAM ($5117 is always ROM)
+// $5120-$5127  CHR banks used by sprites, and everything with 8x8 sprites
+// $5128-$512B  CHR banks used by the background with 8x16 sprites
+// $5130        Upper CHR bank bits
+// $5200        ES-T TTTT  Vertical split enable (E), right side (S), tile (T)
+// $5201        Vertical split Y scroll
+// $5202        Vertical split 4KB CHR bank
+// $5203        Scanline IRQ compare value
+// $5204        IRQ enable (write) / IRQ pending and in-frame status (read)
+// $5205-$5206  Unsigned 8x8 multiplier operands (write) / product (read)
+// $5C00-$5FFF  1KB ExRAM
+pub struct Mmc5 {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    exram: [u8; 0x400],
+
+    prg_mode: u8,
+    chr_mode: u8,
+    prg_ram_protect: [u8; 2],
+    exram_mode: u8,
+    nametable_map: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+    // $5113-$5117
+    prg_regs: [u8; 5],
+    chr_a: [u16; 8],
+    chr_b: [u16; 4],
+    chr_upper: u8,
+    // Set used for $2007 accesses, being the one written to last
+    last_chr_b: bool,
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    multiplicand: u8,
+    multiplier: u8,
+
+    // PPU state snooped from $2000/$2001 writes
+    large_sprites: bool,
+    rendering_enabled: bool,
+
+    // Scanline detection from PPU bus activity
+    in_frame: bool,
+    scanline: u8,
+    last_nametable_addr: u16,
+    nametable_match_count: u8,
+    cycles_since_fetch: u8,
+    phase: FetchPhase,
+    // Background tile fetches since the start of the line; the two
+    // fetched after the sprites are the first tiles of the next line
+    tile: u8,
+    // Scanline the current background fetch belongs to
+    fetch_line: u8,
+    // The current tile is inside the vertical split region
+    in_split: bool,
+    // ExRAM byte for the current tile in extended attribute mode
+    ex_attribute: u8,
+
+    pulses: [Pulse; 2],
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq_pending: bool,
+    pcm: u8,
+    quarter_frame_divider: u16,
+    odd_cycle: bool,
+}
+
+impl Mmc5 {
+    pub fn new(rom: Rom) -> Mmc5 {
+        let chr_is_ram = rom.chr.is_empty();
+        let prg_ram = rom.prg_ram(0x10000);
+        Mmc5 {
+            prg: rom.prg,
+            prg_ram: prg_ram,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            exram: [0; 0x400],
+
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_map: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_regs: [0, 0, 0, 0, 0xFF],
+            chr_a: [0; 8],
+            chr_b: [0; 4],
+            chr_upper: 0,
+            last_chr_b: false,
+
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+
+            large_sprites: false,
+            rendering_enabled: false,
+
+            in_frame: false,
+            scanline: 0,
+            last_nametable_addr: 0,
+            nametable_match_count: 0,
+            cycles_since_fetch: 0,
+            phase: FetchPhase::Cpu,
+            tile: 0,
+            fetch_line: 0,
+            in_split: false,
+            ex_attribute: 0,
+
+            pulses: [Pulse::new(), Pulse::new()],
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq_pending: false,
+            pcm: 0,
+            quarter_frame_divider: 0,
+            odd_cycle: false,
+        }
+    }
+
+    // Resolve $6000-$FFFF to (is ROM, byte offset), or None for RAM the
+    // board doesn't have
+    fn prg_target(&self, addr: u16) -> Option<(bool, usize)> {
+        if addr < 0x8000 {
+            let offset = (self.prg_regs[0] as usize & 0x07) * 0x2000 + (addr as usize & 0x1FFF);
+            return mapper::ram_offset(offset, self.prg_ram.len()).map(|offset| (false, offset));
+        }
+
+        let (reg, size) = match (self.prg_mode, addr) {
+            (0, _) => (4, 0x8000),
+            (1, 0x8000...0xBFFF) => (2, 0x4000),
+            (1, _) => (4, 0x4000),
+            (2, 0x8000...0xBFFF) => (2, 0x4000),
+            (2, 0xC000...0xDFFF) => (3, 0x2000),
+            (2, _) => (4, 0x2000),
+            (_, _) => (1 + ((addr - 0x8000) >> 13) as usize, 0x2000),
+        };
+        let val = self.prg_regs[reg];
+        let rom = reg == 4 || val & 0x80 != 0;
+        // Bank numbers are always in 8KB units, larger windows ignore the low bits
+        let bank = (val as usize & 0x7F) & !(size / 0x2000 - 1);
+        let within = addr as usize & (size - 1);
+        if rom {
+            let len = self.prg.len();
+            Some((true, mapper::bank_offset(bank / (size / 0x2000), size, len) + within))
+        } else {
+            let offset = (bank & 0x07) * 0x2000 + within;
+            mapper::ram_offset(offset, self.prg_ram.len()).map(|offset| (false, offset))
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    fn chr_offset(&self, addr: u16, use_b: bool) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        let (size, bank) = if use_b {
+            // The background set covers $0000-$0FFF and is mirrored at $1000
+            match self.chr_mode {
+                0 => (0x2000, self.chr_b[3]),
+                1 => (0x1000, self.chr_b[3]),
+                2 => (0x0800, self.chr_b[((addr >> 11) & 1) * 2 + 1]),
+                _ => (0x0400, self.chr_b[(addr >> 10) & 3]),
+            }
+        } else {
+            match self.chr_mode {
+                0 => (0x2000, self.chr_a[7]),
+                1 => (0x1000, self.chr_a[(addr >> 12) * 4 + 3]),
+                2 => (0x0800, self.chr_a[(addr >> 11) * 2 + 1]),
+                _ => (0x0400, self.chr_a[addr >> 10]),
+            }
+        };
+        mapper::bank_offset(bank as usize, size, self.chr.len()) + (addr & (size - 1))
+    }
+
+    // Which CHR bank set applies to the fetch currently in progress
+    fn use_chr_b(&self) -> bool {
+        if !self.large_sprites {
+            return false;
+        }
+        match self.phase {
+            FetchPhase::Sprite => false,
+            FetchPhase::Background => true,
+            FetchPhase::Cpu => self.last_chr_b,
+        }
+    }
+
+    fn rendering_background(&self) -> bool {
+        self.rendering_enabled && self.phase == FetchPhase::Background
+    }
+
+    // Y position within the split region for the current fetch
+    fn split_y(&self) -> u16 {
+        (self.split_scroll as u16 + self.fetch_line as u16) % 240
+    }
+
+    // Column of the current background fetch, 0-33
+    fn column(&self) -> u8 {
+        self.tile % TILES_PER_LINE
+    }
+
+    fn split_contains(&self, column: u8) -> bool {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return false;
+        }
+        let threshold = self.split_control & 0x1F;
+        if self.split_control & 0x40 != 0 {
+            column >= threshold
+        } else {
+            column < threshold
+        }
+    }
+
+    // Returns true when the fetch starts a new scanline
+    fn detect_scanline(&mut self, addr: u16) -> bool {
+        let mut detected = false;
+        if addr >= 0x2000 && addr <= 0x2FFF && addr == self.last_nametable_addr {
+            self.nametable_match_count += 1;
+            // Three matching reads: the two dummy fetches at the end of a line
+            // followed by the first fetch of the next one
+            if self.nametable_match_count == 2 {
+                if self.in_frame {
+                    self.scanline = self.scanline.wrapping_add(1);
+                    if self.scanline == self.irq_compare && self.irq_compare != 0 {
+                        self.irq_pending = true;
+                    }
+                } else {
+                    self.in_frame = true;
+                    self.scanline = 0;
+                }
+                detected = true;
+            }
+        } else {
+            self.nametable_match_count = 0;
+        }
+        self.last_nametable_addr = addr;
+        detected
+    }
+
+    fn leave_frame(&mut self) {
+        self.in_frame = false;
+        self.last_nametable_addr = 0;
+        self.nametable_match_count = 0;
+    }
+
+    fn pcm_output(&self) -> f32 {
+        // The 8-bit PCM sits on the same curve as the 2A03 DMC
+        if self.pcm == 0 {
+            0.0
+        } else {
+            159.79 / (1.0 / (self.pcm as f32 / 2.0 / 22638.0) + 100.0)
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => {
+                let val = (self.pcm_irq_pending as u8) << 7 | !self.pcm_read_mode as u8;
+                self.pcm_irq_pending = false;
+                val
+            }
+            0x5015 => {
+                (self.pulses[0].length > 0) as u8 | ((self.pulses[1].length > 0) as u8) << 1
+            }
+            0x5204 => {
+                let val = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                val
+            }
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00...0x5FFF => {
+                if self.exram_mode >= 2 {
+                    self.exram[addr as usize - 0x5C00]
+                } else {
+                    0
+                }
+            }
+            0x6000...0xFFFF => {
+                // Fetching the NMI vector marks the end of the frame
+                if addr == 0xFFFA || addr == 0xFFFB {
+                    self.leave_frame();
+                }
+
+                let val = match self.prg_target(addr) {
+                    Some((true, offset)) => self.prg[offset],
+                    Some((false, offset)) => self.prg_ram[offset],
+                    None => 0,
+                };
+                if self.pcm_read_mode && addr >= 0x8000 && addr <= 0xBFFF {
+                    if val == 0 {
+                        self.pcm_irq_pending = true;
+                    } else {
+                        self.pcm = val;
+                    }
+                }
+                val
+            }
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5000...0x5003 => self.pulses[0].storeb(addr - 0x5000, val),
+            0x5004...0x5007 => self.pulses[1].storeb(addr - 0x5004, val),
+            0x5010 => {
+                self.pcm_read_mode = val & 0x01 != 0;
+                self.pcm_irq_enabled = val & 0x80 != 0;
+            }
+            0x5011 => {
+                if !self.pcm_read_mode && val != 0 {
+                    self.pcm = val;
+                }
+            }
+            0x5015 => {
+                self.pulses[0].set_enabled(val & 0x01 != 0);
+                self.pulses[1].set_enabled(val & 0x02 != 0);
+            }
+            0x5100 => self.prg_mode = val & 0x03,
+            0x5101 => self.chr_mode = val & 0x03,
+            0x5102 => self.prg_ram_protect[0] = val & 0x03,
+            0x5103 => self.prg_ram_protect[1] = val & 0x03,
+            0x5104 => self.exram_mode = val & 0x03,
+            0x5105 => self.nametable_map = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attr = val & 0x03,
+            0x5113...0x5117 => self.prg_regs[(addr - 0x5113) as usize] = val,
+            0x5120...0x5127 => {
+                self.chr_a[(addr - 0x5120) as usize] = val as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_b = false;
+            }
+            0x5128...0x512B => {
+                self.chr_b[(addr - 0x5128) as usize] = val as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_b = true;
+            }
+            0x5130 => self.chr_upper = val & 0x03,
+            0x5200 => self.split_control = val,
+            0x5201 => self.split_scroll = val,
+            0x5202 => self.split_bank = val,
+            0x5203 => self.irq_compare = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 => self.multiplicand = val,
+            0x5206 => self.multiplier = val,
+            0x5C00...0x5FFF => {
+                let index = addr as usize - 0x5C00;
+                match self.exram_mode {
+                    // Only writable while rendering, otherwise zero is written
+                    0 | 1 => self.exram[index] = if self.in_frame { val } else { 0 },
+                    2 => self.exram[index] = val,
+                    _ => {}
+                }
+            }
+            0x6000...0xFFFF => {
+                if let Some((false, offset)) = self.prg_target(addr) {
+                    if self.prg_ram_writable() {
+                        self.prg_ram[offset] = val;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        if self.rendering_background() {
+            if self.in_split {
+                let fine_y = self.split_y() as usize & 7;
+                let offset = (addr as usize & 0x0FF8) | fine_y;
+                let base = mapper::bank_offset(self.split_bank as usize, 0x1000, self.chr.len());
+                return self.chr[base + offset];
+            }
+            if self.exram_mode == 1 {
+                let bank = (self.ex_attribute as usize & 0x3F) | (self.chr_upper as usize) << 6;
+                let base = mapper::bank_offset(bank, 0x1000, self.chr.len());
+                return self.chr[base + (addr as usize & 0x0FFF)];
+            }
+        }
+        let use_b = self.use_chr_b();
+        self.chr[self.chr_offset(addr, use_b)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        if self.chr_is_ram {
+            let use_b = self.use_chr_b();
+            let offset = self.chr_offset(addr, use_b);
+            self.chr[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_map {
+            0x50 => Mirroring::Horizontal,
+            0x00 => Mirroring::SingleScreenLower,
+            0x55 => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn nametable_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        let within = addr as usize & 0x03FF;
+        let is_attribute = within >= 0x3C0;
+
+        if self.rendering_background() {
+            if self.in_split {
+                let y = self.split_y() as usize;
+                let column = self.column() as usize % 32;
+                return if is_attribute {
+                    let attr = self.exram[0x3C0 + (y / 32) * 8 + column / 4];
+                    let shift = ((y & 0x10) >> 2) | (column & 0x02);
+                    ((attr >> shift) & 0x03) * 0x55
+                } else {
+                    self.exram[(y / 8) * 32 + column]
+                };
+            }
+            if self.exram_mode == 1 {
+                if is_attribute {
+                    // Every quadrant takes the palette from the tile's ExRAM byte
+                    return (self.ex_attribute >> 6) * 0x55;
+                }
+                self.ex_attribute = self.exram[within];
+            }
+        }
+
+        let quarter = (addr as usize >> 10) & 0x03;
+        match (self.nametable_map >> (quarter * 2)) & 0x03 {
+            0 => ciram[within],
+            1 => ciram[0x400 + within],
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[within]
+                } else {
+                    0
+                }
+            }
+            _ => {
+                if is_attribute {
+                    self.fill_attr * 0x55
+                } else {
+                    self.fill_tile
+                }
+            }
+        }
+    }
+
+    fn nametable_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        let within = addr as usize & 0x03FF;
+        let quarter = (addr as usize >> 10) & 0x03;
+        match (self.nametable_map >> (quarter * 2)) & 0x03 {
+            0 => ciram[within] = val,
+            1 => ciram[0x400 + within] = val,
+            2 => {
+                if self.exram_mode <= 1 {
+                    self.exram[within] = val;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn ppu_fetch(&mut self, addr: u16, phase: FetchPhase) {
+        self.phase = phase;
+        if phase == FetchPhase::Cpu {
+            return;
+        }
+
+        self.cycles_since_fetch = 0;
+        let is_tile = addr >= 0x2000 && addr & 0x03FF < 0x3C0;
+        if self.detect_scanline(addr) {
+            // Tiles 0 and 1 were prefetched at the end of the previous line
+            self.tile = 2;
+        } else if phase == FetchPhase::Sprite {
+            self.tile = TILES_PER_LINE - 1;
+        } else if is_tile {
+            self.tile = self.tile.wrapping_add(1);
+        }
+
+        if phase == FetchPhase::Background && is_tile {
+            self.fetch_line = if !self.in_frame {
+                0
+            } else if self.tile >= TILES_PER_LINE {
+                self.scanline.wrapping_add(1)
+            } else {
+                self.scanline
+            };
+            let column = self.column();
+            self.in_split = self.split_contains(column);
+        }
+    }
+
+    fn ppu_register_storeb(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.large_sprites = val & 0x20 != 0,
+            1 => {
+                self.rendering_enabled = val & 0x18 != 0;
+                if !self.rendering_enabled {
+                    self.leave_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn step(&mut self) {
+        // The PPU stops reading once rendering ends for the frame
+        if self.cycles_since_fetch < 3 {
+            self.cycles_since_fetch += 1;
+            if self.cycles_since_fetch == 3 {
+                self.leave_frame();
+            }
+        }
+
+        self.odd_cycle = !self.odd_cycle;
+        if self.odd_cycle {
+            self.pulses[0].clock_timer();
+            self.pulses[1].clock_timer();
+        }
+
+        self.quarter_frame_divider += 1;
+        if self.quarter_frame_divider == QUARTER_FRAME_CYCLES {
+            self.quarter_frame_divider = 0;
+            for pulse in self.pulses.iter_mut() {
+                pulse.clock_envelope();
+                pulse.clock_length();
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || (self.pcm_irq_pending && self.pcm_irq_enabled)
+    }
+
+    fn audio_output(&self) -> f32 {
+        let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32;
+        let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+        pulse_out + self.pcm_output()
+    }
+}
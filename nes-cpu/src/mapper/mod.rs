@@ -0,0 +1,257 @@
+use rom::{Rom, RomError};
+
+pub mod fds;
+pub mod fds_audio;
+pub mod fme7;
+pub mod mmc5;
+pub mod namco163;
+pub mod nrom;
+pub mod nsf;
+pub mod opll;
+pub mod sunsoft5b;
+pub mod vrc7;
+pub mod vs_board;
+
+// Nametable arrangement selected by the cartridge
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    // Every nametable maps to the first 1KB of CIRAM
+    SingleScreenLower,
+    // Every nametable maps to the second 1KB of CIRAM
+    SingleScreenUpper,
+    // Four separate nametables, the upper two in cartridge VRAM
+    FourScreen,
+}
+
+// Part of the PPU's fetch pattern that a bus access belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchPhase {
+    // Background tile fetches, dots 1-256 and 321-340
+    Background,
+    // Sprite pattern fetches, dots 257-320
+    Sprite,
+    // PPUDATA ($2007) access by the CPU
+    Cpu,
+}
+
+// Gains that bring each expansion chip's own output to its usual loudness
+// next to the 2A03, from the default mixing levels of the NSF2 format.
+// Those say how loud one of the chip's channels at full volume is against
+// a 2A03 pulse at full volume, which swings 0.149 in Apu::output:
+//
+// Chip  Level     Swing of one channel in the chip's output
+// VRC7  +11dB     2/6, a sine over six channels
+// FDS   +7dB      1, the whole wave at full master volume
+// N163  +11dB     225/128, a 4-bit sample at volume 15
+// 5B    -1.3dB    1/3, a square over three channels
+//
+// The MMC5's pulses are mixed like the 2A03's and need no gain.
+pub const VRC7_GAIN: f32 = 1.590;
+pub const FDS_GAIN: f32 = 0.3344;
+pub const N163_GAIN: f32 = 0.3015;
+pub const SUNSOFT5B_GAIN: f32 = 0.3858;
+
+pub trait Mapper {
+    // Read a byte from cartridge space ($4020-$FFFF) on the CPU bus.
+    // Unlike Mem, the full CPU address is given since mapper registers
+    // are documented by their absolute address.
+    fn prg_loadb(&mut self, addr: u16) -> u8;
+    // Write a byte to cartridge space ($4020-$FFFF) on the CPU bus
+    fn prg_storeb(&mut self, addr: u16, val: u8);
+    // Read a byte from the pattern tables ($0000-$1FFF) on the PPU bus.
+    // The cartridge controls CIRAM's chip enable, so it is passed in for
+    // boards that can map it into pattern space as well as the nametables.
+    fn chr_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8;
+    // Write a byte to the pattern tables ($0000-$1FFF) on the PPU bus
+    fn chr_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]);
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Read a byte from the nametables ($2000-$2FFF) on the PPU bus.
+    // CIRAM is the console's 2KB of nametable RAM; cartridges that
+    // supply their own nametable memory can bypass it entirely.
+    fn nametable_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        ciram[nametable_offset(self.mirroring(), addr)]
+    }
+
+    // Write a byte to the nametables ($2000-$2FFF) on the PPU bus
+    fn nametable_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        ciram[nametable_offset(self.mirroring(), addr)] = val;
+    }
+
+    // Called by the PPU ahead of each nametable, attribute and pattern
+    // fetch so the cartridge can follow the rendering position
+    fn ppu_fetch(&mut self, _addr: u16, _phase: FetchPhase) {}
+
+    // Observe a CPU write to the PPU registers, reg being 0-7
+    fn ppu_register_storeb(&mut self, _reg: u16, _val: u8) {}
+
+    // Advance the cartridge by a single CPU cycle
+    fn step(&mut self) {}
+
+    // Whether the cartridge is currently asserting /IRQ
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Latest expansion audio sample, on the scale of Apu::output so that
+    // the two can be added. Chips running at their own rate hold their
+    // last sample so that this can be read once per CPU cycle.
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Follow the bank select bit a Vs. System game writes to $4016
+    fn vs_bank_select(&mut self, _bank: bool) {}
+}
+
+// Build the mapper implementation named by the ROM header
+pub fn create(rom: Rom) -> Result<Box<Mapper>, RomError> {
+    // The banked boards switch PRG in 8KB units and fix the last bank at
+    // $E000, which needs at least one whole bank. UNIF chunks and NES 2.0
+    // headers can both describe smaller images.
+    let banked = match rom.header.mapper {
+        5 | 19 | 69 | 85 => true,
+        _ => false,
+    };
+    if banked && (rom.prg.is_empty() || rom.prg.len() % 0x2000 != 0) {
+        return Err(RomError::InvalidPrgSize { mapper: rom.header.mapper, size: rom.prg.len() });
+    }
+    match rom.header.mapper {
+        0 => Ok(Box::new(nrom::Nrom::new(rom))),
+        5 => Ok(Box::new(mmc5::Mmc5::new(rom))),
+        19 => Ok(Box::new(namco163::Namco163::new(rom))),
+        69 => Ok(Box::new(fme7::Fme7::new(rom))),
+        85 => Ok(Box::new(vrc7::Vrc7::new(rom))),
+        99 => Ok(Box::new(vs_board::VsBoard::new(rom))),
+        mapper => Err(RomError::UnsupportedMapper(mapper)),
+    }
+}
+
+// Offset into a ROM of `len` bytes for the given bank number.
+// Bank numbers wrap around the ROM size as they do on hardware
+// with unconnected upper address lines.
+fn bank_offset(bank: usize, bank_size: usize, len: usize) -> usize {
+    let count = len / bank_size;
+    if count == 0 {
+        0
+    } else {
+        (bank % count) * bank_size
+    }
+}
+
+// Offset into PRG RAM of `len` bytes, mirrored across RAM smaller than
+// the window it sits in as unconnected address lines do. None on boards
+// without any.
+fn ram_offset(offset: usize, len: usize) -> Option<usize> {
+    if len == 0 {
+        None
+    } else {
+        Some(offset % len)
+    }
+}
+
+// Offset into CIRAM for a nametable address under the given mirroring
+pub fn nametable_offset(mirroring: Mirroring, addr: u16) -> usize {
+    let addr = addr as usize & 0x0FFF;
+    let page = match mirroring {
+        Mirroring::Horizontal => addr >> 11,
+        Mirroring::Vertical => (addr >> 10) & 1,
+        Mirroring::SingleScreenLower => 0,
+        Mirroring::SingleScreenUpper => 1,
+        // Pages 2 and 3 lie beyond CIRAM, mappers using this supply them
+        Mirroring::FourScreen => addr >> 10,
+    };
+    (page << 10) | (addr & 0x03FF)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::create;
+    use rom::{INesHeader, Rom, RomError};
+
+    pub fn rom(mapper: u16, prg_size: usize) -> Rom {
+        Rom {
+            header: INesHeader::builder().mapper(mapper).prg_ram_size(0x2000).build().unwrap(),
+            trainer: Vec::new(),
+            prg: vec![0; prg_size],
+            chr: vec![0; 0x2000],
+            misc_rom: Vec::new(),
+            corrections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn banked_boards_reject_partial_prg_banks() {
+        for &mapper in [5, 19, 69, 85].iter() {
+            match create(rom(mapper, 0x1000)) {
+                Err(RomError::InvalidPrgSize { mapper: m, size: 0x1000 }) => assert_eq!(m, mapper),
+                _ => panic!("mapper {} accepted a 4KB PRG ROM", mapper),
+            }
+            assert!(create(rom(mapper, 0x2000)).is_ok());
+        }
+    }
+
+    #[test]
+    fn last_bank_is_fixed_with_a_single_bank() {
+        let mut mapper = create(rom(69, 0x2000)).unwrap();
+        assert_eq!(mapper.prg_loadb(0xFFFC), 0);
+    }
+
+    // A board with the given PRG RAM size in an NES 2.0 header
+    fn nes2_rom(mapper: u16, prg_ram_size: usize) -> Rom {
+        let mut rom = rom(mapper, 0x8000);
+        rom.header = INesHeader::builder().nes2(true).mapper(mapper).prg_ram_size(prg_ram_size).build().unwrap();
+        rom
+    }
+
+    #[test]
+    fn small_prg_ram_is_mirrored_across_its_window() {
+        let mut mapper = create(nes2_rom(0, 0x800)).unwrap();
+        mapper.prg_storeb(0x6001, 0x5A);
+        assert_eq!(mapper.prg_loadb(0x6801), 0x5A);
+        assert_eq!(mapper.prg_loadb(0x7801), 0x5A);
+    }
+
+    #[test]
+    fn boards_without_prg_ram_read_nothing_there() {
+        // Writes that would enable the RAM on each board
+        let enables: [(u16, &[(u16, u8)]); 5] = [
+            (0, &[]),
+            (5, &[(0x5102, 0x02), (0x5103, 0x01)]),
+            (19, &[(0xF800, 0x40)]),
+            (69, &[(0x8000, 0x08), (0xA000, 0xC0)]),
+            (85, &[(0xE000, 0x80)]),
+        ];
+        for &(number, writes) in enables.iter() {
+            let mut mapper = create(nes2_rom(number, 0)).unwrap();
+            for &(addr, val) in writes.iter() {
+                mapper.prg_storeb(addr, val);
+            }
+            mapper.prg_storeb(0x6000, 0x5A);
+            assert_eq!(mapper.prg_loadb(0x6000), 0, "mapper {}", number);
+        }
+    }
+
+    #[test]
+    fn mmc5_ram_banks_wrap_around_the_ram_fitted() {
+        let mut mapper = create(nes2_rom(5, 0x4000)).unwrap();
+        mapper.prg_storeb(0x5102, 0x02);
+        mapper.prg_storeb(0x5103, 0x01);
+        mapper.prg_storeb(0x5113, 0);
+        mapper.prg_storeb(0x6000, 0x11);
+        mapper.prg_storeb(0x5113, 1);
+        mapper.prg_storeb(0x6000, 0x22);
+
+        // Banks 2-7 are 0 and 1 again with 16KB
+        mapper.prg_storeb(0x5113, 6);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x11);
+        mapper.prg_storeb(0x5113, 3);
+        assert_eq!(mapper.prg_loadb(0x6000), 0x22);
+        // The same goes for RAM banked in at $8000
+        mapper.prg_storeb(0x5114, 0x04);
+        assert_eq!(mapper.prg_loadb(0x8000), 0x11);
+    }
+}
@@ -0,0 +1,353 @@
+use mapper::{self, Mapper, Mirroring};
+use rom::Rom;
+
+// One channel is updated every 15 CPU cycles, round robin
+const CPU_CYCLES_PER_CHANNEL: u8 = 15;
+
+// Wavetable synthesizer of the 163, playing 4-bit samples out of its
+// 128 bytes of internal RAM. Channel registers occupy the top of RAM,
+// eight bytes each from $40 (channel 0) to $78 (channel 7):
+//
+// +0   Frequency low          +1   Phase low
+// +2   Frequency middle       +3   Phase middle
+// +4   LLLL LLFF  Wave length (256 - L * 4 samples), frequency high (F)
+// +5   Phase high
+// +6   Wave address, in 4-bit samples
+// +7   -CCC VVVV  Volume (V); on channel 7 only, enabled channels - 1 (C)
+//
+// Only one channel is output at a time, so with many channels enabled
+// the switching rate drops into the audible range as a high whine.
+struct Namco163Audio {
+    ram: [u8; 0x80],
+    // Latest output of each channel, -120 to 105
+    outputs: [i16; 8],
+    current: usize,
+    divider: u8,
+    // Mix the enabled channels evenly instead of multiplexing them
+    smooth: bool,
+}
+
+impl Namco163Audio {
+    fn new() -> Namco163Audio {
+        Namco163Audio {
+            ram: [0; 0x80],
+            outputs: [0; 8],
+            current: 7,
+            divider: 0,
+            smooth: false,
+        }
+    }
+
+    // Channels are enabled from 7 downwards
+    fn enabled_channels(&self) -> usize {
+        ((self.ram[0x7F] >> 4) & 0x07) as usize + 1
+    }
+
+    fn step(&mut self) {
+        self.divider += 1;
+        if self.divider < CPU_CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.divider = 0;
+
+        let lowest = 8 - self.enabled_channels();
+        self.current = if self.current <= lowest { 7 } else { self.current - 1 };
+        self.update_channel(self.current);
+    }
+
+    fn update_channel(&mut self, ch: usize) {
+        let base = 0x40 + ch * 8;
+        let reg = |i: usize| self.ram[base + i] as u32;
+
+        let freq = reg(0) | reg(2) << 8 | (reg(4) & 0x03) << 16;
+        let length = 256 - (reg(4) & 0xFC);
+        let mut phase = reg(1) | reg(3) << 8 | reg(5) << 16;
+        phase = (phase + freq) % (length << 16);
+
+        let addr = (reg(6) + (phase >> 16)) & 0xFF;
+        let sample = (self.ram[(addr >> 1) as usize] >> ((addr & 1) * 4)) & 0x0F;
+        let volume = reg(7) & 0x0F;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+        self.outputs[ch] = (sample as i16 - 8) * volume as i16;
+    }
+
+    // Current sample, -1.0 to 1.0
+    fn output(&self) -> f32 {
+        let out = if self.smooth {
+            let count = self.enabled_channels();
+            let sum: i16 = self.outputs[8 - count..].iter().sum();
+            sum as f32 / count as f32
+        } else {
+            self.outputs[self.current] as f32
+        };
+        out / 128.0
+    }
+}
+
+// Mapper 19: Namco 129/163
+//
+// Register     Function
+// $4800-$4FFF  Sound RAM data port
+// $5000-$57FF  IRQ counter low 8 bits
+// $5800-$5FFF  EIII IIII  IRQ counter enable (E), counter high 7 bits (I)
+// $8000-$BFFF  1KB CHR banks 0-7 at $8000, $8800, ... $B800
+// $C000-$DFFF  Nametable banks at $C000, $C800, $D000, $D800
+// $E000-$E7FF  -SPP PPPP  Sound disable (S), 8KB PRG bank at $8000
+// $E800-$EFFF  HLPP PPPP  CHR-RAM disable for $1000 (H) and $0000 (L),
+//                         8KB PRG bank at $A000
+// $F000-$F7FF  8KB PRG bank at $C000
+// $F800-$FFFF  IAAA AAAA  Sound RAM address (A), auto increment (I)
+//              KKKK DCBA  Also PRG RAM write protect: writes are allowed
+//                         with K = $4, except to the 2KB blocks at
+//                         $6000 (A), $6800 (B), $7000 (C), $7800 (D)
+//
+// CHR and nametable bank values of $E0 and above select a page of CIRAM
+// by their lowest bit. For the pattern tables this can be disabled so
+// that all 256 banks of CHR ROM are reachable. $E000-$FFFF is fixed to
+// the last 8KB PRG bank.
+pub struct Namco163 {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    chr_banks: [u8; 8],
+    nametable_banks: [u8; 4],
+    prg_banks: [u8; 3],
+    chr_ram_disable: [bool; 2],
+    sound_disabled: bool,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_pending: bool,
+    audio: Namco163Audio,
+    audio_addr: u8,
+    audio_auto_increment: bool,
+    // Last value written to $F800
+    prg_ram_protect: u8,
+}
+
+impl Namco163 {
+    pub fn new(rom: Rom) -> Namco163 {
+        let chr_is_ram = rom.chr.is_empty();
+        let prg_ram = rom.prg_ram(0x2000);
+        Namco163 {
+            prg: rom.prg,
+            prg_ram: prg_ram,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            chr_banks: [0; 8],
+            nametable_banks: [0xE0, 0xE1, 0xE0, 0xE1],
+            prg_banks: [0; 3],
+            chr_ram_disable: [false; 2],
+            sound_disabled: false,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+            audio_addr: 0,
+            audio_auto_increment: false,
+            prg_ram_protect: 0,
+        }
+    }
+
+    // Average the enabled wavetable channels rather than reproducing the
+    // hardware's time multiplexing, which whines with 6 or more channels
+    pub fn set_smooth_mix(&mut self, smooth: bool) {
+        self.audio.smooth = smooth;
+    }
+
+    // CIRAM offset for a bank value, if it selects nametable RAM
+    fn ciram_page(val: u8) -> Option<usize> {
+        if val >= 0xE0 {
+            Some((val as usize & 1) << 10)
+        } else {
+            None
+        }
+    }
+
+    // Resolve a pattern table address to CIRAM or CHR
+    fn chr_target(&self, addr: u16) -> (bool, usize) {
+        let slot = (addr as usize >> 10) & 7;
+        let val = self.chr_banks[slot];
+        let within = addr as usize & 0x3FF;
+        match Namco163::ciram_page(val) {
+            Some(page) if !self.chr_ram_disable[slot / 4] => (true, page + within),
+            _ => (false, mapper::bank_offset(val as usize, 0x400, self.chr.len()) + within),
+        }
+    }
+
+    fn prg_ram_writable(&self, addr: u16) -> bool {
+        let block = (addr as usize >> 11) & 3;
+        self.prg_ram_protect & 0xF0 == 0x40 && self.prg_ram_protect & (1 << block) == 0
+    }
+
+    fn audio_data(&mut self) -> &mut u8 {
+        let addr = self.audio_addr;
+        if self.audio_auto_increment {
+            self.audio_addr = (addr + 1) & 0x7F;
+        }
+        &mut self.audio.ram[addr as usize]
+    }
+}
+
+impl Mapper for Namco163 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        let len = self.prg.len();
+        let bank = match addr {
+            0x4800...0x4FFF => return *self.audio_data(),
+            0x5000...0x57FF => return self.irq_counter as u8,
+            0x5800...0x5FFF => {
+                return (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7;
+            }
+            0x6000...0x7FFF => return mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()).map_or(0, |offset| self.prg_ram[offset]),
+            0x8000...0x9FFF => self.prg_banks[0] as usize,
+            0xA000...0xBFFF => self.prg_banks[1] as usize,
+            0xC000...0xDFFF => self.prg_banks[2] as usize,
+            0xE000...0xFFFF => len / 0x2000 - 1,
+            _ => return 0,
+        };
+        self.prg[mapper::bank_offset(bank, 0x2000, len) + (addr as usize & 0x1FFF)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4800...0x4FFF => *self.audio_data() = val,
+            0x5000...0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | val as u16;
+                self.irq_pending = false;
+            }
+            0x5800...0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (val as u16 & 0x7F) << 8;
+                self.irq_enabled = val & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000...0x7FFF => {
+                if self.prg_ram_writable(addr) {
+                    if let Some(offset) = mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()) {
+                        self.prg_ram[offset] = val;
+                    }
+                }
+            }
+            0x8000...0xBFFF => self.chr_banks[((addr - 0x8000) >> 11) as usize] = val,
+            0xC000...0xDFFF => self.nametable_banks[((addr - 0xC000) >> 11) as usize] = val,
+            0xE000...0xE7FF => {
+                self.prg_banks[0] = val & 0x3F;
+                self.sound_disabled = val & 0x40 != 0;
+            }
+            0xE800...0xEFFF => {
+                self.prg_banks[1] = val & 0x3F;
+                self.chr_ram_disable = [val & 0x40 != 0, val & 0x80 != 0];
+            }
+            0xF000...0xF7FF => self.prg_banks[2] = val & 0x3F,
+            0xF800...0xFFFF => {
+                self.audio_addr = val & 0x7F;
+                self.audio_auto_increment = val & 0x80 != 0;
+                self.prg_ram_protect = val;
+            }
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        match self.chr_target(addr) {
+            (true, offset) => ciram[offset],
+            (false, offset) => self.chr[offset],
+        }
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        match self.chr_target(addr) {
+            (true, offset) => ciram[offset] = val,
+            (false, offset) => {
+                if self.chr_is_ram {
+                    self.chr[offset] = val;
+                }
+            }
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_banks {
+            [0xE0, 0xE0, 0xE1, 0xE1] => Mirroring::Horizontal,
+            [0xE0, 0xE0, 0xE0, 0xE0] => Mirroring::SingleScreenLower,
+            [0xE1, 0xE1, 0xE1, 0xE1] => Mirroring::SingleScreenUpper,
+            _ => Mirroring::Vertical,
+        }
+    }
+
+    fn nametable_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        let val = self.nametable_banks[(addr as usize >> 10) & 3];
+        let within = addr as usize & 0x3FF;
+        match Namco163::ciram_page(val) {
+            Some(page) => ciram[page + within],
+            // Nametables can also be taken from CHR ROM
+            None => self.chr[mapper::bank_offset(val as usize, 0x400, self.chr.len()) + within],
+        }
+    }
+
+    fn nametable_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        let bank = self.nametable_banks[(addr as usize >> 10) & 3];
+        let within = addr as usize & 0x3FF;
+        match Namco163::ciram_page(bank) {
+            Some(page) => ciram[page + within] = val,
+            None => {
+                if self.chr_is_ram {
+                    let offset = mapper::bank_offset(bank as usize, 0x400, self.chr.len());
+                    self.chr[offset + within] = val;
+                }
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+            if self.irq_counter == 0x7FFF {
+                self.irq_pending = true;
+            }
+        }
+
+        if !self.sound_disabled {
+            self.audio.step();
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output() * mapper::N163_GAIN
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::tests::rom;
+
+    #[test]
+    fn prg_ram_is_write_protected_by_f800() {
+        let mut mapper = Namco163::new(rom(19, 0x8000));
+        // Protected until $F800 holds $4x
+        mapper.prg_storeb(0x6000, 0x11);
+        assert_eq!(mapper.prg_loadb(0x6000), 0);
+
+        mapper.prg_storeb(0xF800, 0x45);
+        for (i, &addr) in [0x6000u16, 0x6800, 0x7000, 0x7800].iter().enumerate() {
+            mapper.prg_storeb(addr, 0x22);
+            let expected = if 0x05 & (1 << i) != 0 { 0 } else { 0x22 };
+            assert_eq!(mapper.prg_loadb(addr), expected, "block at {:04X}", addr);
+        }
+
+        mapper.prg_storeb(0xF800, 0xC0);
+        mapper.prg_storeb(0x6800, 0x33);
+        assert_eq!(mapper.prg_loadb(0x6800), 0x22);
+    }
+}
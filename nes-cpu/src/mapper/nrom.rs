@@ -0,0 +1,86 @@
+use mapper::{self, Mapper, Mirroring};
+use rom::Rom;
+
+// Mapper 0: 16KB or 32KB of fixed PRG ROM and 8KB of CHR. Family Basic
+// boards add RAM at $6000-$7FFF, which also holds any trainer.
+pub struct Nrom {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    // Nametable RAM on four-screen boards
+    vram: Vec<u8>,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Nrom {
+        let chr_is_ram = rom.chr.is_empty();
+        let prg_ram = rom.prg_ram(0x2000);
+        let mirroring = rom.header.mirroring;
+        Nrom {
+            prg: rom.prg,
+            prg_ram: prg_ram,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            mirroring: mirroring,
+            vram: if mirroring == Mirroring::FourScreen { vec![0u8; 0x1000] } else { Vec::new() },
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr >= 0x6000 && addr < 0x8000 {
+            return mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()).map_or(0, |offset| self.prg_ram[offset]);
+        }
+        if addr < 0x8000 || self.prg.is_empty() {
+            return 0;
+        }
+        // 16KB images are mirrored into $C000-$FFFF
+        let len = self.prg.len();
+        self.prg[(addr as usize - 0x8000) % len]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x6000 && addr < 0x8000 {
+            if let Some(offset) = mapper::ram_offset(addr as usize & 0x1FFF, self.prg_ram.len()) {
+                self.prg_ram[offset] = val;
+            }
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        let len = self.chr.len();
+        self.chr[addr as usize % len]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        if self.chr_is_ram {
+            let len = self.chr.len();
+            self.chr[addr as usize % len] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn nametable_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        let offset = mapper::nametable_offset(self.mirroring, addr);
+        if self.vram.is_empty() {
+            ciram[offset]
+        } else {
+            self.vram[offset]
+        }
+    }
+
+    fn nametable_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        let offset = mapper::nametable_offset(self.mirroring, addr);
+        if self.vram.is_empty() {
+            ciram[offset] = val;
+        } else {
+            self.vram[offset] = val;
+        }
+    }
+}
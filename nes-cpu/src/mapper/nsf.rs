@@ -0,0 +1,280 @@
+use mapper::{self, Mapper, Mirroring};
+use mapper::fds_audio::FdsAudio;
+use mapper::{fme7, mmc5, namco163, vrc7};
+use nsf::{self, Nsf};
+use rom::{INesHeader, Rom};
+
+// Cartridge side of an NSF player. Without bankswitching the tune is
+// loaded at its load address; with it, $5FF8-$5FFF select the 4KB bank
+// seen at $8000-$FFFF in eight slots, bank 0 starting at the load
+// address rounded down to 4KB. $6000-$7FFF is 8KB of RAM.
+//
+// Tunes for the FDS have RAM at $6000-$FFFF instead. Writes to
+// $5FF6-$5FFF copy a bank into the RAM, $5FF6 and $5FF7 covering
+// $6000-$7FFF.
+//
+// Expansion chips are those of the boards that carry them, with only
+// their audio registers connected:
+//
+// VRC7   $9010 register select, $9030 data
+// FDS    $4040-$4092
+// MMC5   $5000-$5015 pulses and PCM, $5205-$5206 multiplier, $5C00-$5FF5 ExRAM
+// N163   $4800 data, $F800 address
+// 5B     $C000 register select, $E000 data
+//
+// The VRC6 is recognised but has no emulation yet and stays silent.
+pub struct NsfMapper {
+    // Tune data, padded at the front so that it starts at the load
+    // address within its bank
+    image: Vec<u8>,
+    initial_banks: [u8; 8],
+    banks: [u8; 8],
+    prg_ram: Vec<u8>,
+    fds: bool,
+    // Banks loaded into FDS RAM at $6000-$FFFF on reset
+    fds_banks: [u8; 10],
+    // Expansion chips the tune uses, see the nsf::CHIP_ flags
+    chips: u8,
+    fds_audio: Option<FdsAudio>,
+    vrc7: Option<Box<Mapper>>,
+    mmc5: Option<Box<Mapper>>,
+    namco163: Option<Box<Mapper>>,
+    sunsoft5b: Option<Box<Mapper>>,
+}
+
+// Board to host an expansion chip. Only its audio registers are used,
+// so the ROM contents do not matter.
+fn chip_rom() -> Rom {
+    let header = [0x4E, 0x45, 0x53, 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    Rom {
+        header: INesHeader::parse_header(header),
+        trainer: Vec::new(),
+        prg: vec![0u8; 0x8000],
+        chr: vec![0u8; 0x2000],
+        misc_rom: Vec::new(),
+        corrections: Vec::new(),
+    }
+}
+
+impl NsfMapper {
+    pub fn new(tune: &Nsf) -> NsfMapper {
+        let (image, initial_banks) = if tune.is_bankswitched() {
+            let mut image = vec![0u8; tune.load_addr as usize & 0x0FFF];
+            image.extend_from_slice(&tune.data);
+            (image, tune.bankswitch)
+        } else {
+            // Lay the data out from $8000 (or $6000 on the FDS) and
+            // count the banks up from there
+            let base = if tune.has_chip(nsf::CHIP_FDS) { 0x6000 } else { 0x8000 };
+            let pad = (tune.load_addr as usize).saturating_sub(base);
+            let mut image = vec![0u8; pad];
+            image.extend_from_slice(&tune.data);
+            (image, [0, 1, 2, 3, 4, 5, 6, 7])
+        };
+        let fds = tune.has_chip(nsf::CHIP_FDS);
+        let b = initial_banks;
+        let fds_banks = if tune.is_bankswitched() {
+            // $5FF6 and $5FF7 start out as the values given for $5FFE and $5FFF
+            [b[6], b[7], b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]
+        } else {
+            [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]
+        };
+
+        let mut mapper = NsfMapper {
+            image: image,
+            initial_banks: initial_banks,
+            banks: initial_banks,
+            prg_ram: vec![0u8; if fds { 0xA000 } else { 0x2000 }],
+            fds: fds,
+            fds_banks: fds_banks,
+            chips: tune.chips,
+            fds_audio: None,
+            vrc7: None,
+            mmc5: None,
+            namco163: None,
+            sunsoft5b: None,
+        };
+        mapper.reset();
+        mapper
+    }
+
+    // Return to the power on state before initialising a track
+    pub fn reset(&mut self) {
+        for byte in self.prg_ram.iter_mut() {
+            *byte = 0;
+        }
+        self.banks = self.initial_banks;
+        if self.fds {
+            for slot in 0..10 {
+                let bank = self.fds_banks[slot];
+                self.copy_bank(slot, bank);
+            }
+            self.fds_audio = Some(FdsAudio::new());
+        }
+        // Fresh chips, so that nothing carries over from the last track
+        self.vrc7 = self.chip(nsf::CHIP_VRC7, vrc7::Vrc7::new);
+        self.mmc5 = self.chip(nsf::CHIP_MMC5, mmc5::Mmc5::new);
+        self.namco163 = self.chip(nsf::CHIP_NAMCO163, namco163::Namco163::new);
+        self.sunsoft5b = self.chip(nsf::CHIP_SUNSOFT5B, fme7::Fme7::new);
+        if let Some(ref mut mmc5) = self.mmc5 {
+            // ExRAM behaves as plain RAM
+            mmc5.prg_storeb(0x5104, 0x02);
+        }
+    }
+
+    fn chip<M: Mapper + 'static>(&self, flag: u8, new: fn(Rom) -> M) -> Option<Box<Mapper>> {
+        if self.chips & flag != 0 {
+            Some(Box::new(new(chip_rom())))
+        } else {
+            None
+        }
+    }
+
+    fn bank_byte(&self, bank: u8, offset: usize) -> u8 {
+        self.image.get(bank as usize * 0x1000 + offset).cloned().unwrap_or(0)
+    }
+
+    // Copy a 4KB bank into FDS RAM, slot 0 being $6000
+    fn copy_bank(&mut self, slot: usize, bank: u8) {
+        for offset in 0..0x1000 {
+            self.prg_ram[slot * 0x1000 + offset] = self.bank_byte(bank, offset);
+        }
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4040...0x4097 => self.fds_audio.as_mut().map_or(0, |audio| audio.loadb(addr)),
+            0x4800...0x4FFF => self.namco163.as_mut().map_or(0, |chip| chip.prg_loadb(addr)),
+            0x5000...0x5015 | 0x5205...0x5206 | 0x5C00...0x5FF5 => {
+                self.mmc5.as_mut().map_or(0, |chip| chip.prg_loadb(addr))
+            }
+            0x6000...0xFFFF if self.fds => self.prg_ram[addr as usize - 0x6000],
+            0x6000...0x7FFF => self.prg_ram[addr as usize & 0x1FFF],
+            0x8000...0xFFFF => {
+                let bank = self.banks[(addr as usize - 0x8000) >> 12];
+                self.bank_byte(bank, addr as usize & 0x0FFF)
+            }
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040...0x4092 => {
+                if let Some(ref mut audio) = self.fds_audio {
+                    audio.storeb(addr, val);
+                }
+            }
+            0x4800...0x4FFF | 0xF800...0xFFFF if self.namco163.is_some() => {
+                if let Some(ref mut chip) = self.namco163 {
+                    chip.prg_storeb(addr, val);
+                }
+            }
+            0x5000...0x5015 | 0x5205...0x5206 | 0x5C00...0x5FF5 => {
+                if let Some(ref mut chip) = self.mmc5 {
+                    chip.prg_storeb(addr, val);
+                }
+            }
+            0x5FF6...0x5FF7 if self.fds => self.copy_bank(addr as usize - 0x5FF6, val),
+            0x5FF8...0x5FFF => {
+                let slot = addr as usize - 0x5FF8;
+                if self.fds {
+                    self.copy_bank(slot + 2, val);
+                } else {
+                    self.banks[slot] = val;
+                }
+            }
+            0x6000...0xFFFF if self.fds => self.prg_ram[addr as usize - 0x6000] = val,
+            0x6000...0x7FFF => self.prg_ram[addr as usize & 0x1FFF] = val,
+            0x9010 | 0x9030 => {
+                if let Some(ref mut chip) = self.vrc7 {
+                    chip.prg_storeb(addr, val);
+                }
+            }
+            0xC000 | 0xE000 => {
+                if let Some(ref mut chip) = self.sunsoft5b {
+                    chip.prg_storeb(addr, val);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Nothing is connected to the PPU bus
+    fn chr_loadb(&mut self, _: u16, _: &[u8]) -> u8 {
+        0
+    }
+
+    fn chr_storeb(&mut self, _: u16, _: u8, _: &mut [u8]) {}
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn step(&mut self) {
+        if let Some(ref mut audio) = self.fds_audio {
+            audio.clock();
+        }
+        for chip in [&mut self.vrc7, &mut self.mmc5, &mut self.namco163, &mut self.sunsoft5b].iter_mut() {
+            if let Some(ref mut chip) = **chip {
+                chip.step();
+            }
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        let mut output = self.fds_audio.as_ref().map_or(0.0, |audio| audio.output() * mapper::FDS_GAIN);
+        for chip in [&self.vrc7, &self.mmc5, &self.namco163, &self.sunsoft5b].iter() {
+            if let Some(ref chip) = **chip {
+                output += chip.audio_output();
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tune(chips: u8) -> Nsf {
+        let mut data = vec![0u8; 0x80];
+        data[..5].copy_from_slice(&nsf::MAGIC);
+        data[0x05] = 1;
+        data[0x06] = 2;
+        data[0x07] = 1;
+        data[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x03, 0x80]);
+        data[0x7B] = chips;
+        data.extend_from_slice(&[0x60; 0x10]);
+        Nsf::parse(&data).unwrap()
+    }
+
+    #[test]
+    fn reset_rebuilds_every_chip() {
+        let mut mapper = NsfMapper::new(&tune(nsf::CHIP_MMC5 | nsf::CHIP_NAMCO163 | nsf::CHIP_VRC7 | nsf::CHIP_SUNSOFT5B));
+        // N163 sound RAM through $F800/$4800, MMC5 ExRAM and multiplier
+        mapper.prg_storeb(0xF800, 0x00);
+        mapper.prg_storeb(0x4800, 0x5A);
+        mapper.prg_storeb(0x5C00, 0xA5);
+        mapper.prg_storeb(0x5205, 7);
+        mapper.prg_storeb(0x5206, 9);
+        assert_eq!(mapper.prg_loadb(0x5205), 63);
+
+        mapper.reset();
+        mapper.prg_storeb(0xF800, 0x00);
+        assert_eq!(mapper.prg_loadb(0x4800), 0);
+        assert_eq!(mapper.prg_loadb(0x5C00), 0);
+        assert_eq!(mapper.prg_loadb(0x5205), mmc5::Mmc5::new(chip_rom()).prg_loadb(0x5205));
+        assert!(mapper.vrc7.is_some() && mapper.sunsoft5b.is_some());
+    }
+
+    #[test]
+    fn only_the_tune_chips_are_built() {
+        let mapper = NsfMapper::new(&tune(nsf::CHIP_NAMCO163));
+        assert!(mapper.namco163.is_some());
+        assert!(mapper.vrc7.is_none() && mapper.mmc5.is_none() && mapper.sunsoft5b.is_none());
+        assert!(mapper.fds_audio.is_none());
+    }
+}
@@ -0,0 +1,501 @@
+use std::f32::consts::PI;
+
+// Rate at which clock() should be called: the 3.58MHz chip clock / 72
+pub const SAMPLE_RATE: f32 = 49716.0;
+
+const CHANNELS: usize = 6;
+
+// Built-in instruments of the VRC7, which differ from the YM2413 set.
+// Instrument 0 is the user-defined patch held in registers $00-$07.
+//
+// Byte  Bits       Operator
+// 0     AVES MMMM  Modulator: tremolo (A), vibrato (V), sustained envelope (E),
+//                  key scale rate (S), frequency multiplier (M)
+// 1     AVES MMMM  Carrier: as above
+// 2     KKLL LLLL  Modulator: key scale level (K), total level (L)
+// 3     KK-C MFFF  Carrier key scale level (K), carrier rectified (C),
+//                  modulator rectified (M), modulator feedback (F)
+// 4     AAAA DDDD  Modulator: attack rate (A), decay rate (D)
+// 5     AAAA DDDD  Carrier: as above
+// 6     SSSS RRRR  Modulator: sustain level (S), release rate (R)
+// 7     SSSS RRRR  Carrier: as above
+const PATCH_ROM: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27], // Buzzy bell
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12], // Guitar
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12], // Wurly
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27], // Flute
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28], // Clarinet
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4], // Synth
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07], // Trumpet
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17], // Organ
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01], // Bells
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02], // Vibes
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12], // Vibraphone
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16], // Tutti
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02], // Fretless
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6], // Synth bass
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06], // Sweep
+];
+
+// Frequency multiplier for each value of MMMM, in halves
+const MULTIPLIER: [u8; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// Key scale attenuation for the top 4 bits of F-Num at block 7, in 0.375dB units
+const KSL_TABLE: [u8; 16] = [0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56];
+
+// Envelope attenuation is kept in 0.375dB units, giving 48dB of range
+const ENVELOPE_MAX: f32 = 127.0;
+const ENVELOPE_STEP_DB: f32 = 0.375;
+
+// Tremolo depth in dB and rate in Hz
+const AM_DEPTH: f32 = 4.8;
+const AM_RATE: f32 = 3.7;
+// Vibrato depth in cents and rate in Hz
+const PM_DEPTH: f32 = 7.0;
+const PM_RATE: f32 = 6.4;
+
+const MODULATOR: usize = 0;
+const CARRIER: usize = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone, Copy)]
+struct Patch([u8; 8]);
+
+impl Patch {
+    fn tremolo(&self, op: usize) -> bool {
+        self.0[op] & 0x80 != 0
+    }
+
+    fn vibrato(&self, op: usize) -> bool {
+        self.0[op] & 0x40 != 0
+    }
+
+    fn sustained(&self, op: usize) -> bool {
+        self.0[op] & 0x20 != 0
+    }
+
+    fn key_scale_rate(&self, op: usize) -> bool {
+        self.0[op] & 0x10 != 0
+    }
+
+    fn multiplier(&self, op: usize) -> f32 {
+        MULTIPLIER[(self.0[op] & 0x0F) as usize] as f32 / 2.0
+    }
+
+    fn key_scale_level(&self, op: usize) -> u8 {
+        self.0[2 + op] >> 6
+    }
+
+    fn modulator_level(&self) -> u8 {
+        self.0[2] & 0x3F
+    }
+
+    fn rectified(&self, op: usize) -> bool {
+        match op {
+            MODULATOR => self.0[3] & 0x08 != 0,
+            _ => self.0[3] & 0x10 != 0,
+        }
+    }
+
+    fn feedback(&self) -> u8 {
+        self.0[3] & 0x07
+    }
+
+    fn attack_rate(&self, op: usize) -> u8 {
+        self.0[4 + op] >> 4
+    }
+
+    fn decay_rate(&self, op: usize) -> u8 {
+        self.0[4 + op] & 0x0F
+    }
+
+    fn sustain_level(&self, op: usize) -> u8 {
+        self.0[6 + op] >> 4
+    }
+
+    fn release_rate(&self, op: usize) -> u8 {
+        self.0[6 + op] & 0x0F
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Operator {
+    // Position within the waveform, in periods
+    phase: f32,
+    envelope: f32,
+    state: EnvelopeState,
+    // Two most recent outputs, used for modulator feedback
+    output: [f32; 2],
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0.0,
+            envelope: ENVELOPE_MAX,
+            state: EnvelopeState::Release,
+            output: [0.0; 2],
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key_on: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    ops: [Operator; 2],
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            block: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            ops: [Operator::new(); 2],
+        }
+    }
+
+    // Key scale offset added to the envelope rates
+    fn rate_key_scale(&self, patch: &Patch, op: usize) -> u8 {
+        let rks = (self.block << 1) | (self.fnum >> 8) as u8;
+        if patch.key_scale_rate(op) {
+            rks
+        } else {
+            rks >> 2
+        }
+    }
+
+    // Attenuation in dB from the key scale level setting
+    fn key_scale_attenuation(&self, patch: &Patch, op: usize) -> f32 {
+        let base = KSL_TABLE[(self.fnum >> 5) as usize] as f32 * ENVELOPE_STEP_DB
+            - 6.0 * (7 - self.block) as f32;
+        if base <= 0.0 {
+            return 0.0;
+        }
+        // 0, 1.5, 3 and 6 dB per octave
+        match patch.key_scale_level(op) {
+            0 => 0.0,
+            1 => base / 4.0,
+            2 => base / 2.0,
+            _ => base,
+        }
+    }
+}
+
+// Attenuation units per sample for an effective envelope rate of 0 to 63.
+// Every fourth rate doubles the speed, with the two low bits interpolating.
+fn rate_increment(rate: u8) -> f32 {
+    if rate < 4 {
+        0.0
+    } else {
+        (4 + (rate & 3)) as f32 / 4.0 * (1u32 << (rate >> 2)) as f32 / 4096.0
+    }
+}
+
+fn effective_rate(rate: u8, key_scale: u8) -> u8 {
+    if rate == 0 {
+        0
+    } else {
+        ::std::cmp::min(63, rate * 4 + key_scale)
+    }
+}
+
+// Six channel, two operator FM synthesizer compatible with the YM2413 (OPLL)
+// as embedded in the VRC7. Registers:
+//
+// $00-$07   Custom instrument, see PATCH_ROM for layout
+// $10-$15   FFFF FFFF  Low 8 bits of F-Num
+// $20-$25   --ST OOOH  Sustain (S), key on (T), octave/block (O), F-Num bit 8 (H)
+// $30-$35   IIII VVVV  Instrument (I), volume attenuation in 3dB steps (V)
+pub struct Opll {
+    custom: [u8; 8],
+    channels: [Channel; CHANNELS],
+    am_phase: f32,
+    pm_phase: f32,
+    output: f32,
+}
+
+impl Opll {
+    pub fn new() -> Opll {
+        Opll {
+            custom: [0; 8],
+            channels: [Channel::new(); CHANNELS],
+            am_phase: 0.0,
+            pm_phase: 0.0,
+            output: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Opll::new();
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        let index = (reg & 0x0F) as usize;
+        match reg {
+            0x00...0x07 => self.custom[index] = val,
+            0x10...0x15 => {
+                let ch = &mut self.channels[index];
+                ch.fnum = (ch.fnum & 0x100) | val as u16;
+            }
+            0x20...0x25 => {
+                let ch = &mut self.channels[index];
+                ch.fnum = (ch.fnum & 0xFF) | ((val as u16 & 1) << 8);
+                ch.block = (val >> 1) & 0x07;
+                ch.sustain = val & 0x20 != 0;
+
+                let key_on = val & 0x10 != 0;
+                if key_on && !ch.key_on {
+                    ch.ops[MODULATOR].key_on();
+                    ch.ops[CARRIER].key_on();
+                } else if !key_on && ch.key_on {
+                    ch.ops[MODULATOR].key_off();
+                    ch.ops[CARRIER].key_off();
+                }
+                ch.key_on = key_on;
+            }
+            0x30...0x35 => {
+                let ch = &mut self.channels[index];
+                ch.instrument = val >> 4;
+                ch.volume = val & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    // Most recently generated sample, -1.0 to 1.0
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    // Generate the next sample
+    pub fn clock(&mut self) {
+        self.am_phase = (self.am_phase + AM_RATE / SAMPLE_RATE) % 1.0;
+        self.pm_phase = (self.pm_phase + PM_RATE / SAMPLE_RATE) % 1.0;
+
+        // Triangle for tremolo, sine for vibrato
+        let am = AM_DEPTH * (1.0 - (2.0 * self.am_phase - 1.0).abs());
+        let pm = 2f32.powf(PM_DEPTH * (2.0 * PI * self.pm_phase).sin() / 1200.0);
+
+        let mut sum = 0.0;
+        for i in 0..CHANNELS {
+            let patch = self.patch(self.channels[i].instrument);
+            sum += Opll::clock_channel(&mut self.channels[i], &patch, am, pm);
+        }
+        self.output = sum / CHANNELS as f32;
+    }
+
+    fn patch(&self, instrument: u8) -> Patch {
+        match instrument {
+            0 => Patch(self.custom),
+            n => Patch(PATCH_ROM[n as usize - 1]),
+        }
+    }
+
+    fn clock_channel(ch: &mut Channel, patch: &Patch, am: f32, pm: f32) -> f32 {
+        let base_inc = ch.fnum as f32 * (1u32 << ch.block) as f32 / (1u32 << 19) as f32;
+
+        for op in 0..2 {
+            let rks = ch.rate_key_scale(patch, op);
+            let sustain = ch.sustain;
+            Opll::clock_envelope(&mut ch.ops[op], patch, op, rks, sustain);
+
+            let mut inc = base_inc * patch.multiplier(op);
+            if patch.vibrato(op) {
+                inc *= pm;
+            }
+            ch.ops[op].phase = (ch.ops[op].phase + inc) % 1.0;
+        }
+
+        // Modulator, with optional self-feedback of up to 2 periods
+        let modulator = {
+            let fb = patch.feedback();
+            let feedback = if fb == 0 {
+                0.0
+            } else {
+                let prev = ch.ops[MODULATOR].output;
+                (prev[0] + prev[1]) / 2.0 * 2f32.powi(fb as i32 - 6)
+            };
+            let level = patch.modulator_level() as f32 * 0.75
+                + ch.key_scale_attenuation(patch, MODULATOR);
+            let out = Opll::operator_output(&ch.ops[MODULATOR], patch, MODULATOR, feedback, level, am);
+            let m = &mut ch.ops[MODULATOR];
+            m.output = [m.output[1], out];
+            out
+        };
+
+        // The carrier's phase is modulated by up to 4 periods
+        let level = ch.volume as f32 * 3.0 + ch.key_scale_attenuation(patch, CARRIER);
+        Opll::operator_output(&ch.ops[CARRIER], patch, CARRIER, modulator * 4.0, level, am)
+    }
+
+    fn operator_output(op: &Operator, patch: &Patch, index: usize, phase_mod: f32, level: f32, am: f32) -> f32 {
+        if op.envelope >= ENVELOPE_MAX {
+            return 0.0;
+        }
+        let mut attenuation = op.envelope * ENVELOPE_STEP_DB + level;
+        if patch.tremolo(index) {
+            attenuation += am;
+        }
+
+        let wave = (2.0 * PI * (op.phase + phase_mod)).sin();
+        let wave = if patch.rectified(index) && wave < 0.0 { 0.0 } else { wave };
+        wave * 10f32.powf(-attenuation / 20.0)
+    }
+
+    fn clock_envelope(op: &mut Operator, patch: &Patch, index: usize, rks: u8, sustain: bool) {
+        match op.state {
+            EnvelopeState::Attack => {
+                let rate = effective_rate(patch.attack_rate(index), rks);
+                if rate >= 60 {
+                    op.envelope = 0.0;
+                } else {
+                    // Attack is exponential, slowing as it approaches full volume
+                    op.envelope -= (op.envelope + 1.0) * rate_increment(rate) / 8.0;
+                }
+                if op.envelope <= 0.0 {
+                    op.envelope = 0.0;
+                    op.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                let rate = effective_rate(patch.decay_rate(index), rks);
+                op.envelope += rate_increment(rate);
+                // Sustain level is in 3dB steps
+                let level = patch.sustain_level(index) as f32 * 8.0;
+                if op.envelope >= level {
+                    op.envelope = level;
+                    op.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                // Percussive tones keep decaying at the release rate
+                if !patch.sustained(index) {
+                    let rate = effective_rate(patch.release_rate(index), rks);
+                    op.envelope += rate_increment(rate);
+                }
+            }
+            EnvelopeState::Release => {
+                let rate = if sustain {
+                    5
+                } else if patch.sustained(index) {
+                    patch.release_rate(index)
+                } else {
+                    7
+                };
+                op.envelope += rate_increment(effective_rate(rate, rks));
+            }
+        }
+
+        if op.envelope > ENVELOPE_MAX {
+            op.envelope = ENVELOPE_MAX;
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    // Custom instrument: a modulator that never sounds and a carrier at
+    // the note's own frequency that reaches full volume at once and holds
+    pub const SINE: [u8; 8] = [0x20, 0x21, 0x3F, 0x00, 0x00, 0xF0, 0x00, 0x00];
+
+    // A440 is F-Num 290 in block 4: 290 * 2^4 / 2^19 * 49716Hz
+    pub const A440: u16 = 290;
+
+    fn rising_edges(opll: &mut Opll, samples: usize) -> usize {
+        let mut edges = 0;
+        let mut last = opll.output();
+        for _ in 0..samples {
+            opll.clock();
+            if last < 0.0 && opll.output() >= 0.0 {
+                edges += 1;
+            }
+            last = opll.output();
+        }
+        edges
+    }
+
+    fn key_on(opll: &mut Opll, fnum: u16, block: u8) {
+        for (reg, &val) in SINE.iter().enumerate() {
+            opll.write(reg as u8, val);
+        }
+        opll.write(0x30, 0x00);
+        opll.write(0x10, fnum as u8);
+        opll.write(0x20, 0x10 | block << 1 | (fnum >> 8) as u8);
+    }
+
+    #[test]
+    fn silent_until_keyed_on() {
+        let mut opll = Opll::new();
+        for _ in 0..1000 {
+            opll.clock();
+            assert_eq!(opll.output(), 0.0);
+        }
+    }
+
+    #[test]
+    fn a_note_plays_at_its_frequency() {
+        let mut opll = Opll::new();
+        key_on(&mut opll, A440, 4);
+        let edges = rising_edges(&mut opll, SAMPLE_RATE as usize / 10);
+        assert!(edges >= 43 && edges <= 45, "{} periods in 0.1s", edges);
+
+        // An octave up in block 5
+        let mut opll = Opll::new();
+        key_on(&mut opll, A440, 5);
+        let edges = rising_edges(&mut opll, SAMPLE_RATE as usize / 10);
+        assert!(edges >= 87 && edges <= 89, "{} periods in 0.1s", edges);
+    }
+
+    #[test]
+    fn one_channel_at_full_volume_peaks_at_a_sixth() {
+        let mut opll = Opll::new();
+        key_on(&mut opll, A440, 4);
+        let mut peak = 0.0f32;
+        for _ in 0..1000 {
+            opll.clock();
+            peak = peak.max(opll.output());
+        }
+        assert!((peak - 1.0 / 6.0).abs() < 0.001, "peak {}", peak);
+    }
+
+    #[test]
+    fn volume_attenuates_in_3db_steps() {
+        let mut opll = Opll::new();
+        key_on(&mut opll, A440, 4);
+        opll.write(0x30, 0x02);
+        let mut peak = 0.0f32;
+        for _ in 0..1000 {
+            opll.clock();
+            peak = peak.max(opll.output());
+        }
+        // 6dB down is half the amplitude
+        assert!((peak - 1.0 / 12.0).abs() < 0.001, "peak {}", peak);
+    }
+}
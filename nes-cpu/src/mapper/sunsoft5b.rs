@@ -0,0 +1,383 @@
+// The 5B divides the CPU clock by 16 before the tone and noise counters,
+// and toggles the square output on each expiry
+const TONE_DIVIDER: u16 = 16;
+
+// Envelope steps run twice as fast as the AY-3-8910's since the 5B's
+// envelope has 32 steps instead of 16 in the same overall period
+const ENVELOPE_DIVIDER: u16 = 16;
+
+#[derive(Clone, Copy)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    output: bool,
+}
+
+impl Tone {
+    fn new() -> Tone {
+        Tone {
+            period: 0,
+            counter: 0,
+            output: false,
+        }
+    }
+
+    fn clock(&mut self) {
+        self.counter += 1;
+        // A period of zero behaves as one
+        if self.counter >= ::std::cmp::max(self.period, 1) {
+            self.counter = 0;
+            self.output = !self.output;
+        }
+    }
+}
+
+// Three channel square wave generator of the Sunsoft 5B, an AY-3-8910
+// derivative with a finer volume curve. Registers:
+//
+// $00-$05   Channel A-C tone period, low 8 bits then high 4 bits
+// $06       Noise period (5 bits)
+// $07       --CB Acba  Noise disable for channels C-A, tone disable for c-a
+// $08-$0A   ---E VVVV  Channel A-C envelope mode (E), volume (V)
+// $0B-$0C   Envelope period, low then high byte
+// $0D       ---- CAaH  Envelope shape: continue (C), attack (A),
+//                      alternate (a), hold (H)
+pub struct Sunsoft5b {
+    regs: [u8; 16],
+    tones: [Tone; 3],
+    prescaler: u16,
+
+    noise_period: u8,
+    noise_counter: u8,
+    // 17-bit LFSR
+    noise_shift: u32,
+    noise_toggle: bool,
+
+    envelope_period: u16,
+    envelope_counter: u16,
+    envelope_divider: u16,
+    // Position in the 32 step envelope, 0-31
+    envelope_step: u8,
+    envelope_attack: bool,
+    envelope_holding: bool,
+}
+
+impl Sunsoft5b {
+    pub fn new() -> Sunsoft5b {
+        Sunsoft5b {
+            regs: [0; 16],
+            tones: [Tone::new(); 3],
+            prescaler: 0,
+
+            noise_period: 0,
+            noise_counter: 0,
+            noise_shift: 1,
+            noise_toggle: false,
+
+            envelope_period: 0,
+            envelope_counter: 0,
+            envelope_divider: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        let reg = reg & 0x0F;
+        self.regs[reg as usize] = val;
+        match reg {
+            0x00...0x05 => {
+                let ch = (reg >> 1) as usize;
+                let lo = self.regs[ch * 2] as u16;
+                let hi = (self.regs[ch * 2 + 1] & 0x0F) as u16;
+                self.tones[ch].period = hi << 8 | lo;
+            }
+            0x06 => self.noise_period = val & 0x1F,
+            0x0B | 0x0C => {
+                self.envelope_period = (self.regs[0x0C] as u16) << 8 | self.regs[0x0B] as u16;
+            }
+            0x0D => {
+                // Writing the shape restarts the envelope
+                self.envelope_attack = val & 0x04 != 0;
+                self.envelope_step = 0;
+                self.envelope_holding = false;
+                self.envelope_counter = 0;
+                self.envelope_divider = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Advance by one CPU cycle
+    pub fn clock(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler == TONE_DIVIDER {
+            self.prescaler = 0;
+            for tone in self.tones.iter_mut() {
+                tone.clock();
+            }
+            self.clock_noise();
+        }
+
+        self.envelope_divider += 1;
+        if self.envelope_divider == ENVELOPE_DIVIDER {
+            self.envelope_divider = 0;
+            self.envelope_counter += 1;
+            if self.envelope_counter >= ::std::cmp::max(self.envelope_period, 1) {
+                self.envelope_counter = 0;
+                self.clock_envelope();
+            }
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        self.noise_counter += 1;
+        if self.noise_counter >= ::std::cmp::max(self.noise_period, 1) {
+            self.noise_counter = 0;
+            // The LFSR is clocked at half the rate of the period counter
+            self.noise_toggle = !self.noise_toggle;
+            if self.noise_toggle {
+                let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+                self.noise_shift = (self.noise_shift >> 1) | (feedback << 16);
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        if self.envelope_holding {
+            return;
+        }
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let shape = self.regs[0x0D];
+        let cont = shape & 0x08 != 0;
+        let alternate = shape & 0x02 != 0;
+        let hold = shape & 0x01 != 0;
+        if !cont {
+            // Shapes 0-7 drop to silence and stay there
+            self.envelope_attack = false;
+            self.envelope_step = 31;
+            self.envelope_holding = true;
+        } else if hold {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_holding = true;
+        } else {
+            if alternate {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            self.envelope_step = 0;
+        }
+    }
+
+    // Envelope level on the 5-bit volume scale, 0-31
+    fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            31 - self.envelope_step
+        }
+    }
+
+    // Current sample, 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        let mixer = self.regs[0x07];
+        let noise = self.noise_shift & 1 != 0;
+        let mut sum = 0.0;
+        for ch in 0..3 {
+            let tone_on = self.tones[ch].output || mixer & (1 << ch) != 0;
+            let noise_on = noise || mixer & (8 << ch) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+
+            let vol = self.regs[0x08 + ch];
+            // Fixed volume is in 3dB steps, the envelope in 1.5dB steps
+            let level = if vol & 0x10 != 0 {
+                self.envelope_level()
+            } else if vol & 0x0F == 0 {
+                0
+            } else {
+                (vol & 0x0F) * 2 + 1
+            };
+            if level > 0 {
+                sum += 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0);
+            }
+        }
+        sum / 3.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CPU cycles between changes of the output, for the first few changes
+    fn toggles(chip: &mut Sunsoft5b, count: usize) -> Vec<usize> {
+        let mut out = Vec::new();
+        let mut last = chip.output();
+        let mut since = 0;
+        while out.len() < count {
+            chip.clock();
+            since += 1;
+            if chip.output() != last {
+                out.push(since);
+                since = 0;
+                last = chip.output();
+            }
+            assert!(since < 0x100000, "the output stopped changing");
+        }
+        out
+    }
+
+    // Channel A alone at full volume, with its tone on and noise off
+    fn channel_a() -> Sunsoft5b {
+        let mut chip = Sunsoft5b::new();
+        chip.write(0x07, 0x3E);
+        chip.write(0x08, 0x0F);
+        chip
+    }
+
+    #[test]
+    fn the_square_toggles_every_16_cpu_cycles_per_period_step() {
+        let mut chip = channel_a();
+        // Only the low 4 bits of the high byte count
+        chip.write(0x00, 0x23);
+        chip.write(0x01, 0xF1);
+        let toggles = toggles(&mut chip, 4);
+        assert_eq!(&toggles[1..], &[16 * 0x123, 16 * 0x123, 16 * 0x123]);
+
+        // A period of zero acts as one
+        let mut chip = channel_a();
+        assert_eq!(&self::toggles(&mut chip, 4)[1..], &[16, 16, 16]);
+    }
+
+    #[test]
+    fn the_square_swings_between_silence_and_its_volume() {
+        let mut chip = channel_a();
+        let mut levels = Vec::new();
+        for _ in 0..64 {
+            chip.clock();
+            if !levels.contains(&chip.output()) {
+                levels.push(chip.output());
+            }
+        }
+        levels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(levels, vec![0.0, 1.0 / 3.0]);
+    }
+
+    #[test]
+    fn fixed_volumes_fall_3db_a_step() {
+        let mut chip = Sunsoft5b::new();
+        chip.write(0x07, 0x3F);
+        chip.write(0x08, 0x0F);
+        let full = chip.output();
+        chip.write(0x08, 0x0D);
+        let db = 20.0 * (chip.output() / full).log10();
+        assert!((db + 6.0).abs() < 0.01, "{}dB", db);
+        chip.write(0x08, 0x00);
+        assert_eq!(chip.output(), 0.0);
+    }
+
+    #[test]
+    fn mixer_bits_disable_tone_and_noise() {
+        // With both disabled a channel holds its volume
+        let mut chip = channel_a();
+        chip.write(0x07, 0x3F);
+        for _ in 0..1000 {
+            chip.clock();
+            assert_eq!(chip.output(), 1.0 / 3.0);
+        }
+
+        // Bits 0-2 are the tones of channels A-C, 3-5 their noise
+        let mut chip = channel_a();
+        chip.write(0x07, 0x3D);
+        chip.write(0x09, 0x0F);
+        chip.write(0x02, 0x10);
+        assert_eq!(&toggles(&mut chip, 3)[1..], &[16 * 0x10, 16 * 0x10]);
+        // Channel A's tone is off so it holds its level, and muting it
+        // leaves channel B's square as it was
+        chip.write(0x08, 0x00);
+        assert_eq!(&toggles(&mut chip, 3)[1..], &[16 * 0x10, 16 * 0x10]);
+
+        // Noise alone on channel A
+        let mut chip = channel_a();
+        chip.write(0x07, 0x37);
+        let toggles = toggles(&mut chip, 32);
+        // The LFSR changes at most once every 2 noise periods of 16 cycles
+        assert!(toggles[1..].iter().all(|&cycles| cycles % 32 == 0));
+        // and isn't a square
+        assert!(toggles[1..].iter().any(|&cycles| cycles != toggles[1]));
+    }
+
+    // Envelope level at each of its steps, for the given shape
+    fn envelope(shape: u8, steps: usize) -> Vec<u8> {
+        let mut chip = Sunsoft5b::new();
+        chip.write(0x0B, 0x01);
+        chip.write(0x0C, 0x00);
+        chip.write(0x0D, shape);
+        let mut out = vec![chip.envelope_level()];
+        for _ in 1..steps {
+            for _ in 0..ENVELOPE_DIVIDER {
+                chip.clock();
+            }
+            out.push(chip.envelope_level());
+        }
+        out
+    }
+
+    #[test]
+    fn envelope_shapes_8_to_f() {
+        let down: Vec<u8> = (0..32).rev().collect();
+        let up: Vec<u8> = (0..32).collect();
+        let low = vec![0; 32];
+        let high = vec![31; 32];
+        // Three rounds of 32 steps, held shapes staying where they end
+        let shapes: [(u8, [&Vec<u8>; 3]); 8] = [
+            (0x08, [&down, &down, &down]),
+            (0x09, [&down, &low, &low]),
+            (0x0A, [&down, &up, &down]),
+            (0x0B, [&down, &high, &high]),
+            (0x0C, [&up, &up, &up]),
+            (0x0D, [&up, &high, &high]),
+            (0x0E, [&up, &down, &up]),
+            (0x0F, [&up, &low, &low]),
+        ];
+        for &(shape, ref rounds) in shapes.iter() {
+            let expected = [&rounds[0][..], &rounds[1][..], &rounds[2][..]].concat();
+            assert_eq!(envelope(shape, 96), expected, "shape {:02X}", shape);
+        }
+    }
+
+    #[test]
+    fn envelope_steps_every_16_cpu_cycles_per_period_step() {
+        let mut chip = Sunsoft5b::new();
+        chip.write(0x0B, 0x34);
+        chip.write(0x0C, 0x02);
+        chip.write(0x0D, 0x0C);
+        for step in 0..4 {
+            assert_eq!(chip.envelope_level(), step);
+            for _ in 0..16 * 0x234 {
+                chip.clock();
+            }
+        }
+    }
+
+    #[test]
+    fn channels_set_to_the_envelope_follow_it() {
+        let mut chip = Sunsoft5b::new();
+        chip.write(0x07, 0x3F);
+        chip.write(0x08, 0x10);
+        chip.write(0x0D, 0x0D);
+        assert_eq!(chip.output(), 0.0);
+        for _ in 0..32 * 16 {
+            chip.clock();
+        }
+        assert_eq!(chip.output(), 1.0 / 3.0);
+    }
+}
@@ -0,0 +1,373 @@
+use mapper::{self, Mapper, Mirroring};
+use mapper::opll::Opll;
+use rom::Rom;
+
+// The FM core produces a sample every 72 cycles of the 3.58MHz clock
+// fed to the VRC7, which is 36 CPU cycles
+const CPU_CYCLES_PER_SAMPLE: u8 = 36;
+
+// IRQ counter shared by the VRC4, VRC6 and VRC7
+struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    // Divides CPU cycles down to scanlines (341 / 3 = 113.667)
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    // Clock the counter every CPU cycle rather than every scanline
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    fn write_control(&mut self, val: u8) {
+        self.enable_after_ack = val & 0x01 != 0;
+        self.enabled = val & 0x02 != 0;
+        self.cycle_mode = val & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock();
+            }
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+// Mapper 85: Konami VRC7
+//
+// Register  Function
+// $8000     8KB PRG bank at $8000
+// $8010     8KB PRG bank at $A000
+// $9000     8KB PRG bank at $C000
+// $9010     Audio register select
+// $9030     Audio register data
+// $A000-    1KB CHR banks 0-7, two per $1000 block ($A000, $A010, ... $D010)
+// $E000     RS-- --MM  WRAM enable (R), audio reset (S), mirroring (M)
+// $E010     IRQ latch
+// $F000     IRQ control
+// $F010     IRQ acknowledge
+//
+// $E000-$FFFF is fixed to the last 8KB PRG bank.
+pub struct Vrc7 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    wram: Vec<u8>,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    control: u8,
+    irq: VrcIrq,
+    audio: Opll,
+    audio_select: u8,
+    audio_divider: u8,
+}
+
+impl Vrc7 {
+    pub fn new(rom: Rom) -> Vrc7 {
+        let chr_is_ram = rom.chr.is_empty();
+        let wram = rom.prg_ram(0x2000);
+        Vrc7 {
+            prg: rom.prg,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            wram: wram,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Opll::new(),
+            audio_select: 0,
+            audio_divider: 0,
+        }
+    }
+
+    // VRC7a (Lagrange Point) selects the second register of each pair
+    // with A4 while VRC7b (Tiny Toon Adventures 2) uses A3. Fold both onto
+    // $x010, leaving the audio ports which are decoded on A4 and A5.
+    fn register(addr: u16) -> u16 {
+        match addr & 0xF030 {
+            0x9010 | 0x9030 => addr & 0xF030,
+            _ if addr & 0x0018 != 0 => (addr & 0xF000) | 0x0010,
+            _ => addr & 0xF000,
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7] as usize;
+        mapper::bank_offset(bank, 0x400, self.chr.len()) + (addr as usize & 0x3FF)
+    }
+
+    fn wram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        let len = self.prg.len();
+        let bank = match addr {
+            0x6000...0x7FFF => {
+                return if self.wram_enabled() {
+                    mapper::ram_offset(addr as usize & 0x1FFF, self.wram.len()).map_or(0, |offset| self.wram[offset])
+                } else {
+                    0
+                };
+            }
+            0x8000...0x9FFF => self.prg_banks[0] as usize,
+            0xA000...0xBFFF => self.prg_banks[1] as usize,
+            0xC000...0xDFFF => self.prg_banks[2] as usize,
+            0xE000...0xFFFF => len / 0x2000 - 1,
+            _ => return 0,
+        };
+        self.prg[mapper::bank_offset(bank, 0x2000, len) + (addr as usize & 0x1FFF)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            if addr >= 0x6000 && self.wram_enabled() {
+                if let Some(offset) = mapper::ram_offset(addr as usize & 0x1FFF, self.wram.len()) {
+                    self.wram[offset] = val;
+                }
+            }
+            return;
+        }
+
+        match Vrc7::register(addr) {
+            0x8000 => self.prg_banks[0] = val & 0x3F,
+            0x8010 => self.prg_banks[1] = val & 0x3F,
+            0x9000 => self.prg_banks[2] = val & 0x3F,
+            0x9010 => self.audio_select = val,
+            0x9030 => {
+                let reg = self.audio_select;
+                self.audio.write(reg, val);
+            }
+            reg @ 0xA000...0xD010 => {
+                let index = (((reg - 0xA000) >> 12) << 1) | ((reg >> 4) & 1);
+                self.chr_banks[index as usize] = val;
+            }
+            0xE000 => {
+                if val & 0x40 != 0 {
+                    self.audio.reset();
+                }
+                self.control = val;
+            }
+            0xE010 => self.irq.latch = val,
+            0xF000 => self.irq.write_control(val),
+            0xF010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenLower,
+            _ => Mirroring::SingleScreenUpper,
+        }
+    }
+
+    fn step(&mut self) {
+        self.irq.step();
+
+        self.audio_divider += 1;
+        if self.audio_divider == CPU_CYCLES_PER_SAMPLE {
+            self.audio_divider = 0;
+            // The audio reset bit holds the FM core silent
+            if self.control & 0x40 == 0 {
+                self.audio.clock();
+            }
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output() * mapper::VRC7_GAIN
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::opll::tests::{A440, SINE};
+    use mapper::tests::rom;
+
+    fn vrc7() -> Vrc7 {
+        Vrc7::new(rom(85, 0x10000))
+    }
+
+    // CPU cycles until the IRQ fires, up to limit
+    fn cycles_to_irq(mapper: &mut Vrc7, limit: usize) -> Option<usize> {
+        for cycle in 1..limit + 1 {
+            mapper.step();
+            if mapper.irq_pending() {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn the_irq_counts_scanlines_of_341_3_cpu_cycles() {
+        let mut mapper = vrc7();
+        // 16 clocks take the counter from $F0 past $FF
+        mapper.prg_storeb(0xE010, 0xF0);
+        mapper.prg_storeb(0xF000, 0x02);
+        // 16 * 341 / 3 = 1818.67 cycles
+        assert_eq!(cycles_to_irq(&mut mapper, 5000), Some(1819));
+
+        // The counter reloads from the latch and carries on
+        mapper.prg_storeb(0xF010, 0);
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn cycle_mode_counts_every_cpu_cycle() {
+        let mut mapper = vrc7();
+        mapper.prg_storeb(0xE010, 0xF0);
+        mapper.prg_storeb(0xF000, 0x06);
+        assert_eq!(cycles_to_irq(&mut mapper, 5000), Some(16));
+    }
+
+    #[test]
+    fn acknowledging_moves_the_enable_after_ack_bit_into_enable() {
+        let mut mapper = vrc7();
+        mapper.prg_storeb(0xE010, 0xFE);
+        mapper.prg_storeb(0xF000, 0x07);
+        assert_eq!(cycles_to_irq(&mut mapper, 100), Some(2));
+        mapper.prg_storeb(0xF010, 0);
+        assert_eq!(cycles_to_irq(&mut mapper, 100), Some(2));
+
+        mapper.prg_storeb(0xF000, 0x06);
+        assert_eq!(cycles_to_irq(&mut mapper, 100), Some(2));
+        mapper.prg_storeb(0xF010, 0);
+        assert_eq!(cycles_to_irq(&mut mapper, 100), None);
+    }
+
+    #[test]
+    fn audio_ports_are_9010_and_9030() {
+        assert_eq!(Vrc7::register(0x9010), 0x9010);
+        assert_eq!(Vrc7::register(0x9030), 0x9030);
+        // VRC7b's A3 wiring leaves the audio ports alone
+        assert_eq!(Vrc7::register(0x9008), 0x9010);
+        assert_eq!(Vrc7::register(0x9000), 0x9000);
+
+        let mut mapper = vrc7();
+        mapper.prg_storeb(0x9000, 3);
+        mapper.prg_storeb(0x9010, 0x30);
+        mapper.prg_storeb(0x9030, 0x00);
+        assert_eq!(mapper.prg_banks[2], 3);
+        assert_eq!(mapper.audio_select, 0x30);
+    }
+
+    fn play_a440(mapper: &mut Vrc7) {
+        for (reg, &val) in SINE.iter().enumerate() {
+            mapper.prg_storeb(0x9010, reg as u8);
+            mapper.prg_storeb(0x9030, val);
+        }
+        for &(reg, val) in [(0x30, 0x00), (0x10, A440 as u8), (0x20, 0x18 | (A440 >> 8) as u8)].iter() {
+            mapper.prg_storeb(0x9010, reg);
+            mapper.prg_storeb(0x9030, val);
+        }
+    }
+
+    #[test]
+    fn a_note_written_to_the_ports_plays_at_its_frequency() {
+        let mut mapper = vrc7();
+        play_a440(&mut mapper);
+        // A period of 49716 / 440 samples, each 36 CPU cycles
+        let (mut edges, mut last) = (0, 0.0);
+        for _ in 0..(SAMPLE_RATE_CYCLES / 10) {
+            mapper.step();
+            let out = mapper.audio_output();
+            if last < 0.0 && out >= 0.0 {
+                edges += 1;
+            }
+            last = out;
+        }
+        assert!(edges >= 43 && edges <= 45, "{} periods in 0.1s", edges);
+    }
+
+    // CPU cycles in one second of FM samples
+    const SAMPLE_RATE_CYCLES: usize = 49716 * CPU_CYCLES_PER_SAMPLE as usize;
+
+    #[test]
+    fn a_full_volume_channel_mixes_11db_over_a_2a03_pulse() {
+        let mut mapper = vrc7();
+        play_a440(&mut mapper);
+        let (mut low, mut high) = (0.0f32, 0.0f32);
+        for _ in 0..SAMPLE_RATE_CYCLES / 100 {
+            mapper.step();
+            low = low.min(mapper.audio_output());
+            high = high.max(mapper.audio_output());
+        }
+        let pulse = 95.88 / (8128.0 / 15.0 + 100.0);
+        let db = 20.0 * ((high - low) / pulse).log10();
+        assert!((db - 11.0).abs() < 0.1, "{}dB", db);
+    }
+
+    #[test]
+    fn the_audio_reset_bit_silences_the_chip() {
+        let mut mapper = vrc7();
+        play_a440(&mut mapper);
+        mapper.prg_storeb(0xE000, 0x40);
+        for _ in 0..1000 {
+            mapper.step();
+            assert_eq!(mapper.audio_output(), 0.0);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use mapper::{self, Mapper, Mirroring};
+use rom::Rom;
+
+// Mapper 99: the default Vs. System board
+//
+// PRG ROM is fixed at $8000-$FFFF like NROM, and 2KB of RAM shared with
+// the other CPU of a dual system sits at $6000-$7FFF. The bank select bit
+// of $4016 writes picks the 8KB CHR bank. Gumshoe's 40KB of PRG ROM puts
+// the extra 8KB at $8000 when the bit is set as well.
+pub struct VsBoard {
+    prg: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr: Vec<u8>,
+    chr_is_ram: bool,
+    mirroring: Mirroring,
+    // Nametable RAM, as the cabinets wire the PPU for four screens
+    vram: Vec<u8>,
+    bank: usize,
+}
+
+impl VsBoard {
+    pub fn new(rom: Rom) -> VsBoard {
+        let chr_is_ram = rom.chr.is_empty();
+        let prg_ram = rom.prg_ram(0x800);
+        let mirroring = rom.header.mirroring;
+        VsBoard {
+            prg: rom.prg,
+            prg_ram: prg_ram,
+            chr: if chr_is_ram { vec![0u8; 0x2000] } else { rom.chr },
+            chr_is_ram: chr_is_ram,
+            mirroring: mirroring,
+            vram: if mirroring == Mirroring::FourScreen { vec![0u8; 0x1000] } else { Vec::new() },
+            bank: 0,
+        }
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        mapper::bank_offset(self.bank, 0x2000, self.chr.len()) + (addr as usize & 0x1FFF)
+    }
+}
+
+impl Mapper for VsBoard {
+    fn prg_loadb(&mut self, addr: u16) -> u8 {
+        if addr >= 0x6000 && addr < 0x8000 {
+            return mapper::ram_offset(addr as usize, self.prg_ram.len()).map_or(0, |offset| self.prg_ram[offset]);
+        }
+        if addr < 0x8000 || self.prg.is_empty() {
+            return 0;
+        }
+        let len = self.prg.len();
+        if len > 0x8000 && addr < 0xA000 {
+            // Banks 0 and 4 of a 40KB image
+            return self.prg[self.bank * 0x8000 + (addr as usize & 0x1FFF)];
+        }
+        // 16KB images are mirrored into $C000-$FFFF
+        self.prg[(addr as usize - 0x8000) % len.min(0x8000)]
+    }
+
+    fn prg_storeb(&mut self, addr: u16, val: u8) {
+        if addr >= 0x6000 && addr < 0x8000 {
+            if let Some(offset) = mapper::ram_offset(addr as usize, self.prg_ram.len()) {
+                self.prg_ram[offset] = val;
+            }
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: u16, _: &[u8]) -> u8 {
+        self.chr[self.chr_addr(addr)]
+    }
+
+    fn chr_storeb(&mut self, addr: u16, val: u8, _: &mut [u8]) {
+        if self.chr_is_ram {
+            let index = self.chr_addr(addr);
+            self.chr[index] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn nametable_loadb(&mut self, addr: u16, ciram: &[u8]) -> u8 {
+        let offset = mapper::nametable_offset(self.mirroring, addr);
+        if self.vram.is_empty() {
+            ciram[offset]
+        } else {
+            self.vram[offset]
+        }
+    }
+
+    fn nametable_storeb(&mut self, addr: u16, val: u8, ciram: &mut [u8]) {
+        let offset = mapper::nametable_offset(self.mirroring, addr);
+        if self.vram.is_empty() {
+            ciram[offset] = val;
+        } else {
+            self.vram[offset] = val;
+        }
+    }
+
+    fn vs_bank_select(&mut self, bank: bool) {
+        self.bank = bank as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper::tests::rom;
+    use rom::INesHeader;
+
+    #[test]
+    fn the_bank_select_bit_switches_chr() {
+        let mut rom = rom(99, 0x8000);
+        rom.chr = vec![0; 0x4000];
+        rom.chr[0x0010] = 0x11;
+        rom.chr[0x2010] = 0x22;
+        let mut mapper = VsBoard::new(rom);
+        assert_eq!(mapper.chr_loadb(0x0010, &[]), 0x11);
+        mapper.vs_bank_select(true);
+        assert_eq!(mapper.chr_loadb(0x0010, &[]), 0x22);
+    }
+
+    #[test]
+    fn forty_kb_prg_switches_8000_with_the_chr() {
+        let mut rom = rom(99, 0xA000);
+        for (i, byte) in rom.prg.iter_mut().enumerate() {
+            *byte = (i / 0x2000) as u8;
+        }
+        let mut mapper = VsBoard::new(rom);
+        assert_eq!(mapper.prg_loadb(0x8000), 0);
+        assert_eq!(mapper.prg_loadb(0xA000), 1);
+        assert_eq!(mapper.prg_loadb(0xFFFF), 3);
+        mapper.vs_bank_select(true);
+        assert_eq!(mapper.prg_loadb(0x9FFF), 4);
+        assert_eq!(mapper.prg_loadb(0xE000), 3);
+    }
+
+    #[test]
+    fn ram_is_2kb_mirrored() {
+        let mut rom = rom(99, 0x8000);
+        rom.header = INesHeader::builder().mapper(99).prg_ram_size(0x800).build().unwrap();
+        let mut mapper = VsBoard::new(rom);
+        mapper.prg_storeb(0x6001, 0x5A);
+        assert_eq!(mapper.prg_loadb(0x7801), 0x5A);
+    }
+}
@@ -1,8 +1,16 @@
 use ppu::Ppu;
 use apu::Apu;
+use fds::{self, FdsImage};
 use ioport::IoPort;
+use mapper::{self, Mapper};
+use mapper::fds::Fds;
+use region::Region;
+use rom::{Rom, RomError};
+use vs::VsSystem;
 
+use std::cell::RefCell;
 use std::ops::Deref;
+use std::rc::Rc;
 
 pub trait Mem {
     // Retrieve a byte at the given 0-based address
@@ -14,7 +22,7 @@ pub trait Mem {
 
     fn loadw(&mut self, addr: u16) -> u16 {
         let lower = self.loadb(addr);
-        let higher = self.loadb(addr + 1);
+        let higher = self.loadb(addr.wrapping_add(1));
         lower as u16 | (higher as u16) << 8
     }
 }
@@ -23,6 +31,12 @@ pub struct Ram {
     mem: [u8; 0x0800],
 }
 
+impl Ram {
+    pub fn new() -> Ram {
+        Ram { mem: [0; 0x0800] }
+    }
+}
+
 impl Deref for Ram {
     type Target = [u8; 0x0800];
 
@@ -58,10 +72,148 @@ pub struct MemoryMap {
     apu_regs: Apu,
     joy1: IoPort,
     joy2: IoPort,
-    // TODO: Mappers
+    // Shared with the PPU, which reads the pattern tables through it
+    mapper: Rc<RefCell<Box<Mapper>>>,
+    // Cabinet inputs and outputs of Vs. System games
+    vs: Option<VsSystem>,
+    // Set by a $4014 write until the CPU has waited out the OAM DMA
+    oam_dma: bool,
+    // CPU cycles stolen by DMC sample fetches since last asked
+    dmc_cycles: u64,
+    region: Region,
+    // Fraction of a PPU dot owed from the last run_ppu, in units of
+    // 1 / the ratio's denominator
+    ppu_remainder: u64,
 }
 
 impl MemoryMap {
+    pub fn new(mapper: Box<Mapper>, vs: Option<VsSystem>) -> MemoryMap {
+        let mapper = Rc::new(RefCell::new(mapper));
+        let mut ppu = Ppu::new(mapper.clone());
+        if let Some(ref vs) = vs {
+            ppu.set_vs_ppu(vs.ppu);
+        }
+        MemoryMap {
+            ram: Ram::new(),
+            ppu_regs: ppu,
+            apu_regs: Apu::new(),
+            joy1: IoPort::new(),
+            joy2: IoPort::new(),
+            mapper: mapper,
+            vs: vs,
+            oam_dma: false,
+            dmc_cycles: 0,
+            region: Region::Ntsc,
+            ppu_remainder: 0,
+        }
+    }
+
+    // Build the bus for a game, running it as the region its header asks
+    // for unless overridden
+    pub fn from_rom(rom: Rom, region_override: Option<Region>) -> Result<MemoryMap, RomError> {
+        let region = Region::select(&rom.header, region_override);
+        let vs = VsSystem::new(&rom);
+        let mapper = try!(mapper::create(rom));
+        let mut mem = MemoryMap::new(mapper, vs);
+        mem.set_region(region);
+        Ok(mem)
+    }
+
+    // Build the bus for a Famicom Disk System with the image's first side
+    // inserted, the BIOS being the 8KB disksys.rom
+    pub fn from_fds(image: FdsImage, bios: Vec<u8>) -> Result<MemoryMap, RomError> {
+        if bios.len() != fds::BIOS_SIZE || image.sides.is_empty() {
+            return Err(RomError::FormatError);
+        }
+        Ok(MemoryMap::new(Box::new(Fds::new(bios, image)), None))
+    }
+
+    // Run as the given region's console. Best done before power on, as
+    // changing it mid-frame leaves the PPU on a line the region may not
+    // have.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu_remainder = 0;
+        self.ppu_regs.set_region(region);
+        self.apu_regs.set_region(region);
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Run the PPU for as long as the CPU took for the given cycles: three
+    // dots a cycle, or 3.2 on PAL with the fifths carried to the next call
+    pub fn run_ppu(&mut self, cpu_cycles: u64) {
+        let (num, den) = self.region.ppu_ratio();
+        let total = cpu_cycles * num + self.ppu_remainder;
+        self.ppu_remainder = total % den;
+        for _ in 0..total / den {
+            self.ppu_regs.step();
+        }
+    }
+
+    // Run everything else on the bus for the given CPU cycles: the PPU,
+    // the cartridge, the Vs. System cabinet, and the APU with the DMC's
+    // reads of its samples and the cartridge's expansion audio
+    pub fn clock(&mut self, cpu_cycles: u64) {
+        for _ in 0..cpu_cycles {
+            self.run_ppu(1);
+            if let Some(ref mut vs) = self.vs {
+                vs.step();
+            }
+            let expansion = {
+                let mut mapper = self.mapper.borrow_mut();
+                mapper.step();
+                mapper.audio_output()
+            };
+            self.apu_regs.step(expansion);
+            if let Some(addr) = self.apu_regs.dmc_request() {
+                let val = self.loadb(addr);
+                self.apu_regs.dmc_fill(val);
+                self.dmc_cycles += 4;
+            }
+        }
+    }
+
+    // Take the cycles the CPU is halted for by DMA since last asked. An
+    // OAM DMA takes 513, or 514 when the $4014 write was on an odd cycle
+    // and it waits an extra one to line up with the APU. Each DMC sample
+    // fetch takes 4.
+    pub fn take_dma_cycles(&mut self, odd_cycle: bool) -> u64 {
+        let mut cycles = self.dmc_cycles;
+        self.dmc_cycles = 0;
+        if self.oam_dma {
+            self.oam_dma = false;
+            cycles += if odd_cycle { 514 } else { 513 };
+        }
+        cycles
+    }
+
+    // Whether the cartridge or the APU is asserting /IRQ
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.borrow().irq_pending() || self.apu_regs.irq_pending()
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu_regs
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu_regs
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu_regs
+    }
+
+    pub fn vs(&self) -> Option<&VsSystem> {
+        self.vs.as_ref()
+    }
+
+    pub fn vs_mut(&mut self) -> Option<&mut VsSystem> {
+        self.vs.as_mut()
+    }
 }
 
 impl Mem for MemoryMap {
@@ -75,18 +227,29 @@ impl Mem for MemoryMap {
                 // PPU is mirrored every 8 bytes
                 self.ppu_regs.loadb(addr % 8)
             }
-            0x4000...0x4015 => {
+            0x4000...0x4013 | 0x4015 => {
                 self.apu_regs.loadb(addr - 0x4000)
             }
             0x4016 => {
-                self.joy1.loadb(0)
+                match self.vs {
+                    Some(ref vs) => {
+                        let serial = if vs.swaps_controllers() { self.joy2.loadb(0) } else { self.joy1.loadb(0) };
+                        vs.read_4016(serial)
+                    }
+                    None => self.joy1.loadb(0),
+                }
             }
             0x4017 => {
-                self.joy2.loadb(0)
+                match self.vs {
+                    Some(ref vs) => {
+                        let serial = if vs.swaps_controllers() { self.joy1.loadb(0) } else { self.joy2.loadb(0) };
+                        vs.read_4017(serial)
+                    }
+                    None => self.joy2.loadb(0),
+                }
             }
             0x4020...0xFFFF => {
-                // TODO
-                0
+                self.mapper.borrow_mut().prg_loadb(addr)
             }
             _ => {
                 self.ram.loadb(addr)
@@ -97,26 +260,156 @@ impl Mem for MemoryMap {
     fn storeb(&mut self, addr: u16, value: u8) {
         match addr {
             0x0000...0x1FFF => {
-                self.ram.storeb(addr & 0x07FFF, value);
+                self.ram.storeb(addr & 0x07FF, value);
             }
             0x2000...0x3FFF => {
                 self.ppu_regs.storeb(addr % 8, value);
+                self.mapper.borrow_mut().ppu_register_storeb(addr % 8, value);
             }
-            0x4000...0x4015 => {
+            0x4000...0x4013 | 0x4015 => {
                 self.apu_regs.storeb(addr - 0x4000, value);
             }
+            0x4014 => {
+                // OAM DMA copies a page of CPU memory to OAMDATA, halting
+                // the CPU for the cycles take_dma_cycles hands out
+                let page = (value as u16) << 8;
+                for offset in 0..256 {
+                    let val = self.loadb(page | offset);
+                    self.ppu_regs.storeb(4, val);
+                }
+                self.oam_dma = true;
+            }
             0x4016 => {
                 self.joy1.storeb(0, value);
+                self.joy2.storeb(0, value);
+                if let Some(ref mut vs) = self.vs {
+                    vs.write_4016(value);
+                    self.mapper.borrow_mut().vs_bank_select(vs.bank_select());
+                }
             }
             0x4017 => {
-                self.joy2.storeb(0, value);
+                // The frame counter shares its address with the second
+                // controller port, which ignores writes
+                self.apu_regs.storeb(addr - 0x4000, value);
+            }
+            0x4020 if self.vs.is_some() => {
+                if let Some(ref mut vs) = self.vs {
+                    vs.write_4020(value);
+                }
             }
             0x4020...0xFFFF => {
-                // TODO
+                self.mapper.borrow_mut().prg_storeb(addr, value);
             }
             _ => {
                 self.ram.storeb(addr, value);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+    use mapper::tests::rom;
+    use rom::{Console, INesHeader, Timing, VsHardware, VsPpu};
+
+    fn memory_map() -> MemoryMap {
+        MemoryMap::new(mapper::create(rom(0, 0x4000)).unwrap(), None)
+    }
+
+    fn dots(mem: &mut MemoryMap) -> u64 {
+        let ppu = mem.ppu_mut();
+        ppu.scanline() as u64 * 341 + ppu.dot() as u64
+    }
+
+    #[test]
+    fn ram_is_mirrored_up_to_2000() {
+        let mut mem = memory_map();
+        mem.storeb(0x0801, 0x11);
+        assert_eq!(mem.loadb(0x0001), 0x11);
+        assert_eq!(mem.loadb(0x1801), 0x11);
+        mem.storeb(0x1FFF, 0x22);
+        assert_eq!(mem.loadb(0x07FF), 0x22);
+        assert_eq!(mem.loadb(0x0FFF), 0x22);
+    }
+
+    #[test]
+    fn vs_games_bank_chr_from_4016() {
+        let header = INesHeader::builder()
+            .mapper(99)
+            .prg_rom_size(0x8000)
+            .chr_rom_size(0x4000)
+            .console(Console::VsSystem(VsPpu::Rp2c03b, VsHardware::Unisystem))
+            .build()
+            .unwrap();
+        let mut data = header.to_bytes().to_vec();
+        data.extend(vec![0; 0x8000]);
+        data.extend((0..0x4000).map(|i| (i >> 13) as u8 + 1));
+        let rom = Rom::load_uncorrected(&mut &data[..]).unwrap();
+        let mut mem = MemoryMap::from_rom(rom, None).unwrap();
+        assert!(mem.vs().is_some());
+        fn chr(mem: &mut MemoryMap) -> u8 {
+            mem.storeb(0x2006, 0x00);
+            mem.storeb(0x2006, 0x10);
+            mem.loadb(0x2007);
+            mem.loadb(0x2007)
+        }
+        assert_eq!(chr(&mut mem), 1);
+        mem.storeb(0x4016, 0x04);
+        assert_eq!(chr(&mut mem), 2);
+        mem.storeb(0x4016, 0x00);
+        assert_eq!(chr(&mut mem), 1);
+    }
+
+    #[test]
+    fn pal_carries_fifths_of_a_dot() {
+        let mut mem = memory_map();
+        mem.set_region(Region::Pal);
+        mem.run_ppu(1);
+        assert_eq!(dots(&mut mem), 3);
+        // 3.2 dots a cycle: the fifths add up to a whole dot by cycle 5
+        for _ in 0..4 {
+            mem.run_ppu(1);
+        }
+        assert_eq!(dots(&mut mem), 16);
+        mem.run_ppu(2);
+        assert_eq!(dots(&mut mem), 22);
+        mem.run_ppu(3);
+        assert_eq!(dots(&mut mem), 32);
+    }
+
+    #[test]
+    fn the_region_comes_from_the_header_unless_overridden() {
+        let mut pal = rom(0, 0x4000);
+        pal.header.timing = Timing::Pal;
+        assert_eq!(MemoryMap::from_rom(pal, None).unwrap().region(), Region::Pal);
+        let mut pal = rom(0, 0x4000);
+        pal.header.timing = Timing::Pal;
+        assert_eq!(MemoryMap::from_rom(pal, Some(Region::Dendy)).unwrap().region(), Region::Dendy);
+        assert_eq!(MemoryMap::from_rom(rom(0, 0x4000), None).unwrap().region(), Region::Ntsc);
+        assert!(MemoryMap::from_rom(rom(1, 0x4000), None).is_err());
+    }
+
+    #[test]
+    fn dmc_fetches_steal_cpu_cycles() {
+        let mut mem = memory_map();
+        mem.storeb(0x4013, 0x01);
+        mem.storeb(0x4015, 0x10);
+        mem.clock(1);
+        assert_eq!(mem.take_dma_cycles(false), 4);
+        assert_eq!(mem.take_dma_cycles(false), 0);
+        mem.storeb(0x4014, 0x02);
+        assert_eq!(mem.take_dma_cycles(true), 514);
+    }
+
+    #[test]
+    fn frame_counter_writes_reach_the_apu() {
+        let mut mem = memory_map();
+        mem.clock(29829);
+        assert_eq!(mem.loadb(0x4015) & 0x40, 0x40);
+        mem.storeb(0x4017, 0x40);
+        mem.clock(29829);
+        assert_eq!(mem.loadb(0x4015) & 0x40, 0);
+    }
+}
@@ -0,0 +1,660 @@
+use std::io::Read;
+use apu::Apu;
+use archive;
+use cpu::NesCpu;
+use mapper::Mapper;
+use mapper::nsf::NsfMapper;
+use mem::{Mem, Ram};
+use region::Region;
+use rom::{RomError, TvSystem};
+
+pub const MAGIC: [u8; 5] = [0x4E, 0x45, 0x53, 0x4D, 0x1A];
+pub const NSFE_MAGIC: [u8; 4] = [0x4E, 0x53, 0x46, 0x45];
+
+// Expansion chip flags, header byte $7B
+pub const CHIP_VRC6: u8 = 0x01;
+pub const CHIP_VRC7: u8 = 0x02;
+pub const CHIP_FDS: u8 = 0x04;
+pub const CHIP_MMC5: u8 = 0x08;
+pub const CHIP_NAMCO163: u8 = 0x10;
+pub const CHIP_SUNSOFT5B: u8 = 0x20;
+
+const HEADER_SIZE: usize = 0x80;
+
+// Play rates used when a tune gives none, in microseconds per call
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+
+const NTSC_CPU_HZ: f64 = 1789772.7;
+const PAL_CPU_HZ: f64 = 1662607.0;
+
+// Driver shim, mapped where the PPU registers would be
+//
+// $3F00  JSR init
+// $3F03  JMP $3F03
+// $3F06  JSR play
+// $3F09  JMP $3F09
+//
+// The player points the CPU at $3F00 to start a track and at $3F06 at
+// the play rate, skipping calls while INIT or PLAY has not returned.
+const DRIVER_BASE: u16 = 0x3F00;
+const DRIVER_INIT: u16 = 0x3F00;
+const DRIVER_PLAY: u16 = 0x3F06;
+const DRIVER_END: u16 = 0x3F0B;
+
+// Metadata for one song, from NSFe or NSF2 chunks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Track {
+    pub name: Option<String>,
+    // Length before fading out, in milliseconds
+    pub length: Option<u32>,
+    // Fade out time, in milliseconds
+    pub fade: Option<u32>,
+}
+
+// Header                       128 bytes
+// -> $00  "NESM", $1A
+// -> $05  Version
+// -> $06  Total songs
+// -> $07  Starting song, from 1
+// -> $08  Load address of the data
+// -> $0A  INIT address
+// -> $0C  PLAY address
+// -> $0E  Song name, 32 bytes null terminated
+// -> $2E  Artist
+// -> $4E  Copyright
+// -> $6E  NTSC play speed, in microseconds
+// -> $70  Initial banks for $5FF8-$5FFF; all zero if not bankswitched
+// -> $78  PAL play speed
+// -> $7A  Region: PAL (bit 0), dual (bit 1)
+// -> $7B  Expansion chips, see the CHIP_ flags
+// -> $7C  NSF2 flags
+// -> $7D  NSF2 data length, 24 bits; metadata chunks follow the data
+//
+// NSFe files instead hold "NSFE" and then chunks of a 32-bit length, a
+// 4 byte ID and the data. Chunks with an upper case first letter must be
+// understood to play the file:
+//
+// INFO   Load, init and play addresses, region, chips, total songs and
+//        starting song from 0
+// DATA   Tune data
+// BANK   Initial banks
+// RATE   NTSC, PAL and Dendy play speeds
+// NEND   End of file
+// auth   Game, artist, copyright and ripper strings
+// tlbl   Track names
+// time   Track lengths, 32-bit signed milliseconds
+// fade   Track fade times
+// plst   Playlist of song numbers
+pub struct Nsf {
+    pub version: u8,
+    pub nsfe: bool,
+    pub total_songs: u8,
+    // Counting from 0
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub bankswitch: [u8; 8],
+    pub tv_system: TvSystem,
+    pub chips: u8,
+    pub data: Vec<u8>,
+    // Indexed by song number
+    pub tracks: Vec<Track>,
+    // Order to play songs in, empty to play them all in turn
+    pub playlist: Vec<u8>,
+}
+
+fn read_u16(data: &[u8], pos: usize) -> u16 {
+    data[pos] as u16 | (data[pos + 1] as u16) << 8
+}
+
+fn read_u32(data: &[u8], pos: usize) -> u32 {
+    data[pos] as u32 | (data[pos + 1] as u32) << 8 | (data[pos + 2] as u32) << 16 | (data[pos + 3] as u32) << 24
+}
+
+// Null terminated strings packed one after another
+fn read_strings(data: &[u8]) -> Vec<String> {
+    let data = if data.last() == Some(&0) { &data[..data.len() - 1] } else { data };
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.split(|&byte| byte == 0).map(|s| String::from_utf8_lossy(s).into_owned()).collect()
+}
+
+fn tv_system_from_region(region: u8) -> TvSystem {
+    if region & 0x02 != 0 {
+        TvSystem::Dual
+    } else if region & 0x01 != 0 {
+        TvSystem::Pal
+    } else {
+        TvSystem::Ntsc
+    }
+}
+
+impl Nsf {
+    // Load an NSF or NSFe file, possibly compressed
+    pub fn load(r: &mut Read) -> Result<Nsf, RomError> {
+        let data = try!(archive::read(r, None));
+        Nsf::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Nsf, RomError> {
+        if data.starts_with(&MAGIC) {
+            Nsf::parse_nsf(data)
+        } else if data.starts_with(&NSFE_MAGIC) {
+            let mut nsf = Nsf::empty();
+            nsf.nsfe = true;
+            let (info, data_chunk) = try!(nsf.read_chunks(&data[4..]));
+            if !info || !data_chunk {
+                return Err(RomError::FormatError);
+            }
+            nsf.tracks.resize(nsf.total_songs as usize, Track::default());
+            Ok(nsf)
+        } else {
+            let mut magic = [0u8; 4];
+            for (dst, src) in magic.iter_mut().zip(data.iter()) {
+                *dst = *src;
+            }
+            Err(RomError::BadMagic(magic))
+        }
+    }
+
+    fn empty() -> Nsf {
+        Nsf {
+            version: 1,
+            nsfe: false,
+            total_songs: 1,
+            starting_song: 0,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8000,
+            name: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            ntsc_speed: DEFAULT_NTSC_SPEED,
+            pal_speed: DEFAULT_PAL_SPEED,
+            bankswitch: [0; 8],
+            tv_system: TvSystem::Ntsc,
+            chips: 0,
+            data: Vec::new(),
+            tracks: Vec::new(),
+            playlist: Vec::new(),
+        }
+    }
+
+    fn parse_nsf(data: &[u8]) -> Result<Nsf, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::FormatError);
+        }
+        let string = |pos: usize| read_strings(&data[pos..pos + 32]).into_iter().next().unwrap_or_default();
+        let mut nsf = Nsf::empty();
+        nsf.version = data[0x05];
+        nsf.total_songs = data[0x06];
+        nsf.starting_song = data[0x07].saturating_sub(1);
+        nsf.load_addr = read_u16(data, 0x08);
+        nsf.init_addr = read_u16(data, 0x0A);
+        nsf.play_addr = read_u16(data, 0x0C);
+        nsf.name = string(0x0E);
+        nsf.artist = string(0x2E);
+        nsf.copyright = string(0x4E);
+        nsf.ntsc_speed = read_u16(data, 0x6E);
+        nsf.bankswitch.copy_from_slice(&data[0x70..0x78]);
+        nsf.pal_speed = read_u16(data, 0x78);
+        nsf.tv_system = tv_system_from_region(data[0x7A]);
+        nsf.chips = data[0x7B];
+
+        // NSF2 may give the data length, with metadata chunks after it
+        let len = read_u32(data, 0x7C) as usize >> 8;
+        let rest = &data[HEADER_SIZE..];
+        if nsf.version >= 2 && len != 0 && len <= rest.len() {
+            nsf.data = rest[..len].to_vec();
+            try!(nsf.read_chunks(&rest[len..]));
+        } else {
+            nsf.data = rest.to_vec();
+        }
+        nsf.tracks.resize(nsf.total_songs as usize, Track::default());
+        Ok(nsf)
+    }
+
+    // Read NSFe style chunks, returning whether INFO and DATA were seen
+    fn read_chunks(&mut self, data: &[u8]) -> Result<(bool, bool), RomError> {
+        let mut info = false;
+        let mut data_chunk = false;
+        let mut pos = 0;
+        while pos + 8 <= data.len() {
+            let len = read_u32(data, pos) as usize;
+            let id = &data[pos + 4..pos + 8];
+            pos += 8;
+            if data.len() - pos < len {
+                return Err(RomError::FormatError);
+            }
+            let chunk = &data[pos..pos + len];
+            pos += len;
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err(RomError::FormatError);
+                    }
+                    self.load_addr = read_u16(chunk, 0);
+                    self.init_addr = read_u16(chunk, 2);
+                    self.play_addr = read_u16(chunk, 4);
+                    self.tv_system = tv_system_from_region(chunk[6]);
+                    self.chips = chunk[7];
+                    self.total_songs = chunk.get(8).cloned().unwrap_or(1);
+                    self.starting_song = chunk.get(9).cloned().unwrap_or(0);
+                    info = true;
+                }
+                b"DATA" => {
+                    self.data = chunk.to_vec();
+                    data_chunk = true;
+                }
+                b"BANK" => {
+                    self.bankswitch = [0; 8];
+                    for (bank, &val) in self.bankswitch.iter_mut().zip(chunk.iter()) {
+                        *bank = val;
+                    }
+                }
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        self.ntsc_speed = read_u16(chunk, 0);
+                    }
+                    if chunk.len() >= 4 {
+                        self.pal_speed = read_u16(chunk, 2);
+                    }
+                }
+                b"NEND" => break,
+                b"auth" => {
+                    let mut strings = read_strings(chunk).into_iter();
+                    self.name = strings.next().unwrap_or_default();
+                    self.artist = strings.next().unwrap_or_default();
+                    self.copyright = strings.next().unwrap_or_default();
+                    self.ripper = strings.next().unwrap_or_default();
+                }
+                b"tlbl" => {
+                    for (i, name) in read_strings(chunk).into_iter().enumerate() {
+                        self.track_mut(i).name = Some(name);
+                    }
+                }
+                b"time" | b"fade" => {
+                    for i in 0..chunk.len() / 4 {
+                        // Negative values leave the default in place
+                        let ms = read_u32(chunk, i * 4) as i32;
+                        let value = if ms < 0 { None } else { Some(ms as u32) };
+                        if id == b"time" {
+                            self.track_mut(i).length = value;
+                        } else {
+                            self.track_mut(i).fade = value;
+                        }
+                    }
+                }
+                b"plst" => self.playlist = chunk.to_vec(),
+                _ => {
+                    if id[0].is_ascii_uppercase() {
+                        return Err(RomError::FormatError);
+                    }
+                }
+            }
+        }
+        Ok((info, data_chunk))
+    }
+
+    fn track_mut(&mut self, song: usize) -> &mut Track {
+        if self.tracks.len() <= song {
+            self.tracks.resize(song + 1, Track::default());
+        }
+        &mut self.tracks[song]
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bankswitch.iter().any(|&bank| bank != 0)
+    }
+
+    pub fn has_chip(&self, chip: u8) -> bool {
+        self.chips & chip != 0
+    }
+}
+
+// CPU bus of the player: RAM, the APU and the tune's cartridge, with the
+// driver shim in place of the PPU
+pub struct NsfBus {
+    ram: Ram,
+    pub apu: Apu,
+    pub mapper: NsfMapper,
+    init_addr: u16,
+    play_addr: u16,
+}
+
+impl NsfBus {
+    fn driver_byte(&self, offset: u16) -> u8 {
+        let (opcode, addr) = match offset {
+            0...2 => (0x20, self.init_addr),
+            3...5 => (0x4C, DRIVER_INIT + 3),
+            6...8 => (0x20, self.play_addr),
+            _ => (0x4C, DRIVER_PLAY + 3),
+        };
+        match offset % 3 {
+            0 => opcode,
+            1 => addr as u8,
+            _ => (addr >> 8) as u8,
+        }
+    }
+}
+
+impl Mem for NsfBus {
+    fn loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x1FFF => self.ram.loadb(addr & 0x07FF),
+            DRIVER_BASE...DRIVER_END => self.driver_byte(addr - DRIVER_BASE),
+            0x4000...0x4013 | 0x4015 => self.apu.loadb(addr - 0x4000),
+            0x4020...0xFFFF => self.mapper.prg_loadb(addr),
+            _ => 0,
+        }
+    }
+
+    fn storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000...0x1FFF => self.ram.storeb(addr & 0x07FF, val),
+            0x4000...0x4013 | 0x4015 | 0x4017 => self.apu.storeb(addr - 0x4000, val),
+            0x4020...0xFFFF => self.mapper.prg_storeb(addr, val),
+            _ => {}
+        }
+    }
+}
+
+// Plays the songs of an NSF on the CPU, APU and expansion chips alone
+pub struct NsfPlayer {
+    nsf: Nsf,
+    cpu: NesCpu<NsfBus>,
+    track: usize,
+    pal: bool,
+    cpu_hz: f64,
+    // CPU cycles between PLAY calls, and the cycle of the next one
+    play_period: f64,
+    next_play: f64,
+    track_start: u64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> NsfPlayer {
+        let bus = NsfBus {
+            ram: Ram::new(),
+            apu: Apu::new(),
+            mapper: NsfMapper::new(&nsf),
+            init_addr: nsf.init_addr,
+            play_addr: nsf.play_addr,
+        };
+        let pal = nsf.tv_system == TvSystem::Pal;
+        let speed = match (pal, nsf.pal_speed, nsf.ntsc_speed) {
+            (true, 0, _) => DEFAULT_PAL_SPEED,
+            (true, speed, _) => speed,
+            (false, _, 0) => DEFAULT_NTSC_SPEED,
+            (false, _, speed) => speed,
+        };
+        let cpu_hz = if pal { PAL_CPU_HZ } else { NTSC_CPU_HZ };
+        let start = nsf.playlist.iter()
+            .position(|&song| song == nsf.starting_song)
+            .unwrap_or(nsf.starting_song as usize);
+
+        let mut player = NsfPlayer {
+            nsf: nsf,
+            cpu: NesCpu::new(bus),
+            track: 0,
+            pal: pal,
+            cpu_hz: cpu_hz,
+            play_period: speed as f64 * cpu_hz / 1000000.0,
+            next_play: 0.0,
+            track_start: 0,
+        };
+        if !player.select_track(start) {
+            player.select_track(0);
+        }
+        player
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    // Number of tracks, following the playlist if there is one
+    pub fn track_count(&self) -> usize {
+        if self.nsf.playlist.is_empty() {
+            self.nsf.total_songs as usize
+        } else {
+            self.nsf.playlist.len()
+        }
+    }
+
+    pub fn current_track(&self) -> usize {
+        self.track
+    }
+
+    // Song number played for a track
+    pub fn song(&self, track: usize) -> u8 {
+        self.nsf.playlist.get(track).cloned().unwrap_or(track as u8)
+    }
+
+    pub fn track_info(&self, track: usize) -> Option<&Track> {
+        self.nsf.tracks.get(self.song(track) as usize)
+    }
+
+    pub fn track_name(&self, track: usize) -> Option<&str> {
+        self.track_info(track).and_then(|info| info.name.as_ref()).map(|name| &name[..])
+    }
+
+    // Start a track from the beginning, returning false if there is no
+    // such track
+    pub fn select_track(&mut self, track: usize) -> bool {
+        if track >= self.track_count() {
+            return false;
+        }
+        self.track = track;
+        let song = self.song(track);
+
+        {
+            let bus = &mut self.cpu.mem;
+            bus.ram = Ram::new();
+            bus.apu = Apu::new();
+            bus.apu.set_region(if self.pal { Region::Pal } else { Region::Ntsc });
+            bus.mapper.reset();
+            for addr in 0x4000..0x4014 {
+                bus.storeb(addr, 0);
+            }
+            bus.storeb(0x4015, 0x00);
+            bus.storeb(0x4015, 0x0F);
+            bus.storeb(0x4017, 0x40);
+        }
+        self.cpu.set_a(song);
+        self.cpu.set_x(self.pal as u8);
+        self.cpu.set_sp(0xFD);
+        self.cpu.set_pc(DRIVER_INIT);
+        self.track_start = self.cpu.clock();
+        self.next_play = self.track_start as f64 + self.play_period;
+        true
+    }
+
+    // Milliseconds played of the current track
+    pub fn elapsed(&self) -> u64 {
+        ((self.cpu.clock() - self.track_start) as f64 * 1000.0 / self.cpu_hz) as u64
+    }
+
+    // Whether the current track has played out its length and fade.
+    // Tracks of unknown length never finish.
+    pub fn finished(&self) -> bool {
+        match self.track_info(self.track).and_then(|info| info.length) {
+            Some(length) => {
+                let fade = self.track_info(self.track).and_then(|info| info.fade).unwrap_or(0);
+                self.elapsed() >= length as u64 + fade as u64
+            }
+            None => false,
+        }
+    }
+
+    // Volume through the fade at the end of the track
+    fn gain(&self) -> f32 {
+        let info = match self.track_info(self.track) {
+            Some(info) => info,
+            None => return 1.0,
+        };
+        let (length, fade) = match info.length {
+            Some(length) => (length as u64, info.fade.unwrap_or(0) as u64),
+            None => return 1.0,
+        };
+        let elapsed = self.elapsed();
+        if elapsed < length {
+            1.0
+        } else if elapsed >= length + fade {
+            0.0
+        } else {
+            1.0 - (elapsed - length) as f32 / fade as f32
+        }
+    }
+
+    // Execute one instruction and clock the APU and expansion chips
+    // alongside, returning the summed audio output over its cycles
+    fn step(&mut self) -> (u64, f32) {
+        if self.cpu.clock() as f64 >= self.next_play {
+            let pc = self.cpu.pc();
+            if pc == DRIVER_INIT + 3 || pc == DRIVER_PLAY + 3 {
+                self.cpu.set_pc(DRIVER_PLAY);
+            }
+            self.next_play += self.play_period;
+        }
+
+        let cycles = self.cpu.step();
+        let mut output = 0.0;
+        let bus = &mut self.cpu.mem;
+        for _ in 0..cycles {
+            bus.mapper.step();
+            let expansion = bus.mapper.audio_output();
+            bus.apu.step(expansion);
+            if let Some(addr) = bus.apu.dmc_request() {
+                let val = bus.loadb(addr);
+                bus.apu.dmc_fill(val);
+            }
+            output += bus.apu.output() + expansion;
+        }
+        (cycles, output)
+    }
+
+    // Run the CPU up to the given cycle
+    pub fn run_to(&mut self, cycle: u64) {
+        while self.cpu.clock() < cycle {
+            self.step();
+        }
+    }
+
+    // Fill a buffer with mono samples at the given rate, each the average
+    // output over its CPU cycles with the track's fade applied
+    pub fn render(&mut self, out: &mut [f32], sample_rate: u32) {
+        let cycles_per_sample = self.cpu_hz / sample_rate as f64;
+        let mut target = self.cpu.clock() as f64;
+        let mut sum = 0.0;
+        let mut count = 0;
+        for sample in out.iter_mut() {
+            target += cycles_per_sample;
+            while (self.cpu.clock() as f64) < target {
+                let (cycles, output) = self.step();
+                sum += output;
+                count += cycles;
+            }
+            *sample = if count == 0 { 0.0 } else { sum / count as f32 * self.gain() };
+            sum = 0.0;
+            count = 0;
+        }
+    }
+
+    // Latest output of the APU and expansion chips
+    pub fn audio_output(&self) -> f32 {
+        (self.cpu.mem.apu.output() + self.cpu.mem.mapper.audio_output()) * self.gain()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(out: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        let len = data.len() as u32;
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        out.extend_from_slice(id);
+        out.extend_from_slice(data);
+    }
+
+    fn nsfe(chunks: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut data = NSFE_MAGIC.to_vec();
+        for &(id, body) in chunks {
+            chunk(&mut data, id, body);
+        }
+        data
+    }
+
+    const INFO: [u8; 10] = [0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 0x01, CHIP_VRC6, 3, 1];
+
+    #[test]
+    fn nsfe_chunks_fill_in_the_tune() {
+        let data = nsfe(&[
+            (b"INFO", &INFO),
+            (b"DATA", &[0xEA, 0x60]),
+            (b"BANK", &[0, 1, 2]),
+            (b"RATE", &[0x1A, 0x41, 0x20, 0x4E]),
+            (b"auth", b"Game\0Artist\0Copyright\0Ripper\0"),
+            (b"tlbl", b"Intro\0Stage 1\0"),
+            (b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]),
+            (b"fade", &[0xE8, 0x03, 0, 0]),
+            (b"plst", &[2, 0]),
+            (b"xtra", &[1, 2, 3]),
+            (b"NEND", &[]),
+        ]);
+        let nsf = Nsf::parse(&data).unwrap();
+        assert!(nsf.nsfe);
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8003, 0x8006));
+        assert_eq!(nsf.tv_system, TvSystem::Pal);
+        assert!(nsf.has_chip(CHIP_VRC6));
+        assert_eq!((nsf.total_songs, nsf.starting_song), (3, 1));
+        assert_eq!(nsf.data, vec![0xEA, 0x60]);
+        assert_eq!(nsf.bankswitch, [0, 1, 2, 0, 0, 0, 0, 0]);
+        assert!(nsf.is_bankswitched());
+        assert_eq!((nsf.ntsc_speed, nsf.pal_speed), (0x411A, 0x4E20));
+        assert_eq!((&nsf.name[..], &nsf.artist[..]), ("Game", "Artist"));
+        assert_eq!((&nsf.copyright[..], &nsf.ripper[..]), ("Copyright", "Ripper"));
+        assert_eq!(nsf.tracks.len(), 3);
+        assert_eq!(nsf.tracks[0], Track { name: Some("Intro".to_string()), length: Some(10000), fade: Some(1000) });
+        assert_eq!(nsf.tracks[1], Track { name: Some("Stage 1".to_string()), length: None, fade: None });
+        assert_eq!(nsf.tracks[2], Track::default());
+        assert_eq!(nsf.playlist, vec![2, 0]);
+    }
+
+    #[test]
+    fn nsfe_needs_info_and_data() {
+        assert!(Nsf::parse(&nsfe(&[(b"INFO", &INFO)])).is_err());
+        assert!(Nsf::parse(&nsfe(&[(b"DATA", &[0x60])])).is_err());
+        // INFO too short to hold the addresses
+        assert!(Nsf::parse(&nsfe(&[(b"INFO", &INFO[..6]), (b"DATA", &[0x60])])).is_err());
+    }
+
+    #[test]
+    fn nsfe_rejects_unknown_required_chunks_and_overruns() {
+        let data = nsfe(&[(b"INFO", &INFO), (b"DATA", &[0x60]), (b"ZZZZ", &[])]);
+        assert!(Nsf::parse(&data).is_err());
+
+        let mut data = nsfe(&[(b"INFO", &INFO), (b"DATA", &[0x60])]);
+        chunk(&mut data, b"tlbl", b"Intro\0");
+        data.truncate(data.len() - 2);
+        assert!(Nsf::parse(&data).is_err());
+    }
+
+    #[test]
+    fn chunks_after_nend_are_ignored() {
+        let data = nsfe(&[(b"INFO", &INFO), (b"DATA", &[0x60]), (b"NEND", &[]), (b"ZZZZ", &[])]);
+        assert!(Nsf::parse(&data).is_ok());
+    }
+}
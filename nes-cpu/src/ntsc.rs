@@ -0,0 +1,361 @@
+use palette::{self, NtscParams};
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use video::Image;
+
+// Width of the filtered picture. A line of 256 pixels takes up about 602
+// pixels of a 4:3 picture sampled like a square pixel 640 wide one.
+pub const OUT_WIDTH: usize = 602;
+
+// The PPU puts out 8 samples of its signal per pixel, 12 to a cycle of
+// the colour subcarrier
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = SCREEN_WIDTH * SAMPLES_PER_PIXEL;
+// Each line starts 4 samples further through the colour cycle than the
+// last, since 341 pixels of 8 samples leave 4 over
+const LINE_PHASE_STEP: usize = 4;
+
+// Names accepted by NtscSetup::preset
+pub const PRESET_NAMES: [&'static str; 4] = ["composite", "svideo", "rgb", "monochrome"];
+
+// How the picture is decoded. The last four controls run from 0, a clean
+// signal, to 1, that of a composite connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscSetup {
+    // Hue, saturation, contrast, brightness and gamma as for palettes
+    pub params: NtscParams,
+    // Edge enhancement of the luma, negative to blur
+    pub sharpness: f32,
+    // Chroma left in the luma, which shows as dot crawl and the colours
+    // of fine dithering
+    pub artifacts: f32,
+    // Luma edges getting into the chroma, which shows as colour fringes
+    pub fringing: f32,
+    // Chroma spreading over neighbouring pixels
+    pub bleed: f32,
+    // Average two frames' colour phases to hide dot crawl
+    pub merge_fields: bool,
+}
+
+impl NtscSetup {
+    // Composite video as from the NES's RCA jack
+    pub fn composite() -> NtscSetup {
+        NtscSetup {
+            params: NtscParams::new(),
+            sharpness: 0.0,
+            artifacts: 1.0,
+            fringing: 1.0,
+            bleed: 1.0,
+            merge_fields: false,
+        }
+    }
+
+    // Separate luma and chroma, so no artifacts or fringing
+    pub fn svideo() -> NtscSetup {
+        NtscSetup {
+            sharpness: 0.2,
+            artifacts: 0.0,
+            fringing: 0.0,
+            bleed: 0.5,
+            ..NtscSetup::composite()
+        }
+    }
+
+    // Colours as a palette gives them, with no signal effects at all
+    pub fn rgb() -> NtscSetup {
+        NtscSetup {
+            sharpness: 0.0,
+            artifacts: 0.0,
+            fringing: 0.0,
+            bleed: 0.0,
+            ..NtscSetup::composite()
+        }
+    }
+
+    // Composite on a black and white set
+    pub fn monochrome() -> NtscSetup {
+        let mut setup = NtscSetup::composite();
+        setup.params.saturation = 0.0;
+        setup
+    }
+
+    pub fn preset(name: &str) -> Option<NtscSetup> {
+        match name {
+            "composite" => Some(NtscSetup::composite()),
+            "svideo" => Some(NtscSetup::svideo()),
+            "rgb" => Some(NtscSetup::rgb()),
+            "monochrome" => Some(NtscSetup::monochrome()),
+            _ => None,
+        }
+    }
+}
+
+// Running sums of a line of values, for averages over any window
+struct PrefixSum {
+    sums: Vec<f32>,
+}
+
+impl PrefixSum {
+    fn new() -> PrefixSum {
+        PrefixSum { sums: vec![0.0; LINE_SAMPLES + 1] }
+    }
+
+    fn fill<F: Fn(usize) -> f32>(&mut self, f: F) {
+        for k in 0..LINE_SAMPLES {
+            self.sums[k + 1] = self.sums[k] + f(k);
+        }
+    }
+
+    // Mean over samples centre - half to centre + half, cut off at the
+    // ends of the line
+    fn mean(&self, centre: usize, half: usize) -> f32 {
+        let start = centre.saturating_sub(half);
+        let end = (centre + half).min(LINE_SAMPLES);
+        (self.sums[end] - self.sums[start]) / (end - start) as f32
+    }
+}
+
+// Simulates the composite signal of each line of the PPU's output and
+// decodes it as a TV would, giving the colour artifacts and dot crawl of
+// a real set. Works on the CPU alone, a line at a time.
+pub struct NtscFilter {
+    setup: NtscSetup,
+    // Luma and chroma of each palette index over a whole colour cycle,
+    // what a perfect separation would give
+    index_yiq: Vec<(f32, f32, f32)>,
+    // Subcarrier at each phase, after the hue control
+    carrier: [(f32, f32); 12],
+    // Signal level of each sample of the line being decoded
+    samples: Vec<f32>,
+    // Luma taken from the composite signal less the clean luma, at each
+    // sample
+    crosstalk: Vec<f32>,
+    signal: PrefixSum,
+    luma: PrefixSum,
+    chroma_i: PrefixSum,
+    chroma_q: PrefixSum,
+    crosstalk_i: PrefixSum,
+    crosstalk_q: PrefixSum,
+    // Decoded luma and chroma of each output pixel
+    line: Vec<(f32, f32, f32)>,
+}
+
+impl NtscFilter {
+    pub fn new(setup: &NtscSetup) -> NtscFilter {
+        let mut carrier = [(0.0, 0.0); 12];
+        for (phase, carrier) in carrier.iter_mut().enumerate() {
+            let angle = palette::subcarrier_angle(phase as f32, &setup.params);
+            *carrier = (angle.cos(), angle.sin());
+        }
+        NtscFilter {
+            setup: *setup,
+            index_yiq: (0..512).map(|index| palette::ntsc_yiq(index, &setup.params)).collect(),
+            carrier: carrier,
+            samples: vec![0.0; LINE_SAMPLES],
+            crosstalk: vec![0.0; LINE_SAMPLES],
+            signal: PrefixSum::new(),
+            luma: PrefixSum::new(),
+            chroma_i: PrefixSum::new(),
+            chroma_q: PrefixSum::new(),
+            crosstalk_i: PrefixSum::new(),
+            crosstalk_q: PrefixSum::new(),
+            line: vec![(0.0, 0.0, 0.0); OUT_WIDTH],
+        }
+    }
+
+    pub fn setup(&self) -> &NtscSetup {
+        &self.setup
+    }
+
+    // Filter a frame of 9-bit palette indices, such as Ppu::frame, into
+    // an image OUT_WIDTH by 240. burst_phase, 0-2, is where the first
+    // line starts in the colour cycle in thirds; advancing it each frame
+    // as the NES does gives dot crawl.
+    pub fn render(&mut self, frame: &[u16], burst_phase: usize, out: &mut Image) {
+        if out.width != OUT_WIDTH || out.height != SCREEN_HEIGHT {
+            *out = Image::new(OUT_WIDTH, SCREEN_HEIGHT);
+        }
+        for y in 0..SCREEN_HEIGHT {
+            let pixels = &frame[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+            let phase = (burst_phase * LINE_PHASE_STEP + y * LINE_PHASE_STEP) % 12;
+            for yiq in self.line.iter_mut() {
+                *yiq = (0.0, 0.0, 0.0);
+            }
+            if self.setup.merge_fields {
+                self.decode_line(pixels, phase, 0.5);
+                self.decode_line(pixels, (phase + LINE_PHASE_STEP) % 12, 0.5);
+            } else {
+                self.decode_line(pixels, phase, 1.0);
+            }
+            let row = &mut out.pixels[y * OUT_WIDTH..(y + 1) * OUT_WIDTH];
+            for (rgb, &yiq) in row.iter_mut().zip(self.line.iter()) {
+                *rgb = palette::yiq_to_rgb(yiq, &self.setup.params);
+            }
+        }
+    }
+
+    // Decode a line starting at the given phase, adding weight times the
+    // result to self.line
+    fn decode_line(&mut self, pixels: &[u16], phase: usize, weight: f32) {
+        let setup = self.setup;
+        let carrier = self.carrier;
+        let index_yiq = &self.index_yiq;
+        let clean_luma = |k: usize| index_yiq[pixels[k / SAMPLES_PER_PIXEL] as usize].0;
+        let sample_phase = |k: usize| (phase + k) % 12;
+
+        for (k, sample) in self.samples.iter_mut().enumerate() {
+            *sample = palette::ntsc_signal(pixels[k / SAMPLES_PER_PIXEL], sample_phase(k) as u8);
+        }
+        {
+            let samples = &self.samples;
+            self.signal.fill(|k| samples[k]);
+        }
+        self.luma.fill(|k| clean_luma(k));
+        // A notch filter a colour cycle wide takes the chroma out of the
+        // composite signal, but not cleanly where the picture changes
+        for (k, crosstalk) in self.crosstalk.iter_mut().enumerate() {
+            *crosstalk = self.signal.mean(k, 6) - clean_luma(k);
+        }
+        // Chroma demodulated from the signal less its clean luma, and what
+        // the crosstalk adds to it
+        {
+            let samples = &self.samples;
+            let crosstalk = &self.crosstalk;
+            self.chroma_i.fill(|k| (samples[k] - clean_luma(k)) * carrier[sample_phase(k)].0);
+            self.chroma_q.fill(|k| (samples[k] - clean_luma(k)) * carrier[sample_phase(k)].1);
+            self.crosstalk_i.fill(|k| crosstalk[k] * carrier[sample_phase(k)].0);
+            self.crosstalk_q.fill(|k| crosstalk[k] * carrier[sample_phase(k)].1);
+        }
+
+        for x in 0..OUT_WIDTH {
+            let k = (2 * x + 1) * LINE_SAMPLES / (2 * OUT_WIDTH);
+            let clean = index_yiq[pixels[k / SAMPLES_PER_PIXEL] as usize];
+
+            let mut y = clean.0 + setup.artifacts * self.crosstalk[k];
+            if setup.sharpness != 0.0 {
+                let blurred = self.luma.mean(k, SAMPLES_PER_PIXEL);
+                y += setup.sharpness * (clean.0 - blurred);
+            }
+
+            // Low pass filter the chroma over two colour cycles, then blend
+            // back towards each pixel's own chroma for less bleed
+            let i = 2.0 * (self.chroma_i.mean(k, 12) - setup.fringing * self.crosstalk_i.mean(k, 12));
+            let q = 2.0 * (self.chroma_q.mean(k, 12) - setup.fringing * self.crosstalk_q.mean(k, 12));
+            let i = clean.1 + setup.bleed * (i - clean.1);
+            let q = clean.2 + setup.bleed * (q - clean.2);
+
+            let out = &mut self.line[x];
+            out.0 += weight * y;
+            out.1 += weight * i;
+            out.2 += weight * q;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palette::Palette;
+
+    fn flat(index: u16) -> Vec<u16> {
+        vec![index; SCREEN_WIDTH * SCREEN_HEIGHT]
+    }
+
+    // Columns of two pixels each of $16 and $2A, finer than the chroma
+    // can follow
+    fn stripes() -> Vec<u16> {
+        (0..SCREEN_WIDTH * SCREEN_HEIGHT).map(|i| if i % 4 < 2 { 0x16 } else { 0x2A }).collect()
+    }
+
+    fn render(setup: NtscSetup, frame: &[u16], burst_phase: usize) -> Image {
+        let mut out = Image::new(0, 0);
+        NtscFilter::new(&setup).render(frame, burst_phase, &mut out);
+        out
+    }
+
+    #[test]
+    fn frames_are_filtered_to_602_by_240() {
+        let out = render(NtscSetup::composite(), &flat(0x0F), 0);
+        assert_eq!((out.width, out.height, out.pixels.len()), (OUT_WIDTH, 240, OUT_WIDTH * 240));
+        assert!(out.pixels.iter().all(|&rgb| rgb == (0, 0, 0)));
+    }
+
+    #[test]
+    fn the_rgb_preset_gives_the_palette_colours() {
+        let palette = Palette::builtin("ntsc").unwrap();
+        for &index in [0x16, 0x2A, 0x11, 0x30, 0x60].iter() {
+            let out = render(NtscSetup::rgb(), &flat(index), 0);
+            let color = palette.colors()[index as usize];
+            assert!(out.pixels.iter().all(|&rgb| rgb == color), "${:03X}", index);
+        }
+
+        // Fine detail too, each output pixel taking the colour under it
+        let out = render(NtscSetup::rgb(), &stripes(), 1);
+        for x in 0..OUT_WIDTH {
+            let source = (2 * x + 1) * SCREEN_WIDTH / (2 * OUT_WIDTH);
+            let index = if source % 4 < 2 { 0x16 } else { 0x2A };
+            assert_eq!(out.pixel(x, 100), palette.colors()[index], "x = {}", x);
+        }
+    }
+
+    #[test]
+    fn a_flat_field_stays_flat_away_from_the_edges() {
+        let palette = Palette::builtin("ntsc").unwrap();
+        for &name in PRESET_NAMES.iter() {
+            let setup = NtscSetup::preset(name).unwrap();
+            let out = render(setup, &flat(0x16), 2);
+            let expected = if name == "monochrome" {
+                let mut params = NtscParams::new();
+                params.saturation = 0.0;
+                Palette::generate_ntsc(&params).colors()[0x16]
+            } else {
+                palette.colors()[0x16]
+            };
+            // The filters reach a few pixels past each end of the line
+            for x in 8..OUT_WIDTH - 8 {
+                let (r, g, b) = out.pixel(x, 57);
+                let close = |a: u8, b: u8| (a as i16 - b as i16).abs() <= 1;
+                assert!(close(r, expected.0) && close(g, expected.1) && close(b, expected.2),
+                        "{} at x = {}: {:?}, not {:?}", name, x, (r, g, b), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn composite_artifacts_crawl_with_the_burst_phase() {
+        let frame = stripes();
+        let phases: Vec<Image> = (0..3).map(|phase| render(NtscSetup::composite(), &frame, phase)).collect();
+        assert!(phases[0] != phases[1] && phases[1] != phases[2]);
+        // Fine stripes come out in colours neither of them has
+        let palette = Palette::builtin("ntsc").unwrap();
+        let own = [palette.colors()[0x16], palette.colors()[0x2A]];
+        assert!(phases[0].pixels.iter().any(|rgb| !own.contains(rgb)));
+
+        // RGB has no subcarrier to crawl
+        let rgb: Vec<Image> = (0..3).map(|phase| render(NtscSetup::rgb(), &frame, phase)).collect();
+        assert!(rgb[0] == rgb[1] && rgb[1] == rgb[2]);
+    }
+
+    #[test]
+    fn merging_fields_hides_the_crawl_between_frames() {
+        let mut setup = NtscSetup::composite();
+        setup.merge_fields = true;
+        let frame = stripes();
+        let a = render(setup, &frame, 0);
+        let b = render(setup, &frame, 1);
+        // Each line is the average of two phases a frame apart, so lines
+        // of consecutive frames share one of their phases and differ less
+        let unmerged = (render(NtscSetup::composite(), &frame, 0), render(NtscSetup::composite(), &frame, 1));
+        let diff = |x: &Image, y: &Image| {
+            x.pixels.iter().zip(y.pixels.iter())
+                .map(|(p, q)| (p.0 as i32 - q.0 as i32).abs() + (p.1 as i32 - q.1 as i32).abs() + (p.2 as i32 - q.2 as i32).abs())
+                .sum::<i32>()
+        };
+        assert!(diff(&a, &b) < diff(&unmerged.0, &unmerged.1));
+    }
+
+    #[test]
+    fn monochrome_has_no_colour() {
+        let out = render(NtscSetup::monochrome(), &stripes(), 0);
+        assert!(out.pixels.iter().all(|&(r, g, b)| r == g && g == b));
+    }
+}
@@ -0,0 +1,318 @@
+use rom::{RomError, VsPpu};
+use vs;
+
+use std::f32::consts::PI;
+use std::io::{self, Read, Write};
+
+// Names accepted by Palette::builtin
+pub const BUILTIN_NAMES: [&'static str; 4] = ["ntsc", "nesdev", "rgb", "greyscale"];
+
+// Voltages of the 2C02's composite output relative to sync. Each colour is
+// a square wave between a low and high level for its luma row; colour 0
+// of a row is flat at the high level and colours $D-$F at the low one.
+const LOW_LEVELS: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const HIGH_LEVELS: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// Emphasis scales the signal down during the phases of its colour
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// Phase of the colour burst against colour 1's wave, in twelfths of a cycle
+const BURST_PHASE: f32 = 3.9;
+
+// The 2C02 palette from the NESdev wiki, as measured from hardware
+const NESDEV_PALETTE: [(u8, u8, u8); 64] = [
+    (84, 84, 84), (0, 30, 116), (8, 16, 144), (48, 0, 136), (68, 0, 100), (92, 0, 48), (84, 4, 0), (60, 24, 0),
+    (32, 42, 0), (8, 58, 0), (0, 64, 0), (0, 60, 0), (0, 50, 60), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (152, 150, 152), (8, 76, 196), (48, 50, 236), (92, 30, 228), (136, 20, 176), (160, 20, 100), (152, 34, 32), (120, 60, 0),
+    (84, 90, 0), (40, 114, 0), (8, 124, 0), (0, 118, 40), (0, 102, 120), (0, 0, 0), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (76, 154, 236), (120, 124, 236), (176, 98, 236), (228, 84, 236), (236, 88, 180), (236, 106, 100), (212, 136, 32),
+    (160, 170, 0), (116, 196, 0), (76, 208, 32), (56, 204, 108), (56, 180, 204), (60, 60, 60), (0, 0, 0), (0, 0, 0),
+    (236, 238, 236), (168, 204, 236), (188, 188, 236), (212, 178, 236), (236, 174, 236), (236, 174, 212), (236, 180, 176), (228, 196, 144),
+    (204, 210, 120), (180, 222, 120), (168, 226, 144), (152, 226, 180), (160, 214, 228), (160, 162, 160), (0, 0, 0), (0, 0, 0),
+];
+
+// Adjustments applied when decoding the NTSC signal to RGB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+    // Rotation of every colour's hue, in degrees
+    pub hue: f32,
+    // Multiplier of the chroma, 0 giving greyscale
+    pub saturation: f32,
+    // Multiplier of the luma
+    pub contrast: f32,
+    // Added to the luma, -1 to 1
+    pub brightness: f32,
+    // Gamma of the display the palette is for. The signal is taken as
+    // gamma 2.2, so 2.2 leaves it unchanged.
+    pub gamma: f32,
+}
+
+impl NtscParams {
+    pub fn new() -> NtscParams {
+        NtscParams {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+// Colours for the PPU's palette indices, either 64 for the 6-bit colours
+// alone or 512 for every combination with the emphasis bits, the index
+// being emphasis << 6 | colour. A .pal file is the colours as R, G, B
+// bytes in the same order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+// Whether a colour's square wave is high during one of the twelve phases
+// of a colour cycle
+fn in_color_phase(color: u8, phase: u8) -> bool {
+    (color + phase) % 12 < 6
+}
+
+// Signal level of a 9-bit palette index during one of the twelve phases
+// of a colour cycle, normalised to 0 for black and 1 for white
+pub fn ntsc_signal(index: u16, phase: u8) -> f32 {
+    let color = (index & 0x0F) as u8;
+    let row = if color > 0x0D { 1 } else { (index >> 4) as usize & 0x03 };
+    let emphasis = (index >> 6) as u8;
+
+    let mut low = LOW_LEVELS[row];
+    let mut high = HIGH_LEVELS[row];
+    if color == 0 {
+        low = high;
+    }
+    if color > 0x0C {
+        high = low;
+    }
+    let mut signal = if in_color_phase(color, phase) { high } else { low };
+    if (emphasis & 0x01 != 0 && in_color_phase(0, phase))
+        || (emphasis & 0x02 != 0 && in_color_phase(4, phase))
+        || (emphasis & 0x04 != 0 && in_color_phase(8, phase)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+// Angle of the colour subcarrier during a phase, as the TV's decoder
+// sees it after the hue control
+pub fn subcarrier_angle(phase: f32, params: &NtscParams) -> f32 {
+    PI * (phase + BURST_PHASE + params.hue / 30.0) / 6.0
+}
+
+// Luma and chroma of a palette index as decoded over a whole colour
+// cycle, before the picture controls are applied
+pub fn ntsc_yiq(index: u16, params: &NtscParams) -> (f32, f32, f32) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let signal = ntsc_signal(index, phase);
+        let angle = subcarrier_angle(phase as f32, params);
+        y += signal;
+        i += signal * angle.cos();
+        q += signal * angle.sin();
+    }
+    // Demodulating halves the chroma's amplitude, so I and Q are
+    // doubled back
+    (y / 12.0, i / 6.0, q / 6.0)
+}
+
+fn to_byte(val: f32, gamma: f32) -> u8 {
+    let val = if val <= 0.0 { 0.0 } else { val.powf(2.2 / gamma) };
+    (val * 255.0).round().max(0.0).min(255.0) as u8
+}
+
+// Apply the picture controls to decoded luma and chroma and convert them
+// to RGB with the FCC matrix
+pub fn yiq_to_rgb(yiq: (f32, f32, f32), params: &NtscParams) -> (u8, u8, u8) {
+    let y = yiq.0 * params.contrast + params.brightness;
+    let i = yiq.1 * params.saturation;
+    let q = yiq.2 * params.saturation;
+    let r = y + 0.946882 * i + 0.623557 * q;
+    let g = y - 0.274788 * i - 0.635691 * q;
+    let b = y - 1.108545 * i + 1.709007 * q;
+    (to_byte(r, params.gamma), to_byte(g, params.gamma), to_byte(b, params.gamma))
+}
+
+impl Palette {
+    // Build a palette from 64 or 512 colours
+    pub fn from_colors(colors: Vec<(u8, u8, u8)>) -> Option<Palette> {
+        if colors.len() == 64 || colors.len() == 512 {
+            Some(Palette { colors: colors })
+        } else {
+            None
+        }
+    }
+
+    // Decode the composite signal of each palette index as a TV would,
+    // averaging one colour cycle into luma and the two chroma components
+    pub fn generate_ntsc(params: &NtscParams) -> Palette {
+        let colors = (0..512).map(|index| yiq_to_rgb(ntsc_yiq(index, params), params)).collect();
+        Palette { colors: colors }
+    }
+
+    // One of the palettes named in BUILTIN_NAMES:
+    //
+    // ntsc       generate_ntsc with the default parameters
+    // nesdev     the NESdev wiki's 2C02 palette
+    // rgb        the 2C03 and 2C05 RGB PPUs of the Vs. System and PlayChoice-10
+    // greyscale  generate_ntsc with no saturation
+    pub fn builtin(name: &str) -> Option<Palette> {
+        match name {
+            "ntsc" => Some(Palette::generate_ntsc(&NtscParams::new())),
+            "nesdev" => Some(Palette { colors: NESDEV_PALETTE.to_vec() }),
+            "rgb" => Palette::vs(VsPpu::Rp2c03b),
+            "greyscale" => {
+                let mut params = NtscParams::new();
+                params.saturation = 0.0;
+                Some(Palette::generate_ntsc(&params))
+            }
+            _ => None,
+        }
+    }
+
+    // Colours of a Vs. System PPU, which has RGB output in place of
+    // composite
+    pub fn vs(ppu: VsPpu) -> Option<Palette> {
+        vs::palette(ppu).map(|colors| Palette { colors: colors.iter().map(|&color| vs::rgb888(color)).collect() })
+    }
+
+    // Read a .pal file of 64 or 512 colours
+    pub fn load(r: &mut Read) -> Result<Palette, RomError> {
+        let mut data = Vec::new();
+        try!(r.read_to_end(&mut data));
+        if data.len() != 64 * 3 && data.len() != 512 * 3 {
+            return Err(RomError::FormatError);
+        }
+        let colors = data.chunks(3).map(|rgb| (rgb[0], rgb[1], rgb[2])).collect();
+        Ok(Palette { colors: colors })
+    }
+
+    // Write the palette as a .pal file
+    pub fn save(&self, w: &mut Write) -> io::Result<()> {
+        for &(r, g, b) in self.colors.iter() {
+            try!(w.write_all(&[r, g, b]));
+        }
+        Ok(())
+    }
+
+    // Colours to pass to video::convert
+    pub fn colors(&self) -> &[(u8, u8, u8)] {
+        &self.colors
+    }
+
+    // Whether the palette has colours for the emphasis bits
+    pub fn has_emphasis(&self) -> bool {
+        self.colors.len() == 512
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn colors(count: usize) -> Vec<(u8, u8, u8)> {
+        (0..count).map(|i| (i as u8, (i >> 8) as u8, (i * 7) as u8)).collect()
+    }
+
+    #[test]
+    fn pal_files_round_trip_with_64_or_512_colours() {
+        for &count in [64, 512].iter() {
+            let palette = Palette::from_colors(colors(count)).unwrap();
+            let mut file = Vec::new();
+            palette.save(&mut file).unwrap();
+            assert_eq!(file.len(), count * 3);
+            assert_eq!(&file[3..6], &[1, 0, 7]);
+            let loaded = Palette::load(&mut &file[..]).unwrap();
+            assert_eq!(loaded, palette);
+            assert_eq!(loaded.has_emphasis(), count == 512);
+        }
+    }
+
+    #[test]
+    fn other_sizes_are_not_palettes() {
+        for &len in [0, 63 * 3, 64 * 3 + 1, 65 * 3, 511 * 3].iter() {
+            match Palette::load(&mut &vec![0; len][..]) {
+                Err(RomError::FormatError) => {}
+                _ => panic!("loaded {} bytes", len),
+            }
+        }
+        assert!(Palette::from_colors(colors(100)).is_none());
+    }
+
+    #[test]
+    fn the_ntsc_palette_has_black_white_and_greys() {
+        let palette = Palette::builtin("ntsc").unwrap();
+        assert!(palette.has_emphasis());
+        let colors = palette.colors();
+        for &black in [0x0D, 0x0E, 0x0F, 0x1D, 0x1F, 0x2E, 0x3F].iter() {
+            assert_eq!(colors[black], (0, 0, 0), "${:02X}", black);
+        }
+        assert_eq!(colors[0x20], (255, 255, 255));
+        assert_eq!(colors[0x30], (255, 255, 255));
+        // Colour 0 of each row is flat, so grey
+        assert_eq!(colors[0x00], (102, 102, 102));
+        assert_eq!(colors[0x10], (174, 174, 174));
+        // as are $2D and $3D, flat at their row's low level
+        for &grey in [0x2D, 0x3D].iter() {
+            let (r, g, b) = colors[grey];
+            assert!(r == g && g == b && r > 0, "${:02X} {:?}", grey, colors[grey]);
+        }
+    }
+
+    // Whether one channel is more than twice another
+    fn over(a: u8, b: u8) -> bool {
+        a as u16 > 2 * b as u16
+    }
+
+    #[test]
+    fn ntsc_hues_go_round_the_colour_wheel() {
+        let colors = Palette::builtin("ntsc").unwrap().colors().to_vec();
+        let (r, g, b) = colors[0x12];
+        assert!(over(b, r.max(g)), "blue {:?}", colors[0x12]);
+        let (r, g, b) = colors[0x14];
+        assert!(over(r.min(b), g), "magenta {:?}", colors[0x14]);
+        let (r, g, b) = colors[0x16];
+        assert!(over(r, g.max(b)), "red {:?}", colors[0x16]);
+        let (r, g, b) = colors[0x18];
+        assert!(over(r.min(g), b), "yellow {:?}", colors[0x18]);
+        let (r, g, b) = colors[0x1A];
+        assert!(over(g, r.max(b)), "green {:?}", colors[0x1A]);
+        let (r, g, b) = colors[0x1C];
+        assert!(over(g.min(b), r), "cyan {:?}", colors[0x1C]);
+    }
+
+    #[test]
+    fn emphasis_darkens_the_other_colours() {
+        let colors = Palette::builtin("ntsc").unwrap().colors().to_vec();
+        // White with one colour emphasised is tinted that colour
+        let (r, g, b) = colors[0x60];
+        assert!(r > g && r > b, "{:?}", colors[0x60]);
+        let (r, g, b) = colors[0xA0];
+        assert!(g > r && g > b, "{:?}", colors[0xA0]);
+        let (r, g, b) = colors[0x120];
+        assert!(b > r && b > g, "{:?}", colors[0x120]);
+        // All three darken it evenly
+        let (r, g, b) = colors[0x1E0];
+        assert!(r == g && g == b && r < 255, "{:?}", colors[0x1E0]);
+    }
+
+    #[test]
+    fn the_greyscale_palette_has_no_colour() {
+        let palette = Palette::builtin("greyscale").unwrap();
+        assert!(palette.colors().iter().all(|&(r, g, b)| r == g && g == b));
+        assert_eq!(palette.colors()[0x10], Palette::builtin("ntsc").unwrap().colors()[0x10]);
+    }
+
+    #[test]
+    fn builtin_palettes_have_their_names() {
+        for name in BUILTIN_NAMES.iter() {
+            assert!(Palette::builtin(name).is_some(), "{}", name);
+        }
+        assert!(Palette::builtin("sepia").is_none());
+        assert_eq!(Palette::builtin("nesdev").unwrap().colors()[0x16], (152, 34, 32));
+    }
+}
@@ -0,0 +1,498 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use archive;
+use rom::{PatchChecksum, Rom, RomError};
+use util;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+// IPS offsets are 24 bits, and a record may not start at the offset
+// that spells "EOF"
+const IPS_MAX_SIZE: usize = 0x1000000;
+const IPS_EOF_OFFSET: usize = 0x454F46;
+
+// Patch files looked for next to a ROM, applied in this order
+const SIBLING_EXTENSIONS: [&'static str; 3] = ["ips", "ups", "bps"];
+
+impl PatchFormat {
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(b"PATCH") {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(b"UPS1") {
+            Some(PatchFormat::Ups)
+        } else if patch.starts_with(b"BPS1") {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+// Apply a patch of any supported format to an image
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, RomError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(patch, source),
+        Some(PatchFormat::Ups) => apply_ups(patch, source),
+        Some(PatchFormat::Bps) => apply_bps(patch, source),
+        None => Err(RomError::FormatError),
+    }
+}
+
+// Apply several patches in order, each to the result of the last
+pub fn apply_all(patches: &[Vec<u8>], source: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut data = source.to_vec();
+    for patch in patches {
+        data = try!(apply(patch, &data));
+    }
+    Ok(data)
+}
+
+// Patches named after the ROM with an .ips, .ups or .bps extension
+pub fn sibling_patches(rom: &Path) -> Vec<PathBuf> {
+    SIBLING_EXTENSIONS.iter()
+        .map(|ext| rom.with_extension(ext))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, RomError> {
+    let mut data = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut data));
+    Ok(data)
+}
+
+// Load a ROM with its sibling patches applied, followed by any others
+// given in order. Compressed ROMs and patches are unpacked first.
+pub fn load_patched(path: &Path, extra: &[PathBuf]) -> Result<Rom, RomError> {
+    let mut patches = Vec::new();
+    for patch in sibling_patches(path).iter().chain(extra.iter()) {
+        patches.push(try!(archive::extract(try!(read_file(patch)), None)));
+    }
+    let image = try!(archive::extract(try!(read_file(path)), None));
+    let data = try!(apply_all(&patches, &image));
+    Rom::load(&mut &data[..])
+}
+
+// IPS: "PATCH", then records of a 24-bit offset, 16-bit length and the
+// data, all big endian, until "EOF". A zero length introduces a run of
+// a 16-bit count and the byte to repeat. An optional 24-bit size after
+// "EOF" truncates the output.
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, RomError> {
+    let mut out = source.to_vec();
+    let mut pos = 5;
+    let read = |pos: usize, len: usize| -> Result<usize, RomError> {
+        if pos + len > patch.len() {
+            return Err(RomError::FormatError);
+        }
+        Ok(patch[pos..pos + len].iter().fold(0, |acc, &byte| acc << 8 | byte as usize))
+    };
+
+    loop {
+        if patch[pos..].starts_with(b"EOF") {
+            pos += 3;
+            break;
+        }
+        let offset = try!(read(pos, 3));
+        let len = try!(read(pos + 3, 2));
+        pos += 5;
+
+        let (data, run) = if len == 0 {
+            let count = try!(read(pos, 2));
+            let value = try!(read(pos + 2, 1)) as u8;
+            pos += 3;
+            (None, (count, value))
+        } else {
+            if pos + len > patch.len() {
+                return Err(RomError::FormatError);
+            }
+            pos += len;
+            (Some(&patch[pos - len..pos]), (len, 0))
+        };
+
+        let end = offset + run.0;
+        if out.len() < end {
+            out.resize(end, 0);
+        }
+        match data {
+            Some(data) => out[offset..end].copy_from_slice(data),
+            None => {
+                for byte in out[offset..end].iter_mut() {
+                    *byte = run.1;
+                }
+            }
+        }
+    }
+
+    if patch.len() >= pos + 3 {
+        let size = try!(read(pos, 3));
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// Write an IPS patch that turns source into target. A target shorter
+// than the source is cut down by the size after EOF.
+pub fn write_ips(source: &[u8], target: &[u8], w: &mut Write) -> io::Result<()> {
+    if target.len() > IPS_MAX_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "image too large for an IPS patch"));
+    }
+    try!(w.write_all(b"PATCH"));
+    let mut pos = 0;
+    while pos < target.len() {
+        if source.get(pos) == Some(&target[pos]) {
+            pos += 1;
+            continue;
+        }
+        let start = if pos == IPS_EOF_OFFSET { pos - 1 } else { pos };
+        let mut end = pos;
+        while end < target.len() && end - start < 0xFFFF && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+        let len = end - start;
+        try!(w.write_all(&[(start >> 16) as u8, (start >> 8) as u8, start as u8, (len >> 8) as u8, len as u8]));
+        try!(w.write_all(&target[start..end]));
+        pos = end;
+    }
+    try!(w.write_all(b"EOF"));
+    if target.len() < source.len() {
+        let size = target.len();
+        try!(w.write_all(&[(size >> 16) as u8, (size >> 8) as u8, size as u8]));
+    }
+    Ok(())
+}
+
+// Variable length number used by UPS and BPS
+fn read_varint(patch: &[u8], pos: &mut usize) -> Result<usize, RomError> {
+    let mut value = 0usize;
+    let mut shift = 1usize;
+    loop {
+        let byte = match patch.get(*pos) {
+            Some(&byte) => byte,
+            None => return Err(RomError::FormatError),
+        };
+        *pos += 1;
+        value = value.wrapping_add((byte as usize & 0x7F).wrapping_mul(shift));
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift.wrapping_shl(7);
+        value = value.wrapping_add(shift);
+    }
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+// UPS and BPS both end with the CRC-32 of the source, the target and the
+// patch up to that point. Check the patch and source CRCs, returning the
+// expected target CRC.
+fn check_footer(patch: &[u8], source: &[u8]) -> Result<u32, RomError> {
+    if patch.len() < 16 {
+        return Err(RomError::FormatError);
+    }
+    let footer = &patch[patch.len() - 12..];
+    try!(check_crc(PatchChecksum::Patch, read_u32_le(&footer[8..12]), &patch[..patch.len() - 4]));
+    try!(check_crc(PatchChecksum::Source, read_u32_le(&footer[0..4]), source));
+    Ok(read_u32_le(&footer[4..8]))
+}
+
+fn check_crc(checksum: PatchChecksum, expected: u32, data: &[u8]) -> Result<(), RomError> {
+    let actual = util::crc32(0, data);
+    if actual != expected {
+        return Err(RomError::PatchChecksumMismatch {
+            checksum: checksum,
+            expected: expected,
+            actual: actual,
+        });
+    }
+    Ok(())
+}
+
+// UPS: "UPS1", source and target sizes, then hunks of a relative offset
+// and bytes XORed into the image up to and including a terminating zero
+fn apply_ups(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, RomError> {
+    let target_crc = try!(check_footer(patch, source));
+    let end = patch.len() - 12;
+    let mut pos = 4;
+    let source_size = try!(read_varint(patch, &mut pos));
+    let target_size = try!(read_varint(patch, &mut pos));
+    if source_size != source.len() {
+        return Err(RomError::FormatError);
+    }
+
+    let mut out = source.to_vec();
+    out.resize(target_size, 0);
+    let mut offset = 0;
+    while pos < end {
+        offset += try!(read_varint(patch, &mut pos));
+        loop {
+            let byte = match patch[..end].get(pos) {
+                Some(&byte) => byte,
+                None => return Err(RomError::FormatError),
+            };
+            pos += 1;
+            if offset < out.len() {
+                out[offset] ^= byte;
+            }
+            offset += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    try!(check_crc(PatchChecksum::Target, target_crc, &out));
+    Ok(out)
+}
+
+// BPS: "BPS1", source and target sizes and metadata, then commands of a
+// length and action in the low two bits: copy from the same offset in
+// the source, take bytes from the patch, or copy from a relative offset
+// in the source or in the output written so far
+fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, RomError> {
+    let target_crc = try!(check_footer(patch, source));
+    let end = patch.len() - 12;
+    let mut pos = 4;
+    let source_size = try!(read_varint(patch, &mut pos));
+    let target_size = try!(read_varint(patch, &mut pos));
+    let metadata_size = try!(read_varint(patch, &mut pos));
+    pos += metadata_size;
+    if source_size != source.len() {
+        return Err(RomError::FormatError);
+    }
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    // Relative offsets are stored as magnitude and sign bit
+    let read_relative = |pos: &mut usize| -> Result<isize, RomError> {
+        let data = try!(read_varint(patch, pos));
+        let magnitude = (data >> 1) as isize;
+        Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+    };
+
+    while pos < end {
+        let data = try!(read_varint(patch, &mut pos));
+        let len = (data >> 2) + 1;
+        match data & 3 {
+            0 => {
+                let start = out.len();
+                match source.get(start..start + len) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return Err(RomError::FormatError),
+                }
+            }
+            1 => {
+                match patch[..end].get(pos..pos + len) {
+                    Some(bytes) => out.extend_from_slice(bytes),
+                    None => return Err(RomError::FormatError),
+                }
+                pos += len;
+            }
+            2 => {
+                source_offset += try!(read_relative(&mut pos));
+                let start = source_offset as usize;
+                if source_offset < 0 || start + len > source.len() {
+                    return Err(RomError::FormatError);
+                }
+                out.extend_from_slice(&source[start..start + len]);
+                source_offset += len as isize;
+            }
+            _ => {
+                target_offset += try!(read_relative(&mut pos));
+                // The copy may overlap the bytes it produces
+                for _ in 0..len {
+                    let byte = match out.get(target_offset as usize) {
+                        Some(&byte) if target_offset >= 0 => byte,
+                        _ => return Err(RomError::FormatError),
+                    };
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(RomError::FormatError);
+    }
+    try!(check_crc(PatchChecksum::Target, target_crc, &out));
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn push_u32_le(out: &mut Vec<u8>, value: u32) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    // Append the UPS/BPS footer for the given source and target
+    fn finish(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        push_u32_le(&mut patch, util::crc32(0, source));
+        push_u32_le(&mut patch, util::crc32(0, target));
+        let crc = util::crc32(0, &patch);
+        push_u32_le(&mut patch, crc);
+        patch
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for &value in &[0, 1, 0x7F, 0x80, 0x3FFF, 0x4000, 0x4080, 0x123456] {
+            let mut data = Vec::new();
+            push_varint(&mut data, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&data, &mut pos).unwrap(), value);
+            assert_eq!(pos, data.len());
+        }
+    }
+
+    #[test]
+    fn ips_writes_runs_and_truncates() {
+        let source = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // Two bytes at 1, a run of four 0xAA at 4, then one byte past the end
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0x11, 0x22]);
+        patch.extend_from_slice(&[0, 0, 4, 0, 0, 0, 4, 0xAA]);
+        patch.extend_from_slice(&[0, 0, 9, 0, 1, 0x33]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&patch, &source).unwrap(),
+                   vec![0, 0x11, 0x22, 0, 0xAA, 0xAA, 0xAA, 0xAA, 0, 0x33]);
+
+        // The optional size after EOF cuts the image short
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply(&patch, &source).unwrap(), vec![0, 0x11, 0x22]);
+    }
+
+    #[test]
+    fn written_ips_patches_apply() {
+        let source: Vec<u8> = (0..0x30000).map(|i| (i % 251) as u8).collect();
+        let mut target = source.clone();
+        target[0] = 0xFF;
+        // A change long enough to need several records
+        for byte in target[0x100..0x100 + 0x12345].iter_mut() {
+            *byte = !*byte;
+        }
+        target.extend_from_slice(&[1, 2, 3]);
+
+        let mut patch = Vec::new();
+        write_ips(&source, &target, &mut patch).unwrap();
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+
+        let mut patch = Vec::new();
+        write_ips(&source, &source[..0x1234], &mut patch).unwrap();
+        assert_eq!(patch.len(), 5 + 3 + 3);
+        assert_eq!(apply(&patch, &source).unwrap(), &source[..0x1234]);
+    }
+
+    #[test]
+    fn written_ips_records_never_start_at_eof() {
+        let source = vec![0u8; IPS_EOF_OFFSET + 4];
+        let mut target = source.clone();
+        target[IPS_EOF_OFFSET] = 1;
+        let mut patch = Vec::new();
+        write_ips(&source, &target, &mut patch).unwrap();
+        assert_eq!(&patch[5..8], &[0x45, 0x4F, 0x45]);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    fn ips_rejects_a_cut_off_record() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0, 0, 1, 0, 4, 0x11]);
+        assert!(apply(&patch, &[0; 8]).is_err());
+    }
+
+    #[test]
+    fn ups_xors_hunks_and_grows_the_image() {
+        let source = vec![1u8, 2, 3, 4];
+        let target = vec![1u8, 9, 3, 4, 5, 6];
+        let mut patch = b"UPS1".to_vec();
+        push_varint(&mut patch, source.len());
+        push_varint(&mut patch, target.len());
+        push_varint(&mut patch, 1);
+        patch.extend_from_slice(&[2 ^ 9, 0]);
+        push_varint(&mut patch, 1);
+        patch.extend_from_slice(&[5, 6, 0]);
+        let patch = finish(patch, &source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_runs_every_action() {
+        let source = b"abcdef".to_vec();
+        let target = b"abXYcdcdcdef".to_vec();
+        let mut patch = b"BPS1".to_vec();
+        push_varint(&mut patch, source.len());
+        push_varint(&mut patch, target.len());
+        push_varint(&mut patch, 0);
+        // Source read "ab", target read "XY", source copy "cd" from 2,
+        // target copy "cdcd" from 4 overlapping itself, source copy "ef"
+        push_varint(&mut patch, 1 << 2);
+        push_varint(&mut patch, (1 << 2) | 1);
+        patch.extend_from_slice(b"XY");
+        push_varint(&mut patch, (1 << 2) | 2);
+        push_varint(&mut patch, 2 << 1);
+        push_varint(&mut patch, (3 << 2) | 3);
+        push_varint(&mut patch, 4 << 1);
+        push_varint(&mut patch, (1 << 2) | 2);
+        push_varint(&mut patch, 0);
+        let patch = finish(patch, &source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+    }
+
+    #[test]
+    fn checksum_mismatches_are_reported() {
+        let source = vec![1u8, 2, 3, 4];
+        let target = vec![1u8, 9, 3, 4];
+        let mut body = b"UPS1".to_vec();
+        push_varint(&mut body, 4);
+        push_varint(&mut body, 4);
+        push_varint(&mut body, 1);
+        body.extend_from_slice(&[2 ^ 9, 0]);
+
+        let patch = finish(body.clone(), &source, &target);
+        match apply(&patch, &[1, 2, 3, 5]) {
+            Err(RomError::PatchChecksumMismatch { checksum: PatchChecksum::Source, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let patch = finish(body.clone(), &source, &[0, 0, 0, 0]);
+        match apply(&patch, &source) {
+            Err(RomError::PatchChecksumMismatch { checksum: PatchChecksum::Target, expected, actual }) => {
+                assert_eq!(expected, util::crc32(0, &[0, 0, 0, 0]));
+                assert_eq!(actual, util::crc32(0, &target));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut patch = finish(body, &source, &target);
+        let len = patch.len();
+        patch[len - 1] ^= 0xFF;
+        match apply(&patch, &source) {
+            Err(RomError::PatchChecksumMismatch { checksum: PatchChecksum::Patch, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -1,6 +1,52 @@
 use mem::Mem;
+use mapper::{FetchPhase, Mapper};
+use region::Region;
+use rom::VsPpu;
+use vs;
 
-use std::ops::Deref;
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+
+// Dots on a scanline. The lines in a frame depend on the region.
+pub const DOTS_PER_LINE: u16 = 341;
+
+const CTRL_NAMETABLE: u8 = 0x03;
+const CTRL_INCREMENT: u8 = 0x04;
+const CTRL_SPRITE_TABLE: u8 = 0x08;
+const CTRL_BG_TABLE: u8 = 0x10;
+const CTRL_SPRITE_SIZE: u8 = 0x20;
+const CTRL_NMI: u8 = 0x80;
+
+const MASK_GREYSCALE: u8 = 0x01;
+const MASK_BG_LEFT: u8 = 0x02;
+const MASK_SPRITES_LEFT: u8 = 0x04;
+const MASK_BG: u8 = 0x08;
+const MASK_SPRITES: u8 = 0x10;
+const MASK_EMPHASIS: u8 = 0xE0;
+// Red and green as NTSC has them
+const MASK_EMPHASIS_RED: u8 = 0x20;
+const MASK_EMPHASIS_GREEN: u8 = 0x40;
+
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_SPRITE0: u8 = 0x40;
+const STATUS_VBLANK: u8 = 0x80;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Sprites the PPU can draw on one scanline, and the most there can be
+// with the limit removed
+const SPRITES_PER_LINE: usize = 8;
+const MAX_SPRITES_PER_LINE: usize = 64;
+
+// Sprite attribute bits
+const ATTR_PALETTE: u8 = 0x03;
+// Bits 2-4 of the attribute byte are not implemented in OAM
+const ATTR_UNUSED: u8 = 0x1C;
+const ATTR_BEHIND: u8 = 0x20;
+const ATTR_FLIP_X: u8 = 0x40;
+const ATTR_FLIP_Y: u8 = 0x80;
 
 pub struct Ppu {
     /*
@@ -13,22 +59,1353 @@ pub struct Ppu {
     PPUSCROLL       $2005 	    xxxx xxxx 	fine scroll position (two writes: X, Y)
     PPUADDR         $2006 	    aaaa aaaa 	PPU read/write address (two writes: MSB, LSB)
     PPUDATA         $2007 	    dddd dddd 	PPU data read/write
-    OAMDMA 	        $4014 	    aaaa aaaa 	OAM DMA high address 
+    OAMDMA 	        $4014 	    aaaa aaaa 	OAM DMA high address
     */
+    // The cartridge, shared with the CPU's memory map, supplies the
+    // pattern tables and decides where the nametables live
+    mapper: Rc<RefCell<Box<Mapper>>>,
+    ctrl: u8,
+    mask: u8,
+    // Only the top three bits are stored, the rest read as open bus
+    status: u8,
+    oam_addr: u8,
+    // Scroll and address state shared by $2005 and $2006. v is the
+    // current VRAM address, t the address of the top left of the screen,
+    // x the fine X scroll and w the write toggle. v and t are laid out as
+    //
+    //   yyy NN YYYYY XXXXX
+    //   ||| || ||||| +++++-- coarse X scroll
+    //   ||| || +++++-------- coarse Y scroll
+    //   ||| ++-------------- nametable select
+    //   +++----------------- fine Y scroll
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    // Reads of $2007 below the palette return the previous read's byte
+    read_buffer: u8,
+    // The register bus holds the last value driven onto it, which is
+    // what write-only registers and unused status bits read back as
+    io_latch: u8,
+    // The console's 2KB of nametable RAM. Which 1KB page each nametable
+    // uses is up to the cartridge, which can also replace it with its
+    // own memory.
+    ciram: [u8; 0x800],
+    // Backdrop and background palettes followed by the sprite palettes.
+    // Entry 0 of each sprite palette is the same memory as the
+    // background palette's.
+    palette: [u8; 32],
+    // 64 sprites of 4 bytes: y, tile, attributes and x
+    oam: [u8; 256],
+    // Last dot processed, dot 0 of line 0 being the first of a frame
+    scanline: u16,
+    dot: u16,
+    frame: u64,
+    // Background tile being fetched: its nametable byte, the 2-bit
+    // palette from its attribute byte and its two pattern planes
+    nt_latch: u8,
+    at_latch: u8,
+    pattern_lo_latch: u8,
+    pattern_hi_latch: u8,
+    // Pattern and attribute shifters. The high byte holds the tile being
+    // drawn and the low byte the next one, which is loaded every 8 dots;
+    // fine X picks the bit that is output.
+    bg_pattern_lo: u16,
+    bg_pattern_hi: u16,
+    bg_attr_lo: u16,
+    bg_attr_hi: u16,
+    // Sprites found in range of the next line during evaluation, as
+    // y, tile, attributes and x
+    secondary_oam: [u8; 32],
+    secondary_count: usize,
+    secondary_has_sprite0: bool,
+    // Draw only the eight sprites hardware can on each line. Without the
+    // limit, sprites in range past the eighth are drawn after them, but
+    // evaluation and the overflow flag are unchanged so games behave the
+    // same.
+    sprite_limit: bool,
+    // Sets the lines in a frame, where vblank falls and the order of the
+    // emphasis bits
+    region: Region,
+    // OAM indices of the sprites in range past the eighth
+    extra_sprites: Vec<usize>,
+    // Sprites being drawn on the current line, fetched from secondary
+    // OAM at the end of the previous one
+    sprite_count: usize,
+    sprite_pattern_lo: [u8; MAX_SPRITES_PER_LINE],
+    sprite_pattern_hi: [u8; MAX_SPRITES_PER_LINE],
+    sprite_attr: [u8; MAX_SPRITES_PER_LINE],
+    sprite_x: [u8; MAX_SPRITES_PER_LINE],
+    sprite0_on_line: bool,
+    // Pixels of the frame being drawn and of the last one completed, as
+    // 9-bit palette indices: the 6-bit colour with the emphasis bits of
+    // PPUMASK above it
+    back_buffer: Vec<u16>,
+    front_buffer: Vec<u16>,
+    frame_complete: bool,
+    // NMI waiting for the CPU to take it
+    nmi_pending: bool,
+    // A PPUSTATUS read the dot before vblank starts stops it being set
+    // for that frame
+    suppress_vblank: bool,
+    // The Vs. System's 2C05 swaps the addresses of PPUCTRL and PPUMASK
+    // and has an ID in the low bits of PPUSTATUS
+    swap_ctrl_mask: bool,
+    status_id: Option<u8>,
+}
+
+// Offset into palette RAM for an address in $3F00-$3FFF. Entry 0 of each
+// sprite palette ($3F10/$3F14/$3F18/$3F1C) mirrors the one of the
+// background palette 16 bytes below.
+fn palette_offset(addr: u16) -> usize {
+    let offset = addr as usize & 0x1F;
+    if offset & 0x13 == 0x10 {
+        offset & 0x0F
+    } else {
+        offset
+    }
 }
 
 impl Mem for Ppu {
+    // Read a register, addr being 0-7
     fn loadb(&mut self, addr: u16) -> u8 {
-        0
+        let val = match addr {
+            2 => {
+                let val = self.status_value();
+                if self.scanline == self.region.vblank_line() {
+                    match self.dot {
+                        // Vblank is about to start: it reads clear and
+                        // neither the flag nor the NMI happen this frame
+                        0 => self.suppress_vblank = true,
+                        // Vblank has just started: it reads set, but the
+                        // read clears it before the NMI gets out
+                        1...2 => self.nmi_pending = false,
+                        _ => {}
+                    }
+                }
+                self.status &= !STATUS_VBLANK;
+                self.w = false;
+                val
+            }
+            4 => {
+                // Secondary OAM is being cleared to $FF at the start of
+                // a rendering line, and reads see the value being written
+                if self.rendering_line() && self.dot >= 1 && self.dot <= 64 {
+                    0xFF
+                } else {
+                    self.oam[self.oam_addr as usize]
+                }
+            }
+            7 => {
+                let addr = self.v & 0x3FFF;
+                let val = if addr >= 0x3F00 {
+                    // Palette reads skip the buffer, which is filled from
+                    // the nametable underneath instead. Palette entries are
+                    // 6 bits with open bus above them.
+                    self.read_buffer = self.vram_loadb(addr & 0x2FFF);
+                    (self.vram_loadb(addr) & 0x3F) | (self.io_latch & 0xC0)
+                } else {
+                    let val = self.read_buffer;
+                    self.read_buffer = self.vram_loadb(addr);
+                    val
+                };
+                self.increment_v();
+                val
+            }
+            _ => self.io_latch,
+        };
+        self.io_latch = val;
+        val
     }
 
+    // Write a register, addr being 0-7
     fn storeb(&mut self, addr: u16, val: u8) {
-
+        self.io_latch = val;
+        let addr = if self.swap_ctrl_mask && addr < 2 { addr ^ 1 } else { addr };
+        match addr {
+            0 => {
+                // Enabling NMI during vblank raises one straight away
+                if self.ctrl & CTRL_NMI == 0 && val & CTRL_NMI != 0 && self.status & STATUS_VBLANK != 0 {
+                    self.nmi_pending = true;
+                }
+                self.ctrl = val;
+                self.t = (self.t & 0xF3FF) | ((val & CTRL_NAMETABLE) as u16) << 10;
+            }
+            1 => self.mask = val,
+            3 => self.oam_addr = val,
+            4 => {
+                let addr = self.oam_addr;
+                self.write_oam(addr, val);
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 => {
+                if !self.w {
+                    self.t = (self.t & 0xFFE0) | (val >> 3) as u16;
+                    self.x = val & 0x07;
+                } else {
+                    self.t = (self.t & 0x8C1F) | ((val & 0x07) as u16) << 12 | ((val & 0xF8) as u16) << 2;
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                if !self.w {
+                    // The top bit of the 15-bit address is cleared
+                    self.t = (self.t & 0x00FF) | ((val & 0x3F) as u16) << 8;
+                } else {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            7 => {
+                let addr = self.v & 0x3FFF;
+                self.vram_storeb(addr, val);
+                self.increment_v();
+            }
+            _ => {}
+        }
     }
 }
 
 impl Ppu {
-    fn getReg(&mut self, addr: u16) -> u8 {
-        0
+    pub fn new(mapper: Rc<RefCell<Box<Mapper>>>) -> Ppu {
+        Ppu {
+            mapper: mapper,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            ciram: [0; 0x800],
+            palette: [0; 32],
+            oam: [0; 256],
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nt_latch: 0,
+            at_latch: 0,
+            pattern_lo_latch: 0,
+            pattern_hi_latch: 0,
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attr_lo: 0,
+            bg_attr_hi: 0,
+            secondary_oam: [0xFF; 32],
+            secondary_count: 0,
+            secondary_has_sprite0: false,
+            sprite_limit: true,
+            region: Region::Ntsc,
+            extra_sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            sprite_count: 0,
+            sprite_pattern_lo: [0; MAX_SPRITES_PER_LINE],
+            sprite_pattern_hi: [0; MAX_SPRITES_PER_LINE],
+            sprite_attr: [0; MAX_SPRITES_PER_LINE],
+            sprite_x: [0; MAX_SPRITES_PER_LINE],
+            sprite0_on_line: false,
+            back_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            front_buffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_complete: false,
+            nmi_pending: false,
+            suppress_vblank: false,
+            swap_ctrl_mask: false,
+            status_id: None,
+        }
+    }
+
+    // Behave as the given Vs. System PPU
+    pub fn set_vs_ppu(&mut self, ppu: VsPpu) {
+        self.swap_ctrl_mask = vs::swaps_ctrl_mask(ppu);
+        self.status_id = vs::status_id(ppu);
+    }
+
+    // PPUSTATUS: the flags over open bus, or over the 2C05's ID. The ID
+    // covers bit 5, the sprite overflow flag, when it is over 5 bits.
+    fn status_value(&self) -> u8 {
+        match self.status_id {
+            Some(id) if id > 0x1F => (self.status & 0xC0) | id,
+            Some(id) => (self.status & 0xE0) | id,
+            None => self.status | (self.io_latch & 0x1F),
+        }
+    }
+
+    // Read a register as loadb would, without any of its side effects
+    pub fn getReg(&mut self, addr: u16) -> u8 {
+        match addr {
+            2 => self.status_value(),
+            4 => self.oam[self.oam_addr as usize],
+            7 => {
+                let addr = self.v & 0x3FFF;
+                if addr >= 0x3F00 {
+                    (self.palette[palette_offset(addr)] & 0x3F) | (self.io_latch & 0xC0)
+                } else {
+                    self.read_buffer
+                }
+            }
+            _ => self.io_latch,
+        }
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    // Frames started since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame
+    }
+
+    // Take the pending NMI, if any, for the CPU to service
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
+    // The last completed frame, 256 by 240 pixels of 9-bit palette
+    // indices. See video::convert to turn it into colours.
+    pub fn frame(&self) -> &[u16] {
+        &self.front_buffer
+    }
+
+    // Whether a frame has been completed since last asked
+    pub fn take_frame_complete(&mut self) -> bool {
+        let complete = self.frame_complete;
+        self.frame_complete = false;
+        complete
+    }
+
+    // Store a byte in OAM. The unused attribute bits are not
+    // implemented and always read back as 0.
+    fn write_oam(&mut self, addr: u8, val: u8) {
+        let val = if addr & 0x03 == 2 { val & !ATTR_UNUSED } else { val };
+        self.oam[addr as usize] = val;
+    }
+
+    // Turn the eight sprites per line limit off to stop the flicker games
+    // use to work around it. Best left on for games that rely on the limit
+    // to hide sprites.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // Vblank ends and the next frame's fetches start on the last line
+    fn pre_render_line(&self) -> u16 {
+        self.region.lines_per_frame() - 1
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_BG | MASK_SPRITES) != 0
+    }
+
+    // Whether the PPU is fetching from memory: on the visible and
+    // pre-render lines with rendering on
+    fn rendering_line(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < SCREEN_HEIGHT as u16 || self.scanline == self.pre_render_line())
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+
+    // Advance by a single dot. See Region::ppu_ratio for how many make
+    // a CPU cycle.
+    pub fn step(&mut self) {
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE - 1 && self.scanline == self.pre_render_line()
+            && self.frame % 2 == 1 && self.rendering_enabled() && self.region.skips_odd_dot() {
+            // Odd frames skip the last dot of the pre-render line while
+            // rendering, leaving NTSC frames alternately a dot short
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.region.lines_per_frame() {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+
+        if self.dot == 0 && self.scanline == SCREEN_HEIGHT as u16 {
+            // The last visible line is done
+            mem::swap(&mut self.front_buffer, &mut self.back_buffer);
+            self.frame_complete = true;
+        }
+
+        if self.dot == 1 {
+            if self.scanline == self.region.vblank_line() {
+                if !self.suppress_vblank {
+                    self.status |= STATUS_VBLANK;
+                    if self.ctrl & CTRL_NMI != 0 {
+                        self.nmi_pending = true;
+                    }
+                }
+                self.suppress_vblank = false;
+            } else if self.scanline == self.pre_render_line() {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE0 | STATUS_OVERFLOW);
+            }
+        }
+
+        if self.rendering_line() {
+            self.fetch_background();
+            self.fetch_sprites();
+        }
+        if self.scanline < SCREEN_HEIGHT as u16 && self.dot >= 1 && self.dot <= 256 {
+            self.render_pixel();
+        }
+    }
+
+    // Background fetches and scroll updates of a rendering line. Each
+    // tile takes 8 dots: nametable byte, attribute byte, then the low and
+    // high pattern planes, two dots apiece. Dots 1-256 fetch the tiles
+    // for the next line's dots 9-256 and 321-336 its first two tiles.
+    fn fetch_background(&mut self) {
+        let dot = self.dot;
+        if (dot >= 2 && dot <= 257) || (dot >= 322 && dot <= 337) {
+            self.shift_background();
+        }
+        if (dot >= 1 && dot <= 256) || (dot >= 321 && dot <= 336) {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    let addr = 0x2000 | (self.v & 0x0FFF);
+                    self.nt_latch = self.fetch(addr, FetchPhase::Background);
+                }
+                2 => {
+                    let v = self.v;
+                    let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    // Each attribute byte covers 4x4 tiles, 2 bits per 2x2
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.at_latch = (self.fetch(addr, FetchPhase::Background) >> shift) & 0x03;
+                }
+                4 => {
+                    let addr = self.pattern_addr();
+                    self.pattern_lo_latch = self.fetch(addr, FetchPhase::Background);
+                }
+                6 => {
+                    let addr = self.pattern_addr() + 8;
+                    self.pattern_hi_latch = self.fetch(addr, FetchPhase::Background);
+                }
+                7 => self.increment_x(),
+                _ => {}
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                // Back to the left edge: coarse X and the horizontal nametable
+                self.v = (self.v & !0x041F) | (self.t & 0x041F);
+            }
+            280...304 if self.scanline == self.pre_render_line() => {
+                // Back to the top: fine Y, coarse Y and the vertical nametable
+                self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+            }
+            // Two unused nametable fetches end the line, which some
+            // mappers count to find the end of a scanline
+            337 | 339 => {
+                let addr = 0x2000 | (self.v & 0x0FFF);
+                self.nt_latch = self.fetch(addr, FetchPhase::Background);
+            }
+            _ => {}
+        }
+    }
+
+    // Address of the current tile's low pattern plane
+    fn pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BG_TABLE != 0 { 0x1000 } else { 0 };
+        table | (self.nt_latch as u16) << 4 | (self.v >> 12)
+    }
+
+    fn shift_background(&mut self) {
+        if self.mask & MASK_BG != 0 {
+            self.bg_pattern_lo <<= 1;
+            self.bg_pattern_hi <<= 1;
+            self.bg_attr_lo <<= 1;
+            self.bg_attr_hi <<= 1;
+        }
+    }
+
+    // Put the tile just fetched into the low byte of the shifters
+    fn load_background_shifters(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.pattern_lo_latch as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.pattern_hi_latch as u16;
+        let attr_lo = if self.at_latch & 0x01 != 0 { 0xFF } else { 0x00 };
+        let attr_hi = if self.at_latch & 0x02 != 0 { 0xFF } else { 0x00 };
+        self.bg_attr_lo = (self.bg_attr_lo & 0xFF00) | attr_lo;
+        self.bg_attr_hi = (self.bg_attr_hi & 0xFF00) | attr_hi;
+    }
+
+    // Next tile across, into the horizontally adjacent nametable at the
+    // right edge
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v = (self.v & !0x001F) ^ 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Next pixel row down. Row 29 is the last of a nametable and moves
+    // to the vertically adjacent one; rows 30 and 31 hold attributes and
+    // wrap to 0 in the same nametable when scrolled into.
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+            let mut coarse_y = (self.v & 0x03E0) >> 5;
+            if coarse_y == 29 {
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            } else if coarse_y == 31 {
+                coarse_y = 0;
+            } else {
+                coarse_y += 1;
+            }
+            self.v = (self.v & !0x03E0) | coarse_y << 5;
+        }
+    }
+
+    // Sprite evaluation and pattern fetches for the next line. Dots 1-64
+    // clear secondary OAM and dots 65-256 fill it; the result is only
+    // used from dot 257, so evaluation runs in one go at dot 256.
+    fn fetch_sprites(&mut self) {
+        let dot = self.dot;
+        if dot == 256 {
+            if self.scanline == self.pre_render_line() {
+                // Nothing is evaluated for line 0, which never shows sprites
+                self.secondary_count = 0;
+                self.secondary_has_sprite0 = false;
+                self.extra_sprites.clear();
+            } else {
+                self.evaluate_sprites();
+            }
+        }
+        if dot >= 257 && dot <= 320 {
+            self.oam_addr = 0;
+            let slot = (dot as usize - 257) / 8;
+            let phase = (dot - 257) % 8;
+            match phase {
+                // Two unused nametable fetches come before each sprite's
+                // pattern planes
+                0 | 2 => {
+                    let addr = 0x2000 | (self.v & 0x0FFF);
+                    self.fetch(addr, FetchPhase::Sprite);
+                }
+                4 | 6 => {
+                    let entry = slot * 4;
+                    let (y, tile, attr, x) = (self.secondary_oam[entry], self.secondary_oam[entry + 1],
+                                              self.secondary_oam[entry + 2], self.secondary_oam[entry + 3]);
+                    let plane = if phase == 4 { 0 } else { 8 };
+                    let addr = self.sprite_pattern_addr(y, tile, attr) + plane;
+                    let mut pattern = self.fetch(addr, FetchPhase::Sprite);
+                    if slot >= self.secondary_count {
+                        // Empty slots are transparent
+                        pattern = 0;
+                    }
+                    self.load_sprite(slot, attr, x, plane, pattern);
+                }
+                _ => {}
+            }
+        }
+        if dot == 320 {
+            self.sprite_count = self.secondary_count;
+            self.sprite0_on_line = self.secondary_has_sprite0;
+            self.load_extra_sprites();
+        }
+    }
+
+    // Fetch the patterns of sprites past the eighth. The fetches are
+    // hidden from the cartridge so that mappers counting them see only
+    // what hardware does.
+    fn load_extra_sprites(&mut self) {
+        for i in 0..self.extra_sprites.len() {
+            let entry = self.extra_sprites[i] * 4;
+            let (y, tile, attr, x) = (self.oam[entry], self.oam[entry + 1], self.oam[entry + 2], self.oam[entry + 3]);
+            let addr = self.sprite_pattern_addr(y, tile, attr);
+            let slot = self.sprite_count;
+            for &plane in [0, 8].iter() {
+                let pattern = self.mapper.borrow_mut().chr_loadb(addr + plane, &self.ciram);
+                self.load_sprite(slot, attr, x, plane, pattern);
+            }
+            self.sprite_count += 1;
+        }
+    }
+
+    fn sprite_in_range(&self, y: u8) -> bool {
+        let row = self.scanline as i32 - y as i32;
+        row >= 0 && row < self.sprite_height() as i32
+    }
+
+    // Copy the first eight sprites in range of the next line into
+    // secondary OAM. Once it is full the PPU keeps looking for a ninth to
+    // set the overflow flag, but wrongly steps through the sprites'
+    // bytes as well as the sprites, so it compares tile numbers,
+    // attributes and X positions as if they were Y.
+    fn evaluate_sprites(&mut self) {
+        for byte in self.secondary_oam.iter_mut() {
+            *byte = 0xFF;
+        }
+        self.secondary_count = 0;
+        self.secondary_has_sprite0 = false;
+
+        let mut n = 0;
+        while n < 64 {
+            let y = self.oam[n * 4];
+            if self.secondary_count < SPRITES_PER_LINE {
+                let slot = self.secondary_count * 4;
+                self.secondary_oam[slot] = y;
+                if self.sprite_in_range(y) {
+                    self.secondary_oam[slot + 1..slot + 4].copy_from_slice(&self.oam[n * 4 + 1..n * 4 + 4]);
+                    self.secondary_count += 1;
+                    if n == 0 {
+                        self.secondary_has_sprite0 = true;
+                    }
+                }
+                n += 1;
+            } else {
+                let mut m = 0;
+                while n < 64 {
+                    let y = self.oam[n * 4 + m];
+                    if self.sprite_in_range(y) {
+                        self.status |= STATUS_OVERFLOW;
+                        break;
+                    }
+                    n += 1;
+                    m = (m + 1) & 3;
+                }
+                break;
+            }
+        }
+
+        self.extra_sprites.clear();
+        if !self.sprite_limit && self.secondary_count == SPRITES_PER_LINE {
+            let mut found = 0;
+            for n in 0..64 {
+                if self.sprite_in_range(self.oam[n * 4]) {
+                    found += 1;
+                    if found > SPRITES_PER_LINE {
+                        self.extra_sprites.push(n);
+                    }
+                }
+            }
+        }
+    }
+
+    // Address of a sprite's low pattern plane for the next line. Empty
+    // secondary OAM slots fetch tile $FF, which mappers watching the
+    // address lines see.
+    fn sprite_pattern_addr(&self, y: u8, tile: u8, attr: u8) -> u16 {
+        let mut tile = tile as u16;
+        let height = self.sprite_height();
+        let mut row = self.scanline.wrapping_sub(y as u16) & (height - 1);
+        if attr & ATTR_FLIP_Y != 0 {
+            row = height - 1 - row;
+        }
+        let table = if height == 16 {
+            // 8x16 sprites take their pattern table from bit 0 of the
+            // tile number and use an even/odd pair of tiles
+            let table = (tile & 0x01) << 12;
+            tile &= 0xFE;
+            if row >= 8 {
+                tile += 1;
+                row -= 8;
+            }
+            table
+        } else if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        table | tile << 4 | row
+    }
+
+    // Latch one pattern plane of a sprite to be drawn, reversing it for
+    // horizontally flipped sprites
+    fn load_sprite(&mut self, slot: usize, attr: u8, x: u8, plane: u16, pattern: u8) {
+        let pattern = if attr & ATTR_FLIP_X != 0 { pattern.reverse_bits() } else { pattern };
+        if plane == 0 {
+            self.sprite_pattern_lo[slot] = pattern;
+        } else {
+            self.sprite_pattern_hi[slot] = pattern;
+        }
+        self.sprite_attr[slot] = attr;
+        self.sprite_x[slot] = x;
+    }
+
+    // Frontmost opaque sprite pixel under the current dot as its slot and
+    // 2-bit pattern value
+    fn sprite_pixel(&self) -> Option<(usize, u8)> {
+        let x = self.dot - 1;
+        if self.mask & MASK_SPRITES == 0 || (x < 8 && self.mask & MASK_SPRITES_LEFT == 0) {
+            return None;
+        }
+        for slot in 0..self.sprite_count {
+            let column = x as i32 - self.sprite_x[slot] as i32;
+            if column < 0 || column >= 8 {
+                continue;
+            }
+            let bit = 7 - column;
+            let pattern = ((self.sprite_pattern_lo[slot] >> bit) & 1) | ((self.sprite_pattern_hi[slot] >> bit) & 1) << 1;
+            if pattern != 0 {
+                return Some((slot, pattern));
+            }
+        }
+        None
+    }
+
+    // Background pixel under the current dot as a 4-bit palette index,
+    // 0 being transparent
+    fn background_pixel(&self) -> u8 {
+        let x = self.dot - 1;
+        if self.mask & MASK_BG == 0 || (x < 8 && self.mask & MASK_BG_LEFT == 0) {
+            return 0;
+        }
+        let bit = 15 - self.x as u16;
+        let pattern = ((self.bg_pattern_lo >> bit) & 1) | ((self.bg_pattern_hi >> bit) & 1) << 1;
+        if pattern == 0 {
+            return 0;
+        }
+        let attr = ((self.bg_attr_lo >> bit) & 1) | ((self.bg_attr_hi >> bit) & 1) << 1;
+        (attr << 2 | pattern) as u8
+    }
+
+    fn render_pixel(&mut self) {
+        let index = if self.rendering_enabled() {
+            let background = self.background_pixel();
+            match self.sprite_pixel() {
+                Some((slot, pattern)) => {
+                    // Sprite 0 hits where it overlaps opaque background,
+                    // except at x=255. Clipping either layer in the left
+                    // column hides the pixel there, so no hit either.
+                    if slot == 0 && self.sprite0_on_line && background != 0 && self.dot != 256 {
+                        self.status |= STATUS_SPRITE0;
+                    }
+                    let attr = self.sprite_attr[slot];
+                    if background != 0 && attr & ATTR_BEHIND != 0 {
+                        background as u16
+                    } else {
+                        (0x10 | (attr & ATTR_PALETTE) << 2 | pattern) as u16
+                    }
+                }
+                None => background as u16,
+            }
+        } else if self.v & 0x3F00 == 0x3F00 {
+            // With rendering off, the backdrop is whatever palette entry
+            // v happens to point at
+            self.v
+        } else {
+            0
+        };
+        let mut color = self.palette[palette_offset(index)] & 0x3F;
+        if self.mask & MASK_GREYSCALE != 0 {
+            color &= 0x30;
+        }
+        let x = (self.dot - 1) as usize;
+        let pixel = color as u16 | (self.emphasis() as u16) << 1;
+        self.back_buffer[self.scanline as usize * SCREEN_WIDTH + x] = pixel;
+    }
+
+    // The emphasis bits of PPUMASK in NTSC order, which palettes expect
+    fn emphasis(&self) -> u8 {
+        let emphasis = self.mask & MASK_EMPHASIS;
+        if self.region.swaps_emphasis() {
+            let red_green = MASK_EMPHASIS_RED | MASK_EMPHASIS_GREEN;
+            (emphasis & !red_green) | (emphasis & MASK_EMPHASIS_RED) << 1 | (emphasis & MASK_EMPHASIS_GREEN) >> 1
+        } else {
+            emphasis
+        }
+    }
+
+    // Move v on after a $2007 access, across or down the nametable.
+    // While rendering the access collides with the background fetches and
+    // bumps coarse X and Y instead.
+    fn increment_v(&mut self) {
+        if self.rendering_line() {
+            self.increment_x();
+            self.increment_y();
+            return;
+        }
+        let step = if self.ctrl & CTRL_INCREMENT != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    // Read a byte from the PPU's own address space ($0000-$3FFF)
+    fn vram_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000...0x3EFF => self.fetch(addr, FetchPhase::Cpu),
+            _ => self.palette[palette_offset(addr)],
+        }
+    }
+
+    fn vram_storeb(&mut self, addr: u16, val: u8) {
+        let mut mapper = self.mapper.borrow_mut();
+        match addr {
+            0x0000...0x1FFF => {
+                mapper.ppu_fetch(addr, FetchPhase::Cpu);
+                mapper.chr_storeb(addr, val, &mut self.ciram)
+            }
+            0x2000...0x3EFF => {
+                mapper.ppu_fetch(addr, FetchPhase::Cpu);
+                mapper.nametable_storeb(addr & 0x2FFF, val, &mut self.ciram)
+            }
+            _ => self.palette[palette_offset(addr)] = val,
+        }
+    }
+
+    // Read the pattern tables or nametables, letting the cartridge see
+    // the address first
+    fn fetch(&mut self, addr: u16, phase: FetchPhase) -> u8 {
+        let mut mapper = self.mapper.borrow_mut();
+        mapper.ppu_fetch(addr, phase);
+        if addr < 0x2000 {
+            mapper.chr_loadb(addr, &self.ciram)
+        } else {
+            mapper.nametable_loadb(addr & 0x2FFF, &self.ciram)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+    use mapper::tests::rom;
+
+    // A PPU on an NROM board with 8KB of CHR RAM
+    fn ppu() -> Ppu {
+        let mut rom = rom(0, 0x8000);
+        rom.chr = Vec::new();
+        Ppu::new(Rc::new(RefCell::new(mapper::create(rom).unwrap())))
+    }
+
+    // Step until dot has been processed on scanline
+    fn run_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        while ppu.scanline != scanline || ppu.dot != dot {
+            ppu.step();
+        }
+    }
+
+    fn set_addr(ppu: &mut Ppu, addr: u16) {
+        ppu.storeb(6, (addr >> 8) as u8);
+        ppu.storeb(6, addr as u8);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn scroll_and_address_writes_fill_t_v_x_and_w() {
+        let mut ppu = ppu();
+        ppu.storeb(0, 0x02);
+        assert_eq!(ppu.t, 0x0800);
+
+        // Coarse X 15, fine X 5
+        ppu.storeb(5, 0x7D);
+        assert_eq!((ppu.t, ppu.x, ppu.w), (0x080F, 5, true));
+        // Coarse Y 11, fine Y 6
+        ppu.storeb(5, 0x5E);
+        assert_eq!((ppu.t, ppu.w), (0x696F, false));
+
+        ppu.storeb(6, 0x3D);
+        assert_eq!((ppu.t, ppu.v, ppu.w), (0x3D6F, 0, true));
+        ppu.storeb(6, 0xF0);
+        assert_eq!((ppu.t, ppu.v, ppu.w), (0x3DF0, 0x3DF0, false));
+        assert_eq!(ppu.x, 5);
+    }
+
+    #[test]
+    fn reading_status_resets_the_write_toggle() {
+        let mut ppu = ppu();
+        ppu.storeb(6, 0x21);
+        ppu.loadb(2);
+        set_addr(&mut ppu, 0x2305);
+        assert_eq!(ppu.v, 0x2305);
+
+        ppu.storeb(5, 0x08);
+        ppu.loadb(2);
+        ppu.storeb(5, 0x13);
+        assert_eq!((ppu.t & 0x1F, ppu.x, ppu.w), (2, 3, true));
+    }
+
+    #[test]
+    fn data_reads_are_buffered_except_for_the_palette() {
+        let mut ppu = ppu();
+        set_addr(&mut ppu, 0x2400);
+        ppu.storeb(7, 0x11);
+        ppu.storeb(7, 0x22);
+        set_addr(&mut ppu, 0x2F00);
+        ppu.storeb(7, 0x55);
+        set_addr(&mut ppu, 0x3F00);
+        ppu.storeb(7, 0x2A);
+
+        set_addr(&mut ppu, 0x2400);
+        assert_eq!(ppu.loadb(7), 0x00);
+        assert_eq!(ppu.loadb(7), 0x11);
+        assert_eq!(ppu.loadb(7), 0x22);
+
+        // The palette comes straight back, and the nametable byte under it
+        // goes into the buffer
+        set_addr(&mut ppu, 0x3F00);
+        assert_eq!(ppu.loadb(7), 0x2A);
+        set_addr(&mut ppu, 0x2000);
+        assert_eq!(ppu.loadb(7), 0x55);
+    }
+
+    #[test]
+    fn data_accesses_step_across_or_down() {
+        let mut ppu = ppu();
+        set_addr(&mut ppu, 0x2000);
+        ppu.storeb(7, 0);
+        assert_eq!(ppu.v, 0x2001);
+        ppu.storeb(0, CTRL_INCREMENT);
+        ppu.storeb(7, 0);
+        assert_eq!(ppu.v, 0x2021);
+    }
+
+    #[test]
+    fn vblank_starts_at_dot_1_of_line_241_and_ends_on_the_pre_render_line() {
+        let mut ppu = ppu();
+        ppu.storeb(0, CTRL_NMI);
+        run_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!ppu.take_nmi());
+
+        ppu.step();
+        assert!(ppu.take_nmi());
+        assert_eq!(ppu.getReg(2) & STATUS_VBLANK, STATUS_VBLANK);
+
+        run_to(&mut ppu, 261, 0);
+        assert_eq!(ppu.getReg(2) & STATUS_VBLANK, STATUS_VBLANK);
+        ppu.step();
+        assert_eq!(ppu.getReg(2) & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn reading_status_clears_vblank() {
+        let mut ppu = ppu();
+        run_to(&mut ppu, 245, 0);
+        assert_eq!(ppu.loadb(2) & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.loadb(2) & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn reading_status_the_dot_before_vblank_suppresses_it_for_the_frame() {
+        let mut ppu = ppu();
+        ppu.storeb(0, CTRL_NMI);
+        run_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.loadb(2) & STATUS_VBLANK, 0);
+        ppu.step();
+        assert_eq!(ppu.getReg(2) & STATUS_VBLANK, 0);
+        assert!(!ppu.take_nmi());
+
+        // The next frame is back to normal
+        run_to(&mut ppu, 240, 0);
+        run_to(&mut ppu, 241, 1);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn reading_status_as_vblank_starts_reads_it_set_but_cancels_the_nmi() {
+        let mut ppu = ppu();
+        ppu.storeb(0, CTRL_NMI);
+        run_to(&mut ppu, 241, 1);
+        assert_eq!(ppu.loadb(2) & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(!ppu.take_nmi());
+
+    }
+
+    #[test]
+    fn reading_status_after_the_race_leaves_the_nmi() {
+        let mut ppu = ppu();
+        ppu.storeb(0, CTRL_NMI);
+        run_to(&mut ppu, 241, 3);
+        ppu.loadb(2);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_raises_one_straight_away() {
+        let mut ppu = ppu();
+        run_to(&mut ppu, 241, 10);
+        assert!(!ppu.take_nmi());
+        ppu.storeb(0, CTRL_NMI);
+        assert!(ppu.take_nmi());
+        // Only on the change from off to on
+        ppu.storeb(0, CTRL_NMI);
+        assert!(!ppu.take_nmi());
+    }
+
+    // Fill the pattern table from tile, the nametable at $2000 with
+    // tile 1 and its attributes with attr, then set up palettes 0 and 1
+    // as $01-$03 and $05-$07 over a $0F backdrop
+    fn tiled(ppu: &mut Ppu, tile: &[u8; 16], attr: u8) {
+        set_addr(ppu, 0x0010);
+        for &byte in tile.iter() {
+            ppu.storeb(7, byte);
+        }
+        set_addr(ppu, 0x2000);
+        for i in 0..0x400 {
+            ppu.storeb(7, if i < 0x3C0 { 1 } else { attr });
+        }
+        set_addr(ppu, 0x3F00);
+        for &color in [0x0F, 0x01, 0x02, 0x03, 0x0F, 0x05, 0x06, 0x07].iter() {
+            ppu.storeb(7, color);
+        }
+    }
+
+    fn scroll(ppu: &mut Ppu, x: u8, y: u8) {
+        ppu.storeb(0, ppu.ctrl & !CTRL_NAMETABLE);
+        ppu.storeb(5, x);
+        ppu.storeb(5, y);
+    }
+
+    // Run until a whole frame has been drawn with the registers as they
+    // are
+    fn draw_frame(ppu: &mut Ppu) -> Vec<u16> {
+        for _ in 0..2 {
+            while !ppu.take_frame_complete() {
+                ppu.step();
+            }
+        }
+        ppu.frame().to_vec()
+    }
+
+    // Low plane $F0 and high plane $CC on every row, which gives pixels
+    // 3 3 1 1 2 2 0 0
+    const STRIPES: [u8; 16] = [0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0,
+                               0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC];
+
+    #[test]
+    fn the_shifters_draw_a_tile_left_to_right() {
+        let mut ppu = ppu();
+        tiled(&mut ppu, &STRIPES, 0);
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_BG | MASK_BG_LEFT);
+        let frame = draw_frame(&mut ppu);
+        for row in [0, 7, 100, 239].iter() {
+            let line = &frame[row * SCREEN_WIDTH..];
+            assert_eq!(&line[..16], &[3, 3, 1, 1, 2, 2, 0x0F, 0x0F, 3, 3, 1, 1, 2, 2, 0x0F, 0x0F]);
+        }
+    }
+
+    #[test]
+    fn fine_x_picks_the_first_bit_out_of_the_shifters() {
+        let mut ppu = ppu();
+        tiled(&mut ppu, &STRIPES, 0);
+        scroll(&mut ppu, 3, 0);
+        ppu.storeb(1, MASK_BG | MASK_BG_LEFT);
+        let frame = draw_frame(&mut ppu);
+        assert_eq!(&frame[..10], &[1, 2, 2, 0x0F, 0x0F, 3, 3, 1, 1, 2]);
+        // The last tile on the line comes from past the right edge
+        assert_eq!(&frame[250..256], &[2, 0x0F, 0x0F, 3, 3, 1]);
+    }
+
+    #[test]
+    fn attributes_pick_the_palette_of_each_2x2_tiles() {
+        let mut ppu = ppu();
+        // Palette 0 top left, 1 top right, 2 bottom left, 3 bottom right
+        tiled(&mut ppu, &STRIPES, 0xE4);
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_BG | MASK_BG_LEFT);
+        let frame = draw_frame(&mut ppu);
+        assert_eq!(frame[0], 3);
+        assert_eq!(frame[16], 7);
+        assert_eq!(frame[31], 0x0F);
+    }
+
+    #[test]
+    fn the_left_column_can_be_hidden() {
+        let mut ppu = ppu();
+        tiled(&mut ppu, &STRIPES, 0);
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_BG);
+        let frame = draw_frame(&mut ppu);
+        assert_eq!(&frame[..10], &[0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 0x0F, 3, 3]);
+    }
+
+    // Make tile 2 solid in colour 1 of sprite palette 0, $21
+    fn solid_sprite_tile(ppu: &mut Ppu) {
+        set_addr(ppu, 0x0020);
+        for i in 0..16 {
+            ppu.storeb(7, if i < 8 { 0xFF } else { 0x00 });
+        }
+        set_addr(ppu, 0x3F11);
+        ppu.storeb(7, 0x21);
+    }
+
+    // Fill OAM with sprites given as y, tile, attributes and x, hiding
+    // the rest below the screen with bytes that are never in range
+    fn set_sprites(ppu: &mut Ppu, sprites: &[(u8, u8, u8, u8)]) {
+        ppu.storeb(3, 0);
+        for i in 0..64 {
+            let (y, tile, attr, x) = sprites.get(i).cloned().unwrap_or((0xFF, 0xFF, 0xFF, 0xFF));
+            for &byte in [y, tile, attr, x].iter() {
+                ppu.storeb(4, byte);
+            }
+        }
+    }
+
+    fn sprite_frame(sprites: &[(u8, u8, u8, u8)], background: &[u8; 16]) -> (Ppu, Vec<u16>) {
+        let mut ppu = ppu();
+        tiled(&mut ppu, background, 0);
+        solid_sprite_tile(&mut ppu);
+        set_sprites(&mut ppu, sprites);
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_BG | MASK_BG_LEFT | MASK_SPRITES | MASK_SPRITES_LEFT);
+        let frame = draw_frame(&mut ppu);
+        (ppu, frame)
+    }
+
+    // Ten sprites side by side on line 50
+    fn row_of_ten() -> Vec<(u8, u8, u8, u8)> {
+        (0..10).map(|i| (50, 2, 0, i * 10)).collect()
+    }
+
+    #[test]
+    fn only_the_first_eight_sprites_on_a_line_are_drawn() {
+        let (ppu, frame) = sprite_frame(&row_of_ten(), &[0; 16]);
+        // Sprites show up the line after their Y
+        let line = &frame[51 * SCREEN_WIDTH..];
+        for i in 0..8 {
+            assert_eq!(line[i * 10], 0x21, "sprite {}", i);
+        }
+        assert_eq!(line[80], 0x0F);
+        assert_eq!(line[90], 0x0F);
+        assert_eq!(frame[50 * SCREEN_WIDTH], 0x0F);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, STATUS_OVERFLOW);
+    }
+
+    #[test]
+    fn eight_sprites_on_a_line_do_not_overflow() {
+        let (ppu, _) = sprite_frame(&row_of_ten()[..8], &[0; 16]);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_checks_miss_a_ninth_sprite_after_a_gap() {
+        // Sprite 8 is elsewhere, so evaluation moves on to the tile byte
+        // of sprite 9 rather than its Y and never sees it
+        let mut sprites = row_of_ten();
+        sprites[8].0 = 200;
+        let (ppu, _) = sprite_frame(&sprites, &[0; 16]);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_checks_can_take_a_tile_number_for_a_y() {
+        // Only eight sprites on line 50, but sprite 9's tile number is
+        // compared as if it were its Y
+        let mut sprites = row_of_ten();
+        sprites[8].0 = 200;
+        sprites[9] = (200, 48, 0, 0);
+        let (ppu, _) = sprite_frame(&sprites, &[0; 16]);
+        assert_eq!(ppu.status & STATUS_OVERFLOW, STATUS_OVERFLOW);
+    }
+
+    const SOLID: [u8; 16] = [0xFF; 16];
+
+    #[test]
+    fn sprite_0_hits_opaque_background() {
+        let (ppu, _) = sprite_frame(&[(30, 2, 0, 20)], &SOLID);
+        assert_eq!(ppu.status & STATUS_SPRITE0, STATUS_SPRITE0);
+    }
+
+    #[test]
+    fn sprite_0_misses_transparent_background() {
+        let (ppu, _) = sprite_frame(&[(30, 2, 0, 20)], &[0; 16]);
+        assert_eq!(ppu.status & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn other_sprites_do_not_hit() {
+        let (ppu, _) = sprite_frame(&[(0xF8, 2, 0, 20), (30, 2, 0, 20)], &SOLID);
+        assert_eq!(ppu.status & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn sprite_0_misses_at_x_255() {
+        let (ppu, _) = sprite_frame(&[(30, 2, 0, 255)], &SOLID);
+        assert_eq!(ppu.status & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn sprite_0_hit_clears_on_the_pre_render_line() {
+        let (mut ppu, _) = sprite_frame(&[(30, 2, 0, 20)], &SOLID);
+        ppu.storeb(1, 0);
+        run_to(&mut ppu, 261, 1);
+        assert_eq!(ppu.status & STATUS_SPRITE0, 0);
+    }
+
+    #[test]
+    fn lifting_the_sprite_limit_draws_every_sprite_on_a_line() {
+        let mut ppu = ppu();
+        ppu.set_sprite_limit(false);
+        tiled(&mut ppu, &[0; 16], 0);
+        solid_sprite_tile(&mut ppu);
+        set_sprites(&mut ppu, &row_of_ten());
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_SPRITES | MASK_SPRITES_LEFT);
+        let frame = draw_frame(&mut ppu);
+        let line = &frame[51 * SCREEN_WIDTH..];
+        for i in 0..10 {
+            assert_eq!(line[i * 10], 0x21, "sprite {}", i);
+        }
+        // Games still see the overflow the hardware would give
+        assert_eq!(ppu.status & STATUS_OVERFLOW, STATUS_OVERFLOW);
+    }
+
+    #[test]
+    fn sprites_past_the_eighth_go_behind_the_others() {
+        let mut ppu = ppu();
+        ppu.set_sprite_limit(false);
+        tiled(&mut ppu, &[0; 16], 0);
+        solid_sprite_tile(&mut ppu);
+        set_addr(&mut ppu, 0x3F15);
+        ppu.storeb(7, 0x25);
+        // Nine sprites in palette 0 then a tenth in palette 1 over the first
+        let mut sprites = row_of_ten();
+        sprites[9] = (50, 2, 1, 0);
+        set_sprites(&mut ppu, &sprites);
+        scroll(&mut ppu, 0, 0);
+        ppu.storeb(1, MASK_SPRITES | MASK_SPRITES_LEFT);
+        let frame = draw_frame(&mut ppu);
+        assert_eq!(frame[51 * SCREEN_WIDTH], 0x21);
+    }
+
+    fn read(ppu: &mut Ppu, addr: u16) -> u8 {
+        set_addr(ppu, addr);
+        if addr < 0x3F00 {
+            ppu.loadb(7);
+        }
+        ppu.loadb(7)
+    }
+
+    fn write(ppu: &mut Ppu, addr: u16, val: u8) {
+        set_addr(ppu, addr);
+        ppu.storeb(7, val);
+    }
+
+    #[test]
+    fn sprite_palette_backdrops_mirror_the_background_ones() {
+        let mut ppu = ppu();
+        for (i, &addr) in [0x3F10, 0x3F14, 0x3F18, 0x3F1C].iter().enumerate() {
+            write(&mut ppu, addr, 0x20 + i as u8);
+            assert_eq!(read(&mut ppu, addr - 0x10), 0x20 + i as u8);
+            write(&mut ppu, addr - 0x10, 0x30 + i as u8);
+            assert_eq!(read(&mut ppu, addr), 0x30 + i as u8);
+        }
+        // The other sprite colours are their own
+        write(&mut ppu, 0x3F11, 0x11);
+        assert_eq!(read(&mut ppu, 0x3F01), 0x00);
+    }
+
+    #[test]
+    fn palette_ram_repeats_every_32_bytes() {
+        let mut ppu = ppu();
+        write(&mut ppu, 0x3F05, 0x15);
+        assert_eq!(read(&mut ppu, 0x3F25), 0x15);
+        // The top two bits are open bus, left at $E5 by the address write
+        assert_eq!(read(&mut ppu, 0x3FE5), 0xD5);
+    }
+
+    #[test]
+    fn nametables_follow_the_cartridge_mirroring() {
+        // Horizontal: $2000 and $2400 share a page, $2800 is the other
+        let mut ppu = ppu();
+        write(&mut ppu, 0x2005, 0xAB);
+        assert_eq!(read(&mut ppu, 0x2405), 0xAB);
+        assert_eq!(read(&mut ppu, 0x2805), 0x00);
+        // $3000-$3EFF mirrors $2000-$2EFF
+        assert_eq!(read(&mut ppu, 0x3005), 0xAB);
+    }
+
+    #[test]
+    fn unused_sprite_attribute_bits_read_back_clear() {
+        let mut ppu = ppu();
+        ppu.storeb(3, 0x02);
+        ppu.storeb(4, 0xFF);
+        ppu.storeb(4, 0xFF);
+        ppu.storeb(3, 0x02);
+        assert_eq!(ppu.loadb(4), 0xE3);
+        ppu.storeb(3, 0x03);
+        assert_eq!(ppu.loadb(4), 0xFF);
+    }
+
+    #[test]
+    fn a_frame_completes_once_after_the_last_visible_line() {
+        let mut ppu = ppu();
+        for _ in 0..2 {
+            run_to(&mut ppu, 239, 340);
+            assert!(!ppu.take_frame_complete());
+            ppu.step();
+            assert!(ppu.take_frame_complete());
+            assert!(!ppu.take_frame_complete());
+        }
+    }
+
+    // Show the backdrop colour, the background being transparent tile 0
+    fn backdrop(ppu: &mut Ppu, color: u8) {
+        write(ppu, 0x3F00, color);
+        ppu.storeb(1, ppu.mask | MASK_BG | MASK_BG_LEFT);
+    }
+
+    #[test]
+    fn the_finished_frame_holds_while_the_next_is_drawn() {
+        let mut ppu = ppu();
+        backdrop(&mut ppu, 0x21);
+        let first = draw_frame(&mut ppu);
+        assert_eq!(first.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(first.iter().all(|&pixel| pixel == 0x21));
+
+        backdrop(&mut ppu, 0x16);
+        run_to(&mut ppu, 120, 0);
+        assert_eq!(ppu.frame(), &first[..]);
+        while !ppu.take_frame_complete() {
+            ppu.step();
+        }
+        assert!(ppu.frame().iter().all(|&pixel| pixel == 0x16));
+    }
+
+    #[test]
+    fn pixels_carry_the_emphasis_bits_above_the_colour() {
+        let mut ppu = ppu();
+        ppu.storeb(1, 0x20 | MASK_GREYSCALE);
+        backdrop(&mut ppu, 0x21);
+        // Red emphasis is bit 6, and greyscale drops the low bits
+        assert_eq!(draw_frame(&mut ppu)[0], 0x60);
+
+        ppu.storeb(1, 0xC0 | MASK_BG | MASK_BG_LEFT);
+        assert_eq!(draw_frame(&mut ppu)[0], 0x180 | 0x21);
+    }
+
+    #[test]
+    fn pal_emphasis_bits_are_given_in_ntsc_order() {
+        let mut ppu = ppu();
+        ppu.set_region(Region::Pal);
+        // PAL's $2001 bit 5 is green, which NTSC palettes hold at bit 7
+        ppu.storeb(1, 0x20);
+        backdrop(&mut ppu, 0x21);
+        assert_eq!(draw_frame(&mut ppu)[0], 0x80 | 0x21);
+    }
+
+}
@@ -0,0 +1,127 @@
+use rom::{INesHeader, Timing};
+
+// Names accepted by Region::from_name
+pub const REGION_NAMES: [&'static str; 3] = ["ntsc", "pal", "dendy"];
+
+// The console a game runs on, which sets the clock rates and the shape
+// of the frame:
+//
+//              CPU clock     PPU dots per    Lines   Vblank
+//                            CPU cycle               line
+// NTSC         1.789773 MHz  3               262     241
+// PAL          1.662607 MHz  3.2             312     241
+// Dendy        1.773448 MHz  3               312     291
+//
+// Dendy is the Famiclone sold in Russia, which runs at 50 Hz but keeps
+// NTSC's CPU to PPU ratio and puts its extra lines before vblank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    // Region a header's timing asks for. Games that run on either are
+    // played as NTSC.
+    pub fn from_timing(timing: Timing) -> Region {
+        match timing {
+            Timing::Ntsc | Timing::MultiRegion => Region::Ntsc,
+            Timing::Pal => Region::Pal,
+            Timing::Dendy => Region::Dendy,
+        }
+    }
+
+    // Region to run a game in: the override if given, otherwise the
+    // header's timing. Rom::load has already corrected that from the
+    // ROM database for known dumps.
+    pub fn select(header: &INesHeader, region_override: Option<Region>) -> Region {
+        region_override.unwrap_or_else(|| Region::from_timing(header.timing))
+    }
+
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+
+    // CPU cycles per second
+    pub fn cpu_clock(&self) -> u32 {
+        match *self {
+            Region::Ntsc => 1789773,
+            Region::Pal => 1662607,
+            Region::Dendy => 1773448,
+        }
+    }
+
+    // PPU dots per CPU cycle, as a numerator and denominator
+    pub fn ppu_ratio(&self) -> (u64, u64) {
+        match *self {
+            Region::Ntsc | Region::Dendy => (3, 1),
+            Region::Pal => (16, 5),
+        }
+    }
+
+    pub fn lines_per_frame(&self) -> u16 {
+        match *self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    // Line on whose dot 1 vblank starts and the NMI fires
+    pub fn vblank_line(&self) -> u16 {
+        match *self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    // Only the NTSC PPU skips a dot on odd frames
+    pub fn skips_odd_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // The PAL and Dendy PPUs have PPUMASK's red and green emphasis bits
+    // the other way round
+    pub fn swaps_emphasis(&self) -> bool {
+        *self != Region::Ntsc
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let dots = self.lines_per_frame() as f64 * 341.0
+            - if self.skips_odd_dot() { 0.5 } else { 0.0 };
+        let (num, den) = self.ppu_ratio();
+        self.cpu_clock() as f64 * num as f64 / den as f64 / dots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rom::INesHeader;
+
+    #[test]
+    fn ppu_ratio() {
+        assert_eq!(Region::Ntsc.ppu_ratio(), (3, 1));
+        assert_eq!(Region::Pal.ppu_ratio(), (16, 5));
+        assert_eq!(Region::Dendy.ppu_ratio(), (3, 1));
+    }
+
+    #[test]
+    fn frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::Pal.frame_rate() - 50.0070).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn multi_region_games_run_as_ntsc() {
+        let header = INesHeader::builder().timing(Timing::MultiRegion).build().unwrap();
+        assert_eq!(Region::select(&header, None), Region::Ntsc);
+        assert_eq!(Region::select(&header, Some(Region::Pal)), Region::Pal);
+    }
+}
@@ -1,11 +1,91 @@
-use std::io::{self, Read};
+use std::io::{self, Read, Write};
+use std::error::Error;
 use std::fmt;
 use util;
+use unif;
+use archive::{self, Container};
+use romdb::{Correction, RomDb};
+use mapper::Mirroring;
+
+// Largest ROM accepted from an NES 2.0 header. The non-exponent form
+// tops out just under this, anything larger is a corrupt header.
+const MAX_ROM_SIZE: usize = 0x4000000;
+
+// A PlayChoice-10 dump follows CHR ROM with the 8KB INST-ROM holding
+// the instruction screens shown by the menu CPU, then the security
+// PROM's 16 data bytes and 16 CounterOut bytes
+const PC10_INST_ROM_SIZE: usize = 0x2000;
+const PC10_PROM_SIZE: usize = 0x20;
+
+// Checksum that failed to match while applying a patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchChecksum {
+    Source,
+    Target,
+    Patch,
+}
 
 #[derive(Debug)]
 pub enum RomError {
     IoError(io::Error),
+    // Malformed data not covered by the variants below
     FormatError,
+    // First four bytes are not a known image format
+    BadMagic([u8; 4]),
+    // The file ended partway through a section, such as the header,
+    // trainer, PRG ROM or CHR ROM
+    Truncated { section: &'static str, expected: usize, got: usize },
+    UnsupportedMapper(u16),
+    // PRG ROM size the mapper cannot bank, such as under 8KB
+    InvalidPrgSize { mapper: u16, size: usize },
+    // UNIF board name with no known mapper
+    UnsupportedBoard(String),
+    // NES 2.0 header gives a ROM size that is zero or too large
+    InvalidNes2Size { field: &'static str, size: usize },
+    PatchChecksumMismatch { checksum: PatchChecksum, expected: u32, actual: u32 },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::IoError(ref err) => write!(f, "I/O error: {}", err),
+            RomError::FormatError => write!(f, "malformed image"),
+            RomError::BadMagic(magic) => {
+                write!(f, "unrecognised image, starts with {:02X} {:02X} {:02X} {:02X}",
+                       magic[0], magic[1], magic[2], magic[3])
+            }
+            RomError::Truncated { section, expected, got } => {
+                write!(f, "{} truncated, expected {} bytes but found {}, {} short",
+                       section, expected, got, expected - got)
+            }
+            RomError::UnsupportedMapper(mapper) => write!(f, "mapper {} is not supported", mapper),
+            RomError::InvalidPrgSize { mapper, size } => {
+                write!(f, "PRG ROM of {} bytes cannot be used with mapper {}", size, mapper)
+            }
+            RomError::UnsupportedBoard(ref board) => write!(f, "UNIF board {} is not supported", board),
+            RomError::InvalidNes2Size { field, size } => {
+                write!(f, "invalid NES 2.0 {} size of {} bytes", field, size)
+            }
+            RomError::PatchChecksumMismatch { checksum, expected, actual } => {
+                write!(f, "patch {} checksum mismatch, expected {:08X} but found {:08X}",
+                       match checksum {
+                           PatchChecksum::Source => "source",
+                           PatchChecksum::Target => "target",
+                           PatchChecksum::Patch => "file",
+                       },
+                       expected, actual)
+            }
+        }
+    }
+}
+
+impl Error for RomError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            RomError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<io::Error> for RomError {
@@ -14,6 +94,214 @@ impl From<io::Error> for RomError {
     }
 }
 
+// TV system from flags 9 and 10 of an iNES 1.0 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TvSystem {
+    Ntsc,
+    Pal,
+    // Runs on either, or the header says both
+    Dual,
+}
+
+// CPU/PPU timing, byte 12 of an NES 2.0 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
+}
+
+// PPU fitted to a Vs. System board, byte 13 low nibble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsPpu {
+    Rp2c03b,
+    Rp2c03g,
+    Rp2c04_0001,
+    Rp2c04_0002,
+    Rp2c04_0003,
+    Rp2c04_0004,
+    Rc2c03b,
+    Rc2c03c,
+    Rc2c05_01,
+    Rc2c05_02,
+    Rc2c05_03,
+    Rc2c05_04,
+    Rc2c05_05,
+    Unknown(u8),
+}
+
+impl VsPpu {
+    fn from_u8(val: u8) -> VsPpu {
+        match val {
+            0x0 => VsPpu::Rp2c03b,
+            0x1 => VsPpu::Rp2c03g,
+            0x2 => VsPpu::Rp2c04_0001,
+            0x3 => VsPpu::Rp2c04_0002,
+            0x4 => VsPpu::Rp2c04_0003,
+            0x5 => VsPpu::Rp2c04_0004,
+            0x6 => VsPpu::Rc2c03b,
+            0x7 => VsPpu::Rc2c03c,
+            0x8 => VsPpu::Rc2c05_01,
+            0x9 => VsPpu::Rc2c05_02,
+            0xA => VsPpu::Rc2c05_03,
+            0xB => VsPpu::Rc2c05_04,
+            0xC => VsPpu::Rc2c05_05,
+            _ => VsPpu::Unknown(val),
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match *self {
+            VsPpu::Rp2c03b => 0x0,
+            VsPpu::Rp2c03g => 0x1,
+            VsPpu::Rp2c04_0001 => 0x2,
+            VsPpu::Rp2c04_0002 => 0x3,
+            VsPpu::Rp2c04_0003 => 0x4,
+            VsPpu::Rp2c04_0004 => 0x5,
+            VsPpu::Rc2c03b => 0x6,
+            VsPpu::Rc2c03c => 0x7,
+            VsPpu::Rc2c05_01 => 0x8,
+            VsPpu::Rc2c05_02 => 0x9,
+            VsPpu::Rc2c05_03 => 0xA,
+            VsPpu::Rc2c05_04 => 0xB,
+            VsPpu::Rc2c05_05 => 0xC,
+            VsPpu::Unknown(val) => val,
+        }
+    }
+}
+
+// Vs. System protection and wiring variant, byte 13 high nibble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VsHardware {
+    Unisystem,
+    UnisystemRbiBaseball,
+    UnisystemTkoBoxing,
+    UnisystemSuperXevious,
+    UnisystemIceClimber,
+    DualSystem,
+    DualSystemBungelingBay,
+    Unknown(u8),
+}
+
+impl VsHardware {
+    fn from_u8(val: u8) -> VsHardware {
+        match val {
+            0 => VsHardware::Unisystem,
+            1 => VsHardware::UnisystemRbiBaseball,
+            2 => VsHardware::UnisystemTkoBoxing,
+            3 => VsHardware::UnisystemSuperXevious,
+            4 => VsHardware::UnisystemIceClimber,
+            5 => VsHardware::DualSystem,
+            6 => VsHardware::DualSystemBungelingBay,
+            _ => VsHardware::Unknown(val),
+        }
+    }
+
+    fn to_u8(&self) -> u8 {
+        match *self {
+            VsHardware::Unisystem => 0,
+            VsHardware::UnisystemRbiBaseball => 1,
+            VsHardware::UnisystemTkoBoxing => 2,
+            VsHardware::UnisystemSuperXevious => 3,
+            VsHardware::UnisystemIceClimber => 4,
+            VsHardware::DualSystem => 5,
+            VsHardware::DualSystemBungelingBay => 6,
+            VsHardware::Unknown(val) => val,
+        }
+    }
+}
+
+// Console type from flags 7 bits 0-1, extended by byte 13 in NES 2.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Nes,
+    VsSystem(VsPpu, VsHardware),
+    Playchoice10,
+    // NES 2.0 extended console type, byte 13 low nibble
+    Extended(u8),
+}
+
+// Input device the game expects by default, byte 15 of an NES 2.0 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionDevice {
+    Unspecified,
+    StandardControllers,
+    FourScore,
+    FamicomFourPlayers,
+    VsSystem,
+    VsSystemReversed,
+    VsPinball,
+    VsZapper,
+    Zapper,
+    TwoZappers,
+    HyperShot,
+    PowerPadA,
+    PowerPadB,
+    FamilyTrainerA,
+    FamilyTrainerB,
+    ArkanoidNes,
+    ArkanoidFamicom,
+    ArkanoidFamicomWithRecorder,
+    FamilyBasicKeyboard,
+    SnesMouse,
+    Other(u8),
+}
+
+impl ExpansionDevice {
+    pub fn from_u8(val: u8) -> ExpansionDevice {
+        match val {
+            0x00 => ExpansionDevice::Unspecified,
+            0x01 => ExpansionDevice::StandardControllers,
+            0x02 => ExpansionDevice::FourScore,
+            0x03 => ExpansionDevice::FamicomFourPlayers,
+            0x04 => ExpansionDevice::VsSystem,
+            0x05 => ExpansionDevice::VsSystemReversed,
+            0x06 => ExpansionDevice::VsPinball,
+            0x07 => ExpansionDevice::VsZapper,
+            0x08 => ExpansionDevice::Zapper,
+            0x09 => ExpansionDevice::TwoZappers,
+            0x0A => ExpansionDevice::HyperShot,
+            0x0B => ExpansionDevice::PowerPadA,
+            0x0C => ExpansionDevice::PowerPadB,
+            0x0D => ExpansionDevice::FamilyTrainerA,
+            0x0E => ExpansionDevice::FamilyTrainerB,
+            0x0F => ExpansionDevice::ArkanoidNes,
+            0x10 => ExpansionDevice::ArkanoidFamicom,
+            0x11 => ExpansionDevice::ArkanoidFamicomWithRecorder,
+            0x23 => ExpansionDevice::FamilyBasicKeyboard,
+            0x2E => ExpansionDevice::SnesMouse,
+            _ => ExpansionDevice::Other(val),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            ExpansionDevice::Unspecified => 0x00,
+            ExpansionDevice::StandardControllers => 0x01,
+            ExpansionDevice::FourScore => 0x02,
+            ExpansionDevice::FamicomFourPlayers => 0x03,
+            ExpansionDevice::VsSystem => 0x04,
+            ExpansionDevice::VsSystemReversed => 0x05,
+            ExpansionDevice::VsPinball => 0x06,
+            ExpansionDevice::VsZapper => 0x07,
+            ExpansionDevice::Zapper => 0x08,
+            ExpansionDevice::TwoZappers => 0x09,
+            ExpansionDevice::HyperShot => 0x0A,
+            ExpansionDevice::PowerPadA => 0x0B,
+            ExpansionDevice::PowerPadB => 0x0C,
+            ExpansionDevice::FamilyTrainerA => 0x0D,
+            ExpansionDevice::FamilyTrainerB => 0x0E,
+            ExpansionDevice::ArkanoidNes => 0x0F,
+            ExpansionDevice::ArkanoidFamicom => 0x10,
+            ExpansionDevice::ArkanoidFamicomWithRecorder => 0x11,
+            ExpansionDevice::FamilyBasicKeyboard => 0x23,
+            ExpansionDevice::SnesMouse => 0x2E,
+            ExpansionDevice::Other(val) => val,
+        }
+    }
+}
+
 pub struct INesHeader {
     // Header                       16 bytes
     // $4E $45 $53 $1A
@@ -42,21 +330,147 @@ pub struct INesHeader {
     pub flags_9: u8,
     // -> 10  Flags: https://wiki.nesdev.com/w/index.php/INES#Flags_10
     // Not a part of official specification
+    // RRPPRRTT
+    // P - Board has bus conflicts (1) or PRG RAM at $6000 absent (0)
+    // T - TV system: 0 NTSC, 2 PAL, 1 or 3 dual compatible
     pub flags_10: u8,
-    zero: [u8; 5]
+    // -> 11-15  NES 2.0 fields, zero in iNES 1.0
+    pub extra: [u8; 5],
+    // The 16 bytes as they were read, before any garbage was cleaned
+    // from them
+    raw: [u8; 16],
+
+    // Decoded fields, from the NES 2.0 layout when flags 7 bits 2-3 are
+    // 2 and otherwise from the iNES 1.0 fields above
+    pub nes2: bool,
+    // Bytes 7-15 held garbage such as "DiskDude!" and were ignored
+    pub garbage_cleaned: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    // Sizes in bytes
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub mirroring: Mirroring,
+    // Battery backed PRG RAM or other persistent memory
+    pub battery: bool,
+    // 512 bytes at $7000 precede the PRG ROM
+    pub trainer: bool,
+    pub tv_system: TvSystem,
+    pub timing: Timing,
+    pub console: Console,
+    pub misc_roms: u8,
+    pub expansion_device: ExpansionDevice,
 }
 
 impl fmt::Display for INesHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PRG_ROM: {} KB, CHR_ROM: {} KB",
-            self.prg_rom as u32 * 16,
-            self.chr_rom as u32 * 8
+        write!(f, "{}, mapper {}.{}, PRG_ROM: {} KB, CHR_ROM: {} KB, {:?}",
+            if self.nes2 { "NES 2.0" } else { "iNES" },
+            self.mapper,
+            self.submapper,
+            self.prg_rom_size / 1024,
+            self.chr_rom_size / 1024,
+            self.timing
         )
     }
 }
 
 impl INesHeader {
-    fn parse_header(header: [u8; 16]) -> Self {
+    pub fn parse_header(header: [u8; 16]) -> Self {
+        let raw = header;
+        let mut header = header;
+        // Old dumping tools wrote their name over bytes 7-15, DiskDude! being
+        // the best known. Without the NES 2.0 marker bytes 12-15 must be zero,
+        // so treat anything else there as garbage and keep only bytes 0-6.
+        let garbage_cleaned = match header[7] & 0x0C {
+            0x04 => true,
+            0x00 => header[12..16].iter().any(|&byte| byte != 0),
+            _ => false,
+        };
+        if garbage_cleaned {
+            for byte in header[7..16].iter_mut() {
+                *byte = 0;
+            }
+        }
+
+        let nes2 = header[7] & 0x0C == 0x08;
+        let mut mapper = (header[7] & 0xF0) as u16 | (header[6] >> 4) as u16;
+
+        let mut submapper = 0;
+        let mut prg_rom_size = header[4] as usize * 0x4000;
+        let mut chr_rom_size = header[5] as usize * 0x2000;
+        // iNES 1.0 treats a PRG RAM size of 0 as 8KB for compatibility
+        let mut prg_ram_size = ::std::cmp::max(header[8] as usize, 1) * 0x2000;
+        let mut prg_nvram_size = 0;
+        let mut chr_ram_size = if header[5] == 0 { 0x2000 } else { 0 };
+        let mut chr_nvram_size = 0;
+        let mirroring = if header[6] & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if header[6] & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = header[6] & 0x02 != 0;
+        let trainer = header[6] & 0x04 != 0;
+        let mut tv_system = match (header[9] & 0x01, header[10] & 0x03) {
+            (_, 1) | (_, 3) => TvSystem::Dual,
+            (1, _) | (_, 2) => TvSystem::Pal,
+            _ => TvSystem::Ntsc,
+        };
+        let mut timing = match tv_system {
+            TvSystem::Ntsc => Timing::Ntsc,
+            TvSystem::Pal => Timing::Pal,
+            TvSystem::Dual => Timing::MultiRegion,
+        };
+        let mut console = match header[7] & 0x03 {
+            1 => Console::VsSystem(VsPpu::Rp2c03b, VsHardware::Unisystem),
+            2 => Console::Playchoice10,
+            _ => Console::Nes,
+        };
+        let mut misc_roms = 0;
+        let mut expansion_device = ExpansionDevice::Unspecified;
+
+        if battery {
+            prg_nvram_size = prg_ram_size;
+            prg_ram_size = 0;
+        }
+
+        if nes2 {
+            mapper |= (header[8] as u16 & 0x0F) << 8;
+            submapper = header[8] >> 4;
+            prg_rom_size = INesHeader::rom_size(header[4], header[9] & 0x0F, 0x4000);
+            chr_rom_size = INesHeader::rom_size(header[5], header[9] >> 4, 0x2000);
+            prg_ram_size = INesHeader::ram_size(header[10] & 0x0F);
+            prg_nvram_size = INesHeader::ram_size(header[10] >> 4);
+            chr_ram_size = INesHeader::ram_size(header[11] & 0x0F);
+            chr_nvram_size = INesHeader::ram_size(header[11] >> 4);
+            timing = match header[12] & 0x03 {
+                0 => Timing::Ntsc,
+                1 => Timing::Pal,
+                2 => Timing::MultiRegion,
+                _ => Timing::Dendy,
+            };
+            tv_system = match timing {
+                Timing::Ntsc => TvSystem::Ntsc,
+                Timing::Pal | Timing::Dendy => TvSystem::Pal,
+                Timing::MultiRegion => TvSystem::Dual,
+            };
+            console = match header[7] & 0x03 {
+                0 => Console::Nes,
+                1 => Console::VsSystem(VsPpu::from_u8(header[13] & 0x0F),
+                                       VsHardware::from_u8(header[13] >> 4)),
+                2 => Console::Playchoice10,
+                _ => Console::Extended(header[13] & 0x0F),
+            };
+            misc_roms = header[14] & 0x03;
+            expansion_device = ExpansionDevice::from_u8(header[15] & 0x3F);
+        }
+
         INesHeader {
             magic: [
                 header[0],
@@ -71,44 +485,642 @@ impl INesHeader {
             prg_ram: header[8],
             flags_9: header[9],
             flags_10: header[10],
-            zero: [0u8; 5]
+            extra: [
+                header[11],
+                header[12],
+                header[13],
+                header[14],
+                header[15],
+            ],
+            raw: raw,
+
+            nes2: nes2,
+            garbage_cleaned: garbage_cleaned,
+            mapper: mapper,
+            submapper: submapper,
+            prg_rom_size: prg_rom_size,
+            chr_rom_size: chr_rom_size,
+            prg_ram_size: prg_ram_size,
+            prg_nvram_size: prg_nvram_size,
+            chr_ram_size: chr_ram_size,
+            chr_nvram_size: chr_nvram_size,
+            mirroring: mirroring,
+            battery: battery,
+            trainer: trainer,
+            tv_system: tv_system,
+            timing: timing,
+            console: console,
+            misc_roms: misc_roms,
+            expansion_device: expansion_device,
         }
     }
 
+    // NES 2.0 ROM size from the LSB byte and MSB nibble. An MSB nibble of
+    // $F switches the LSB to EEEEEEMM, giving 2^E * (MM * 2 + 1) bytes.
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0x03) as usize * 2 + 1;
+            2usize.checked_pow(exponent).map_or(usize::MAX, |size| size.saturating_mul(multiplier))
+        } else {
+            ((msb as usize) << 8 | lsb as usize) * unit
+        }
+    }
+
+    // Reject NES 2.0 ROM sizes that no real cartridge could have, before
+    // trying to allocate them
+    fn check_sizes(&self) -> Result<(), RomError> {
+        if self.prg_rom_size == 0 || self.prg_rom_size > MAX_ROM_SIZE {
+            return Err(RomError::InvalidNes2Size { field: "PRG ROM", size: self.prg_rom_size });
+        }
+        if self.chr_rom_size > MAX_ROM_SIZE {
+            return Err(RomError::InvalidNes2Size { field: "CHR ROM", size: self.chr_rom_size });
+        }
+        Ok(())
+    }
+
+    // NES 2.0 RAM sizes are shift counts: 64 << n bytes, or none for 0
+    fn ram_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+
     fn check_magic(&self) -> bool {
         self.magic == [0x4E, 0x45, 0x53, 0x1A]
     }
+
+    // The 16 header bytes as stored in the image
+    pub fn raw_bytes(&self) -> [u8; 16] {
+        self.raw
+    }
+
+    // The 16 header bytes with any garbage in bytes 7-15 cleaned out
+    pub fn to_bytes(&self) -> [u8; 16] {
+        [
+            self.magic[0], self.magic[1], self.magic[2], self.magic[3],
+            self.prg_rom, self.chr_rom, self.flags_6, self.flags_7,
+            self.prg_ram, self.flags_9, self.flags_10,
+            self.extra[0], self.extra[1], self.extra[2], self.extra[3], self.extra[4],
+        ]
+    }
+
+    pub fn builder() -> INesHeaderBuilder {
+        INesHeaderBuilder::new()
+    }
+
+    // Builder starting from this header's decoded fields
+    pub fn to_builder(&self) -> INesHeaderBuilder {
+        INesHeaderBuilder {
+            nes2: self.nes2,
+            mapper: self.mapper,
+            submapper: self.submapper,
+            prg_rom_size: self.prg_rom_size,
+            chr_rom_size: self.chr_rom_size,
+            prg_ram_size: self.prg_ram_size,
+            prg_nvram_size: self.prg_nvram_size,
+            chr_ram_size: self.chr_ram_size,
+            chr_nvram_size: self.chr_nvram_size,
+            mirroring: self.mirroring,
+            battery: self.battery,
+            trainer: self.trainer,
+            timing: self.timing,
+            console: self.console,
+            misc_roms: self.misc_roms,
+            expansion_device: self.expansion_device,
+        }
+    }
+}
+
+// Encodes decoded header fields back into iNES 1.0 or NES 2.0 bytes
+//
+//     let header = try!(INesHeader::builder()
+//         .mapper(4)
+//         .prg_rom_size(0x20000)
+//         .chr_rom_size(0x20000)
+//         .build());
+pub struct INesHeaderBuilder {
+    nes2: bool,
+    mapper: u16,
+    submapper: u8,
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    mirroring: Mirroring,
+    battery: bool,
+    trainer: bool,
+    timing: Timing,
+    console: Console,
+    misc_roms: u8,
+    expansion_device: ExpansionDevice,
+}
+
+impl INesHeaderBuilder {
+    pub fn new() -> INesHeaderBuilder {
+        INesHeaderBuilder {
+            nes2: true,
+            mapper: 0,
+            submapper: 0,
+            prg_rom_size: 0x4000,
+            chr_rom_size: 0,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: false,
+            timing: Timing::Ntsc,
+            console: Console::Nes,
+            misc_roms: 0,
+            expansion_device: ExpansionDevice::Unspecified,
+        }
+    }
+
+    pub fn nes2(mut self, nes2: bool) -> Self {
+        self.nes2 = nes2;
+        self
+    }
+
+    pub fn mapper(mut self, mapper: u16) -> Self {
+        self.mapper = mapper;
+        self
+    }
+
+    pub fn submapper(mut self, submapper: u8) -> Self {
+        self.submapper = submapper;
+        self
+    }
+
+    pub fn prg_rom_size(mut self, size: usize) -> Self {
+        self.prg_rom_size = size;
+        self
+    }
+
+    pub fn chr_rom_size(mut self, size: usize) -> Self {
+        self.chr_rom_size = size;
+        self
+    }
+
+    pub fn prg_ram_size(mut self, size: usize) -> Self {
+        self.prg_ram_size = size;
+        self
+    }
+
+    pub fn prg_nvram_size(mut self, size: usize) -> Self {
+        self.prg_nvram_size = size;
+        self
+    }
+
+    pub fn chr_ram_size(mut self, size: usize) -> Self {
+        self.chr_ram_size = size;
+        self
+    }
+
+    pub fn chr_nvram_size(mut self, size: usize) -> Self {
+        self.chr_nvram_size = size;
+        self
+    }
+
+    pub fn mirroring(mut self, mirroring: Mirroring) -> Self {
+        self.mirroring = mirroring;
+        self
+    }
+
+    pub fn battery(mut self, battery: bool) -> Self {
+        self.battery = battery;
+        self
+    }
+
+    pub fn trainer(mut self, trainer: bool) -> Self {
+        self.trainer = trainer;
+        self
+    }
+
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    pub fn console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+
+    pub fn misc_roms(mut self, count: u8) -> Self {
+        self.misc_roms = count;
+        self
+    }
+
+    pub fn expansion_device(mut self, device: ExpansionDevice) -> Self {
+        self.expansion_device = device;
+        self
+    }
+
+    // NES 2.0 ROM size as the LSB byte and MSB nibble, using the exponent
+    // form when the size is not a whole number of units
+    fn encode_rom_size(size: usize, unit: usize, field: &'static str) -> Result<(u8, u8), RomError> {
+        let units = size / unit;
+        if size % unit == 0 && units < 0xF00 {
+            return Ok((units as u8, (units >> 8) as u8));
+        }
+        for multiplier in 0..4 {
+            let odd = multiplier * 2 + 1;
+            if size % odd == 0 && (size / odd).is_power_of_two() {
+                let exponent = (size / odd).trailing_zeros() as usize;
+                if exponent < 64 {
+                    return Ok(((exponent << 2 | multiplier) as u8, 0x0F));
+                }
+            }
+        }
+        Err(RomError::InvalidNes2Size { field: field, size: size })
+    }
+
+    // NES 2.0 RAM size as a shift count, rounding up to a power of two
+    fn encode_ram_size(size: usize) -> u8 {
+        if size == 0 {
+            return 0;
+        }
+        let mut shift = 1;
+        while 64 << shift < size && shift < 15 {
+            shift += 1;
+        }
+        shift
+    }
+
+    pub fn build(self) -> Result<INesHeader, RomError> {
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(&[0x4E, 0x45, 0x53, 0x1A]);
+
+        header[6] = (self.mapper as u8 & 0x0F) << 4;
+        header[6] |= match self.mirroring {
+            Mirroring::Vertical => 0x01,
+            Mirroring::FourScreen => 0x08,
+            _ => 0x00,
+        };
+        if self.battery {
+            header[6] |= 0x02;
+        }
+        if self.trainer {
+            header[6] |= 0x04;
+        }
+        header[7] = self.mapper as u8 & 0xF0;
+        header[7] |= match self.console {
+            Console::Nes => 0,
+            Console::VsSystem(..) => 1,
+            Console::Playchoice10 => 2,
+            Console::Extended(_) => 3,
+        };
+
+        if self.nes2 {
+            let (prg_lsb, prg_msb) = try!(INesHeaderBuilder::encode_rom_size(self.prg_rom_size, 0x4000, "PRG ROM"));
+            let (chr_lsb, chr_msb) = try!(INesHeaderBuilder::encode_rom_size(self.chr_rom_size, 0x2000, "CHR ROM"));
+            header[4] = prg_lsb;
+            header[5] = chr_lsb;
+            header[7] |= 0x08;
+            header[8] = self.submapper << 4 | (self.mapper >> 8) as u8 & 0x0F;
+            header[9] = chr_msb << 4 | prg_msb;
+            header[10] = INesHeaderBuilder::encode_ram_size(self.prg_nvram_size) << 4
+                | INesHeaderBuilder::encode_ram_size(self.prg_ram_size);
+            header[11] = INesHeaderBuilder::encode_ram_size(self.chr_nvram_size) << 4
+                | INesHeaderBuilder::encode_ram_size(self.chr_ram_size);
+            header[12] = match self.timing {
+                Timing::Ntsc => 0,
+                Timing::Pal => 1,
+                Timing::MultiRegion => 2,
+                Timing::Dendy => 3,
+            };
+            header[13] = match self.console {
+                Console::VsSystem(ppu, hardware) => hardware.to_u8() << 4 | ppu.to_u8() & 0x0F,
+                Console::Extended(kind) => kind & 0x0F,
+                _ => 0,
+            };
+            header[14] = self.misc_roms & 0x03;
+            header[15] = self.expansion_device.to_u8() & 0x3F;
+        } else {
+            if self.mapper > 0xFF {
+                return Err(RomError::UnsupportedMapper(self.mapper));
+            }
+            if self.prg_rom_size % 0x4000 != 0 || self.prg_rom_size / 0x4000 > 0xFF {
+                return Err(RomError::FormatError);
+            }
+            if self.chr_rom_size % 0x2000 != 0 || self.chr_rom_size / 0x2000 > 0xFF {
+                return Err(RomError::FormatError);
+            }
+            header[4] = (self.prg_rom_size / 0x4000) as u8;
+            header[5] = (self.chr_rom_size / 0x2000) as u8;
+            // iNES 1.0 has one PRG RAM size, counting 0 as 8KB
+            let prg_ram = (self.prg_ram_size + self.prg_nvram_size + 0x1FFF) / 0x2000;
+            header[8] = if prg_ram <= 1 { 0 } else { ::std::cmp::min(prg_ram, 0xFF) as u8 };
+            header[9] = match self.timing {
+                Timing::Pal | Timing::Dendy => 0x01,
+                _ => 0x00,
+            };
+        }
+
+        Ok(INesHeader::parse_header(header))
+    }
+}
+
+// Fill buf from the image, naming the section if the file ends first
+pub fn read_section(buf: &mut [u8], section: &'static str, r: &mut Read) -> Result<(), RomError> {
+    let got = try!(util::read_up_to(buf, r));
+    if got < buf.len() {
+        return Err(RomError::Truncated { section: section, expected: buf.len(), got: got });
+    }
+    Ok(())
 }
 
 pub struct Rom {
     pub header: INesHeader,
+    // 512 bytes, empty if the image has no trainer
+    pub trainer: Vec<u8>,
     pub prg: Vec<u8>,
-    pub chr: Vec<u8>
+    pub chr: Vec<u8>,
+    // Anything after CHR ROM: NES 2.0 miscellaneous ROMs, or the INST-ROM
+    // and PROM of a PlayChoice-10 dump
+    pub misc_rom: Vec<u8>,
+    // Header fields the ROM database replaced
+    pub corrections: Vec<Correction>,
 }
 
 impl Rom {
+    // Load an iNES, NES 2.0 or UNIF image, told apart by their magic,
+    // and correct its header from the built in ROM database. The image
+    // may be compressed with gzip or inside a zip.
     pub fn load(r: &mut Read) -> Result<Rom, RomError> {
+        let mut rom = try!(Rom::load_uncorrected(r));
+        rom.correct(RomDb::builtin());
+        Ok(rom)
+    }
+
+    // Load an image trusting its header as it is
+    pub fn load_uncorrected(r: &mut Read) -> Result<Rom, RomError> {
         // iNES header
         let mut header = [0u8; 16];
-        try!(util::read_to_buf(&mut header, r));
+        try!(read_section(&mut header, "header", r));
+        if header[0..4] == unif::MAGIC {
+            return unif::load(header, r);
+        }
+        if Container::detect(&header) != Container::Plain {
+            let data = try!(archive::read(&mut (&header[..]).chain(r), None));
+            return Rom::load_uncorrected(&mut &data[..]);
+        }
         let nes_header = INesHeader::parse_header(header);
         if !nes_header.check_magic() {
-            return Err(RomError::FormatError);
+            return Err(RomError::BadMagic(nes_header.magic));
         } else {
+            if nes_header.nes2 {
+                try!(nes_header.check_sizes());
+            }
+
             // Trainer (if present)         0 or 512 bytes
-            let prg_rom_bytes = nes_header.prg_rom as usize * 16384;
-            let mut prg_rom = vec![0u8; prg_rom_bytes];
-            try!(util::read_to_buf(&mut prg_rom, r));
+            let mut trainer = vec![0u8; if nes_header.trainer { 512 } else { 0 }];
+            try!(read_section(&mut trainer, "trainer", r));
+
+            let mut prg_rom = vec![0u8; nes_header.prg_rom_size];
+            try!(read_section(&mut prg_rom, "PRG ROM", r));
 
-            let chr_rom_bytes = nes_header.chr_rom as usize * 8192;
-            let mut chr_rom = vec![0u8; chr_rom_bytes];
-            try!(util::read_to_buf(&mut chr_rom, r));
+            let mut chr_rom = vec![0u8; nes_header.chr_rom_size];
+            try!(read_section(&mut chr_rom, "CHR ROM", r));
+
+            let mut misc_rom = Vec::new();
+            try!(r.read_to_end(&mut misc_rom));
 
             Ok(Rom{
                 header: nes_header,
+                trainer: trainer,
                 prg: prg_rom,
-                chr: chr_rom
+                chr: chr_rom,
+                misc_rom: misc_rom,
+                corrections: Vec::new(),
             })
         }
     }
-}
\ No newline at end of file
+
+    // Apply a ROM database to the header, adding to the list of
+    // corrections made
+    pub fn correct(&mut self, db: &RomDb) {
+        let corrections = db.correct(self);
+        self.corrections.extend(corrections);
+    }
+
+    // Write the image in iNES form. A ROM loaded from an iNES file is
+    // written back byte for byte, header fields changed through the
+    // database are only written after upgrade_header.
+    pub fn save(&self, w: &mut Write) -> io::Result<()> {
+        try!(w.write_all(&self.header.raw_bytes()));
+        try!(w.write_all(&self.trainer));
+        try!(w.write_all(&self.prg));
+        try!(w.write_all(&self.chr));
+        w.write_all(&self.misc_rom)
+    }
+
+    // Replace the header with an NES 2.0 one built from the decoded
+    // fields, carrying over any database corrections
+    pub fn upgrade_header(&mut self) -> Result<(), RomError> {
+        let header = try!(self.header.to_builder()
+            .nes2(true)
+            .prg_rom_size(self.prg.len())
+            .chr_rom_size(self.chr.len())
+            .trainer(!self.trainer.is_empty())
+            .build());
+        self.header = header;
+        Ok(())
+    }
+
+    // The INST-ROM of a PlayChoice-10 game, if the dump includes it
+    pub fn inst_rom(&self) -> Option<&[u8]> {
+        if self.header.console != Console::Playchoice10 || self.misc_rom.len() < PC10_INST_ROM_SIZE {
+            return None;
+        }
+        Some(&self.misc_rom[..PC10_INST_ROM_SIZE])
+    }
+
+    // The security PROM contents of a PlayChoice-10 game, data then
+    // CounterOut, if the dump includes them
+    pub fn pc10_prom(&self) -> Option<&[u8]> {
+        if self.inst_rom().is_none() || self.misc_rom.len() < PC10_INST_ROM_SIZE + PC10_PROM_SIZE {
+            return None;
+        }
+        Some(&self.misc_rom[PC10_INST_ROM_SIZE..PC10_INST_ROM_SIZE + PC10_PROM_SIZE])
+    }
+
+    // PRG RAM for the $6000-$7FFF window with the trainer, if any, loaded
+    // at $7000. NES 2.0 headers size it, battery backed or not; iNES 1.0
+    // ones can't be trusted to, so the board's usual size is given.
+    pub fn prg_ram(&self, default_size: usize) -> Vec<u8> {
+        let size = if self.header.nes2 {
+            self.header.prg_ram_size + self.header.prg_nvram_size
+        } else {
+            default_size
+        };
+        let mut ram = vec![0u8; size];
+        if size >= 0x1000 + self.trainer.len() {
+            ram[0x1000..0x1000 + self.trainer.len()].copy_from_slice(&self.trainer);
+        }
+        ram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(header: &INesHeader, body_len: usize) -> Vec<u8> {
+        let mut data = header.to_bytes().to_vec();
+        data.extend((0..body_len).map(|i| i as u8));
+        data
+    }
+
+    fn truncation(data: &[u8]) -> Option<(&'static str, usize, usize)> {
+        match Rom::load_uncorrected(&mut &data[..]) {
+            Err(RomError::Truncated { section, expected, got }) => Some((section, expected, got)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn truncated_sections_are_named() {
+        let header = INesHeader::builder().prg_rom_size(0x4000).chr_rom_size(0x2000).trainer(true).build().unwrap();
+        let data = image(&header, 512 + 0x4000 + 0x2000);
+        assert_eq!(truncation(&data[..10]), Some(("header", 16, 10)));
+        assert_eq!(truncation(&data[..16 + 100]), Some(("trainer", 512, 100)));
+        assert_eq!(truncation(&data[..16 + 512 + 0x1000]), Some(("PRG ROM", 0x4000, 0x1000)));
+        assert_eq!(truncation(&data[..data.len() - 1]), Some(("CHR ROM", 0x2000, 0x1FFF)));
+        assert!(Rom::load_uncorrected(&mut &data[..]).is_ok());
+    }
+
+    #[test]
+    fn save_is_byte_exact_with_a_garbage_header() {
+        let header = INesHeader::builder().nes2(false).prg_rom_size(0x4000).chr_rom_size(0x2000).build().unwrap();
+        let mut data = image(&header, 0x4000 + 0x2000);
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let rom = Rom::load_uncorrected(&mut &data[..]).unwrap();
+        assert!(rom.header.garbage_cleaned);
+        assert_eq!(rom.header.mapper, 0);
+
+        let mut saved = Vec::new();
+        rom.save(&mut saved).unwrap();
+        assert_eq!(saved, data);
+    }
+
+    #[test]
+    fn upgrade_header_writes_a_clean_nes2_header() {
+        let header = INesHeader::builder().nes2(false).prg_rom_size(0x4000).chr_rom_size(0x2000).build().unwrap();
+        let mut data = image(&header, 0x4000 + 0x2000);
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let mut rom = Rom::load_uncorrected(&mut &data[..]).unwrap();
+        rom.upgrade_header().unwrap();
+
+        let mut saved = Vec::new();
+        rom.save(&mut saved).unwrap();
+        assert_eq!(saved[7] & 0x0C, 0x08);
+        assert!(saved[11..16].iter().all(|&byte| byte == 0));
+        assert_eq!(&saved[16..], &data[16..]);
+        let reloaded = Rom::load_uncorrected(&mut &saved[..]).unwrap();
+        assert_eq!(reloaded.header.prg_rom_size, 0x4000);
+        assert!(!reloaded.header.garbage_cleaned);
+    }
+
+    #[test]
+    fn truncation_message_gives_the_shortfall() {
+        let err = RomError::Truncated { section: "trainer", expected: 512, got: 100 };
+        assert_eq!(err.to_string(), "trainer truncated, expected 512 bytes but found 100, 412 short");
+    }
+
+    fn nes2_header(prg: (u8, u8), chr: (u8, u8)) -> [u8; 16] {
+        let mut header = [0u8; 16];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg.0;
+        header[5] = chr.0;
+        header[7] = 0x08;
+        header[9] = chr.1 << 4 | prg.1;
+        header
+    }
+
+    fn rom(header: INesHeader, trainer: bool) -> Rom {
+        Rom {
+            header: header,
+            trainer: if trainer { (0..512).map(|i| i as u8).collect() } else { Vec::new() },
+            prg: vec![0; 0x8000],
+            chr: vec![0; 0x2000],
+            misc_rom: Vec::new(),
+            corrections: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn nes2_headers_size_prg_ram() {
+        let header = INesHeader::builder().nes2(true).prg_ram_size(0x2000).prg_nvram_size(0x8000).build().unwrap();
+        assert_eq!(rom(header, false).prg_ram(0x2000).len(), 0xA000);
+        let header = INesHeader::builder().nes2(true).prg_ram_size(0x800).build().unwrap();
+        assert_eq!(rom(header, false).prg_ram(0x2000).len(), 0x800);
+        let header = INesHeader::builder().nes2(true).build().unwrap();
+        assert!(rom(header, false).prg_ram(0x2000).is_empty());
+    }
+
+    #[test]
+    fn ines_headers_get_the_boards_prg_ram() {
+        let header = INesHeader::builder().nes2(false).prg_ram_size(0x8000).build().unwrap();
+        assert_eq!(rom(header, false).prg_ram(0x2000).len(), 0x2000);
+    }
+
+    #[test]
+    fn trainers_load_at_7000_if_the_ram_reaches() {
+        let ram = rom(INesHeader::builder().nes2(false).trainer(true).build().unwrap(), true).prg_ram(0x2000);
+        assert_eq!(&ram[0x1000..0x1004], &[0, 1, 2, 3]);
+        assert_eq!(ram[0x11FF], 0xFF);
+
+        let header = INesHeader::builder().nes2(true).trainer(true).prg_ram_size(0x800).build().unwrap();
+        assert!(rom(header, true).prg_ram(0x2000).iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn nes2_sizes_use_the_exponent_form() {
+        // 2^E * (2M + 1) bytes when the MSB nibble is $F
+        let header = INesHeader::parse_header(nes2_header((0x0F << 2 | 1, 0x0F), (0x0A << 2, 0x0F)));
+        assert_eq!(header.prg_rom_size, (1 << 15) * 3);
+        assert_eq!(header.chr_rom_size, 1 << 10);
+
+        // Otherwise the nibble extends the count of 16KB and 8KB units
+        let header = INesHeader::parse_header(nes2_header((0x02, 0x01), (0x03, 0x00)));
+        assert_eq!(header.prg_rom_size, 0x102 * 0x4000);
+        assert_eq!(header.chr_rom_size, 3 * 0x2000);
+    }
+
+    #[test]
+    fn nes2_sizes_no_cartridge_could_have_are_rejected() {
+        let mut data = nes2_header((0x3F << 2, 0x0F), (0, 0)).to_vec();
+        data.extend_from_slice(&[0; 64]);
+        match Rom::load_uncorrected(&mut &data[..]) {
+            Err(RomError::InvalidNes2Size { field: "PRG ROM", .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|rom| rom.header.prg_rom_size)),
+        }
+    }
+
+    #[test]
+    fn diskdude_garbage_does_not_reach_the_mapper_number() {
+        let mut header = [0u8; 16];
+        header[..4].copy_from_slice(b"NES\x1A");
+        header[4] = 2;
+        header[6] = 0x41;
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let header = INesHeader::parse_header(header);
+        assert!(header.garbage_cleaned);
+        assert!(!header.nes2);
+        assert_eq!(header.mapper, 4);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+
+        // A clean iNES header keeps its upper mapper nibble
+        let mut clean = [0u8; 16];
+        clean[..4].copy_from_slice(b"NES\x1A");
+        clean[6] = 0x41;
+        clean[7] = 0x10;
+        let clean = INesHeader::parse_header(clean);
+        assert!(!clean.garbage_cleaned);
+        assert_eq!(clean.mapper, 0x14);
+    }
+
+}
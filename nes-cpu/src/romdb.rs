@@ -0,0 +1,398 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
+use mapper::Mirroring;
+use rom::{ExpansionDevice, Rom, RomError, Timing, TvSystem};
+use util;
+
+// Database built into the emulator, in the format written by RomDb::write.
+// Regenerate it from the NES 2.0 XML database with from_nes20db_xml.
+const BUILTIN: &'static str = include_str!("romdb.txt");
+
+static BUILTIN_DB: OnceLock<RomDb> = OnceLock::new();
+
+// Header field changed by the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Correction {
+    Mapper,
+    Submapper,
+    Mirroring,
+    Battery,
+    PrgRamSize,
+    PrgNvramSize,
+    ChrRamSize,
+    ChrNvramSize,
+    Timing,
+    ExpansionDevice,
+}
+
+// Known good header values for one dump, keyed by the CRC-32 and SHA-1
+// of its PRG ROM followed by its CHR ROM
+#[derive(Debug, Clone)]
+pub struct DbEntry {
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None where the mapper controls mirroring
+    pub mirroring: Option<Mirroring>,
+    pub battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub expansion_device: u8,
+}
+
+pub struct RomDb {
+    entries: Vec<DbEntry>,
+}
+
+// One entry per line, fields separated by whitespace:
+//
+//   crc32 sha1 mapper submapper mirroring battery prgram prgnvram chrram
+//   chrnvram timing device
+//
+// The CRC and SHA-1 are hex, with "-" for an unknown SHA-1. Mirroring
+// is H, V, 4 or - for mapper controlled. RAM sizes are in bytes. Timing
+// and device are the NES 2.0 byte 12 and byte 15 values. Text after #
+// is a comment.
+impl RomDb {
+    pub fn new() -> RomDb {
+        RomDb { entries: Vec::new() }
+    }
+
+    // The built in database, parsed the first time it is asked for. The
+    // tests check it parses, so a malformed table would only leave it
+    // empty.
+    pub fn builtin() -> &'static RomDb {
+        BUILTIN_DB.get_or_init(|| RomDb::parse(BUILTIN).unwrap_or_else(|_| RomDb::new()))
+    }
+
+    pub fn load(path: &Path) -> Result<RomDb, RomError> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        RomDb::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<RomDb, RomError> {
+        let mut db = RomDb::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            match RomDb::parse_entry(line) {
+                Some(entry) => db.entries.push(entry),
+                None => return Err(RomError::FormatError),
+            }
+        }
+        Ok(db)
+    }
+
+    fn parse_entry(line: &str) -> Option<DbEntry> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 12 {
+            return None;
+        }
+        Some(DbEntry {
+            crc32: u32::from_str_radix(fields[0], 16).ok()?,
+            sha1: if fields[1] == "-" { None } else { Some(parse_sha1(fields[1])?) },
+            mapper: fields[2].parse().ok()?,
+            submapper: fields[3].parse().ok()?,
+            mirroring: match fields[4] {
+                "H" => Some(Mirroring::Horizontal),
+                "V" => Some(Mirroring::Vertical),
+                "4" => Some(Mirroring::FourScreen),
+                "-" => None,
+                _ => return None,
+            },
+            battery: fields[5] == "1",
+            prg_ram_size: fields[6].parse().ok()?,
+            prg_nvram_size: fields[7].parse().ok()?,
+            chr_ram_size: fields[8].parse().ok()?,
+            chr_nvram_size: fields[9].parse().ok()?,
+            timing: timing_from_u8(fields[10].parse().ok()?)?,
+            expansion_device: fields[11].parse().ok()?,
+        })
+    }
+
+    // Write the database in the format read by parse
+    pub fn write(&self, w: &mut Write) -> io::Result<()> {
+        for entry in &self.entries {
+            let sha1 = match entry.sha1 {
+                Some(digest) => digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                None => "-".to_string(),
+            };
+            try!(writeln!(w, "{:08x} {} {} {} {} {} {} {} {} {} {} {}",
+                entry.crc32,
+                sha1,
+                entry.mapper,
+                entry.submapper,
+                match entry.mirroring {
+                    Some(Mirroring::Horizontal) => "H",
+                    Some(Mirroring::Vertical) => "V",
+                    Some(Mirroring::FourScreen) => "4",
+                    _ => "-",
+                },
+                entry.battery as u8,
+                entry.prg_ram_size,
+                entry.prg_nvram_size,
+                entry.chr_ram_size,
+                entry.chr_nvram_size,
+                timing_to_u8(entry.timing),
+                entry.expansion_device
+            ));
+        }
+        Ok(())
+    }
+
+    // Import the NES 2.0 XML database (nes20db.xml). Each <game> holds a
+    // <rom> element with the checksums of PRG followed by CHR, and the
+    // header values in <pcb>, <prgram>, <console>, <expansion> and so on.
+    pub fn from_nes20db_xml(xml: &str) -> RomDb {
+        let mut db = RomDb::new();
+        for game in xml.split("<game>").skip(1) {
+            let game = game.split("</game>").next().unwrap_or("");
+            let size = |name: &str| {
+                element(game, name).and_then(|tag| attr(tag, "size")).and_then(|size| size.parse().ok()).unwrap_or(0)
+            };
+            let rom = match element(game, "rom") {
+                Some(rom) => rom,
+                None => continue,
+            };
+            let pcb = element(game, "pcb").unwrap_or("");
+            let crc32 = match attr(rom, "crc32").and_then(|crc| u32::from_str_radix(crc, 16).ok()) {
+                Some(crc) => crc,
+                None => continue,
+            };
+            db.entries.push(DbEntry {
+                crc32: crc32,
+                sha1: attr(rom, "sha1").and_then(parse_sha1),
+                mapper: attr(pcb, "mapper").and_then(|val| val.parse().ok()).unwrap_or(0),
+                submapper: attr(pcb, "submapper").and_then(|val| val.parse().ok()).unwrap_or(0),
+                mirroring: match attr(pcb, "mirroring") {
+                    Some("H") => Some(Mirroring::Horizontal),
+                    Some("V") => Some(Mirroring::Vertical),
+                    Some("4") => Some(Mirroring::FourScreen),
+                    _ => None,
+                },
+                battery: attr(pcb, "battery") == Some("1"),
+                prg_ram_size: size("prgram"),
+                prg_nvram_size: size("prgnvram"),
+                chr_ram_size: size("chrram"),
+                chr_nvram_size: size("chrnvram"),
+                timing: element(game, "console")
+                    .and_then(|tag| attr(tag, "region"))
+                    .and_then(|val| val.parse().ok())
+                    .and_then(timing_from_u8)
+                    .unwrap_or(Timing::Ntsc),
+                expansion_device: element(game, "expansion")
+                    .and_then(|tag| attr(tag, "type"))
+                    .and_then(|val| val.parse().ok())
+                    .unwrap_or(0),
+            });
+        }
+        db
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Find the entry for a dump. A SHA-1 match is preferred, falling
+    // back to entries that only carry a CRC.
+    pub fn lookup(&self, prg: &[u8], chr: &[u8]) -> Option<&DbEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let crc = util::crc32(util::crc32(0, prg), chr);
+        let candidates: Vec<&DbEntry> = self.entries.iter().filter(|entry| entry.crc32 == crc).collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let sha1 = util::sha1(&[prg, chr]);
+        candidates.iter()
+            .find(|entry| entry.sha1 == Some(sha1))
+            .or_else(|| candidates.iter().find(|entry| entry.sha1.is_none()))
+            .cloned()
+    }
+
+    // Bring the header of a known dump in line with the database,
+    // returning the fields that had to change
+    pub fn correct(&self, rom: &mut Rom) -> Vec<Correction> {
+        let entry = match self.lookup(&rom.prg, &rom.chr) {
+            Some(entry) => entry.clone(),
+            None => return Vec::new(),
+        };
+        let header = &mut rom.header;
+        let mut corrections = Vec::new();
+
+        macro_rules! correct {
+            ($field:ident, $value:expr, $kind:expr) => {
+                if header.$field != $value {
+                    header.$field = $value;
+                    corrections.push($kind);
+                }
+            }
+        }
+        correct!(mapper, entry.mapper, Correction::Mapper);
+        correct!(submapper, entry.submapper, Correction::Submapper);
+        if let Some(mirroring) = entry.mirroring {
+            correct!(mirroring, mirroring, Correction::Mirroring);
+        }
+        correct!(battery, entry.battery, Correction::Battery);
+        correct!(prg_ram_size, entry.prg_ram_size, Correction::PrgRamSize);
+        correct!(prg_nvram_size, entry.prg_nvram_size, Correction::PrgNvramSize);
+        correct!(chr_ram_size, entry.chr_ram_size, Correction::ChrRamSize);
+        correct!(chr_nvram_size, entry.chr_nvram_size, Correction::ChrNvramSize);
+        correct!(timing, entry.timing, Correction::Timing);
+        correct!(expansion_device, ExpansionDevice::from_u8(entry.expansion_device),
+                 Correction::ExpansionDevice);
+
+        header.tv_system = match header.timing {
+            Timing::Ntsc => TvSystem::Ntsc,
+            Timing::Pal | Timing::Dendy => TvSystem::Pal,
+            Timing::MultiRegion => TvSystem::Dual,
+        };
+        corrections
+    }
+}
+
+fn timing_from_u8(val: u8) -> Option<Timing> {
+    match val {
+        0 => Some(Timing::Ntsc),
+        1 => Some(Timing::Pal),
+        2 => Some(Timing::MultiRegion),
+        3 => Some(Timing::Dendy),
+        _ => None,
+    }
+}
+
+fn timing_to_u8(timing: Timing) -> u8 {
+    match timing {
+        Timing::Ntsc => 0,
+        Timing::Pal => 1,
+        Timing::MultiRegion => 2,
+        Timing::Dendy => 3,
+    }
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut digest = [0u8; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Attributes of the first <name .../> element in an XML fragment
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{} ", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find('>')?;
+    Some(&xml[start..start + end])
+}
+
+// Value of name="..." within an element's attributes
+fn attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=\"", name);
+    let mut search = 0;
+    // Skip matches that are the tail of a longer attribute name
+    while let Some(found) = tag[search..].find(&key) {
+        let start = search + found;
+        if start == 0 || tag.as_bytes()[start - 1] == b' ' {
+            let value = start + key.len();
+            let end = tag[value..].find('"')?;
+            return Some(&tag[value..value + end]);
+        }
+        search = start + key.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_table_parses_and_has_entries() {
+        let db = RomDb::parse(BUILTIN).unwrap();
+        assert!(!db.is_empty());
+        assert_eq!(RomDb::builtin().len(), db.len());
+    }
+
+    #[test]
+    fn builtin_is_parsed_once() {
+        assert!(::std::ptr::eq(RomDb::builtin(), RomDb::builtin()));
+    }
+
+    #[test]
+    fn write_and_parse_round_trip() {
+        let db = RomDb::builtin();
+        let mut text = Vec::new();
+        db.write(&mut text).unwrap();
+        let reparsed = RomDb::parse(::std::str::from_utf8(&text).unwrap()).unwrap();
+        assert_eq!(reparsed.len(), db.len());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        assert!(RomDb::parse("3337ec46 - 0 0 X 0 0 0 0 0 0 1").is_err());
+        assert!(RomDb::parse("3337ec46 - 0 0 V").is_err());
+        assert!(RomDb::parse("# only a comment").unwrap().is_empty());
+    }
+
+    #[test]
+    fn xml_import_reads_header_values() {
+        let xml = r#"<game>
+            <rom size="40960" crc32="0000ABCD"/>
+            <console type="0" region="1"/>
+            <pcb mapper="69" submapper="0" mirroring="H" battery="1"/>
+            <prgram size="8192"/>
+            <expansion type="1"/>
+            </game>"#;
+        let db = RomDb::from_nes20db_xml(xml);
+        assert_eq!(db.len(), 1);
+        let entry = &db.entries[0];
+        assert_eq!(entry.crc32, 0xABCD);
+        assert_eq!(entry.sha1, None);
+        assert_eq!(entry.mapper, 69);
+        assert_eq!(entry.mirroring, Some(Mirroring::Horizontal));
+        assert!(entry.battery);
+        assert_eq!(entry.prg_ram_size, 8192);
+        assert_eq!(entry.timing, Timing::Pal);
+    }
+
+    #[test]
+    fn correct_fixes_header_of_known_dump() {
+        let prg = vec![0x11; 0x4000];
+        let chr = vec![0x22; 0x2000];
+        let crc = util::crc32(util::crc32(0, &prg), &chr);
+        let db = RomDb::parse(&format!("{:08x} - 4 0 V 1 0 8192 0 0 1 1", crc)).unwrap();
+        let mut rom = Rom {
+            header: ::rom::INesHeader::builder().build().unwrap(),
+            trainer: Vec::new(),
+            prg: prg,
+            chr: chr,
+            misc_rom: Vec::new(),
+            corrections: Vec::new(),
+        };
+        let corrections = db.correct(&mut rom);
+        assert_eq!(rom.header.mapper, 4);
+        assert_eq!(rom.header.mirroring, Mirroring::Vertical);
+        assert!(rom.header.battery);
+        assert_eq!(rom.header.timing, Timing::Pal);
+        assert!(corrections.contains(&Correction::Mapper));
+        assert!(corrections.contains(&Correction::Timing));
+    }
+}
@@ -0,0 +1,279 @@
+use std::io::Read;
+use mapper::Mirroring;
+use rom::{self, ExpansionDevice, INesHeader, Rom, RomError};
+
+pub const MAGIC: [u8; 4] = [0x55, 0x4E, 0x49, 0x46];
+
+// Header                       32 bytes
+// -> 0   "UNIF"
+// -> 4   Revision, 32-bit little endian
+// -> 8   Reserved, 24 bytes
+//
+// Followed by chunks of a 4 byte ID, 32-bit little endian length and
+// the data. The chunks used here are:
+//
+// MAPR        Board name, null terminated
+// PRG0-PRGF   PRG ROM, concatenated in order
+// CHR0-CHRF   CHR ROM, concatenated in order
+// MIRR        0 horizontal, 1 vertical, 2/3 single screen lower/upper,
+//             4 four-screen, 5 controlled by the mapper
+// BATR        Battery present
+// CTRL        Controllers: standard (bit 0), Zapper (1), R.O.B. (2),
+//             Arkanoid (3), Power Pad (4), Four Score (5)
+const HEADER_SIZE: usize = 32;
+
+// Board names with their iNES mapper and submapper numbers, limited to
+// boards whose mappers mapper::create implements. Names are matched
+// after dropping the NES-, HVC-, UNL-, BMC- or BTL- prefix.
+const BOARDS: &'static [(&'static str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("RROM-128", 0, 0),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("163", 19, 0),
+    ("BTR", 69, 0),
+    ("JLROM", 69, 0),
+    ("JSROM", 69, 0),
+    ("SUNSOFT_FME-7", 69, 0),
+    ("VRC7", 85, 0),
+];
+
+// Look up the iNES mapper and submapper for a UNIF board name
+pub fn board_mapper(name: &str) -> Option<(u16, u8)> {
+    let stripped = ["NES-", "HVC-", "UNL-", "BMC-", "BTL-"].iter()
+        .find(|prefix| name.starts_with(*prefix))
+        .map_or(name, |prefix| &name[prefix.len()..]);
+    BOARDS.iter()
+        .find(|&&(board, _, _)| board.eq_ignore_ascii_case(stripped))
+        .map(|&(_, mapper, submapper)| (mapper, submapper))
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+// Load a UNIF image whose first 16 bytes have already been read, as
+// Rom::load does when it sees the UNIF magic
+pub fn load(start: [u8; 16], r: &mut Read) -> Result<Rom, RomError> {
+    if start[0..4] != MAGIC {
+        return Err(RomError::FormatError);
+    }
+    let mut reserved = [0u8; HEADER_SIZE - 16];
+    try!(rom::read_section(&mut reserved, "UNIF header", r));
+
+    let mut data = Vec::new();
+    try!(r.read_to_end(&mut data));
+
+    let mut board = None;
+    let mut prg_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_chunks: [Option<&[u8]>; 16] = [None; 16];
+    let mut mirr = None;
+    let mut battery = false;
+    let mut ctrl = 0;
+
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = read_u32(&data[pos + 4..pos + 8]) as usize;
+        pos += 8;
+        if data.len() - pos < len {
+            return Err(RomError::FormatError);
+        }
+        let chunk = &data[pos..pos + len];
+        pos += len;
+
+        // PRGn and CHRn take a hex digit for n
+        let index = (id[3] as char).to_digit(16).map(|digit| digit as usize);
+        match (&id[0..3], index) {
+            (b"PRG", Some(n)) => prg_chunks[n] = Some(chunk),
+            (b"CHR", Some(n)) => chr_chunks[n] = Some(chunk),
+            _ => match id {
+                b"MAPR" => {
+                    let end = chunk.iter().position(|&byte| byte == 0).unwrap_or(chunk.len());
+                    board = Some(String::from_utf8_lossy(&chunk[..end]).into_owned());
+                }
+                b"MIRR" => mirr = chunk.first().cloned(),
+                b"BATR" => battery = chunk.first().map_or(false, |&val| val != 0),
+                b"CTRL" => ctrl = chunk.first().cloned().unwrap_or(0),
+                _ => {}
+            },
+        }
+    }
+
+    let board = match board {
+        Some(board) => board,
+        None => return Err(RomError::FormatError),
+    };
+    let (mapper, submapper) = match board_mapper(&board) {
+        Some(numbers) => numbers,
+        None => return Err(RomError::UnsupportedBoard(board)),
+    };
+    let prg: Vec<u8> = prg_chunks.iter().filter_map(|chunk| *chunk).flat_map(|chunk| chunk.iter().cloned()).collect();
+    let chr: Vec<u8> = chr_chunks.iter().filter_map(|chunk| *chunk).flat_map(|chunk| chunk.iter().cloned()).collect();
+    if prg.is_empty() {
+        return Err(RomError::FormatError);
+    }
+
+    // Describe the board with an equivalent NES 2.0 header
+    let mut flags_6 = (mapper as u8 & 0x0F) << 4;
+    if battery {
+        flags_6 |= 0x02;
+    }
+    match mirr {
+        Some(1) => flags_6 |= 0x01,
+        Some(4) => flags_6 |= 0x08,
+        _ => {}
+    }
+    let header = [
+        0x4E, 0x45, 0x53, 0x1A,
+        ((prg.len() + 0x3FFF) / 0x4000) as u8,
+        ((chr.len() + 0x1FFF) / 0x2000) as u8,
+        flags_6,
+        (mapper as u8 & 0xF0) | 0x08,
+        submapper << 4 | (mapper >> 8) as u8,
+        0,
+        // 8KB of PRG RAM, battery backed if BATR says so
+        if battery { 0x70 } else { 0x07 },
+        if chr.is_empty() { 0x07 } else { 0x00 },
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut header = INesHeader::parse_header(header);
+    header.prg_rom_size = prg.len();
+    header.chr_rom_size = chr.len();
+    match mirr {
+        Some(2) => header.mirroring = Mirroring::SingleScreenLower,
+        Some(3) => header.mirroring = Mirroring::SingleScreenUpper,
+        _ => {}
+    }
+    header.expansion_device = if ctrl & 0x20 != 0 {
+        ExpansionDevice::FourScore
+    } else if ctrl & 0x10 != 0 {
+        ExpansionDevice::PowerPadA
+    } else if ctrl & 0x08 != 0 {
+        ExpansionDevice::ArkanoidNes
+    } else if ctrl & 0x02 != 0 {
+        ExpansionDevice::Zapper
+    } else if ctrl & 0x01 != 0 {
+        ExpansionDevice::StandardControllers
+    } else {
+        ExpansionDevice::Unspecified
+    };
+
+    Ok(Rom {
+        header: header,
+        trainer: Vec::new(),
+        prg: prg,
+        chr: chr,
+        misc_rom: Vec::new(),
+        corrections: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mapper;
+
+    fn chunk(out: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+        let len = data.len() as u32;
+        out.extend_from_slice(id);
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        out.extend_from_slice(data);
+    }
+
+    // A UNIF image split into the 16 bytes Rom::load reads and the rest
+    fn image(chunks: &[(&[u8], &[u8])]) -> ([u8; 16], Vec<u8>) {
+        let mut start = [0u8; 16];
+        start[..4].copy_from_slice(&MAGIC);
+        start[4] = 7;
+        let mut rest = vec![0u8; HEADER_SIZE - 16];
+        for &(id, data) in chunks {
+            chunk(&mut rest, id, data);
+        }
+        (start, rest)
+    }
+
+    fn load_image(chunks: &[(&[u8], &[u8])]) -> Result<Rom, RomError> {
+        let (start, rest) = image(chunks);
+        load(start, &mut &rest[..])
+    }
+
+    #[test]
+    fn board_names_drop_the_prefix_and_ignore_case() {
+        assert_eq!(board_mapper("NES-NROM-256"), Some((0, 0)));
+        assert_eq!(board_mapper("HVC-ekrom"), Some((5, 0)));
+        assert_eq!(board_mapper("UNL-SUNSOFT_FME-7"), Some((69, 0)));
+        assert_eq!(board_mapper("NES-SLROM"), None);
+    }
+
+    #[test]
+    fn every_board_has_an_implemented_mapper() {
+        for &(board, _, _) in BOARDS {
+            let (start, rest) = image(&[(b"MAPR", board.as_bytes()), (b"PRG0", &[0; 0x8000]), (b"CHR0", &[0; 0x2000])]);
+            let rom = load(start, &mut &rest[..]).unwrap();
+            assert!(mapper::create(rom).is_ok(), "{} has no mapper", board);
+        }
+    }
+
+    #[test]
+    fn chunks_become_an_equivalent_header() {
+        let prg1 = [2u8; 0x4000];
+        let rom = load_image(&[
+            (b"MAPR", b"NES-NROM-256\0"),
+            (b"PRG1", &prg1),
+            (b"PRG0", &[1; 0x4000]),
+            (b"CHR0", &[3; 0x2000]),
+            (b"MIRR", &[1]),
+            (b"BATR", &[1]),
+            (b"CTRL", &[0x02]),
+            (b"READ", b"ignored"),
+        ]).unwrap();
+        assert_eq!(rom.header.mapper, 0);
+        assert_eq!(rom.header.prg_rom_size, 0x8000);
+        assert_eq!(rom.header.chr_rom_size, 0x2000);
+        // PRG chunks are joined in index order, not file order
+        assert_eq!((rom.prg[0], rom.prg[0x4000]), (1, 2));
+        assert_eq!(rom.chr.len(), 0x2000);
+        assert_eq!(rom.header.mirroring, Mirroring::Vertical);
+        assert!(rom.header.battery);
+        assert_eq!(rom.header.prg_nvram_size, 0x2000);
+        assert_eq!(rom.header.expansion_device, ExpansionDevice::Zapper);
+    }
+
+    #[test]
+    fn single_screen_mirroring_and_chr_ram() {
+        let rom = load_image(&[(b"MAPR", b"VRC7"), (b"PRG0", &[0; 0x4000]), (b"MIRR", &[3])]).unwrap();
+        assert_eq!(rom.header.mapper, 85);
+        assert_eq!(rom.header.mirroring, Mirroring::SingleScreenUpper);
+        assert!(rom.chr.is_empty());
+        assert_eq!(rom.header.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn bad_images_are_rejected() {
+        match load_image(&[(b"MAPR", b"NES-SLROM"), (b"PRG0", &[0; 0x4000])]) {
+            Err(RomError::UnsupportedBoard(ref board)) if board == "NES-SLROM" => {}
+            other => panic!("unexpected result {:?}", other.map(|rom| rom.header.mapper)),
+        }
+        assert!(load_image(&[(b"PRG0", &[0; 0x4000])]).is_err());
+        assert!(load_image(&[(b"MAPR", b"NROM")]).is_err());
+
+        let (start, mut rest) = image(&[(b"MAPR", b"NROM"), (b"PRG0", &[0; 0x4000])]);
+        rest.truncate(rest.len() - 1);
+        assert!(load(start, &mut &rest[..]).is_err());
+
+        let (start, rest) = image(&[]);
+        match load(start, &mut &rest[..4]) {
+            Err(RomError::Truncated { section: "UNIF header", expected: 16, got: 4 }) => {}
+            other => panic!("unexpected result {:?}", other.map(|rom| rom.header.mapper)),
+        }
+    }
+}
@@ -12,4 +12,131 @@ pub fn read_to_buf(mut buf: &mut [u8], rd: &mut Read) -> io::Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Fill as much of the buffer as the reader can, returning the number of
+// bytes read
+pub fn read_up_to(buf: &mut [u8], rd: &mut Read) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        let count = try!(rd.read(&mut buf[total..]));
+        if count == 0 {
+            break;
+        }
+        total += count;
+    }
+
+    Ok(total)
+}
+
+// CRC-32 (IEEE 802.3) as used by zip, PNG and ROM databases. Pass 0 to
+// start and the previous result to continue over several buffers.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// SHA-1 digest of the concatenation of the given buffers
+pub fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let total: usize = parts.iter().map(|part| part.len()).sum();
+
+    // Message followed by a 1 bit, zero padding and the bit length
+    let mut tail = Vec::with_capacity(128);
+    let mut block = [0u8; 64];
+    let mut filled = 0;
+    let padding_len = (119 - total % 64) % 64 + 1;
+    tail.push(0x80);
+    tail.extend(::std::iter::repeat(0).take(padding_len - 1));
+    for i in (0..8).rev() {
+        tail.push(((total as u64 * 8) >> (i * 8)) as u8);
+    }
+
+    for &byte in parts.iter().flat_map(|part| part.iter()).chain(tail.iter()) {
+        block[filled] = byte;
+        filled += 1;
+        if filled < 64 {
+            continue;
+        }
+        filled = 0;
+
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16
+                | (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5A827999),
+                20...39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8]) -> String {
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn sha1_test_vectors() {
+        assert_eq!(hex(&sha1(&[])), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(&[b"abc"])), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1(&[b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"])),
+                   "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        let million = vec![b'a'; 1000000];
+        assert_eq!(hex(&sha1(&[&million])), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+    }
+
+    #[test]
+    fn sha1_of_parts_matches_the_whole() {
+        let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let whole = sha1(&[&data]);
+        for split in &[0, 1, 55, 56, 64, 119, 200] {
+            assert_eq!(sha1(&[&data[..*split], &data[*split..]]), whole);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Layout of converted pixels in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // Bytes R, G, B, A
+    Rgba8888,
+    // Bytes B, G, R, A, as used by Windows bitmaps and many textures
+    Bgra8888,
+    // 16-bit little endian 5:6:5, red in the top bits
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match *self {
+            PixelFormat::Rgba8888 | PixelFormat::Bgra8888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+}
+
+// Bytes needed to hold a whole frame in the given format
+pub fn frame_size(format: PixelFormat) -> usize {
+    SCREEN_WIDTH * SCREEN_HEIGHT * format.bytes_per_pixel()
+}
+
+// Colour of a 9-bit palette index. A palette of 512 entries covers every
+// combination of the emphasis bits; with only 64 they are ignored.
+pub fn color(palette: &[(u8, u8, u8)], index: u16) -> (u8, u8, u8) {
+    if palette.len() >= 512 {
+        palette[index as usize & 0x1FF]
+    } else {
+        palette[index as usize & 0x3F]
+    }
+}
+
+// Store a colour as one pixel of the given format
+pub fn write_pixel(format: PixelFormat, (r, g, b): (u8, u8, u8), out: &mut [u8]) {
+    match format {
+        PixelFormat::Rgba8888 => out[..4].copy_from_slice(&[r, g, b, 0xFF]),
+        PixelFormat::Bgra8888 => out[..4].copy_from_slice(&[b, g, r, 0xFF]),
+        PixelFormat::Rgb565 => {
+            let val = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+            out[0] = val as u8;
+            out[1] = (val >> 8) as u8;
+        }
+    }
+}
+
+// Convert pixels of 9-bit palette indices, such as Ppu::frame, to colours
+// in the given format. out must hold at least bytes_per_pixel bytes for
+// each pixel.
+pub fn convert(pixels: &[u16], palette: &[(u8, u8, u8)], format: PixelFormat, out: &mut [u8]) {
+    let size = format.bytes_per_pixel();
+    for (&index, out) in pixels.iter().zip(out.chunks_mut(size)) {
+        write_pixel(format, color(palette, index), out);
+    }
+}
+
+// A picture in RGB, row by row from the top left, as produced by the
+// NTSC filter
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<(u8, u8, u8)>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width: width,
+            height: height,
+            pixels: vec![(0, 0, 0); width * height],
+        }
+    }
+
+    // Colour a frame of palette indices
+    pub fn from_frame(pixels: &[u16], palette: &[(u8, u8, u8)]) -> Image {
+        Image {
+            width: SCREEN_WIDTH,
+            height: SCREEN_HEIGHT,
+            pixels: pixels.iter().map(|&index| color(palette, index)).collect(),
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        self.pixels[y * self.width + x]
+    }
+
+    // Write the picture in the given format, a row after another. out
+    // must hold width * height * bytes_per_pixel bytes.
+    pub fn write(&self, format: PixelFormat, out: &mut [u8]) {
+        let size = format.bytes_per_pixel();
+        for (&rgb, out) in self.pixels.iter().zip(out.chunks_mut(size)) {
+            write_pixel(format, rgb, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Entry i of a 512 colour palette, telling the index apart by its
+    // colour
+    fn full_palette() -> Vec<(u8, u8, u8)> {
+        (0..512).map(|i| (i as u8, (i >> 8) as u8, 0x40)).collect()
+    }
+
+    #[test]
+    fn emphasis_bits_pick_from_512_colour_palettes_only() {
+        let full = full_palette();
+        assert_eq!(color(&full, 0x1C5), (0xC5, 0x01, 0x40));
+        let plain: Vec<(u8, u8, u8)> = full[..64].to_vec();
+        assert_eq!(color(&plain, 0x1C5), (0x05, 0x00, 0x40));
+    }
+
+    #[test]
+    fn formats_lay_out_their_bytes() {
+        let colors = [(0xFF, 0x00, 0x00), (0x00, 0xFF, 0x00), (0x00, 0x00, 0xFF), (0x12, 0x34, 0x56)];
+        let formats: [(PixelFormat, [&[u8]; 4]); 3] = [
+            (PixelFormat::Rgba8888, [&[0xFF, 0, 0, 0xFF], &[0, 0xFF, 0, 0xFF], &[0, 0, 0xFF, 0xFF], &[0x12, 0x34, 0x56, 0xFF]]),
+            (PixelFormat::Bgra8888, [&[0, 0, 0xFF, 0xFF], &[0, 0xFF, 0, 0xFF], &[0xFF, 0, 0, 0xFF], &[0x56, 0x34, 0x12, 0xFF]]),
+            // Little endian $F800, $07E0, $001F and 00010 001101 01010
+            (PixelFormat::Rgb565, [&[0x00, 0xF8], &[0xE0, 0x07], &[0x1F, 0x00], &[0xAA, 0x11]]),
+        ];
+        for &(format, ref expected) in formats.iter() {
+            for (&color, &bytes) in colors.iter().zip(expected.iter()) {
+                let mut out = [0; 4];
+                write_pixel(format, color, &mut out);
+                assert_eq!(&out[..format.bytes_per_pixel()], bytes, "{:?} {:?}", format, color);
+            }
+        }
+    }
+
+    #[test]
+    fn frames_convert_pixel_by_pixel() {
+        let full = full_palette();
+        let mut frame = vec![0u16; SCREEN_WIDTH * SCREEN_HEIGHT];
+        frame[1] = 0x0121;
+        frame[SCREEN_WIDTH * SCREEN_HEIGHT - 1] = 0x003F;
+        let mut out = vec![0; frame_size(PixelFormat::Bgra8888)];
+        assert_eq!(out.len(), 256 * 240 * 4);
+        convert(&frame, &full, PixelFormat::Bgra8888, &mut out);
+        assert_eq!(&out[..8], &[0x40, 0x00, 0x00, 0xFF, 0x40, 0x01, 0x21, 0xFF]);
+        assert_eq!(&out[out.len() - 4..], &[0x40, 0x00, 0x3F, 0xFF]);
+
+        // An image of the frame writes the same bytes
+        let mut written = vec![0; out.len()];
+        Image::from_frame(&frame, &full).write(PixelFormat::Bgra8888, &mut written);
+        assert_eq!(written, out);
+    }
+}
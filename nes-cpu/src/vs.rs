@@ -0,0 +1,220 @@
+use rom::{Console, ExpansionDevice, Rom, VsHardware, VsPpu};
+
+// Coin switches are held closed for about four frames so that games
+// polling once a frame see them
+const COIN_PULSE_CYCLES: u32 = 4 * 29781;
+
+// Colours of the RGB PPUs as 3 bits each of red, green and blue. The
+// 2C03 and 2C05 output these in the usual order.
+const RGB_PALETTE: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+// The 2C04 variants share one set of 64 colours, scrambled into a
+// different order on each so that games only look right on their own
+const RP2C04_0001_PALETTE: [u16; 64] = [
+    0o755, 0o637, 0o700, 0o447, 0o044, 0o120, 0o222, 0o704, 0o777, 0o333, 0o750, 0o503, 0o403, 0o660, 0o320, 0o777,
+    0o357, 0o653, 0o310, 0o360, 0o467, 0o657, 0o764, 0o027, 0o760, 0o276, 0o000, 0o200, 0o666, 0o444, 0o707, 0o014,
+    0o003, 0o567, 0o757, 0o070, 0o077, 0o022, 0o053, 0o507, 0o000, 0o420, 0o747, 0o510, 0o407, 0o006, 0o740, 0o000,
+    0o000, 0o140, 0o555, 0o031, 0o572, 0o326, 0o770, 0o630, 0o020, 0o036, 0o040, 0o111, 0o773, 0o737, 0o430, 0o473,
+];
+
+const RP2C04_0002_PALETTE: [u16; 64] = [
+    0o000, 0o750, 0o430, 0o572, 0o473, 0o737, 0o044, 0o567, 0o700, 0o407, 0o773, 0o747, 0o777, 0o637, 0o467, 0o040,
+    0o020, 0o357, 0o510, 0o666, 0o053, 0o360, 0o200, 0o447, 0o222, 0o707, 0o003, 0o276, 0o657, 0o320, 0o000, 0o326,
+    0o403, 0o764, 0o740, 0o757, 0o036, 0o310, 0o555, 0o006, 0o507, 0o760, 0o333, 0o120, 0o027, 0o000, 0o660, 0o777,
+    0o653, 0o111, 0o070, 0o630, 0o022, 0o014, 0o704, 0o140, 0o000, 0o077, 0o420, 0o770, 0o755, 0o503, 0o031, 0o444,
+];
+
+const RP2C04_0004_PALETTE: [u16; 64] = [
+    0o430, 0o326, 0o044, 0o660, 0o000, 0o755, 0o014, 0o630, 0o555, 0o310, 0o070, 0o003, 0o764, 0o770, 0o040, 0o572,
+    0o737, 0o200, 0o027, 0o747, 0o000, 0o222, 0o510, 0o740, 0o653, 0o053, 0o447, 0o140, 0o403, 0o000, 0o473, 0o357,
+    0o503, 0o031, 0o420, 0o006, 0o407, 0o507, 0o333, 0o704, 0o022, 0o666, 0o036, 0o020, 0o111, 0o773, 0o444, 0o707,
+    0o757, 0o777, 0o320, 0o700, 0o760, 0o276, 0o777, 0o467, 0o000, 0o750, 0o637, 0o567, 0o360, 0o657, 0o077, 0o120,
+];
+
+const RP2C04_0003_PALETTE: [u16; 64] = [
+    0o507, 0o737, 0o473, 0o555, 0o040, 0o777, 0o567, 0o120, 0o014, 0o000, 0o764, 0o320, 0o704, 0o666, 0o653, 0o467,
+    0o447, 0o044, 0o503, 0o027, 0o140, 0o430, 0o630, 0o053, 0o333, 0o326, 0o000, 0o006, 0o700, 0o510, 0o747, 0o755,
+    0o637, 0o020, 0o003, 0o770, 0o111, 0o750, 0o740, 0o777, 0o360, 0o403, 0o357, 0o707, 0o036, 0o444, 0o000, 0o310,
+    0o077, 0o200, 0o572, 0o757, 0o420, 0o070, 0o660, 0o222, 0o031, 0o000, 0o657, 0o773, 0o407, 0o276, 0o760, 0o022,
+];
+
+// Colours for a Vs. System PPU as 9-bit RGB values
+pub fn palette(ppu: VsPpu) -> Option<&'static [u16; 64]> {
+    match ppu {
+        VsPpu::Rp2c03b | VsPpu::Rp2c03g | VsPpu::Rc2c03b | VsPpu::Rc2c03c |
+        VsPpu::Rc2c05_01 | VsPpu::Rc2c05_02 | VsPpu::Rc2c05_03 | VsPpu::Rc2c05_04 |
+        VsPpu::Rc2c05_05 => Some(&RGB_PALETTE),
+        VsPpu::Rp2c04_0001 => Some(&RP2C04_0001_PALETTE),
+        VsPpu::Rp2c04_0002 => Some(&RP2C04_0002_PALETTE),
+        VsPpu::Rp2c04_0003 => Some(&RP2C04_0003_PALETTE),
+        VsPpu::Rp2c04_0004 => Some(&RP2C04_0004_PALETTE),
+        VsPpu::Unknown(_) => None,
+    }
+}
+
+// Expand a 9-bit RGB palette entry to 8 bits per channel
+pub fn rgb888(color: u16) -> (u8, u8, u8) {
+    let expand = |level: u16| ((level & 7) * 255 / 7) as u8;
+    (expand(color >> 6), expand(color >> 3), expand(color))
+}
+
+// The 2C05 swaps the addresses of PPUCTRL and PPUMASK
+pub fn swaps_ctrl_mask(ppu: VsPpu) -> bool {
+    match ppu {
+        VsPpu::Rc2c05_01 | VsPpu::Rc2c05_02 | VsPpu::Rc2c05_03 | VsPpu::Rc2c05_04 |
+        VsPpu::Rc2c05_05 => true,
+        _ => false,
+    }
+}
+
+// The 2C05 returns an ID in the low bits of PPUSTATUS, which games check
+// as copy protection: 5 bits, or 6 for the 2C05-02's. The 2C05-05's is
+// not known.
+pub fn status_id(ppu: VsPpu) -> Option<u8> {
+    match ppu {
+        VsPpu::Rc2c05_01 | VsPpu::Rc2c05_04 => Some(0x1B),
+        VsPpu::Rc2c05_02 => Some(0x3D),
+        VsPpu::Rc2c05_03 => Some(0x1C),
+        _ => None,
+    }
+}
+
+// Inputs and outputs of the Vs. System that differ from the NES:
+//
+// $4016 read   PCCD DS-B  Controller data (B), service button (S), DIP
+//                         switches 1-2 (D), coins 1-2 (C), secondary CPU (P)
+// $4017 read   DDDD DD-B  Controller data (B), DIP switches 3-8 (D)
+// $4016 write  ---- -BIS  Controller strobe (S), interrupt to the other
+//                         CPU of a dual system (I), bank select (B)
+// $4020 write  ---- ---C  Coin counter (C)
+//
+// The bank select bit picks CHR, and on some boards PRG, banks of the
+// default Vs. board. Whether player 1 is read through $4016 or $4017
+// depends on the game and is given by the header's expansion device.
+pub struct VsSystem {
+    pub ppu: VsPpu,
+    pub hardware: VsHardware,
+    // DIP switches 1-8 in bits 0-7, set when on
+    pub dip_switches: u8,
+    // Player 1's controller is wired to $4017
+    swap_controllers: bool,
+    // Cycles left with each coin switch closed
+    coins: [u32; 2],
+    service: bool,
+    coin_counter: bool,
+    bank_select: bool,
+    interrupt_other: bool,
+    // This is the secondary CPU of a dual system
+    secondary: bool,
+}
+
+impl VsSystem {
+    pub fn new(rom: &Rom) -> Option<VsSystem> {
+        let (ppu, hardware) = match rom.header.console {
+            Console::VsSystem(ppu, hardware) => (ppu, hardware),
+            _ => return None,
+        };
+        Some(VsSystem {
+            ppu: ppu,
+            hardware: hardware,
+            dip_switches: 0,
+            swap_controllers: rom.header.expansion_device == ExpansionDevice::VsSystemReversed,
+            coins: [0; 2],
+            service: false,
+            coin_counter: false,
+            bank_select: false,
+            interrupt_other: false,
+            secondary: false,
+        })
+    }
+
+    // Drop a coin into slot 0 or 1
+    pub fn insert_coin(&mut self, slot: usize) {
+        self.coins[slot] = COIN_PULSE_CYCLES;
+    }
+
+    pub fn set_service(&mut self, pressed: bool) {
+        self.service = pressed;
+    }
+
+    pub fn set_secondary(&mut self, secondary: bool) {
+        self.secondary = secondary;
+    }
+
+    pub fn swaps_controllers(&self) -> bool {
+        self.swap_controllers
+    }
+
+    pub fn coin_counter(&self) -> bool {
+        self.coin_counter
+    }
+
+    pub fn bank_select(&self) -> bool {
+        self.bank_select
+    }
+
+    pub fn interrupt_other(&self) -> bool {
+        self.interrupt_other
+    }
+
+    // Advance by a single CPU cycle
+    pub fn step(&mut self) {
+        for coin in self.coins.iter_mut() {
+            if *coin > 0 {
+                *coin -= 1;
+            }
+        }
+    }
+
+    // Combine the controller's serial bit with the cabinet inputs
+    pub fn read_4016(&self, controller: u8) -> u8 {
+        (controller & 0x01)
+            | (self.service as u8) << 2
+            | (self.dip_switches & 0x03) << 3
+            | ((self.coins[0] > 0) as u8) << 5
+            | ((self.coins[1] > 0) as u8) << 6
+            | (self.secondary as u8) << 7
+    }
+
+    pub fn read_4017(&self, controller: u8) -> u8 {
+        (controller & 0x01) | (self.dip_switches & 0xFC)
+    }
+
+    pub fn write_4016(&mut self, val: u8) {
+        self.interrupt_other = val & 0x02 != 0;
+        self.bank_select = val & 0x04 != 0;
+    }
+
+    pub fn write_4020(&mut self, val: u8) {
+        self.coin_counter = val & 0x01 != 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_2c04_orders_the_same_colours() {
+        let mut expected = RP2C04_0001_PALETTE.to_vec();
+        expected.sort();
+        for &ppu in [VsPpu::Rp2c04_0002, VsPpu::Rp2c04_0003, VsPpu::Rp2c04_0004].iter() {
+            let mut colors = palette(ppu).unwrap().to_vec();
+            colors.sort();
+            assert_eq!(colors, expected, "{:?}", ppu);
+        }
+    }
+
+    #[test]
+    fn the_2c05_swaps_ctrl_and_mask_and_has_an_id() {
+        assert!(swaps_ctrl_mask(VsPpu::Rc2c05_03));
+        assert!(!swaps_ctrl_mask(VsPpu::Rp2c04_0001));
+        assert_eq!(status_id(VsPpu::Rc2c05_03), Some(0x1C));
+        assert_eq!(status_id(VsPpu::Rp2c03b), None);
+    }
+}